@@ -0,0 +1,201 @@
+// Runs a scene file headlessly, writing per frame stats and optionally exported frames.
+//
+// gfluid-cli <scene.toml> [--steps N] [--backend cuda|d3d11|d3d12] [--device N]
+//            [--out DIR] [--format ply|ply_binary|vtk|csv] [--export-every N]
+//
+// Exits with 1 on bad arguments or scenes, 2 when a particle goes NaN and 3 when one escapes
+// the scene's bounds.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Instant;
+
+use gfluid::{
+	export::{self, Format},
+	health::Response,
+	scene::Scene,
+	state::Backend,
+};
+
+const USAGE: &str = "usage: gfluid-cli <scene.toml> [--steps N] [--backend cuda|d3d11|d3d12] \
+                     [--device N] [--out DIR] [--format ply|ply_binary|vtk|csv] [--export-every N]";
+
+struct Options {
+	scene: PathBuf,
+	steps: Option<usize>,
+	backend: Backend,
+	device: i32,
+	out: PathBuf,
+	format: Format,
+	/// Export every this many steps, never when 0
+	export_every: usize,
+}
+
+fn parse_args() -> Result<Options, String> {
+	let mut args = std::env::args().skip(1);
+	let mut scene = None;
+	let mut options = Options {
+		scene: PathBuf::new(),
+		steps: None,
+		backend: Backend::default(),
+		device: 0,
+		out: PathBuf::from("out"),
+		format: Format::PlyBinary,
+		export_every: 0,
+	};
+
+	while let Some(arg) = args.next() {
+		let mut value = |name: &str| args.next().ok_or(format!("{} expects a value", name));
+
+		match arg.as_str() {
+			"--steps" => {
+				let steps = value("--steps")?;
+				options.steps = Some(steps.parse().map_err(|_| "Invalid --steps")?);
+			}
+			"--backend" => {
+				let name = value("--backend")?;
+				let backend = Backend::parse(&name);
+				options.backend = backend.ok_or(format!("Unknown backend '{}'", name))?;
+			}
+			"--device" => {
+				let device = value("--device")?;
+				options.device = device.parse().map_err(|_| "Invalid --device")?;
+			}
+			"--out" => options.out = PathBuf::from(value("--out")?),
+			"--format" => {
+				let name = value("--format")?;
+				options.format = Format::parse(&name).ok_or(format!("Unknown format '{}'", name))?;
+			}
+			"--export-every" => {
+				let every = value("--export-every")?;
+				options.export_every = every.parse().map_err(|_| "Invalid --export-every")?;
+			}
+			"-h" | "--help" => return Err(USAGE.to_owned()),
+			_ if arg.starts_with("--") => {
+				return Err(format!("Unknown option '{}'\n{}", arg, USAGE));
+			}
+			_ if scene.is_none() => scene = Some(PathBuf::from(arg)),
+			_ => return Err(USAGE.to_owned()),
+		}
+	}
+
+	options.scene = scene.ok_or(USAGE)?;
+	Ok(options)
+}
+
+/// What went wrong with a frame, if anything.
+#[derive(Default)]
+struct FrameStats {
+	particles: usize,
+	mean_speed: f32,
+	max_speed: f32,
+	nan: usize,
+	escaped: usize,
+}
+
+fn measure(frame: &export::Frame, scene: &Scene) -> FrameStats {
+	let mut stats = FrameStats {
+		particles: frame.len(),
+		..Default::default()
+	};
+
+	for (p, v) in frame.positions.iter().zip(&frame.velocities) {
+		let finite = [p.0, p.1, p.2, v.0, v.1, v.2].iter().all(|c| c.is_finite());
+		if !finite {
+			stats.nan += 1;
+			continue;
+		}
+
+		if scene.bounds.map_or(false, |bounds| !bounds.contains(p.xyz())) {
+			stats.escaped += 1;
+		}
+
+		let speed = v.length();
+		stats.mean_speed += speed;
+		stats.max_speed = stats.max_speed.max(speed);
+	}
+
+	let finite = stats.particles - stats.nan;
+	if finite > 0 {
+		stats.mean_speed /= finite as f32;
+	}
+
+	stats
+}
+
+fn run(options: &Options) -> Result<u8, Box<dyn std::error::Error>> {
+	let scene = Scene::load(&options.scene)?;
+
+	let mut state = scene.build(options.backend, options.device)?;
+	// Leave bad particles in place, so they fail the run instead of quietly disappearing
+	state.health.response = Response::Ignore;
+
+	std::fs::create_dir_all(&options.out)?;
+	let mut stats_file = BufWriter::new(File::create(options.out.join("stats.csv"))?);
+	writeln!(stats_file, "step,time,particles,mean_speed,max_speed,nan,escaped,step_ms")?;
+
+	let steps = options.steps.unwrap_or_else(|| scene.steps());
+	for step in 1 ..= steps {
+		let start = Instant::now();
+		state.step(scene.dt);
+		let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+
+		let frame = state.get_export_frame();
+		let stats = measure(&frame, &scene);
+		writeln!(
+			stats_file,
+			"{},{},{},{},{},{},{},{:.3}",
+			step,
+			step as f32 * scene.dt,
+			stats.particles,
+			stats.mean_speed,
+			stats.max_speed,
+			stats.nan,
+			stats.escaped,
+			elapsed
+		)?;
+
+		if options.export_every > 0 && step % options.export_every == 0 {
+			let name = export::sequence_name("frame", step as u32);
+			let path = options.out.join(format!("{}.{}", name, options.format.extension()));
+			let mut out = BufWriter::new(File::create(path)?);
+			export::write_frame(&mut out, &frame, options.format)?;
+			out.flush()?;
+		}
+
+		if stats.nan > 0 {
+			stats_file.flush()?;
+			eprintln!("step {}: {} particle(s) went NaN", step, stats.nan);
+			return Ok(2);
+		}
+
+		if stats.escaped > 0 {
+			stats_file.flush()?;
+			eprintln!("step {}: {} particle(s) escaped the scene bounds", step, stats.escaped);
+			return Ok(3);
+		}
+	}
+
+	stats_file.flush()?;
+	println!("Simulated {} steps without problems", steps);
+	Ok(0)
+}
+
+fn main() -> ExitCode {
+	let options = match parse_args() {
+		Ok(options) => options,
+		Err(why) => {
+			eprintln!("{}", why);
+			return ExitCode::from(1);
+		}
+	};
+
+	match run(&options) {
+		Ok(code) => ExitCode::from(code),
+		Err(why) => {
+			eprintln!("{}", why);
+			ExitCode::from(1)
+		}
+	}
+}
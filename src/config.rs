@@ -1,7 +1,35 @@
 use nvflex_sys::*;
+use serde::{Deserialize, Serialize};
 
 pub const MAX_PARTICLES: i32 = 10;
 pub const MAX_SHAPES: i32 = 50;
+pub const MAX_CONTACTS_PER_PARTICLE: i32 = 6;
+pub const MAX_NEIGHBORS_PER_PARTICLE: i32 = 96;
+/// Max foam, spray and bubble particles alive at once.
+pub const MAX_DIFFUSE_PARTICLES: i32 = 4096;
+
+/// How much room FleX allocates for everything, fixed once the solver is created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Capacity {
+	pub particles: i32,
+	pub shapes: i32,
+	pub diffuse_particles: i32,
+	pub contacts_per_particle: i32,
+	pub neighbors_per_particle: i32,
+}
+
+impl Default for Capacity {
+	fn default() -> Self {
+		Self {
+			particles: MAX_PARTICLES,
+			shapes: MAX_SHAPES,
+			diffuse_particles: MAX_DIFFUSE_PARTICLES,
+			contacts_per_particle: MAX_CONTACTS_PER_PARTICLE,
+			neighbors_per_particle: MAX_NEIGHBORS_PER_PARTICLE,
+		}
+	}
+}
 
 pub const PARAMS: NvFlexParams = NvFlexParams {
 	numIterations: 3,
@@ -58,3 +86,22 @@ pub const PARAMS: NvFlexParams = NvFlexParams {
 };
 
 const MAX_PLANES: i32 = 12;
+
+/// Spacing of fluid particles at rest density.
+/// Falls back to FleX's recommended fraction of the radius when the params leave it unset.
+pub fn rest_distance(params: &NvFlexParams) -> f32 {
+	if params.fluidRestDistance > 0.0 {
+		params.fluidRestDistance
+	} else {
+		params.radius * 0.55
+	}
+}
+
+/// Spacing of solid particles, like rigid bodies, at rest.
+pub fn solid_rest_distance(params: &NvFlexParams) -> f32 {
+	if params.solidRestDistance > 0.0 {
+		params.solidRestDistance
+	} else {
+		params.radius
+	}
+}
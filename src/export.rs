@@ -0,0 +1,573 @@
+// Exports particles and colliders to formats external tools like ParaView and Blender can open.
+use nvflex_sys::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::{
+	snapshot::{self, SnapshotError},
+	types::{Quat, Vector3, Vector4},
+};
+
+/// Folder exported files are written to, relative to the game's working directory.
+pub const EXPORT_FOLDER: &str = "garrysmod/data/gfluid/export";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+	PlyAscii,
+	PlyBinary,
+	/// Legacy VTK polydata, ASCII
+	Vtk,
+	Csv,
+}
+
+impl Format {
+	pub fn parse(name: &str) -> Option<Self> {
+		Some(match name {
+			"ply" | "ply_ascii" => Format::PlyAscii,
+			"ply_binary" => Format::PlyBinary,
+			"vtk" => Format::Vtk,
+			"csv" => Format::Csv,
+			_ => return None,
+		})
+	}
+
+	pub fn extension(&self) -> &'static str {
+		match self {
+			Format::PlyAscii | Format::PlyBinary => "ply",
+			Format::Vtk => "vtk",
+			Format::Csv => "csv",
+		}
+	}
+}
+
+/// Every exported property of the active particles, one entry per particle.
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+	pub ids: Vec<i32>,
+	pub positions: Vec<Vector4>,
+	pub velocities: Vec<Vector3>,
+	pub phases: Vec<i32>,
+	pub colors: Vec<[f32; 4]>,
+	pub temperatures: Vec<f32>,
+	pub owners: Vec<i32>,
+	/// Named user channels
+	pub channels: Vec<(String, Vec<f32>)>,
+}
+
+/// A per particle property, flattened to scalars for formats that only know those.
+enum Column<'a> {
+	Float(&'a str, Box<dyn Fn(usize) -> f32 + 'a>),
+	Int(&'a str, Box<dyn Fn(usize) -> i32 + 'a>),
+}
+
+impl Frame {
+	pub fn len(&self) -> usize {
+		self.ids.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.ids.is_empty()
+	}
+
+	/// Every property besides the position, in export order.
+	fn columns(&self) -> Vec<Column> {
+		let mut columns = vec![
+			Column::Int("id", Box::new(move |i| self.ids[i])),
+			Column::Float("vx", Box::new(move |i| self.velocities[i].0)),
+			Column::Float("vy", Box::new(move |i| self.velocities[i].1)),
+			Column::Float("vz", Box::new(move |i| self.velocities[i].2)),
+			Column::Float("inverse_mass", Box::new(move |i| self.positions[i].3)),
+			Column::Int("phase", Box::new(move |i| self.phases[i])),
+			Column::Int("group", Box::new(move |i| self.phases[i] & eNvFlexPhaseGroupMask)),
+		];
+
+		// Attributes may be missing for particles spawned since the last resize
+		let color = move |i: usize, c: usize| self.colors.get(i).map_or(1.0, |color| color[c]);
+		columns.extend([
+			Column::Float("red", Box::new(move |i| color(i, 0))),
+			Column::Float("green", Box::new(move |i| color(i, 1))),
+			Column::Float("blue", Box::new(move |i| color(i, 2))),
+			Column::Float("alpha", Box::new(move |i| color(i, 3))),
+			Column::Float(
+				"temperature",
+				Box::new(move |i| self.temperatures.get(i).copied().unwrap_or_default()),
+			),
+			Column::Int("owner", Box::new(move |i| self.owners.get(i).copied().unwrap_or(-1))),
+		]);
+
+		for (name, values) in &self.channels {
+			let value = move |i: usize| values.get(i).copied().unwrap_or_default();
+			columns.push(Column::Float(name, Box::new(value)));
+		}
+
+		columns
+	}
+}
+
+/// PLY and VTK property names can't have whitespace in them.
+fn property_name(name: &str) -> String {
+	name.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
+/// Writes the particles as a point cloud.
+pub fn write_frame(out: &mut impl Write, frame: &Frame, format: Format) -> io::Result<()> {
+	match format {
+		Format::PlyAscii => write_ply(out, frame, false),
+		Format::PlyBinary => write_ply(out, frame, true),
+		Format::Vtk => write_vtk(out, frame),
+		Format::Csv => write_csv(out, frame),
+	}
+}
+
+fn write_ply(out: &mut impl Write, frame: &Frame, binary: bool) -> io::Result<()> {
+	let columns = frame.columns();
+
+	writeln!(out, "ply")?;
+	if binary {
+		writeln!(out, "format binary_little_endian 1.0")?;
+	} else {
+		writeln!(out, "format ascii 1.0")?;
+	}
+	writeln!(out, "comment gfluid particles")?;
+	writeln!(out, "element vertex {}", frame.len())?;
+	writeln!(out, "property float x\nproperty float y\nproperty float z")?;
+	for column in &columns {
+		match column {
+			Column::Float(name, _) => writeln!(out, "property float {}", property_name(name))?,
+			Column::Int(name, _) => writeln!(out, "property int {}", property_name(name))?,
+		}
+	}
+	writeln!(out, "end_header")?;
+
+	for (i, p) in frame.positions.iter().enumerate() {
+		if binary {
+			for c in [p.0, p.1, p.2] {
+				out.write_all(&c.to_le_bytes())?;
+			}
+
+			for column in &columns {
+				match column {
+					Column::Float(_, f) => out.write_all(&f(i).to_le_bytes())?,
+					Column::Int(_, f) => out.write_all(&f(i).to_le_bytes())?,
+				}
+			}
+		} else {
+			write!(out, "{} {} {}", p.0, p.1, p.2)?;
+			for column in &columns {
+				match column {
+					Column::Float(_, f) => write!(out, " {}", f(i))?,
+					Column::Int(_, f) => write!(out, " {}", f(i))?,
+				}
+			}
+			writeln!(out)?;
+		}
+	}
+
+	Ok(())
+}
+
+fn write_vtk(out: &mut impl Write, frame: &Frame) -> io::Result<()> {
+	let n = frame.len();
+
+	writeln!(out, "# vtk DataFile Version 3.0")?;
+	writeln!(out, "gfluid particles")?;
+	writeln!(out, "ASCII")?;
+	writeln!(out, "DATASET POLYDATA")?;
+
+	writeln!(out, "POINTS {} float", n)?;
+	for p in &frame.positions {
+		writeln!(out, "{} {} {}", p.0, p.1, p.2)?;
+	}
+
+	// One vertex cell per point so ParaView renders them without a glyph filter
+	writeln!(out, "VERTICES {} {}", n, n * 2)?;
+	for i in 0 .. n {
+		writeln!(out, "1 {}", i)?;
+	}
+
+	writeln!(out, "POINT_DATA {}", n)?;
+	writeln!(out, "VECTORS velocity float")?;
+	for v in &frame.velocities {
+		writeln!(out, "{} {} {}", v.0, v.1, v.2)?;
+	}
+
+	for column in frame.columns() {
+		let (name, ty) = match &column {
+			Column::Float(name, _) => (name, "float"),
+			Column::Int(name, _) => (name, "int"),
+		};
+
+		// Velocity is already written as a vector
+		if matches!(*name, "vx" | "vy" | "vz") {
+			continue;
+		}
+
+		writeln!(out, "SCALARS {} {} 1", property_name(name), ty)?;
+		writeln!(out, "LOOKUP_TABLE default")?;
+		for i in 0 .. n {
+			match &column {
+				Column::Float(_, f) => writeln!(out, "{}", f(i))?,
+				Column::Int(_, f) => writeln!(out, "{}", f(i))?,
+			}
+		}
+	}
+
+	Ok(())
+}
+
+fn write_csv(out: &mut impl Write, frame: &Frame) -> io::Result<()> {
+	let columns = frame.columns();
+
+	write!(out, "x,y,z")?;
+	for column in &columns {
+		let name = match column {
+			Column::Float(name, _) | Column::Int(name, _) => name,
+		};
+
+		// Quote names so user channels can't break the header
+		write!(out, ",\"{}\"", name.replace('"', "\"\""))?;
+	}
+	writeln!(out)?;
+
+	for (i, p) in frame.positions.iter().enumerate() {
+		write!(out, "{},{},{}", p.0, p.1, p.2)?;
+		for column in &columns {
+			match column {
+				Column::Float(_, f) => write!(out, ",{}", f(i))?,
+				Column::Int(_, f) => write!(out, ",{}", f(i))?,
+			}
+		}
+		writeln!(out)?;
+	}
+
+	Ok(())
+}
+
+/// Name of a frame in a numbered sequence, like `name_00042`.
+pub fn sequence_name(name: &str, frame: u32) -> String {
+	format!("{}_{:05}", name, frame)
+}
+
+/// Creates `<name>.<extension>` in the export folder, validating the name like snapshot names.
+pub fn create_file(name: &str, extension: &str) -> Result<BufWriter<File>, SnapshotError> {
+	snapshot::check_name(name)?;
+	std::fs::create_dir_all(EXPORT_FOLDER)?;
+
+	let path = std::path::Path::new(EXPORT_FOLDER).join(format!("{}.{}", name, extension));
+	Ok(BufWriter::new(File::create(path)?))
+}
+
+/// A triangle mesh in world space, with zero based indices.
+pub type Mesh = (Vec<Vector3>, Vec<u32>);
+
+/// Triangulates a collision shape, returning None for shape types that can't be.
+/// `triangles` is the local space mesh of triangle mesh shapes, which only their id is kept of.
+pub fn collider_mesh(
+	geometry: &NvFlexCollisionGeometry,
+	ty: NvFlexCollisionShapeType,
+	triangles: Option<(&[Vector3], &[u32])>,
+	position: Vector4,
+	rotation: Quat,
+) -> Option<Mesh> {
+	const SEGMENTS: usize = 16;
+
+	let (vertices, indices) = unsafe {
+		match ty {
+			x if x == eNvFlexShapeBox => {
+				let [x, y, z] = geometry.box_.halfExtents;
+				box_mesh(Vector3(x, y, z))
+			}
+			x if x == eNvFlexShapeSphere => capsule_mesh(geometry.sphere.radius, 0.0, SEGMENTS),
+			x if x == eNvFlexShapeCapsule => {
+				let capsule = geometry.capsule;
+				capsule_mesh(capsule.radius, capsule.halfHeight, SEGMENTS)
+			}
+			x if x == eNvFlexShapeTriangleMesh => {
+				let [sx, sy, sz] = geometry.triMesh.scale;
+				let (vertices, indices) = triangles?;
+				let scaled = vertices.iter().map(|v| Vector3(v.0 * sx, v.1 * sy, v.2 * sz));
+				(scaled.collect(), indices.to_vec())
+			}
+			_ => return None,
+		}
+	};
+
+	let vertices = vertices
+		.into_iter()
+		.map(|v| rotation.rotate(v) + position.xyz())
+		.collect();
+
+	Some((vertices, indices))
+}
+
+fn box_mesh(half: Vector3) -> Mesh {
+	let vertices = (0 .. 8)
+		.map(|c| {
+			let sign = |bit: usize| if c & bit != 0 { 1.0 } else { -1.0 };
+			Vector3(half.0 * sign(1), half.1 * sign(2), half.2 * sign(4))
+		})
+		.collect();
+
+	// Two triangles per face, wound outwards
+	let indices = vec![
+		0, 2, 1, 1, 2, 3, // -z
+		4, 5, 6, 5, 7, 6, // +z
+		0, 1, 4, 1, 5, 4, // -y
+		2, 6, 3, 3, 6, 7, // +y
+		0, 4, 2, 2, 4, 6, // -x
+		1, 3, 5, 3, 7, 5, // +x
+	];
+
+	(vertices, indices)
+}
+
+/// A capsule along the x axis like FleX's, or a sphere when `half_height` is 0.
+fn capsule_mesh(radius: f32, half_height: f32, segments: usize) -> Mesh {
+	let rings = segments / 2;
+	let mut vertices = vec![];
+
+	// Rings from pole to pole along x, the two middle rings offset to each end of the capsule
+	for ring in 0 ..= rings + 1 {
+		let (latitude, offset) = if ring <= rings / 2 {
+			(ring, -half_height)
+		} else {
+			(ring - 1, half_height)
+		};
+
+		let theta = std::f32::consts::PI * latitude as f32 / rings as f32;
+		let (x, r) = (-theta.cos() * radius + offset, theta.sin() * radius);
+
+		for segment in 0 .. segments {
+			let phi = std::f32::consts::TAU * segment as f32 / segments as f32;
+			vertices.push(Vector3(x, r * phi.cos(), r * phi.sin()));
+		}
+	}
+
+	let mut indices = vec![];
+	for ring in 0 ..= rings {
+		for segment in 0 .. segments {
+			let next = (segment + 1) % segments;
+			let a = (ring * segments + segment) as u32;
+			let b = (ring * segments + next) as u32;
+			let c = ((ring + 1) * segments + segment) as u32;
+			let d = ((ring + 1) * segments + next) as u32;
+
+			indices.extend_from_slice(&[a, c, b, b, c, d]);
+		}
+	}
+
+	(vertices, indices)
+}
+
+/// Writes a triangle mesh as an ASCII PLY file.
+pub fn write_mesh(out: &mut impl Write, (vertices, indices): &Mesh) -> io::Result<()> {
+	writeln!(out, "ply")?;
+	writeln!(out, "format ascii 1.0")?;
+	writeln!(out, "comment gfluid collider")?;
+	writeln!(out, "element vertex {}", vertices.len())?;
+	writeln!(out, "property float x\nproperty float y\nproperty float z")?;
+	writeln!(out, "element face {}", indices.len() / 3)?;
+	writeln!(out, "property list uchar int vertex_indices")?;
+	writeln!(out, "end_header")?;
+
+	for v in vertices {
+		writeln!(out, "{} {} {}", v.0, v.1, v.2)?;
+	}
+
+	for tri in indices.chunks_exact(3) {
+		writeln!(out, "3 {} {} {}", tri[0], tri[1], tri[2])?;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Two particles, the second missing its attributes like one spawned since the last resize.
+	fn frame() -> Frame {
+		Frame {
+			ids: vec![3, 7],
+			positions: vec![Vector4(1.0, 2.0, 3.0, 1.0), Vector4(-1.0, 0.5, 0.0, 0.0)],
+			velocities: vec![Vector3(0.5, 0.0, -1.0), Vector3::default()],
+			phases: vec![2, 5],
+			colors: vec![[1.0, 0.0, 0.0, 1.0]],
+			temperatures: vec![80.0],
+			owners: vec![12],
+			channels: vec![("wet ness".to_owned(), vec![0.25])],
+		}
+	}
+
+	/// Position, the 13 built in columns and the channel.
+	const COLUMNS: usize = 3 + 13 + 1;
+
+	fn write(format: Format) -> Vec<u8> {
+		let mut out = vec![];
+		write_frame(&mut out, &frame(), format).unwrap();
+		out
+	}
+
+	/// Splits off the PLY header, returning it and the body.
+	fn ply_header(bytes: &[u8]) -> (String, &[u8]) {
+		let end = b"end_header\n";
+		let at = bytes.windows(end.len()).position(|w| w == end).unwrap() + end.len();
+		(String::from_utf8(bytes[.. at].to_vec()).unwrap(), &bytes[at ..])
+	}
+
+	#[test]
+	fn ply_ascii_has_a_row_per_particle() {
+		let bytes = write(Format::PlyAscii);
+		let (header, body) = ply_header(&bytes);
+
+		assert!(header.contains("format ascii 1.0\n"));
+		assert!(header.contains("element vertex 2\n"));
+		assert_eq!(header.matches("property ").count(), COLUMNS);
+		assert!(header.contains("property float wet_ness\n"));
+		assert!(header.contains("property int group\n"));
+
+		let rows: Vec<Vec<&str>> = std::str::from_utf8(body)
+			.unwrap()
+			.lines()
+			.map(|row| row.split(' ').collect())
+			.collect();
+		assert_eq!(rows.len(), 2);
+		assert!(rows.iter().all(|row| row.len() == COLUMNS));
+
+		// Missing attributes fall back to white, no owner and zeroes
+		assert_eq!(rows[0][3], "3");
+		assert_eq!(&rows[1][10 .. 17], &["1", "1", "1", "1", "0", "-1", "0"]);
+	}
+
+	#[test]
+	fn ply_binary_is_little_endian() {
+		let bytes = write(Format::PlyBinary);
+		let (header, body) = ply_header(&bytes);
+
+		assert!(header.contains("format binary_little_endian 1.0\n"));
+		assert_eq!(body.len(), 2 * COLUMNS * 4);
+		assert_eq!(body[.. 4], 1.0f32.to_le_bytes());
+		assert_eq!(body[12 .. 16], 3i32.to_le_bytes());
+		assert_eq!(body[COLUMNS * 4 .. COLUMNS * 4 + 4], (-1.0f32).to_le_bytes());
+	}
+
+	#[test]
+	fn vtk_writes_vertices_and_scalars() {
+		let text = String::from_utf8(write(Format::Vtk)).unwrap();
+
+		assert!(text.contains("POINTS 2 float\n"));
+		assert!(text.contains("VERTICES 2 4\n1 0\n1 1\n"));
+		assert!(text.contains("POINT_DATA 2\nVECTORS velocity float\n0.5 0 -1\n0 0 0\n"));
+		// Velocity components are written as the vector instead
+		assert_eq!(text.matches("SCALARS ").count(), 13 + 1 - 3);
+		assert!(!text.contains("SCALARS vx"));
+		assert!(text.contains("SCALARS wet_ness float 1\n"));
+	}
+
+	#[test]
+	fn csv_quotes_names() {
+		let text = String::from_utf8(write(Format::Csv)).unwrap();
+		let lines: Vec<&str> = text.lines().collect();
+
+		assert_eq!(lines.len(), 3);
+		assert!(lines[0].starts_with("x,y,z,\"id\","));
+		assert!(lines[0].ends_with(",\"wet ness\""));
+		assert!(lines.iter().all(|line| line.split(',').count() == COLUMNS));
+		assert!(lines[1].starts_with("1,2,3,3,"));
+	}
+
+	#[test]
+	fn empty_frames_still_have_headers() {
+		let mut out = vec![];
+		write_frame(&mut out, &Frame::default(), Format::PlyAscii).unwrap();
+		let (header, body) = ply_header(&out);
+
+		assert!(header.contains("element vertex 0\n"));
+		assert!(body.is_empty());
+	}
+
+	fn collider(
+		geometry: NvFlexCollisionGeometry,
+		ty: NvFlexCollisionShapeType,
+		triangles: Option<(&[Vector3], &[u32])>,
+	) -> Option<Mesh> {
+		let identity = Quat(0.0, 0.0, 0.0, 1.0);
+		collider_mesh(&geometry, ty, triangles, Vector4(10.0, 0.0, 0.0, 0.0), identity)
+	}
+
+	fn in_range((vertices, indices): &Mesh) -> bool {
+		indices.len() % 3 == 0 && indices.iter().all(|&i| (i as usize) < vertices.len())
+	}
+
+	#[test]
+	fn box_colliders_have_twelve_triangles() {
+		let geometry = NvFlexCollisionGeometry {
+			box_: NvFlexBoxGeometry {
+				halfExtents: [1.0, 2.0, 3.0],
+			},
+		};
+		let mesh = collider(geometry, eNvFlexShapeBox, None).unwrap();
+
+		assert_eq!((mesh.0.len(), mesh.1.len() / 3), (8, 12));
+		assert!(in_range(&mesh));
+
+		// Moved to the shape's position
+		let (lower, upper) = crate::voxel::bounds(&mesh.0).unwrap();
+		assert_eq!((lower.0, upper.0, lower.2, upper.2), (9.0, 11.0, -3.0, 3.0));
+	}
+
+	#[test]
+	fn round_colliders_are_closed_bands() {
+		let (rings, segments) = (16 / 2, 16);
+		let triangles = (rings + 1) * segments * 2;
+
+		let sphere = NvFlexCollisionGeometry {
+			sphere: NvFlexSphereGeometry { radius: 2.0 },
+		};
+		let mesh = collider(sphere, eNvFlexShapeSphere, None).unwrap();
+		assert_eq!(mesh.1.len() / 3, triangles);
+		assert!(in_range(&mesh));
+		assert!(mesh.0.iter().all(|v| ((*v - Vector3(10.0, 0.0, 0.0)).length() - 2.0).abs() < 1e-4));
+
+		let capsule = NvFlexCollisionGeometry {
+			capsule: NvFlexCapsuleGeometry {
+				radius: 1.0,
+				halfHeight: 3.0,
+			},
+		};
+		let mesh = collider(capsule, eNvFlexShapeCapsule, None).unwrap();
+		assert_eq!(mesh.1.len() / 3, triangles);
+		assert!(in_range(&mesh));
+
+		let (lower, upper) = crate::voxel::bounds(&mesh.0).unwrap();
+		assert!((lower.0 - 6.0).abs() < 1e-4 && (upper.0 - 14.0).abs() < 1e-4);
+	}
+
+	#[test]
+	fn triangle_mesh_colliders_are_scaled() {
+		let mut geometry: NvFlexCollisionGeometry = unsafe { std::mem::zeroed() };
+		geometry.triMesh.scale = [2.0, 1.0, 1.0];
+
+		let vertices = [Vector3(0.0, 0.0, 0.0), Vector3(1.0, 0.0, 0.0), Vector3(0.0, 1.0, 0.0)];
+		let indices = [0, 1, 2];
+		let mesh = collider(geometry, eNvFlexShapeTriangleMesh, Some((&vertices, &indices))).unwrap();
+
+		assert_eq!(mesh.1, vec![0, 1, 2]);
+		assert_eq!((mesh.0[1].0, mesh.0[2].1), (12.0, 1.0));
+
+		// Without the mesh data there's nothing to triangulate
+		assert!(collider(geometry, eNvFlexShapeTriangleMesh, None).is_none());
+		assert!(collider(geometry, eNvFlexShapeSDF, None).is_none());
+	}
+
+	#[test]
+	fn mesh_ply_counts_faces() {
+		let mut out = vec![];
+		write_mesh(&mut out, &box_mesh(Vector3(1.0, 1.0, 1.0))).unwrap();
+		let text = String::from_utf8(out).unwrap();
+
+		assert!(text.contains("element vertex 8\n"));
+		assert!(text.contains("element face 12\n"));
+		assert_eq!(text.lines().filter(|line| line.starts_with("3 ")).count(), 12);
+	}
+}
@@ -0,0 +1,564 @@
+// Force fields that push particles around, applied to their velocities before each solver step.
+use crate::{
+	snapshot::{Reader, SnapshotError, Writer},
+	types::{Vector3, Vector4},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub enum FieldKind {
+	/// Pushes away from the field's position, or pulls towards it when `strength` is negative.
+	/// Strength fades out to the radius, raised to the power of `falloff`.
+	Radial {
+		strength: f32,
+		radius: f32,
+		falloff: f32,
+	},
+	/// Constant acceleration inside of a box centered on the field's position, like a wind zone.
+	Directional {
+		acceleration: Vector3,
+		half_extents: Vector3,
+	},
+	/// Swirls around an axis through the field's position, fading out to the radius.
+	Vortex {
+		axis: Vector3,
+		strength: f32,
+		radius: f32,
+	},
+	/// Divergence free turbulence inside of a radius around the field's position.
+	Noise {
+		strength: f32,
+		frequency: f32,
+		radius: f32,
+	},
+}
+
+#[derive(Clone, Debug)]
+pub struct ForceField {
+	pub kind: FieldKind,
+	pub position: Vector3,
+	pub enabled: bool,
+	/// Phase groups this field affects, every group when empty.
+	pub groups: Vec<i32>,
+}
+
+impl ForceField {
+	pub fn new(kind: FieldKind, position: Vector3) -> Self {
+		Self {
+			kind,
+			position,
+			enabled: true,
+			groups: vec![],
+		}
+	}
+
+	pub fn affects(&self, group: i32) -> bool {
+		self.enabled && (self.groups.is_empty() || self.groups.contains(&group))
+	}
+
+	/// Acceleration this field applies at a point, `time` animating the noise.
+	pub fn acceleration(&self, point: Vector3, time: f32) -> Vector3 {
+		let offset = point - self.position;
+
+		match self.kind {
+			FieldKind::Radial {
+				strength,
+				radius,
+				falloff,
+			} => {
+				let distance = offset.length();
+				if distance >= radius || distance <= 0.0 {
+					return Vector3::default();
+				}
+
+				let scale = (1.0 - distance / radius).powf(falloff);
+				offset * (strength * scale / distance)
+			}
+			FieldKind::Directional {
+				acceleration,
+				half_extents,
+			} => {
+				let inside = offset.0.abs() <= half_extents.0
+					&& offset.1.abs() <= half_extents.1
+					&& offset.2.abs() <= half_extents.2;
+
+				if inside {
+					acceleration
+				} else {
+					Vector3::default()
+				}
+			}
+			FieldKind::Vortex {
+				axis,
+				strength,
+				radius,
+			} => {
+				// Distance from the axis rather than the center
+				let along = axis * offset.dot(axis);
+				let radial = offset - along;
+				let distance = radial.length();
+				if distance >= radius || distance <= 0.0 {
+					return Vector3::default();
+				}
+
+				let tangent = axis.cross(radial) * (1.0 / distance);
+				tangent * (strength * (1.0 - distance / radius))
+			}
+			FieldKind::Noise {
+				strength,
+				frequency,
+				radius,
+			} => {
+				if offset.length() >= radius {
+					return Vector3::default();
+				}
+
+				curl_noise(point * frequency, time) * strength
+			}
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct ForceFields {
+	/// Removed fields leave a hole so handles stay valid.
+	fields: Vec<Option<ForceField>>,
+}
+
+impl ForceFields {
+	pub fn add(&mut self, field: ForceField) -> usize {
+		self.fields.push(Some(field));
+		self.fields.len() - 1
+	}
+
+	pub fn remove(&mut self, handle: usize) -> bool {
+		self.fields
+			.get_mut(handle)
+			.and_then(|field| field.take())
+			.is_some()
+	}
+
+	pub fn get_mut(&mut self, handle: usize) -> Option<&mut ForceField> {
+		self.fields.get_mut(handle)?.as_mut()
+	}
+
+	pub fn any_enabled(&self) -> bool {
+		self.fields.iter().flatten().any(|f| f.enabled)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &ForceField> {
+		self.fields.iter().flatten()
+	}
+
+	/// Writes every field into a snapshot, holes included so handles survive a reload.
+	pub fn write_snapshot(&self, w: &mut Writer) {
+		w.count(self.fields.len());
+		for field in &self.fields {
+			let field = match field {
+				Some(field) => field,
+				None => {
+					w.u8(0);
+					continue;
+				}
+			};
+
+			match field.kind {
+				FieldKind::Radial {
+					strength,
+					radius,
+					falloff,
+				} => {
+					w.u8(1);
+					[strength, radius, falloff].into_iter().for_each(|v| w.f32(v));
+				}
+				FieldKind::Directional {
+					acceleration,
+					half_extents,
+				} => {
+					w.u8(2);
+					w.vector3(acceleration);
+					w.vector3(half_extents);
+				}
+				FieldKind::Vortex {
+					axis,
+					strength,
+					radius,
+				} => {
+					w.u8(3);
+					w.vector3(axis);
+					w.f32(strength);
+					w.f32(radius);
+				}
+				FieldKind::Noise {
+					strength,
+					frequency,
+					radius,
+				} => {
+					w.u8(4);
+					[strength, frequency, radius].into_iter().for_each(|v| w.f32(v));
+				}
+			}
+
+			w.vector3(field.position);
+			w.u8(field.enabled as u8);
+			w.slice(&field.groups, Writer::i32);
+		}
+	}
+
+	pub fn read_snapshot(r: &mut Reader) -> Result<Self, SnapshotError> {
+		let mut fields = vec![];
+		for _ in 0 .. r.count(1)? {
+			let kind = match r.u8()? {
+				0 => {
+					fields.push(None);
+					continue;
+				}
+				1 => FieldKind::Radial {
+					strength: r.f32()?,
+					radius: r.f32()?,
+					falloff: r.f32()?,
+				},
+				2 => FieldKind::Directional {
+					acceleration: r.vector3()?,
+					half_extents: r.vector3()?,
+				},
+				3 => FieldKind::Vortex {
+					axis: r.vector3()?,
+					strength: r.f32()?,
+					radius: r.f32()?,
+				},
+				4 => FieldKind::Noise {
+					strength: r.f32()?,
+					frequency: r.f32()?,
+					radius: r.f32()?,
+				},
+				_ => return Err(SnapshotError::Range("force field")),
+			};
+
+			fields.push(Some(ForceField {
+				kind,
+				position: r.vector3()?,
+				enabled: r.u8()? != 0,
+				groups: r.vec(4, Reader::i32)?,
+			}));
+		}
+
+		Ok(Self { fields })
+	}
+
+	/// Integrates every field's acceleration into the particle velocities over `dt`.
+	/// `groups` holds the phase group of each particle.
+	pub fn apply(
+		&self,
+		positions: impl Iterator<Item = Vector3>,
+		velocities: &mut [Vector3],
+		groups: &[i32],
+		dt: f32,
+		time: f32,
+	) {
+		let fields: Vec<&ForceField> = self.fields.iter().flatten().collect();
+
+		for ((pos, vel), group) in positions.zip(velocities.iter_mut()).zip(groups) {
+			for field in &fields {
+				if field.affects(*group) {
+					*vel += field.acceleration(pos, time) * dt;
+				}
+			}
+		}
+	}
+}
+
+/// An instantaneous change in velocity for every particle in a sphere, like a grenade going off.
+#[derive(Clone, Copy, Debug)]
+pub struct Impulse {
+	pub center: Vector3,
+	pub radius: f32,
+	/// Velocity added at the center, pointing away from it. Negative values implode.
+	pub strength: f32,
+	/// Exponent of the fade towards the radius, 0 for none.
+	pub falloff: f32,
+	/// Extra upwards velocity, as a fraction of the outwards one, to throw things into the air.
+	pub lift: f32,
+}
+
+impl Impulse {
+	/// Applies the impulse, returning how many particles were affected.
+	/// Particles with zero inverse mass, like pinned cloth, don't move.
+	pub fn apply(&self, positions: &[Vector4], velocities: &mut [Vector3]) -> usize {
+		let mut affected = 0;
+
+		for (pos, vel) in positions.iter().zip(velocities.iter_mut()) {
+			if pos.3 <= 0.0 {
+				continue;
+			}
+
+			let offset = pos.xyz() - self.center;
+			let distance = offset.length();
+			if distance >= self.radius {
+				continue;
+			}
+
+			let direction = if distance > 0.0 {
+				offset * (1.0 / distance)
+			} else {
+				Vector3(0.0, 0.0, 1.0)
+			};
+
+			let scale = self.strength * (1.0 - distance / self.radius).powf(self.falloff);
+			*vel += (direction + Vector3(0.0, 0.0, self.lift)) * scale;
+			affected += 1;
+		}
+
+		affected
+	}
+}
+
+/// Curl of a vector potential made of three decorrelated noise fields.
+/// Being a curl, the flow has no sources or sinks, so it stirs the fluid without compressing it.
+fn curl_noise(p: Vector3, time: f32) -> Vector3 {
+	const EPSILON: f32 = 1e-2;
+
+	let potential = |p: Vector3| {
+		Vector3(
+			noise(p + Vector3(0.0, 0.0, time)),
+			noise(p + Vector3(31.4, 12.7, time)),
+			noise(p + Vector3(-17.2, 45.3, time)),
+		)
+	};
+
+	let dx = Vector3(EPSILON, 0.0, 0.0);
+	let dy = Vector3(0.0, EPSILON, 0.0);
+	let dz = Vector3(0.0, 0.0, EPSILON);
+
+	let (px0, px1) = (potential(p - dx), potential(p + dx));
+	let (py0, py1) = (potential(p - dy), potential(p + dy));
+	let (pz0, pz1) = (potential(p - dz), potential(p + dz));
+
+	let scale = 1.0 / (2.0 * EPSILON);
+	Vector3(
+		((py1.2 - py0.2) - (pz1.1 - pz0.1)) * scale,
+		((pz1.0 - pz0.0) - (px1.2 - px0.2)) * scale,
+		((px1.1 - px0.1) - (py1.0 - py0.0)) * scale,
+	)
+}
+
+/// Smoothly interpolated value noise in the range -1 to 1.
+fn noise(p: Vector3) -> f32 {
+	let (x, y, z) = (p.0.floor(), p.1.floor(), p.2.floor());
+	let (fx, fy, fz) = (p.0 - x, p.1 - y, p.2 - z);
+	let (x, y, z) = (x as i32, y as i32, z as i32);
+
+	let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+	let (sx, sy, sz) = (smooth(fx), smooth(fy), smooth(fz));
+	let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+	let corner = |dx: i32, dy: i32, dz: i32| hash(x + dx, y + dy, z + dz);
+
+	lerp(
+		lerp(
+			lerp(corner(0, 0, 0), corner(1, 0, 0), sx),
+			lerp(corner(0, 1, 0), corner(1, 1, 0), sx),
+			sy,
+		),
+		lerp(
+			lerp(corner(0, 0, 1), corner(1, 0, 1), sx),
+			lerp(corner(0, 1, 1), corner(1, 1, 1), sx),
+			sy,
+		),
+		sz,
+	)
+}
+
+fn hash(x: i32, y: i32, z: i32) -> f32 {
+	let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+		^ (y as u32).wrapping_mul(0xd816_3841)
+		^ (z as u32).wrapping_mul(0xcb1a_b31f);
+
+	h ^= h >> 13;
+	h = h.wrapping_mul(0x5bd1_e995);
+	h ^= h >> 15;
+
+	(h as f32 / u32::MAX as f32) * 2.0 - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn close(a: Vector3, b: Vector3) -> bool {
+		(a - b).length() < 1e-5
+	}
+
+	fn radial(strength: f32, falloff: f32) -> ForceField {
+		let kind = FieldKind::Radial {
+			strength,
+			radius: 10.0,
+			falloff,
+		};
+		ForceField::new(kind, Vector3(1.0, 2.0, 3.0))
+	}
+
+	#[test]
+	fn radial_fades_out_to_the_radius() {
+		let field = radial(4.0, 1.0);
+
+		// Halfway out, pushing away from the center at half strength
+		let a = field.acceleration(Vector3(6.0, 2.0, 3.0), 0.0);
+		assert!(close(a, Vector3(2.0, 0.0, 0.0)));
+
+		let a = radial(4.0, 2.0).acceleration(Vector3(6.0, 2.0, 3.0), 0.0);
+		assert!(close(a, Vector3(1.0, 0.0, 0.0)));
+
+		// Negative strength pulls inwards
+		let a = radial(-4.0, 1.0).acceleration(Vector3(6.0, 2.0, 3.0), 0.0);
+		assert!(close(a, Vector3(-2.0, 0.0, 0.0)));
+
+		assert!(close(field.acceleration(Vector3(11.0, 2.0, 3.0), 0.0), Vector3::default()));
+		assert!(close(field.acceleration(field.position, 0.0), Vector3::default()));
+	}
+
+	#[test]
+	fn directional_only_inside_its_box() {
+		let kind = FieldKind::Directional {
+			acceleration: Vector3(0.0, 5.0, 0.0),
+			half_extents: Vector3(1.0, 2.0, 3.0),
+		};
+		let field = ForceField::new(kind, Vector3::default());
+
+		assert!(close(field.acceleration(Vector3(1.0, -2.0, 3.0), 0.0), Vector3(0.0, 5.0, 0.0)));
+		assert!(close(field.acceleration(Vector3(1.5, 0.0, 0.0), 0.0), Vector3::default()));
+	}
+
+	#[test]
+	fn vortex_swirls_around_its_axis() {
+		let kind = FieldKind::Vortex {
+			axis: Vector3(0.0, 0.0, 1.0),
+			strength: 2.0,
+			radius: 4.0,
+		};
+		let field = ForceField::new(kind, Vector3::default());
+
+		// Height along the axis doesn't matter, only the distance from it
+		let a = field.acceleration(Vector3(2.0, 0.0, 100.0), 0.0);
+		assert!(close(a, Vector3(0.0, 1.0, 0.0)));
+
+		assert!(close(field.acceleration(Vector3(0.0, 0.0, 5.0), 0.0), Vector3::default()));
+		assert!(close(field.acceleration(Vector3(5.0, 0.0, 0.0), 0.0), Vector3::default()));
+	}
+
+	#[test]
+	fn noise_is_bounded_by_the_radius() {
+		let kind = FieldKind::Noise {
+			strength: 1.0,
+			frequency: 0.5,
+			radius: 10.0,
+		};
+		let field = ForceField::new(kind, Vector3::default());
+
+		let a = field.acceleration(Vector3(1.3, 2.7, -0.4), 0.0);
+		assert!(a.length().is_finite() && a.length() > 0.0);
+		assert!(close(field.acceleration(Vector3(20.0, 0.0, 0.0), 0.0), Vector3::default()));
+	}
+
+	#[test]
+	fn groups_and_enabled_filter_fields() {
+		let mut field = radial(1.0, 1.0);
+		assert!(field.affects(3));
+
+		field.groups = vec![1, 2];
+		assert!(field.affects(2));
+		assert!(!field.affects(3));
+
+		field.enabled = false;
+		assert!(!field.affects(2));
+	}
+
+	#[test]
+	fn snapshot_keeps_holes() {
+		let mut fields = ForceFields::default();
+		fields.add(radial(4.0, 2.0));
+		let removed = fields.add(radial(1.0, 1.0));
+		let mut wind = ForceField::new(
+			FieldKind::Directional {
+				acceleration: Vector3(1.0, 2.0, 3.0),
+				half_extents: Vector3(4.0, 5.0, 6.0),
+			},
+			Vector3(7.0, 8.0, 9.0),
+		);
+		wind.enabled = false;
+		wind.groups = vec![5, -1];
+		fields.add(wind);
+		assert!(fields.remove(removed));
+
+		let mut w = Writer::default();
+		fields.write_snapshot(&mut w);
+		let read = ForceFields::read_snapshot(&mut Reader::new(&w.bytes)).unwrap();
+
+		assert_eq!(read.fields.len(), 3);
+		assert!(read.fields[1].is_none());
+
+		match read.fields[0].as_ref().map(|f| f.kind) {
+			Some(FieldKind::Radial {
+				strength,
+				radius,
+				falloff,
+			}) => assert_eq!((strength, radius, falloff), (4.0, 10.0, 2.0)),
+			other => panic!("expected a radial field, got {:?}", other),
+		}
+
+		let wind = read.fields[2].as_ref().unwrap();
+		assert!(matches!(wind.kind, FieldKind::Directional { .. }));
+		assert!(close(wind.position, Vector3(7.0, 8.0, 9.0)));
+		assert!(!wind.enabled);
+		assert_eq!(wind.groups, vec![5, -1]);
+	}
+
+	#[test]
+	fn snapshot_rejects_unknown_kinds() {
+		let mut w = Writer::default();
+		w.count(1);
+		w.u8(9);
+
+		assert!(matches!(
+			ForceFields::read_snapshot(&mut Reader::new(&w.bytes)),
+			Err(SnapshotError::Range(_))
+		));
+	}
+
+	fn impulse(lift: f32) -> Impulse {
+		Impulse {
+			center: Vector3::default(),
+			radius: 10.0,
+			strength: 4.0,
+			falloff: 1.0,
+			lift,
+		}
+	}
+
+	#[test]
+	fn impulse_pushes_outwards_with_falloff() {
+		let positions = [Vector4(5.0, 0.0, 0.0, 1.0), Vector4(0.0, 20.0, 0.0, 1.0)];
+		let mut velocities = [Vector3::default(); 2];
+
+		assert_eq!(impulse(0.5).apply(&positions, &mut velocities), 1);
+		assert!(close(velocities[0], Vector3(2.0, 0.0, 1.0)));
+		assert!(close(velocities[1], Vector3::default()));
+	}
+
+	#[test]
+	fn impulse_skips_zero_inverse_mass() {
+		let positions = [Vector4(1.0, 0.0, 0.0, 0.0)];
+		let mut velocities = [Vector3::default()];
+
+		assert_eq!(impulse(0.0).apply(&positions, &mut velocities), 0);
+		assert!(close(velocities[0], Vector3::default()));
+	}
+
+	#[test]
+	fn impulse_at_the_center_goes_up() {
+		let positions = [Vector4(0.0, 0.0, 0.0, 1.0)];
+		let mut velocities = [Vector3::default()];
+
+		assert_eq!(impulse(0.0).apply(&positions, &mut velocities), 1);
+		assert!(close(velocities[0], Vector3(0.0, 0.0, 4.0)));
+	}
+}
@@ -0,0 +1,67 @@
+// Uniform spatial hash for neighbor queries over particle positions on the CPU.
+use std::collections::HashMap;
+
+use crate::types::Vector3;
+
+#[derive(Debug)]
+pub struct HashGrid {
+	cell: f32,
+	cells: HashMap<(i32, i32, i32), Vec<usize>>,
+}
+
+impl HashGrid {
+	/// Buckets points into cubic cells of the given size.
+	/// Queries are cheapest when the cell size is close to the query radius.
+	pub fn new(points: impl IntoIterator<Item = Vector3>, cell: f32) -> Self {
+		let mut grid = Self {
+			cell: cell.max(f32::EPSILON),
+			cells: HashMap::new(),
+		};
+
+		for (i, p) in points.into_iter().enumerate() {
+			let key = grid.key(p);
+			grid.cells.entry(key).or_default().push(i);
+		}
+
+		grid
+	}
+
+	fn key(&self, p: Vector3) -> (i32, i32, i32) {
+		(
+			(p.0 / self.cell).floor() as i32,
+			(p.1 / self.cell).floor() as i32,
+			(p.2 / self.cell).floor() as i32,
+		)
+	}
+
+	/// Calls `f` with the index of every point in cells overlapping a sphere.
+	/// Candidates still need their distance checked against `radius`.
+	pub fn query<F: FnMut(usize)>(&self, center: Vector3, radius: f32, mut f: F) {
+		let lo = self.key(center - Vector3(radius, radius, radius));
+		let hi = self.key(center + Vector3(radius, radius, radius));
+
+		for x in lo.0 ..= hi.0 {
+			for y in lo.1 ..= hi.1 {
+				for z in lo.2 ..= hi.2 {
+					if let Some(bucket) = self.cells.get(&(x, y, z)) {
+						bucket.iter().copied().for_each(&mut f);
+					}
+				}
+			}
+		}
+	}
+
+	/// Amount of `points` within `radius` of `center`, `points` being what the grid was built from.
+	pub fn count_within(&self, points: &[Vector3], center: Vector3, radius: f32) -> usize {
+		let mut count = 0;
+		let radius2 = radius * radius;
+
+		self.query(center, radius, |i| {
+			if (points[i] - center).length_squared() <= radius2 {
+				count += 1;
+			}
+		});
+
+		count
+	}
+}
@@ -0,0 +1,407 @@
+// Catches simulations blowing up, checking particles after each step for NaNs, runaway speeds,
+// particles escaping the world and sudden jumps in kinetic energy.
+use std::time::{Duration, Instant};
+
+use crate::types::{Vector3, Vector4};
+
+/// Speeds this far past maxSpeed count as runaway, as FleX clamps them a little loosely.
+const SPEED_TOLERANCE: f32 = 1.1;
+/// How fast the average kinetic energy follows the current one.
+const ENERGY_SMOOTHING: f32 = 0.05;
+
+/// What happens to particles that fail a check.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Response {
+	/// Stops simulating them, keeping their ids around to look at, see [HealthMonitor::quarantined].
+	#[default]
+	Quarantine,
+	/// Removes them like a kill volume would.
+	Remove,
+	/// Only warns about them.
+	Ignore,
+}
+
+impl Response {
+	pub fn parse(name: &str) -> Option<Self> {
+		Some(match name {
+			"quarantine" => Response::Quarantine,
+			"remove" => Response::Remove,
+			"ignore" => Response::Ignore,
+			_ => return None,
+		})
+	}
+}
+
+/// Counts of particles failing each check.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Report {
+	/// Particles with a NaN or infinite position or velocity
+	pub nan: u32,
+	/// Particles faster than maxSpeed
+	pub fast: u32,
+	/// Particles outside of the world bounds
+	pub escaped: u32,
+	/// Steps where the kinetic energy jumped past [HealthMonitor::spike_factor] times its average
+	pub spikes: u32,
+	/// Times the simulation was rolled back to the last good snapshot
+	pub rollbacks: u32,
+}
+
+impl Report {
+	pub fn is_healthy(&self) -> bool {
+		*self == Report::default()
+	}
+
+	fn add(&mut self, other: &Report) {
+		self.nan += other.nan;
+		self.fast += other.fast;
+		self.escaped += other.escaped;
+		self.spikes += other.spikes;
+		self.rollbacks += other.rollbacks;
+	}
+}
+
+impl std::fmt::Display for Report {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{} NaN, {} too fast, {} out of bounds, {} energy spike(s), {} rollback(s)",
+			self.nan, self.fast, self.escaped, self.spikes, self.rollbacks
+		)
+	}
+}
+
+/// Result of checking one step.
+#[derive(Debug, Default)]
+pub struct Check {
+	pub report: Report,
+	/// Particles that failed any of the per particle checks
+	pub offenders: Vec<i32>,
+}
+
+impl Check {
+	/// Whether the step is bad enough to roll back, rather than just dealing with a few particles.
+	pub fn is_fatal(&self) -> bool {
+		self.report.nan > 0 || self.report.spikes > 0
+	}
+}
+
+#[derive(Debug)]
+pub struct HealthMonitor {
+	/// Checks read back every particle each step, so they're off until asked for.
+	pub enabled: bool,
+	pub response: Response,
+	/// Particles outside of this box count as escaped, when set.
+	pub bounds: Option<(Vector3, Vector3)>,
+	/// Kinetic energy this many times its recent average counts as a spike.
+	pub spike_factor: f32,
+	/// Energy only counts as a spike with particles moving this fast on average,
+	/// so splashing a resting pool doesn't.
+	pub spike_speed: f32,
+	/// Restores the last good snapshot when particles go NaN or energy spikes.
+	pub rollback: bool,
+	/// Healthy steps between two snapshots kept for rolling back.
+	pub snapshot_interval: u32,
+	/// Minimum time between two warnings.
+	pub interval: Duration,
+
+	/// Particles stopped by [Response::Quarantine], until they're compacted away.
+	pub quarantined: Vec<i32>,
+	/// Everything found since monitoring started.
+	pub total: Report,
+
+	average_energy: Option<f32>,
+	last_good: Option<Vec<u8>>,
+	healthy_steps: u32,
+	unreported: Report,
+	last_warning: Option<Instant>,
+}
+
+impl Default for HealthMonitor {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			response: Response::default(),
+			bounds: None,
+			spike_factor: 10.0,
+			spike_speed: 100.0,
+			rollback: false,
+			snapshot_interval: 60,
+			interval: Duration::from_secs(1),
+
+			quarantined: vec![],
+			total: Report::default(),
+
+			average_energy: None,
+			last_good: None,
+			healthy_steps: 0,
+			unreported: Report::default(),
+			last_warning: None,
+		}
+	}
+}
+
+impl HealthMonitor {
+	/// Checks every active particle after a step. `max_speed` is the solver's maxSpeed param.
+	pub fn check(
+		&mut self,
+		positions: &[Vector4],
+		velocities: &[Vector3],
+		active: &[i32],
+		max_speed: f32,
+	) -> Check {
+		let mut check = Check::default();
+		let max_speed2 = (max_speed * SPEED_TOLERANCE).powi(2);
+		let (mut energy, mut mass) = (0.0, 0.0);
+
+		for &id in active {
+			let (p, v) = match (positions.get(id as usize), velocities.get(id as usize)) {
+				(Some(p), Some(v)) => (*p, *v),
+				_ => continue,
+			};
+
+			let finite = [p.0, p.1, p.2, p.3, v.0, v.1, v.2].iter().all(|x| x.is_finite());
+			if !finite {
+				check.report.nan += 1;
+				check.offenders.push(id);
+				continue;
+			}
+
+			let speed2 = v.length_squared();
+			let escaped = self.bounds.map_or(false, |(min, max)| {
+				let inside = (min.0 ..= max.0).contains(&p.0)
+					&& (min.1 ..= max.1).contains(&p.1)
+					&& (min.2 ..= max.2).contains(&p.2);
+				!inside
+			});
+
+			if speed2 > max_speed2 {
+				check.report.fast += 1;
+			}
+			if escaped {
+				check.report.escaped += 1;
+			}
+			if speed2 > max_speed2 || escaped {
+				check.offenders.push(id);
+			}
+
+			// Particles with no inverse mass are kinematic and don't count
+			if p.3 > 0.0 {
+				energy += 0.5 * speed2 / p.3;
+				mass += 1.0 / p.3;
+			}
+		}
+
+		let floor = 0.5 * mass * self.spike_speed * self.spike_speed;
+		if let Some(average) = self.average_energy {
+			if energy > floor && energy > average * self.spike_factor {
+				check.report.spikes += 1;
+			}
+		}
+
+		// NaNs leave out particles, so the energy of those steps means nothing
+		if check.report.nan == 0 {
+			self.average_energy = Some(match self.average_energy {
+				Some(average) => average + (energy - average) * ENERGY_SMOOTHING,
+				None => energy,
+			});
+		}
+
+		check
+	}
+
+	/// Whether a snapshot should be kept after a step that passed every check.
+	pub fn wants_snapshot(&mut self) -> bool {
+		self.healthy_steps += 1;
+		self.rollback && (self.last_good.is_none() || self.healthy_steps >= self.snapshot_interval)
+	}
+
+	pub fn keep_snapshot(&mut self, snapshot: Vec<u8>) {
+		self.last_good = Some(snapshot);
+		self.healthy_steps = 0;
+	}
+
+	pub fn last_good(&self) -> Option<&[u8]> {
+		self.last_good.as_deref()
+	}
+
+	/// Records a check, so it shows up in the next warning.
+	pub fn record(&mut self, report: &Report) {
+		if !report.is_healthy() {
+			self.healthy_steps = 0;
+		}
+
+		self.total.add(report);
+		self.unreported.add(report);
+	}
+
+	/// Forgets the energy history, so energy added on purpose like an explosion isn't a spike.
+	pub fn forget_energy(&mut self) {
+		self.average_energy = None;
+	}
+
+	/// Problems found since the last warning, if there are any and [Self::interval] has passed.
+	pub fn warning(&mut self) -> Option<Report> {
+		if self.unreported.is_healthy() {
+			return None;
+		}
+
+		let now = Instant::now();
+		if self.last_warning.map_or(false, |last| now.duration_since(last) < self.interval) {
+			return None;
+		}
+
+		self.last_warning = Some(now);
+		Some(std::mem::take(&mut self.unreported))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Particles at the origin with unit inverse mass, moving along x.
+	fn moving(speeds: &[f32]) -> (Vec<Vector4>, Vec<Vector3>, Vec<i32>) {
+		let positions = vec![Vector4(0.0, 0.0, 0.0, 1.0); speeds.len()];
+		let velocities = speeds.iter().map(|&s| Vector3(s, 0.0, 0.0)).collect();
+		(positions, velocities, (0 .. speeds.len() as i32).collect())
+	}
+
+	#[test]
+	fn nan_particles_are_fatal() {
+		let mut monitor = HealthMonitor::default();
+		let (mut positions, mut velocities, active) = moving(&[1.0, 1.0, 1.0]);
+		positions[0].1 = f32::NAN;
+		velocities[2].2 = f32::INFINITY;
+
+		let check = monitor.check(&positions, &velocities, &active, 10.0);
+		assert_eq!(check.report.nan, 2);
+		assert_eq!(check.offenders, vec![0, 2]);
+		assert!(check.is_fatal());
+		// The energy of what's left doesn't count as the average
+		assert!(monitor.average_energy.is_none());
+	}
+
+	#[test]
+	fn fast_particles_have_some_tolerance() {
+		let mut monitor = HealthMonitor::default();
+		let (positions, velocities, active) = moving(&[10.5, 12.0, -12.0]);
+
+		let check = monitor.check(&positions, &velocities, &active, 10.0);
+		assert_eq!(check.report.fast, 2);
+		assert_eq!(check.offenders, vec![1, 2]);
+		assert!(!check.is_fatal());
+	}
+
+	#[test]
+	fn escaped_particles_need_bounds() {
+		let mut monitor = HealthMonitor::default();
+		let (mut positions, velocities, active) = moving(&[0.0, 0.0]);
+		positions[1].2 = 50.0;
+
+		let check = monitor.check(&positions, &velocities, &active, 10.0);
+		assert!(check.report.is_healthy());
+
+		monitor.bounds = Some((Vector3(-10.0, -10.0, -10.0), Vector3(10.0, 10.0, 10.0)));
+		let check = monitor.check(&positions, &velocities, &active, 10.0);
+		assert_eq!(check.report.escaped, 1);
+		assert_eq!(check.offenders, vec![1]);
+	}
+
+	#[test]
+	fn inactive_and_missing_particles_are_skipped() {
+		let mut monitor = HealthMonitor::default();
+		let (mut positions, velocities, _) = moving(&[1.0, 1.0]);
+		positions[1].0 = f32::NAN;
+
+		let check = monitor.check(&positions, &velocities, &[0, 5], 10.0);
+		assert!(check.report.is_healthy() && check.offenders.is_empty());
+	}
+
+	#[test]
+	fn energy_spikes_past_the_speed_floor() {
+		let mut monitor = HealthMonitor {
+			spike_speed: 5.0,
+			..HealthMonitor::default()
+		};
+		let mut step = |speed: f32| {
+			let (positions, velocities, active) = moving(&[speed, speed]);
+			monitor.check(&positions, &velocities, &active, 1000.0).report.spikes
+		};
+
+		// The first step only sets the average
+		assert_eq!(step(1.0), 0);
+		// Way past the average, but slower than spike_speed
+		assert_eq!(step(4.0), 0);
+		assert_eq!(step(20.0), 1);
+
+		monitor.forget_energy();
+		let (positions, velocities, active) = moving(&[40.0, 40.0]);
+		let check = monitor.check(&positions, &velocities, &active, 1000.0);
+		assert_eq!(check.report.spikes, 0);
+	}
+
+	#[test]
+	fn kinematic_particles_carry_no_energy() {
+		let mut monitor = HealthMonitor {
+			spike_speed: 0.0,
+			..HealthMonitor::default()
+		};
+		let (mut positions, velocities, active) = moving(&[1.0, 500.0]);
+		monitor.check(&positions, &velocities[.. 1], &active, 1000.0);
+
+		positions[1].3 = 0.0;
+		let check = monitor.check(&positions, &velocities, &active, 1000.0);
+		assert_eq!(check.report.spikes, 0);
+		assert_eq!(monitor.average_energy, Some(0.5));
+	}
+
+	#[test]
+	fn unhealthy_steps_delay_snapshots() {
+		let mut monitor = HealthMonitor {
+			rollback: true,
+			snapshot_interval: 3,
+			..HealthMonitor::default()
+		};
+
+		// The first healthy step always has one taken
+		assert!(monitor.wants_snapshot());
+		monitor.keep_snapshot(vec![1]);
+		assert_eq!(monitor.last_good(), Some(&[1][..]));
+
+		assert!(!monitor.wants_snapshot());
+		monitor.record(&Report {
+			fast: 1,
+			..Report::default()
+		});
+		assert!(!monitor.wants_snapshot());
+		assert!(!monitor.wants_snapshot());
+		assert!(monitor.wants_snapshot());
+
+		monitor.rollback = false;
+		assert!(!monitor.wants_snapshot());
+	}
+
+	#[test]
+	fn warnings_add_up_between_intervals() {
+		let mut monitor = HealthMonitor::default();
+		assert!(monitor.warning().is_none());
+
+		let report = Report {
+			escaped: 2,
+			..Report::default()
+		};
+		monitor.record(&report);
+		assert_eq!(monitor.warning(), Some(report));
+
+		// Too soon after the last one, so it waits and adds up
+		monitor.record(&report);
+		monitor.record(&report);
+		assert!(monitor.warning().is_none());
+
+		monitor.interval = Duration::ZERO;
+		assert_eq!(monitor.warning().map(|r| r.escaped), Some(4));
+		assert!(monitor.warning().is_none());
+		assert_eq!(monitor.total.escaped, 6);
+	}
+}
@@ -2,14 +2,33 @@ use nvflex_sys::*;
 
 #[inline(always)]
 #[allow(non_snake_case)]
-pub fn NvFlexMakePhase(group: i32, particle_flags: i32) -> i32 {
+pub fn NvFlexMakePhaseWithChannels(group: i32, particle_flags: i32, shape_channels: i32) -> i32 {
 	(group & eNvFlexPhaseGroupMask)
 		| (particle_flags & eNvFlexPhaseFlagsMask)
-		| eNvFlexPhaseShapeChannelMask
+		| (shape_channels & eNvFlexPhaseShapeChannelMask)
+}
+
+/// Makes a phase that collides with every shape channel.
+#[inline(always)]
+#[allow(non_snake_case)]
+pub fn NvFlexMakePhase(group: i32, particle_flags: i32) -> i32 {
+	NvFlexMakePhaseWithChannels(group, particle_flags, eNvFlexPhaseShapeChannelMask)
+}
+
+#[inline(always)]
+#[allow(non_snake_case)]
+pub fn NvFlexMakeShapeFlagsWithChannels(
+	ty: NvFlexCollisionShapeType,
+	dynamic: bool,
+	shape_channels: i32,
+) -> i32 {
+	ty | (if dynamic { eNvFlexShapeFlagDynamic } else { 0 })
+		| (shape_channels & eNvFlexPhaseShapeChannelMask)
 }
 
+/// Makes shape flags for a shape that collides with every phase channel.
 #[inline(always)]
 #[allow(non_snake_case)]
 pub fn NvFlexMakeShapeFlags(ty: NvFlexCollisionShapeType, dynamic: bool) -> i32 {
-	ty | (if dynamic { eNvFlexShapeFlagDynamic } else { 0 }) | eNvFlexPhaseShapeChannelMask
+	NvFlexMakeShapeFlagsWithChannels(ty, dynamic, eNvFlexPhaseShapeChannelMask)
 }
@@ -0,0 +1,262 @@
+// Client side jitter buffer for replicated particles.
+//
+// Frames from the server are timestamped with its simulation time. Rendering runs a little behind
+// the newest frame, so there's usually a frame on each side to interpolate between even when some
+// arrive late. When frames stop arriving, particles keep moving with their last velocity for a bit.
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Instant;
+
+use crate::types::Vector3;
+
+/// Seconds rendering lags behind the newest frame by default, to absorb jitter.
+pub const DEFAULT_DELAY: f32 = 0.1;
+/// Seconds particles are extrapolated for by default once frames run out.
+pub const DEFAULT_MAX_EXTRAPOLATION: f32 = 0.25;
+
+/// Frames kept in the buffer at most.
+const CAPACITY: usize = 32;
+/// How fast the estimate of the server clock follows new frames.
+const CLOCK_SMOOTHING: f32 = 0.1;
+/// Clock errors beyond this many seconds snap instead of being smoothed, like after a map change.
+const CLOCK_SNAP: f32 = 1.0;
+
+#[derive(Clone, Debug, Default)]
+struct Snapshot {
+	time: f32,
+	particles: BTreeMap<i32, Vector3>,
+}
+
+#[derive(Debug)]
+pub struct Interpolator {
+	/// Seconds rendering lags behind the estimated server time.
+	pub delay: f32,
+	/// Seconds particles keep moving past the newest frame before freezing.
+	pub max_extrapolation: f32,
+
+	snapshots: VecDeque<Snapshot>,
+	/// Estimated server time minus local time.
+	offset: Option<f32>,
+	clock: Instant,
+}
+
+impl Default for Interpolator {
+	fn default() -> Self {
+		Self {
+			delay: DEFAULT_DELAY,
+			max_extrapolation: DEFAULT_MAX_EXTRAPOLATION,
+
+			snapshots: VecDeque::new(),
+			offset: None,
+			clock: Instant::now(),
+		}
+	}
+}
+
+impl Interpolator {
+	fn now(&self) -> f32 {
+		self.clock.elapsed().as_secs_f32()
+	}
+
+	/// Buffers a frame taken at server `time`. Frames arriving too late to matter are dropped.
+	pub fn push(&mut self, time: f32, particles: impl IntoIterator<Item = (i32, Vector3)>) {
+		let sample = time - self.now();
+		self.offset = Some(match self.offset {
+			Some(offset) if (sample - offset).abs() < CLOCK_SNAP => {
+				offset + (sample - offset) * CLOCK_SMOOTHING
+			}
+			_ => {
+				// The server started over, nothing buffered is relevant anymore
+				self.snapshots.clear();
+				sample
+			}
+		});
+
+		let at = self.snapshots.partition_point(|s| s.time < time);
+		if at == 0 && self.snapshots.len() >= CAPACITY {
+			return;
+		}
+
+		let snapshot = Snapshot {
+			time,
+			particles: particles.into_iter().collect(),
+		};
+
+		match self.snapshots.get(at) {
+			Some(existing) if existing.time == time => self.snapshots[at] = snapshot,
+			_ => self.snapshots.insert(at, snapshot),
+		}
+
+		if self.snapshots.len() > CAPACITY {
+			self.snapshots.pop_front();
+		}
+	}
+
+	/// Server time being rendered right now.
+	pub fn render_time(&self) -> Option<f32> {
+		Some(self.now() + self.offset? - self.delay)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.snapshots.is_empty()
+	}
+
+	pub fn clear(&mut self) {
+		self.snapshots.clear();
+		self.offset = None;
+	}
+
+	/// (id, position) of every particle at the current render time.
+	pub fn sample(&self) -> Vec<(i32, Vector3)> {
+		match self.render_time() {
+			Some(time) => self.sample_at(time),
+			None => vec![],
+		}
+	}
+
+	/// (id, position) of every particle at a server time.
+	///
+	/// Particles in both frames around `time` are interpolated. Ones that disappear in the later
+	/// frame keep moving until it, and ones that appear in it only show up once it's reached.
+	pub fn sample_at(&self, time: f32) -> Vec<(i32, Vector3)> {
+		let next = self.snapshots.partition_point(|s| s.time <= time);
+
+		let (a, b) = match (next.checked_sub(1), self.snapshots.get(next)) {
+			(Some(prev), Some(b)) => (&self.snapshots[prev], b),
+			(Some(prev), None) => return self.extrapolate(prev, time),
+			(None, Some(first)) => {
+				return first.particles.iter().map(|(id, p)| (*id, *p)).collect();
+			}
+			(None, None) => return vec![],
+		};
+
+		let t = ((time - a.time) / (b.time - a.time).max(f32::EPSILON)).clamp(0.0, 1.0);
+		a.particles
+			.iter()
+			.map(|(id, pa)| match b.particles.get(id) {
+				Some(pb) => (*id, *pa + (*pb - *pa) * t),
+				None => (*id, *pa + self.velocity(next - 1, *id) * (time - a.time)),
+			})
+			.collect()
+	}
+
+	/// Positions past the newest frame, moving with their last velocity up to the extrapolation limit.
+	fn extrapolate(&self, newest: usize, time: f32) -> Vec<(i32, Vector3)> {
+		let snapshot = &self.snapshots[newest];
+		let dt = (time - snapshot.time).clamp(0.0, self.max_extrapolation);
+
+		snapshot
+			.particles
+			.iter()
+			.map(|(id, p)| (*id, *p + self.velocity(newest, *id) * dt))
+			.collect()
+	}
+
+	/// Velocity of a particle going into a snapshot, from the one before it.
+	fn velocity(&self, index: usize, id: i32) -> Vector3 {
+		let current = &self.snapshots[index];
+		let previous = match index.checked_sub(1).map(|i| &self.snapshots[i]) {
+			Some(previous) => previous,
+			None => return Vector3::default(),
+		};
+
+		match (previous.particles.get(&id), current.particles.get(&id)) {
+			(Some(from), Some(to)) if current.time > previous.time => {
+				(*to - *from) * (1.0 / (current.time - previous.time))
+			}
+			_ => Vector3::default(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn at(x: f32) -> Vector3 {
+		Vector3(x, 0.0, 0.0)
+	}
+
+	fn xs(particles: &[(i32, Vector3)]) -> Vec<(i32, f32)> {
+		particles.iter().map(|(id, p)| (*id, p.0)).collect()
+	}
+
+	#[test]
+	fn samples_between_frames() {
+		let mut interpolator = Interpolator::default();
+		interpolator.push(0.0, [(1, at(0.0)), (2, at(10.0))]);
+		interpolator.push(0.5, [(1, at(1.0)), (2, at(20.0))]);
+
+		assert_eq!(xs(&interpolator.sample_at(0.25)), [(1, 0.5), (2, 15.0)]);
+		assert_eq!(xs(&interpolator.sample_at(0.5)), [(1, 1.0), (2, 20.0)]);
+		// Before the first frame it's shown as is
+		assert_eq!(xs(&interpolator.sample_at(-1.0)), [(1, 0.0), (2, 10.0)]);
+	}
+
+	#[test]
+	fn extrapolates_up_to_the_limit() {
+		let mut interpolator = Interpolator::default();
+		interpolator.push(0.0, [(1, at(0.0))]);
+		interpolator.push(0.5, [(1, at(1.0))]);
+
+		assert_eq!(xs(&interpolator.sample_at(0.6)), [(1, 1.2)]);
+		let limit = 1.0 + 2.0 * DEFAULT_MAX_EXTRAPOLATION;
+		assert_eq!(xs(&interpolator.sample_at(10.0)), [(1, limit)]);
+	}
+
+	#[test]
+	fn orders_late_frames() {
+		let mut interpolator = Interpolator::default();
+		interpolator.push(0.0, [(1, at(0.0))]);
+		interpolator.push(0.5, [(1, at(4.0))]);
+		interpolator.push(0.25, [(1, at(1.0))]);
+
+		assert_eq!(xs(&interpolator.sample_at(0.125)), [(1, 0.5)]);
+		assert_eq!(xs(&interpolator.sample_at(0.375)), [(1, 2.5)]);
+
+		// A repeated frame replaces the one buffered
+		interpolator.push(0.25, [(1, at(2.0))]);
+		assert_eq!(xs(&interpolator.sample_at(0.125)), [(1, 1.0)]);
+	}
+
+	#[test]
+	fn drops_frames_older_than_a_full_buffer() {
+		let mut interpolator = Interpolator::default();
+		for i in 0 .. CAPACITY {
+			interpolator.push(0.5 + i as f32 * 0.01, [(1, at(i as f32))]);
+		}
+		interpolator.push(0.0, [(1, at(-100.0))]);
+
+		assert_eq!(xs(&interpolator.sample_at(0.0)), [(1, 0.0)]);
+	}
+
+	#[test]
+	fn clock_jumps_clear_the_buffer() {
+		let mut interpolator = Interpolator::default();
+		interpolator.push(100.0, [(1, at(0.0))]);
+		interpolator.push(100.5, [(1, at(1.0))]);
+
+		// Like a map change restarting the server's clock
+		interpolator.push(0.0, [(2, at(5.0))]);
+		assert_eq!(xs(&interpolator.sample_at(100.25)), [(2, 5.0)]);
+
+		let render = interpolator.render_time().unwrap();
+		assert!((render + DEFAULT_DELAY).abs() < 0.05);
+
+		interpolator.clear();
+		assert!(interpolator.is_empty() && interpolator.render_time().is_none());
+		assert!(interpolator.sample().is_empty());
+	}
+
+	#[test]
+	fn particles_in_one_frame() {
+		let mut interpolator = Interpolator::default();
+		interpolator.push(0.0, [(1, at(0.0)), (2, at(0.0))]);
+		interpolator.push(0.25, [(1, at(1.0)), (2, at(2.0)), (3, at(9.0))]);
+		// 2 and 3 disappear, and 4 only shows up in the last frame
+		interpolator.push(0.5, [(1, at(2.0)), (4, at(7.0))]);
+
+		// Removed ones keep moving with their last velocity, new ones wait for their frame
+		assert_eq!(xs(&interpolator.sample_at(0.375)), [(1, 1.5), (2, 3.0), (3, 9.0)]);
+		assert_eq!(xs(&interpolator.sample_at(0.5)), [(1, 2.0), (4, 7.0)]);
+	}
+}
@@ -1,14 +1,34 @@
 #![allow(unused)]
 
 use rglua::prelude::*;
+use std::io::Write;
 use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::Instant;
 
-mod config;
-mod helper;
-mod state;
-mod types;
+// Public so the command line runner can drive simulations outside of the game
+pub mod config;
+pub mod emitter;
+pub mod export;
+pub mod forces;
+mod grid;
+pub mod health;
+pub mod helper;
+mod interpolation;
+pub mod params;
+mod render;
+mod replay;
+mod replication;
+pub mod scene;
+mod snapshot;
+mod stats;
+pub mod state;
+mod surface;
+pub mod types;
+mod voxel;
 
+use nvflex_sys::*;
 use state::FlexState;
+use types::{Quat, Vector3};
 
 #[derive(Debug, thiserror::Error)]
 enum OpenError {
@@ -18,6 +38,137 @@ enum OpenError {
 
 static STATE: AtomicPtr<FlexState> = AtomicPtr::new(std::ptr::null_mut());
 
+/// Gets the global FleX state, if the module has been opened.
+fn flex_state<'a>() -> Option<&'a mut FlexState> {
+	unsafe { STATE.load(Ordering::Relaxed).as_mut() }
+}
+
+/// Pushes a vector to the stack as an array table of {x, y, z}
+fn push_vector(l: LuaState, v: &Vector3) {
+	lua_createtable(l, 3, 0);
+
+	lua_pushnumber(l, v.0 as f64);
+	lua_rawseti(l, -2, 1);
+
+	lua_pushnumber(l, v.1 as f64);
+	lua_rawseti(l, -2, 2);
+
+	lua_pushnumber(l, v.2 as f64);
+	lua_rawseti(l, -2, 3);
+}
+
+/// Pushes a quaternion to the stack as an array table of {x, y, z, w}
+fn push_quat(l: LuaState, q: &Quat) {
+	lua_createtable(l, 4, 0);
+
+	lua_pushnumber(l, q.0 as f64);
+	lua_rawseti(l, -2, 1);
+
+	lua_pushnumber(l, q.1 as f64);
+	lua_rawseti(l, -2, 2);
+
+	lua_pushnumber(l, q.2 as f64);
+	lua_rawseti(l, -2, 3);
+
+	lua_pushnumber(l, q.3 as f64);
+	lua_rawseti(l, -2, 4);
+}
+
+/// Reads a mesh from an array of {x, y, z} vertices at `arg` and an array of one based indices at `arg + 1`.
+/// Returned indices are zero based. Errors on indices past the vertices, before anything is built.
+fn check_mesh(l: LuaState, arg: i32) -> Result<(Vec<Vector3>, Vec<u32>), String> {
+	luaL_checktype(l, arg, LUA_TTABLE);
+	luaL_checktype(l, arg + 1, LUA_TTABLE);
+
+	let nvertices = lua_objlen(l, arg) as i32;
+	let mut vertices = Vec::with_capacity(nvertices as usize);
+	for i in 1 ..= nvertices {
+		lua_rawgeti(l, arg, i);
+
+		let mut v = [0.0; 3];
+		for (k, c) in v.iter_mut().enumerate() {
+			lua_rawgeti(l, -1, k as i32 + 1);
+			*c = lua_tonumber(l, -1) as f32;
+			lua_pop(l, 1);
+		}
+
+		lua_pop(l, 1);
+		vertices.push(Vector3(v[0], v[1], v[2]));
+	}
+
+	let nindices = lua_objlen(l, arg + 1) as i32;
+	let mut indices = Vec::with_capacity(nindices as usize);
+	for i in 1 ..= nindices {
+		lua_rawgeti(l, arg + 1, i);
+		let index = lua_tointeger(l, -1);
+		lua_pop(l, 1);
+
+		if index < 1 || index > nvertices as LuaInteger {
+			return Err(format!("Mesh index {} is out of range for {} vertices", index, nvertices));
+		}
+		indices.push(index as u32 - 1);
+	}
+
+	Ok((vertices, indices))
+}
+
+/// Reads a string argument, lossily converting it to UTF-8
+fn check_string(l: LuaState, arg: i32) -> String {
+	let ptr = luaL_checkstring(l, arg);
+	unsafe { std::ffi::CStr::from_ptr(ptr) }
+		.to_string_lossy()
+		.into_owned()
+}
+
+/// Runs the body of a Lua function, raising its error as a Lua error.
+/// lua_error longjmps straight over Rust frames without running destructors, so it's only called
+/// once the body has returned and everything it owned is dropped, with the message on the stack.
+/// luaL_check* and luaL_opt* raise the same way, so bodies check every argument before allocating.
+fn protect(l: LuaState, body: impl FnOnce() -> Result<i32, String>) -> i32 {
+	let why = match body() {
+		Ok(results) => return results,
+		Err(why) => why,
+	};
+
+	lua_pushlstring(l, why.as_ptr() as _, why.len());
+	drop(why);
+	lua_error(l)
+}
+
+/// Counts the time since `start` as spent building tables for Lua, see [stats::Stats].
+fn marshalled(start: Instant) {
+	if let Some(state) = flex_state() {
+		state.stats.add_marshal(start.elapsed());
+	}
+}
+
+/// Reads three numbers starting at `arg` as a vector
+fn check_vector(l: LuaState, arg: i32) -> Vector3 {
+	Vector3(
+		luaL_checknumber(l, arg) as f32,
+		luaL_checknumber(l, arg + 1) as f32,
+		luaL_checknumber(l, arg + 2) as f32,
+	)
+}
+
+/// Optional entity index argument, where anything negative means no entity.
+fn opt_entity(l: LuaState, arg: i32) -> Option<i32> {
+	match luaL_optinteger(l, arg, -1) {
+		x if x < 0 => None,
+		x => Some(x as i32),
+	}
+}
+
+fn push_shape(l: LuaState, shape: Option<usize>) -> i32 {
+	match shape {
+		Some(index) => {
+			lua_pushinteger(l, index as LuaInteger);
+			1
+		}
+		None => 0,
+	}
+}
+
 #[lua_function]
 fn get(l: LuaState) -> i32 {
 	let state = STATE.load(Ordering::Relaxed);
@@ -33,6 +184,9 @@ fn get(l: LuaState) -> i32 {
 	0
 }
 
+// flex.get_particles() -> { { phase, imass, velocity, position, ... }, ... }
+// The local simulation only, even on clients receiving replicated particles. Those are sampled
+// separately by flex.get_replicated_particles, since they carry no phases or velocities.
 #[lua_function]
 pub fn get_particles(l: LuaState) -> i32 {
 	let state = STATE.load(Ordering::Relaxed);
@@ -40,6 +194,7 @@ pub fn get_particles(l: LuaState) -> i32 {
 	match unsafe { state.as_ref() } {
 		Some(state) => {
 			if let Some(data) = unsafe { state.particles.get(state.solver) } {
+				let start = Instant::now();
 				lua_createtable(l, data.len() as i32, 0);
 				for (i, particle) in data.iter().enumerate() {
 					lua_createtable(l, 0, 4); // -3 particle = {}
@@ -80,8 +235,40 @@ pub fn get_particles(l: LuaState) -> i32 {
 
 					lua_rawset(l, -3); // t.position = stack[ #stack - 1 ]
 
+					// Attributes can trail the particles until the next spawn resizes them
+					let attributes = &state.attributes;
+					let defaults = &attributes.defaults;
+					if let Some(color) = attributes.colors.get(i) {
+						lua_pushstring(l, cstr!("color"));
+						lua_createtable(l, 4, 0);
+						for (k, c) in color.iter().enumerate() {
+							lua_pushnumber(l, *c as f64);
+							lua_rawseti(l, -2, k as i32 + 1);
+						}
+						lua_rawset(l, -3); // t.color = {r, g, b, a}
+
+						lua_pushstring(l, cstr!("temperature"));
+						let temperature = attributes.temperatures.get(i).copied();
+						lua_pushnumber(l, temperature.unwrap_or(defaults.temperature) as f64);
+						lua_rawset(l, -3);
+
+						lua_pushstring(l, cstr!("owner"));
+						let owner = attributes.owners.get(i).copied();
+						lua_pushinteger(l, owner.unwrap_or(defaults.owner) as LuaInteger);
+						lua_rawset(l, -3);
+
+						for (name, values) in &attributes.channels {
+							let name = std::ffi::CString::new(name.as_str()).unwrap_or_default();
+							lua_pushstring(l, name.as_ptr());
+							lua_pushnumber(l, values.get(i).copied().unwrap_or(0.0) as f64);
+							lua_rawset(l, -3);
+						}
+					}
+
 					lua_rawseti(l, -2, i as i32 + 1); // particles[i + 1] = stack[#stack] (aka particle)
 				}
+
+				marshalled(start);
 				return 1;
 			}
 			state.particles.unmap();
@@ -92,43 +279,2263 @@ pub fn get_particles(l: LuaState) -> i32 {
 }
 
 #[lua_function]
-fn tick(_l: LuaState) -> i32 {
+fn tick(l: LuaState) -> i32 {
 	let state = STATE.load(Ordering::Relaxed);
 
 	if let Some(a) = unsafe { state.as_mut() } {
 		a.tick();
+
+		if let Some(report) = a.health.warning() {
+			printgm!(l, "gfluid: unhealthy particles caught, {}", report);
+		}
+
+		if a.contacts.enabled {
+			for contact in a.contacts.throttled() {
+				// hook.Run("FluidTouch", shape, count, avgSpeed)
+				lua_getglobal(l, cstr!("hook"));
+				lua_getfield(l, -1, cstr!("Run"));
+				lua_remove(l, -2);
+
+				lua_pushstring(l, cstr!("FluidTouch"));
+				lua_pushinteger(l, contact.shape as LuaInteger);
+				lua_pushinteger(l, contact.count as LuaInteger);
+				lua_pushnumber(l, contact.average_speed as f64);
+
+				lua_call(l, 4, 0);
+			}
+		}
 	};
 
 	0
 }
 
-fn open(l: LuaState) -> Result<(), OpenError> {
-	let mut flex_state = Box::new(FlexState::new());
-	flex_state.init()?;
+// flex.add_box(hx, hy, hz, entity?) -> shape?
+#[lua_function]
+fn add_box(l: LuaState) -> i32 {
+	let half = check_vector(l, 1);
+	let entity = opt_entity(l, 4);
 
-	let flex_ptr = Box::into_raw(flex_state);
-	STATE.store(flex_ptr, Ordering::Relaxed);
+	let shape = flex_state().and_then(|state| {
+		let geometry = NvFlexCollisionGeometry {
+			box_: NvFlexBoxGeometry {
+				halfExtents: [half.0, half.1, half.2],
+			},
+		};
+		state.add_dynamic_shape(geometry, eNvFlexShapeBox, entity)
+	});
 
-	let r = reg! [
-		"get_state" => get_state,
-		"get_particles" => get_particles,
-		"get" => get
-	];
+	push_shape(l, shape)
+}
 
-	lua_getglobal(l, cstr!("hook"));
-	lua_getfield(l, -1, cstr!("Add"));
+// flex.add_sphere(radius, entity?) -> shape?
+#[lua_function]
+fn add_sphere(l: LuaState) -> i32 {
+	let radius = luaL_checknumber(l, 1) as f32;
+	let entity = opt_entity(l, 2);
 
-	lua_remove(l, -2); // Remove 'hook' table from stack
+	let shape = flex_state().and_then(|state| {
+		let geometry = NvFlexCollisionGeometry {
+			sphere: NvFlexSphereGeometry { radius },
+		};
+		state.add_dynamic_shape(geometry, eNvFlexShapeSphere, entity)
+	});
 
-	// Push arguments to hook.Add
-	lua_pushstring(l, cstr!("Tick"));
-	lua_pushstring(l, cstr!("GFluid_Tick"));
-	lua_pushcfunction(l, tick);
+	push_shape(l, shape)
+}
 
-	// Call hook.Add
-	lua_call(l, 3, 0);
+// flex.add_capsule(radius, half_height, entity?) -> shape?
+#[lua_function]
+fn add_capsule(l: LuaState) -> i32 {
+	let radius = luaL_checknumber(l, 1) as f32;
+	let half_height = luaL_checknumber(l, 2) as f32;
+	let entity = opt_entity(l, 3);
 
-	luaL_register(l, cstr!("flex"), r.as_ptr());
+	let shape = flex_state().and_then(|state| {
+		let geometry = NvFlexCollisionGeometry {
+			capsule: NvFlexCapsuleGeometry {
+				radius,
+				halfHeight: half_height,
+			},
+		};
+		state.add_dynamic_shape(geometry, eNvFlexShapeCapsule, entity)
+	});
+
+	push_shape(l, shape)
+}
+
+// flex.set_shape_transform(shape, x, y, z, qx, qy, qz, qw) -> bool
+#[lua_function]
+fn set_shape_transform(l: LuaState) -> i32 {
+	let shape = luaL_checkinteger(l, 1) as usize;
+	let pos = check_vector(l, 2);
+	let rot = types::Quat(
+		luaL_checknumber(l, 5) as f32,
+		luaL_checknumber(l, 6) as f32,
+		luaL_checknumber(l, 7) as f32,
+		luaL_checknumber(l, 8) as f32,
+	);
+
+	let ok = flex_state().map_or(false, |state| {
+		state
+			.geometry
+			.set_transform(shape, types::Vector4(pos.0, pos.1, pos.2, 0.0), rot)
+	});
+
+	lua_pushboolean(l, ok as i32);
+	1
+}
+
+// flex.get_forces() -> { { shape, entity, buoyancy, drag, torque, submerged }, ... }
+#[lua_function]
+fn get_forces(l: LuaState) -> i32 {
+	let state = match flex_state() {
+		Some(state) => state,
+		None => return 0,
+	};
+
+	let forces = state.coupling.forces();
+	lua_createtable(l, forces.len() as i32, 0);
+
+	for (i, force) in forces.iter().enumerate() {
+		lua_createtable(l, 0, 6);
+
+		lua_pushinteger(l, force.shape as LuaInteger);
+		lua_setfield(l, -2, cstr!("shape"));
+
+		lua_pushinteger(l, force.entity as LuaInteger);
+		lua_setfield(l, -2, cstr!("entity"));
+
+		push_vector(l, &force.buoyancy);
+		lua_setfield(l, -2, cstr!("buoyancy"));
+
+		push_vector(l, &force.drag);
+		lua_setfield(l, -2, cstr!("drag"));
+
+		push_vector(l, &force.torque);
+		lua_setfield(l, -2, cstr!("torque"));
+
+		lua_pushnumber(l, force.submerged as f64);
+		lua_setfield(l, -2, cstr!("submerged"));
+
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	1
+}
+
+// flex.set_contact_reporting(enabled, interval?, per_particle?)
+#[lua_function]
+fn set_contact_reporting(l: LuaState) -> i32 {
+	luaL_checktype(l, 1, LUA_TBOOLEAN);
+	let enabled = lua_toboolean(l, 1) != 0;
+	let interval = luaL_optnumber(l, 2, 0.1).max(0.0);
+	let per_particle = lua_toboolean(l, 3) != 0;
+
+	if let Some(state) = flex_state() {
+		state.contacts.enabled = enabled;
+		state.contacts.interval = std::time::Duration::from_secs_f64(interval);
+		state.contacts.per_particle = per_particle;
+	}
+
+	0
+}
+
+// flex.get_contacts() -> { { shape, count, speed }, ... }, { { particle, shape }, ... }?
+#[lua_function]
+fn get_contacts(l: LuaState) -> i32 {
+	let state = match flex_state() {
+		Some(state) => state,
+		None => return 0,
+	};
+
+	let reports = state.contacts.reports();
+	lua_createtable(l, reports.len() as i32, 0);
+
+	for (i, report) in reports.iter().enumerate() {
+		lua_createtable(l, 0, 3);
+
+		lua_pushinteger(l, report.shape as LuaInteger);
+		lua_setfield(l, -2, cstr!("shape"));
+
+		lua_pushinteger(l, report.count as LuaInteger);
+		lua_setfield(l, -2, cstr!("count"));
+
+		lua_pushnumber(l, report.average_speed as f64);
+		lua_setfield(l, -2, cstr!("speed"));
+
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	if !state.contacts.per_particle {
+		return 1;
+	}
+
+	let particles = state.contacts.particles();
+	lua_createtable(l, particles.len() as i32, 0);
+
+	for (i, (particle, shape)) in particles.iter().enumerate() {
+		lua_createtable(l, 2, 0);
+
+		lua_pushinteger(l, *particle as LuaInteger);
+		lua_rawseti(l, -2, 1);
+
+		lua_pushinteger(l, *shape as LuaInteger);
+		lua_rawseti(l, -2, 2);
+
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	2
+}
+
+fn push_rigid(l: LuaState, rigid: Option<usize>) -> i32 {
+	match rigid {
+		Some(handle) => {
+			lua_pushinteger(l, handle as LuaInteger);
+			1
+		}
+		None => 0,
+	}
+}
+
+// flex.add_rigid_box(x, y, z, hx, hy, hz, stiffness?) -> rigid?
+#[lua_function]
+fn add_rigid_box(l: LuaState) -> i32 {
+	let center = check_vector(l, 1);
+	let half = check_vector(l, 4);
+	let stiffness = luaL_optnumber(l, 7, 1.0) as f32;
+
+	let rigid = flex_state().and_then(|state| {
+		let spacing = config::solid_rest_distance(&state.params);
+		// One point past the room is enough for add_rigid to turn the body down
+		let points: Vec<_> = voxel::sample_box(half, spacing).take(state.room() + 1).collect();
+		state.add_rigid(&points, center, stiffness, 0.0, 0.0)
+	});
+
+	push_rigid(l, rigid)
+}
+
+// flex.add_rigid_sphere(x, y, z, radius, stiffness?) -> rigid?
+#[lua_function]
+fn add_rigid_sphere(l: LuaState) -> i32 {
+	let center = check_vector(l, 1);
+	let radius = luaL_checknumber(l, 4) as f32;
+	let stiffness = luaL_optnumber(l, 5, 1.0) as f32;
+
+	let rigid = flex_state().and_then(|state| {
+		let spacing = config::solid_rest_distance(&state.params);
+		let points: Vec<_> = voxel::sample_sphere(radius, spacing).take(state.room() + 1).collect();
+		state.add_rigid(&points, center, stiffness, 0.0, 0.0)
+	});
+
+	push_rigid(l, rigid)
+}
+
+// flex.add_rigid_mesh(x, y, z, vertices, indices, stiffness?) -> rigid?
+#[lua_function]
+fn add_rigid_mesh(l: LuaState) -> i32 {
+	protect(l, || {
+		let center = check_vector(l, 1);
+		let stiffness = luaL_optnumber(l, 6, 1.0) as f32;
+		let (vertices, indices) = check_mesh(l, 4)?;
+
+		let rigid = flex_state().and_then(|state| {
+			let spacing = config::solid_rest_distance(&state.params);
+			let points = voxel::sample_mesh(&vertices, &indices, spacing);
+			let points: Vec<_> = points.take(state.room() + 1).collect();
+			state.add_rigid(&points, center, stiffness, 0.0, 0.0)
+		});
+
+		Ok(push_rigid(l, rigid))
+	})
+}
+
+// flex.get_rigid(rigid) -> position?, rotation?
+#[lua_function]
+fn get_rigid(l: LuaState) -> i32 {
+	let rigid = luaL_checkinteger(l, 1) as usize;
+
+	match flex_state().and_then(|state| state.rigids.get_transform(rigid)) {
+		Some((translation, rotation)) => {
+			push_vector(l, &translation);
+			push_quat(l, &rotation);
+			2
+		}
+		None => 0,
+	}
+}
+
+// flex.get_rigids() -> { [rigid] = { position, rotation }, ... }
+#[lua_function]
+fn get_rigids(l: LuaState) -> i32 {
+	let state = match flex_state() {
+		Some(state) => state,
+		None => return 0,
+	};
+
+	let count = state.rigids.get_count();
+	lua_createtable(l, 0, count as i32);
+
+	for rigid in 0 .. count {
+		if let Some((translation, rotation)) = state.rigids.get_transform(rigid) {
+			lua_createtable(l, 0, 2);
+
+			push_vector(l, &translation);
+			lua_setfield(l, -2, cstr!("position"));
+
+			push_quat(l, &rotation);
+			lua_setfield(l, -2, cstr!("rotation"));
+
+			lua_rawseti(l, -2, rigid as i32);
+		}
+	}
+
+	1
+}
+
+// flex.add_cloth(x, y, z, columns, rows, spacing, stretch?, shear?, bend?) -> cloth?
+#[lua_function]
+fn add_cloth(l: LuaState) -> i32 {
+	let origin = check_vector(l, 1);
+	let columns = luaL_checkinteger(l, 4).max(0) as usize;
+	let rows = luaL_checkinteger(l, 5).max(0) as usize;
+	let spacing = luaL_checknumber(l, 6) as f32;
+
+	let defaults = state::cloth::ClothStiffness::default();
+	let stiffness = state::cloth::ClothStiffness {
+		stretch: luaL_optnumber(l, 7, defaults.stretch as f64) as f32,
+		shear: luaL_optnumber(l, 8, defaults.shear as f64) as f32,
+		bend: luaL_optnumber(l, 9, defaults.bend as f64) as f32,
+	};
+
+	let cloth = flex_state()
+		.and_then(|state| state.add_cloth(origin, columns, rows, spacing, stiffness));
+
+	match cloth {
+		Some(handle) => {
+			lua_pushinteger(l, handle as LuaInteger);
+			1
+		}
+		None => 0,
+	}
+}
+
+// flex.pin_cloth(cloth, vertex, x?, y?, z?) -> bool
+// Vertices are one based, in the same order as flex.get_cloth returns them.
+#[lua_function]
+fn pin_cloth(l: LuaState) -> i32 {
+	let cloth = luaL_checkinteger(l, 1) as usize;
+	let vertex = (luaL_checkinteger(l, 2) - 1) as usize;
+	let position = if lua_isnoneornil(l, 3) {
+		None
+	} else {
+		Some(check_vector(l, 3))
+	};
+
+	let ok = flex_state().map_or(false, |state| state.pin_cloth(cloth, vertex, position));
+
+	lua_pushboolean(l, ok as i32);
+	1
+}
+
+// flex.pin_cloth_to_shape(cloth, vertex, shape) -> bool
+#[lua_function]
+fn pin_cloth_to_shape(l: LuaState) -> i32 {
+	let cloth = luaL_checkinteger(l, 1) as usize;
+	let vertex = (luaL_checkinteger(l, 2) - 1) as usize;
+	let shape = luaL_checkinteger(l, 3) as usize;
+
+	let ok = flex_state().map_or(false, |state| state.pin_cloth_to_shape(cloth, vertex, shape));
+
+	lua_pushboolean(l, ok as i32);
+	1
+}
+
+// flex.get_cloth(cloth) -> vertices?, normals?, indices?
+#[lua_function]
+fn get_cloth(l: LuaState) -> i32 {
+	let cloth = luaL_checkinteger(l, 1) as usize;
+
+	let state = match flex_state() {
+		Some(state) => state,
+		None => return 0,
+	};
+
+	let (vertices, normals) = match state.get_cloth_mesh(cloth) {
+		Some(mesh) => mesh,
+		None => return 0,
+	};
+
+	for list in [&vertices, &normals] {
+		lua_createtable(l, list.len() as i32, 0);
+		for (i, v) in list.iter().enumerate() {
+			push_vector(l, v);
+			lua_rawseti(l, -2, i as i32 + 1);
+		}
+	}
+
+	let indices = &state.cloth.cloths[cloth].indices;
+	lua_createtable(l, indices.len() as i32, 0);
+	for (i, index) in indices.iter().enumerate() {
+		lua_pushinteger(l, *index as LuaInteger + 1);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	3
+}
+
+// flex.add_soft_body(x, y, z, vertices, indices, stiffness?, threshold?, creep?) -> body?
+#[lua_function]
+fn add_soft_body(l: LuaState) -> i32 {
+	protect(l, || {
+		let center = check_vector(l, 1);
+
+		let defaults = state::soft::SoftParams::default();
+		let params = state::soft::SoftParams {
+			stiffness: luaL_optnumber(l, 6, defaults.stiffness as f64) as f32,
+			threshold: luaL_optnumber(l, 7, defaults.threshold as f64) as f32,
+			creep: luaL_optnumber(l, 8, defaults.creep as f64) as f32,
+			..defaults
+		};
+
+		let (vertices, indices) = check_mesh(l, 4)?;
+		let body = flex_state()
+			.and_then(|state| state.add_soft_body(center, &vertices, indices, params));
+
+		match body {
+			Some(handle) => {
+				lua_pushinteger(l, handle as LuaInteger);
+				Ok(1)
+			}
+			None => Ok(0),
+		}
+	})
+}
+
+// flex.get_soft_body(body) -> vertices?, indices?
+#[lua_function]
+fn get_soft_body(l: LuaState) -> i32 {
+	let body = luaL_checkinteger(l, 1) as usize;
+
+	let (vertices, indices) = match flex_state().and_then(|state| state.get_soft_mesh(body)) {
+		Some(mesh) => mesh,
+		None => return 0,
+	};
+
+	lua_createtable(l, vertices.len() as i32, 0);
+	for (i, v) in vertices.iter().enumerate() {
+		push_vector(l, v);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	lua_createtable(l, indices.len() as i32, 0);
+	for (i, index) in indices.iter().enumerate() {
+		lua_pushinteger(l, *index as LuaInteger + 1);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	2
+}
+
+// flex.add_inflatable(x, y, z, vertices, indices, pressure?, stiffness?) -> cloth?
+// The returned handle works with flex.get_cloth and the other cloth functions.
+#[lua_function]
+fn add_inflatable(l: LuaState) -> i32 {
+	protect(l, || {
+		let center = check_vector(l, 1);
+		let pressure = luaL_optnumber(l, 6, 1.0) as f32;
+		let stiffness = luaL_optnumber(l, 7, 1.0) as f32;
+		let (vertices, indices) = check_mesh(l, 4)?;
+
+		let cloth = flex_state().and_then(|state| {
+			state.add_inflatable(center, &vertices, indices, stiffness, pressure)
+		});
+
+		match cloth {
+			Some(handle) => {
+				lua_pushinteger(l, handle as LuaInteger);
+				Ok(1)
+			}
+			None => Ok(0),
+		}
+	})
+}
+
+// flex.set_inflatable_pressure(cloth, pressure) -> bool
+#[lua_function]
+fn set_inflatable_pressure(l: LuaState) -> i32 {
+	let cloth = luaL_checkinteger(l, 1) as usize;
+	let pressure = luaL_checknumber(l, 2) as f32;
+
+	let ok = flex_state().map_or(false, |state| state.cloth.set_pressure(cloth, pressure));
+
+	lua_pushboolean(l, ok as i32);
+	1
+}
+
+// flex.get_diffuse() -> { { position, velocity, lifetime, type }, ... }
+// type is one of "foam", "spray" or "bubble"
+#[lua_function]
+fn get_diffuse(l: LuaState) -> i32 {
+	let particles = match flex_state() {
+		Some(state) => state.get_diffuse(),
+		None => return 0,
+	};
+
+	let start = Instant::now();
+	lua_createtable(l, particles.len() as i32, 0);
+
+	for (i, particle) in particles.iter().enumerate() {
+		lua_createtable(l, 0, 4);
+
+		push_vector(l, &particle.position);
+		lua_setfield(l, -2, cstr!("position"));
+
+		push_vector(l, &particle.velocity);
+		lua_setfield(l, -2, cstr!("velocity"));
+
+		lua_pushnumber(l, particle.lifetime as f64);
+		lua_setfield(l, -2, cstr!("lifetime"));
+
+		let kind = std::ffi::CString::new(particle.kind.name()).unwrap_or_default();
+		lua_pushstring(l, kind.as_ptr());
+		lua_setfield(l, -2, cstr!("type"));
+
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	marshalled(start);
+	1
+}
+
+// flex.set_diffuse({ threshold?, buoyancy?, drag?, lifetime?, max? })
+// Foam, spray and bubbles spawn where the fluid's kinetic energy passes threshold, off by default.
+// max caps how many are alive at once, up to the diffuse capacity the solver was created with.
+// Fields left out keep their current value.
+#[lua_function]
+fn set_diffuse(l: LuaState) -> i32 {
+	luaL_checktype(l, 1, LUA_TTABLE);
+
+	let state = match flex_state() {
+		Some(state) => state,
+		None => return 0,
+	};
+
+	let mut settings = state.diffuse_settings();
+	let fields = [
+		(cstr!("threshold"), &mut settings.threshold),
+		(cstr!("buoyancy"), &mut settings.buoyancy),
+		(cstr!("drag"), &mut settings.drag),
+		(cstr!("lifetime"), &mut settings.lifetime),
+	];
+	for (name, value) in fields {
+		lua_getfield(l, 1, name);
+		*value = luaL_optnumber(l, -1, *value as f64) as f32;
+		lua_pop(l, 1);
+	}
+
+	lua_getfield(l, 1, cstr!("max"));
+	settings.max_particles = luaL_optinteger(l, -1, settings.max_particles as LuaInteger) as i32;
+	lua_pop(l, 1);
+
+	state.set_diffuse(settings);
+	0
+}
+
+// flex.get_diffuse_settings() -> { threshold, buoyancy, drag, lifetime, max, capacity }
+#[lua_function]
+fn get_diffuse_settings(l: LuaState) -> i32 {
+	let state = match flex_state() {
+		Some(state) => state,
+		None => return 0,
+	};
+
+	let settings = state.diffuse_settings();
+	lua_createtable(l, 0, 6);
+
+	lua_pushnumber(l, settings.threshold as f64);
+	lua_setfield(l, -2, cstr!("threshold"));
+
+	lua_pushnumber(l, settings.buoyancy as f64);
+	lua_setfield(l, -2, cstr!("buoyancy"));
+
+	lua_pushnumber(l, settings.drag as f64);
+	lua_setfield(l, -2, cstr!("drag"));
+
+	lua_pushnumber(l, settings.lifetime as f64);
+	lua_setfield(l, -2, cstr!("lifetime"));
+
+	lua_pushinteger(l, settings.max_particles as LuaInteger);
+	lua_setfield(l, -2, cstr!("max"));
+
+	lua_pushinteger(l, state.diffuse.capacity() as LuaInteger);
+	lua_setfield(l, -2, cstr!("capacity"));
+
+	1
+}
+
+// flex.get_ellipsoids() -> { { center, axes = { a1, a2, a3 } }, ... }
+#[cfg(feature = "anisotropy")]
+#[lua_function]
+fn get_ellipsoids(l: LuaState) -> i32 {
+	let ellipsoids = match flex_state() {
+		Some(state) => state.get_ellipsoids(),
+		None => return 0,
+	};
+
+	lua_createtable(l, ellipsoids.len() as i32, 0);
+
+	for (i, ellipsoid) in ellipsoids.iter().enumerate() {
+		lua_createtable(l, 0, 2);
+
+		push_vector(l, &ellipsoid.center);
+		lua_setfield(l, -2, cstr!("center"));
+
+		lua_createtable(l, 3, 0);
+		for (k, axis) in ellipsoid.axes.iter().enumerate() {
+			push_vector(l, axis);
+			lua_rawseti(l, -2, k as i32 + 1);
+		}
+		lua_setfield(l, -2, cstr!("axes"));
+
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	1
+}
+
+// flex.get_surface(cell?, x1?, y1?, z1?, x2?, y2?, z2?) -> vertices, normals, indices
+// Indices are one based and wound clockwise, ready for building an IMesh.
+#[lua_function]
+fn get_surface(l: LuaState) -> i32 {
+	let cell = if lua_isnoneornil(l, 1) {
+		None
+	} else {
+		Some(luaL_checknumber(l, 1) as f32)
+	};
+
+	let region = if lua_isnoneornil(l, 2) {
+		None
+	} else {
+		Some((check_vector(l, 2), check_vector(l, 5)))
+	};
+
+	let mesh = match flex_state() {
+		Some(state) => state.get_surface(cell, region),
+		None => return 0,
+	};
+
+	let start = Instant::now();
+	for list in [&mesh.vertices, &mesh.normals] {
+		lua_createtable(l, list.len() as i32, 0);
+		for (i, v) in list.iter().enumerate() {
+			push_vector(l, v);
+			lua_rawseti(l, -2, i as i32 + 1);
+		}
+	}
+
+	lua_createtable(l, mesh.indices.len() as i32, 0);
+	for (i, index) in mesh.indices.iter().enumerate() {
+		lua_pushinteger(l, *index as LuaInteger + 1);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	marshalled(start);
+	3
+}
+
+// flex.get_view_particles(ox, oy, oz, dx, dy, dz, fov, near?, far?) -> { x, y, z, radius, ... }
+// Flat view space quadruplets of every visible fluid particle, sorted back to front.
+#[lua_function]
+fn get_view_particles(l: LuaState) -> i32 {
+	let origin = check_vector(l, 1);
+	let direction = check_vector(l, 4);
+	let fov = luaL_checknumber(l, 7) as f32;
+	let near = luaL_optnumber(l, 8, 0.0) as f32;
+	let far = luaL_optnumber(l, 9, f32::MAX as f64) as f32;
+
+	let camera = render::Camera::new(origin, direction, fov, near, far);
+	let packed = match flex_state() {
+		Some(state) => render::pack(&state.get_view_particles(&camera)),
+		None => return 0,
+	};
+
+	let start = Instant::now();
+	lua_createtable(l, packed.len() as i32, 0);
+	for (i, v) in packed.iter().enumerate() {
+		lua_pushnumber(l, *v as f64);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	marshalled(start);
+	1
+}
+
+// flex.add_force_field("radial", x, y, z, strength, radius, falloff?) -> field
+// flex.add_force_field("wind", x, y, z, ax, ay, az, hx, hy, hz) -> field
+// flex.add_force_field("vortex", x, y, z, axis_x, axis_y, axis_z, strength, radius) -> field
+// flex.add_force_field("noise", x, y, z, strength, frequency, radius) -> field
+#[lua_function]
+fn add_force_field(l: LuaState) -> i32 {
+	protect(l, || {
+		use forces::FieldKind;
+
+		// Borrowed from the stack, as the arguments after it depend on it and may still raise
+		let kind = unsafe { std::ffi::CStr::from_ptr(luaL_checkstring(l, 1)) }.to_bytes();
+		let position = check_vector(l, 2);
+
+		let kind = match kind {
+			b"radial" => FieldKind::Radial {
+				strength: luaL_checknumber(l, 5) as f32,
+				radius: luaL_checknumber(l, 6) as f32,
+				falloff: luaL_optnumber(l, 7, 1.0) as f32,
+			},
+			b"wind" => FieldKind::Directional {
+				acceleration: check_vector(l, 5),
+				half_extents: check_vector(l, 8),
+			},
+			b"vortex" => {
+				let axis = check_vector(l, 5);
+				let strength = luaL_checknumber(l, 8) as f32;
+				let radius = luaL_checknumber(l, 9) as f32;
+
+				let length = axis.length();
+				if length <= 0.0 {
+					return Err("Vortex axis can't be zero".into());
+				}
+
+				FieldKind::Vortex {
+					axis: axis * (1.0 / length),
+					strength,
+					radius,
+				}
+			}
+			b"noise" => FieldKind::Noise {
+				strength: luaL_checknumber(l, 5) as f32,
+				frequency: luaL_checknumber(l, 6) as f32,
+				radius: luaL_checknumber(l, 7) as f32,
+			},
+			other => {
+				let other = String::from_utf8_lossy(other);
+				return Err(format!("Unknown force field type '{}'", other));
+			}
+		};
+
+		match flex_state() {
+			Some(state) => {
+				let field = state.forces.add(forces::ForceField::new(kind, position));
+				lua_pushinteger(l, field as LuaInteger);
+				Ok(1)
+			}
+			None => Ok(0),
+		}
+	})
+}
+
+// flex.set_force_field_position(field, x, y, z) -> bool
+#[lua_function]
+fn set_force_field_position(l: LuaState) -> i32 {
+	let field = luaL_checkinteger(l, 1) as usize;
+	let position = check_vector(l, 2);
+
+	let found = match flex_state().and_then(|state| state.forces.get_mut(field)) {
+		Some(field) => {
+			field.position = position;
+			true
+		}
+		None => false,
+	};
+
+	lua_pushboolean(l, found as i32);
+	1
+}
+
+// flex.set_force_field_enabled(field, enabled) -> bool
+#[lua_function]
+fn set_force_field_enabled(l: LuaState) -> i32 {
+	let field = luaL_checkinteger(l, 1) as usize;
+	let enabled = lua_toboolean(l, 2) != 0;
+
+	let found = match flex_state().and_then(|state| state.forces.get_mut(field)) {
+		Some(field) => {
+			field.enabled = enabled;
+			true
+		}
+		None => false,
+	};
+
+	lua_pushboolean(l, found as i32);
+	1
+}
+
+// flex.set_force_field_groups(field, { group, ... }?) -> bool
+// Restricts a field to particles of the given phase groups, or every group when nil.
+#[lua_function]
+fn set_force_field_groups(l: LuaState) -> i32 {
+	let field = luaL_checkinteger(l, 1) as usize;
+
+	let mut groups = vec![];
+	if !lua_isnoneornil(l, 2) {
+		luaL_checktype(l, 2, LUA_TTABLE);
+		for i in 1 ..= lua_objlen(l, 2) as i32 {
+			lua_rawgeti(l, 2, i);
+			groups.push(lua_tointeger(l, -1) as i32);
+			lua_pop(l, 1);
+		}
+	}
+
+	let found = match flex_state().and_then(|state| state.forces.get_mut(field)) {
+		Some(field) => {
+			field.groups = groups;
+			true
+		}
+		None => false,
+	};
+
+	lua_pushboolean(l, found as i32);
+	1
+}
+
+// flex.remove_force_field(field) -> bool
+#[lua_function]
+fn remove_force_field(l: LuaState) -> i32 {
+	let field = luaL_checkinteger(l, 1) as usize;
+	let ok = flex_state().map_or(false, |state| state.forces.remove(field));
+
+	lua_pushboolean(l, ok as i32);
+	1
+}
+
+// flex.add_emitter(x, y, z, dx, dy, dz, radius, speed, group?) -> emitter
+// Pours a column of fluid out of a disc, in the default fluid group unless a named group is given.
+#[lua_function]
+fn add_emitter(l: LuaState) -> i32 {
+	protect(l, || {
+		let origin = check_vector(l, 1);
+		let direction = check_vector(l, 4);
+		let radius = luaL_checknumber(l, 7) as f32;
+		let speed = luaL_checknumber(l, 8) as f32;
+		let group = (!lua_isnoneornil(l, 9)).then(|| check_string(l, 9));
+
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		let phase = match &group {
+			Some(name) => match state.phases.get(name) {
+				Some(group) => group.phase(),
+				None => return Err(format!("Unknown phase group '{}'", name)),
+			},
+			None => state.fluid_phase(),
+		};
+
+		let emitter = emitter::Emitter::new(origin, direction, radius, speed, phase);
+		lua_pushinteger(l, state.emitters.add(emitter) as LuaInteger);
+		Ok(1)
+	})
+}
+
+// flex.set_emitter_enabled(emitter, enabled) -> bool
+#[lua_function]
+fn set_emitter_enabled(l: LuaState) -> i32 {
+	let emitter = luaL_checkinteger(l, 1) as usize;
+	let enabled = lua_toboolean(l, 2) != 0;
+
+	let found = match flex_state().and_then(|state| state.emitters.get_mut(emitter)) {
+		Some(emitter) => {
+			emitter.enabled = enabled;
+			true
+		}
+		None => false,
+	};
+
+	lua_pushboolean(l, found as i32);
+	1
+}
+
+// flex.remove_emitter(emitter) -> bool
+#[lua_function]
+fn remove_emitter(l: LuaState) -> i32 {
+	let emitter = luaL_checkinteger(l, 1) as usize;
+	let ok = flex_state().map_or(false, |state| state.emitters.remove(emitter));
+
+	lua_pushboolean(l, ok as i32);
+	1
+}
+
+// flex.add_kill_volume(minx, miny, minz, maxx, maxy, maxz) -> volume
+// Removes every particle that enters the box, like a drain.
+#[lua_function]
+fn add_kill_volume(l: LuaState) -> i32 {
+	let volume = emitter::KillVolume {
+		min: check_vector(l, 1),
+		max: check_vector(l, 4),
+	};
+
+	let state = match flex_state() {
+		Some(state) => state,
+		None => return 0,
+	};
+
+	state.kill_volumes.push(volume);
+	lua_pushinteger(l, (state.kill_volumes.len() - 1) as LuaInteger);
+	1
+}
+
+// flex.clear_kill_volumes()
+#[lua_function]
+fn clear_kill_volumes(_l: LuaState) -> i32 {
+	if let Some(state) = flex_state() {
+		state.kill_volumes.clear();
+	}
+	0
+}
+
+// flex.apply_scene(source)
+// Adds everything in a TOML scene to the simulation, see scene.rs for the format.
+// Mesh files are relative to garrysmod/data/gfluid and can't leave it. The scene's capacity is
+// ignored, as the solver can't be resized, see flex.load_scene. Invalid scenes change nothing.
+#[lua_function]
+fn apply_scene(l: LuaState) -> i32 {
+	protect(l, || {
+		let source = check_string(l, 1);
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		let result = scene::Scene::parse(&source).and_then(|mut scene| {
+			scene.root = std::path::PathBuf::from(scene::DATA_FOLDER);
+			scene.apply(state)
+		});
+
+		match result {
+			Ok(()) => Ok(0),
+			Err(why) => Err(why.to_string()),
+		}
+	})
+}
+
+// flex.load_scene(source)
+// Replaces the simulation with a TOML scene, on a new solver with the scene's [capacity].
+// Everything added before is gone along with its handles. Nothing changes if the scene fails.
+#[lua_function]
+fn load_scene(l: LuaState) -> i32 {
+	protect(l, || {
+		let source = check_string(l, 1);
+		let result = scene::Scene::parse(&source).and_then(|mut scene| {
+			scene.root = std::path::PathBuf::from(scene::DATA_FOLDER);
+			scene.build(state::Backend::default(), 0)
+		});
+
+		let fresh = match result {
+			Ok(fresh) => Box::new(fresh),
+			Err(why) => return Err(why.to_string()),
+		};
+
+		let old = STATE.swap(Box::into_raw(fresh), Ordering::SeqCst);
+		if !old.is_null() {
+			drop(unsafe { Box::from_raw(old) });
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.dump_scene() -> string
+// Writes the current simulation out as a TOML scene, with every particle listed.
+#[lua_function]
+fn dump_scene(l: LuaState) -> i32 {
+	protect(l, || {
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		match scene::Scene::capture(state).to_toml() {
+			Ok(source) => {
+				lua_pushlstring(l, source.as_ptr() as _, source.len());
+				Ok(1)
+			}
+			Err(why) => Err(why.to_string()),
+		}
+	})
+}
+
+// flex.apply_impulse(x, y, z, radius, strength, falloff?) -> affected
+#[lua_function]
+fn apply_impulse(l: LuaState) -> i32 {
+	let impulse = forces::Impulse {
+		center: check_vector(l, 1),
+		radius: luaL_checknumber(l, 4) as f32,
+		strength: luaL_checknumber(l, 5) as f32,
+		falloff: luaL_optnumber(l, 6, 1.0) as f32,
+		lift: 0.0,
+	};
+
+	let affected = flex_state().map_or(0, |state| state.apply_impulse(&impulse));
+
+	lua_pushinteger(l, affected as LuaInteger);
+	1
+}
+
+// flex.explode(x, y, z, radius, strength, lift?, falloff?) -> affected
+// Like apply_impulse, but also throws particles upwards by a fraction of the strength.
+#[lua_function]
+fn explode(l: LuaState) -> i32 {
+	let impulse = forces::Impulse {
+		center: check_vector(l, 1),
+		radius: luaL_checknumber(l, 4) as f32,
+		strength: luaL_checknumber(l, 5) as f32,
+		lift: luaL_optnumber(l, 6, 0.5) as f32,
+		falloff: luaL_optnumber(l, 7, 2.0) as f32,
+	};
+
+	let affected = flex_state().map_or(0, |state| state.apply_impulse(&impulse));
+
+	lua_pushinteger(l, affected as LuaInteger);
+	1
+}
+
+// flex.set_neighbor_tracking(enabled)
+// Reads back neighbor lists every tick, needed for per particle density, pressure and surface flags.
+#[lua_function]
+fn set_neighbor_tracking(l: LuaState) -> i32 {
+	luaL_checktype(l, 1, LUA_TBOOLEAN);
+	let enabled = lua_toboolean(l, 1) != 0;
+
+	if let Some(state) = flex_state() {
+		state.neighbors.enabled = enabled;
+	}
+
+	0
+}
+
+// flex.get_particle_density(id) -> density?, pressure?, surface?
+// Density is relative to the rest density, as of the last tick with neighbor tracking enabled.
+#[lua_function]
+fn get_particle_density(l: LuaState) -> i32 {
+	let id = luaL_checkinteger(l, 1).max(0) as usize;
+
+	match flex_state().and_then(|state| state.neighbors.get(id)) {
+		Some((density, pressure, surface)) => {
+			lua_pushnumber(l, density as f64);
+			lua_pushnumber(l, pressure as f64);
+			lua_pushboolean(l, surface as i32);
+			3
+		}
+		None => 0,
+	}
+}
+
+// flex.get_neighbors(id) -> { id, ... }?
+#[lua_function]
+fn get_neighbors(l: LuaState) -> i32 {
+	let id = luaL_checkinteger(l, 1).max(0) as usize;
+
+	let neighbors = match flex_state().and_then(|state| state.neighbors.get_neighbors(id)) {
+		Some(neighbors) => neighbors,
+		None => return 0,
+	};
+
+	lua_createtable(l, neighbors.len() as i32, 0);
+	for (i, neighbor) in neighbors.iter().enumerate() {
+		lua_pushinteger(l, *neighbor as LuaInteger);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	1
+}
+
+// flex.get_surface_particles() -> { id, ... }
+// Particles on the fluid's surface, or isolated ones like splashes.
+#[lua_function]
+fn get_surface_particles(l: LuaState) -> i32 {
+	let state = match flex_state() {
+		Some(state) => state,
+		None => return 0,
+	};
+
+	lua_createtable(l, 0, 0);
+	for (i, id) in state.neighbors.surface().enumerate() {
+		lua_pushinteger(l, id as LuaInteger);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	1
+}
+
+// flex.get_density_at(x, y, z) -> density
+#[lua_function]
+fn get_density_at(l: LuaState) -> i32 {
+	let point = check_vector(l, 1);
+	let density = flex_state().map_or(0.0, |state| state.get_density_at(point));
+
+	lua_pushnumber(l, density as f64);
+	1
+}
+
+/// Reads an optional array of shape channel numbers at `arg`, nil meaning every channel.
+fn opt_channels(l: LuaState, arg: i32) -> i32 {
+	let mut channels = vec![];
+	if lua_istable(l, arg) {
+		for i in 1 ..= lua_objlen(l, arg) as i32 {
+			lua_rawgeti(l, arg, i);
+			channels.push(lua_tointeger(l, -1).max(0) as u32);
+			lua_pop(l, 1);
+		}
+	}
+
+	state::phase::channel_mask(&channels)
+}
+
+/// Reads generator options at `arg`: { group?, jitter?, spacing?, velocity? }.
+/// Particles go in the default fluid group unless another named group is given.
+fn opt_lattice(l: LuaState, arg: i32, state: &FlexState) -> Result<state::Lattice, String> {
+	let mut group = None;
+	let mut lattice = state.lattice(0);
+
+	if lua_istable(l, arg) {
+		lua_getfield(l, arg, cstr!("jitter"));
+		lattice.jitter = luaL_optnumber(l, -1, 0.0) as f32;
+		lua_pop(l, 1);
+
+		lua_getfield(l, arg, cstr!("spacing"));
+		lattice.spacing = luaL_optnumber(l, -1, lattice.spacing as f64) as f32;
+		lua_pop(l, 1);
+
+		lua_getfield(l, arg, cstr!("velocity"));
+		if lua_istable(l, -1) {
+			let mut v = [0.0; 3];
+			for (k, c) in v.iter_mut().enumerate() {
+				lua_rawgeti(l, -1, k as i32 + 1);
+				*c = lua_tonumber(l, -1) as f32;
+				lua_pop(l, 1);
+			}
+			lattice.velocity = Vector3(v[0], v[1], v[2]);
+		}
+		lua_pop(l, 1);
+
+		// Read last, as the numbers above raise on bad values
+		lua_getfield(l, arg, cstr!("group"));
+		if lua_isstring(l, -1) != 0 {
+			group = Some(check_string(l, -1));
+		}
+		lua_pop(l, 1);
+	}
+
+	if lattice.spacing <= 0.0 {
+		return Err("Spacing must be positive".to_owned());
+	}
+
+	let group = group.as_deref().unwrap_or(state::phase::FLUID);
+	match state.phases.get(group) {
+		Some(group) => lattice.phase = group.phase(),
+		None => return Err(format!("Unknown phase group '{}'", group)),
+	}
+
+	Ok(lattice)
+}
+
+/// Pushes an array of particle ids
+fn push_ids(l: LuaState, ids: &[i32]) -> i32 {
+	lua_createtable(l, ids.len() as i32, 0);
+	for (i, id) in ids.iter().enumerate() {
+		lua_pushinteger(l, *id as LuaInteger);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	1
+}
+
+// flex.fill_box(x, y, z, hx, hy, hz, options?) -> { id, ... }
+// Options are { group?, jitter?, spacing?, velocity? }, spacing defaulting to the fluid rest distance.
+// Stops early when out of particles, returning the ones that were created.
+// Volumes needing more than 16777216 lattice points for the spacing create nothing.
+#[lua_function]
+fn fill_box(l: LuaState) -> i32 {
+	protect(l, || {
+		let center = check_vector(l, 1);
+		let half = check_vector(l, 4);
+
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		let lattice = opt_lattice(l, 7, state)?;
+
+		let ids = state.generate(|factory| factory.fill_box(center, half, &lattice));
+		Ok(push_ids(l, &ids))
+	})
+}
+
+// flex.fill_sphere(x, y, z, radius, options?) -> { id, ... }
+#[lua_function]
+fn fill_sphere(l: LuaState) -> i32 {
+	protect(l, || {
+		let center = check_vector(l, 1);
+		let radius = luaL_checknumber(l, 4) as f32;
+
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		let lattice = opt_lattice(l, 5, state)?;
+
+		let ids = state.generate(|factory| factory.fill_sphere(center, radius, &lattice));
+		Ok(push_ids(l, &ids))
+	})
+}
+
+// flex.fill_cylinder(x, y, z, radius, height, options?) -> { id, ... }
+// The cylinder is upright and centered on the given position.
+#[lua_function]
+fn fill_cylinder(l: LuaState) -> i32 {
+	protect(l, || {
+		let center = check_vector(l, 1);
+		let radius = luaL_checknumber(l, 4) as f32;
+		let half_height = luaL_checknumber(l, 5) as f32 * 0.5;
+
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		let lattice = opt_lattice(l, 6, state)?;
+
+		let ids = state.generate(|factory| {
+			factory.fill_cylinder(center, radius, half_height, &lattice)
+		});
+		Ok(push_ids(l, &ids))
+	})
+}
+
+// flex.fill_mesh(vertices, indices, options?) -> { id, ... }
+// Vertices are in world space, the mesh must be closed.
+#[lua_function]
+fn fill_mesh(l: LuaState) -> i32 {
+	protect(l, || {
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		// Options first, as they raise on bad values and the mesh allocates
+		let lattice = opt_lattice(l, 3, state)?;
+		let (vertices, indices) = check_mesh(l, 1)?;
+
+		let ids = state.generate(|factory| factory.fill_mesh(&vertices, &indices, &lattice));
+		Ok(push_ids(l, &ids))
+	})
+}
+
+// flex.emit_stream(x, y, z, dx, dy, dz, radius, length, speed, options?) -> { id, ... }
+// A one shot round jet of particles moving along the direction.
+#[lua_function]
+fn emit_stream(l: LuaState) -> i32 {
+	protect(l, || {
+		let origin = check_vector(l, 1);
+		let direction = check_vector(l, 4);
+		let radius = luaL_checknumber(l, 7) as f32;
+		let length = luaL_checknumber(l, 8) as f32;
+		let speed = luaL_checknumber(l, 9) as f32;
+
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		let lattice = opt_lattice(l, 10, state)?;
+
+		let ids = state.generate(|factory| {
+			factory.stream(origin, direction, radius, length, speed, &lattice)
+		});
+		Ok(push_ids(l, &ids))
+	})
+}
+
+// flex.emit_sheet(x, y, z, dx, dy, dz, width, length, speed, options?) -> { id, ... }
+// A one shot flat sheet of particles moving along the direction.
+#[lua_function]
+fn emit_sheet(l: LuaState) -> i32 {
+	protect(l, || {
+		let origin = check_vector(l, 1);
+		let direction = check_vector(l, 4);
+		let width = luaL_checknumber(l, 7) as f32;
+		let length = luaL_checknumber(l, 8) as f32;
+		let speed = luaL_checknumber(l, 9) as f32;
+
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		let lattice = opt_lattice(l, 10, state)?;
+
+		let ids = state.generate(|factory| {
+			factory.sheet(origin, direction, width, length, speed, &lattice)
+		});
+		Ok(push_ids(l, &ids))
+	})
+}
+
+// flex.define_phase_group(name, { self_collide?, fluid?, filter?, channels? }?) -> group
+// Redefining an existing group updates the particles already in it.
+// Channels are numbers from 0 to 7, a group only collides with shapes sharing one of them.
+#[lua_function]
+fn define_phase_group(l: LuaState) -> i32 {
+	luaL_checkstring(l, 1);
+
+	let mut flags = 0;
+	let mut channels = eNvFlexPhaseShapeChannelMask;
+	if lua_istable(l, 2) {
+		let options = [
+			(cstr!("self_collide"), eNvFlexPhaseSelfCollide),
+			(cstr!("fluid"), eNvFlexPhaseFluid),
+			(cstr!("filter"), eNvFlexPhaseSelfCollideFilter),
+		];
+
+		for (field, flag) in options {
+			lua_getfield(l, 2, field);
+			if lua_toboolean(l, -1) != 0 {
+				flags |= flag;
+			}
+			lua_pop(l, 1);
+		}
+
+		lua_getfield(l, 2, cstr!("channels"));
+		channels = opt_channels(l, -1);
+		lua_pop(l, 1);
+	}
+
+	let name = check_string(l, 1);
+	match flex_state() {
+		Some(state) => {
+			let group = state.define_phase_group(&name, flags, channels);
+			lua_pushinteger(l, group.group as LuaInteger);
+			1
+		}
+		None => 0,
+	}
+}
+
+// flex.get_phase_group(name) -> group?
+#[lua_function]
+fn get_phase_group(l: LuaState) -> i32 {
+	let name = check_string(l, 1);
+
+	match flex_state().and_then(|state| state.phases.get(&name)) {
+		Some(group) => {
+			lua_pushinteger(l, group.group as LuaInteger);
+			1
+		}
+		None => 0,
+	}
+}
+
+// flex.set_shape_channels(shape, { channel, ... }?) -> bool
+// Nil makes the shape collide with every group again.
+#[lua_function]
+fn set_shape_channels(l: LuaState) -> i32 {
+	let shape = luaL_checkinteger(l, 1) as usize;
+	let channels = opt_channels(l, 2);
+
+	let ok = flex_state().map_or(false, |state| state.geometry.set_channels(shape, channels));
+
+	lua_pushboolean(l, ok as i32);
+	1
+}
+
+/// Reads a particle id argument, erroring if it doesn't exist.
+fn check_particle(l: LuaState, arg: i32, state: &FlexState) -> Result<usize, String> {
+	let id = luaL_checkinteger(l, arg);
+	if id < 0 || id as usize >= state.attributes.len() {
+		return Err(format!("Invalid particle id {}", id));
+	}
+
+	Ok(id as usize)
+}
+
+// flex.set_particle_color(id, r, g, b, a?)
+// Components range from 0 to 1.
+#[lua_function]
+fn set_particle_color(l: LuaState) -> i32 {
+	protect(l, || {
+		if let Some(state) = flex_state() {
+			let id = check_particle(l, 1, state)?;
+			let rgb = check_vector(l, 2);
+			let alpha = luaL_optnumber(l, 5, 1.0) as f32;
+			if let Some(slot) = state.attributes.colors.get_mut(id) {
+				*slot = [rgb.0, rgb.1, rgb.2, alpha];
+			}
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.set_particle_temperature(id, temperature)
+#[lua_function]
+fn set_particle_temperature(l: LuaState) -> i32 {
+	protect(l, || {
+		if let Some(state) = flex_state() {
+			let id = check_particle(l, 1, state)?;
+			let temperature = luaL_checknumber(l, 2) as f32;
+			if let Some(slot) = state.attributes.temperatures.get_mut(id) {
+				*slot = temperature;
+			}
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.set_particle_owner(id, entity?)
+#[lua_function]
+fn set_particle_owner(l: LuaState) -> i32 {
+	protect(l, || {
+		if let Some(state) = flex_state() {
+			let id = check_particle(l, 1, state)?;
+			let owner = opt_entity(l, 2).unwrap_or(-1);
+			if let Some(slot) = state.attributes.owners.get_mut(id) {
+				*slot = owner;
+			}
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.set_particle_channel(id, name, value)
+// Channels are created on first use, with every other particle starting at 0.
+#[lua_function]
+fn set_particle_channel(l: LuaState) -> i32 {
+	protect(l, || {
+		luaL_checkstring(l, 2);
+		let value = luaL_checknumber(l, 3) as f32;
+
+		if let Some(state) = flex_state() {
+			let id = check_particle(l, 1, state)?;
+			let name = check_string(l, 2);
+			state.attributes.set_channel(&name, id, value);
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.get_particle_channel(id, name) -> value?
+#[lua_function]
+fn get_particle_channel(l: LuaState) -> i32 {
+	let id = luaL_checkinteger(l, 1).max(0) as usize;
+	let name = check_string(l, 2);
+
+	match flex_state().and_then(|state| state.attributes.get_channel(&name, id)) {
+		Some(value) => {
+			lua_pushnumber(l, value as f64);
+			1
+		}
+		None => 0,
+	}
+}
+
+// flex.paint(x, y, z, radius, r, g, b, a?) -> painted
+#[lua_function]
+fn paint(l: LuaState) -> i32 {
+	let center = check_vector(l, 1);
+	let radius = luaL_checknumber(l, 4) as f32;
+	let rgb = check_vector(l, 5);
+	let color = [rgb.0, rgb.1, rgb.2, luaL_optnumber(l, 8, 1.0) as f32];
+
+	let painted = flex_state().map_or(0, |state| state.paint(center, radius, color));
+
+	lua_pushinteger(l, painted as LuaInteger);
+	1
+}
+
+// flex.set_color_diffusion(rate)
+// How fast particle colors blend with their neighbors per second, 0 to disable.
+#[lua_function]
+fn set_color_diffusion(l: LuaState) -> i32 {
+	let rate = luaL_checknumber(l, 1) as f32;
+	if let Some(state) = flex_state() {
+		state.attributes.color_diffusion = rate.max(0.0);
+	}
+
+	0
+}
+
+// flex.remove_particles({ id, ... }) -> removed
+// Rigid, soft body and cloth particles are left alone.
+#[lua_function]
+fn remove_particles(l: LuaState) -> i32 {
+	luaL_checktype(l, 1, LUA_TTABLE);
+
+	let mut ids = vec![];
+	for i in 1 ..= lua_objlen(l, 1) as i32 {
+		lua_rawgeti(l, 1, i);
+		ids.push(lua_tointeger(l, -1) as i32);
+		lua_pop(l, 1);
+	}
+
+	let removed = flex_state().map_or(0, |state| state.remove_particles(&ids));
+
+	lua_pushinteger(l, removed as LuaInteger);
+	1
+}
+
+// flex.compact() -> remap
+// Reclaims removed particles. remap[old + 1] is the new id of each particle, or -1 if it was removed.
+#[lua_function]
+fn compact(l: LuaState) -> i32 {
+	let remap = match flex_state() {
+		Some(state) => state.compact(),
+		None => return 0,
+	};
+
+	lua_createtable(l, remap.len() as i32, 0);
+	for (i, new) in remap.iter().enumerate() {
+		lua_pushinteger(l, *new as LuaInteger);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	1
+}
+
+// flex.save_snapshot(name?) -> data?
+// Without a name, returns the snapshot as a binary string, for dupes or net messages.
+// With one, writes it to data/gfluid/<name>.dat instead.
+#[lua_function]
+fn save_snapshot(l: LuaState) -> i32 {
+	protect(l, || {
+		let name = (!lua_isnoneornil(l, 1)).then(|| check_string(l, 1));
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		let bytes = match state.save_snapshot() {
+			Ok(bytes) => bytes,
+			Err(why) => return Err(why.to_string()),
+		};
+
+		let name = match name {
+			Some(name) => name,
+			None => {
+				lua_pushlstring(l, bytes.as_ptr() as _, bytes.len());
+				return Ok(1);
+			}
+		};
+
+		if let Err(why) = snapshot::write_file(&name, &bytes) {
+			return Err(why.to_string());
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.load_snapshot(data)
+#[lua_function]
+fn load_snapshot(l: LuaState) -> i32 {
+	protect(l, || {
+		let mut len = 0;
+		let ptr = luaL_checklstring(l, 1, &mut len);
+		let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, len) };
+
+		if let Some(state) = flex_state() {
+			if let Err(why) = state.load_snapshot(bytes) {
+				return Err(why.to_string());
+			}
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.load_snapshot_file(name)
+// Loads data/gfluid/<name>.dat, as written by flex.save_snapshot(name).
+#[lua_function]
+fn load_snapshot_file(l: LuaState) -> i32 {
+	protect(l, || {
+		let name = check_string(l, 1);
+
+		let result = snapshot::read_file(&name).and_then(|bytes| match flex_state() {
+			Some(state) => state.load_snapshot(&bytes),
+			None => Ok(()),
+		});
+
+		if let Err(why) = result {
+			return Err(why.to_string());
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.export(name, format?, frame?, colliders?) -> file name
+// Writes the active particles to data/gfluid/export/<name>.<ext>.
+// Formats are "ply", "ply_binary" (the default), "vtk" and "csv".
+// With a frame number the file is named <name>_00042.<ext> instead, for numbered sequences.
+// With colliders set, every shape is also written as a <file>_shape<index>.ply mesh.
+#[lua_function]
+fn export_particles(l: LuaState) -> i32 {
+	protect(l, || {
+		// Strings are copied out once every argument is checked, so nothing raises after
+		luaL_checkstring(l, 1);
+		let format = (!lua_isnoneornil(l, 2)).then(|| luaL_checkstring(l, 2));
+		let frame = (!lua_isnoneornil(l, 3)).then(|| luaL_checkinteger(l, 3).max(0) as u32);
+		let colliders = lua_toboolean(l, 4) != 0;
+
+		let format = match format {
+			Some(format) => {
+				let format = unsafe { std::ffi::CStr::from_ptr(format) }.to_string_lossy();
+				match export::Format::parse(&format) {
+					Some(format) => format,
+					None => return Err(format!("Unknown export format '{}'", format)),
+				}
+			}
+			None => export::Format::PlyBinary,
+		};
+
+		let name = check_string(l, 1);
+		let file = match frame {
+			Some(frame) => export::sequence_name(&name, frame),
+			None => name,
+		};
+
+		let state = match flex_state() {
+			Some(state) => state,
+			None => return Ok(0),
+		};
+
+		let frame = state.get_export_frame();
+		let result = export::create_file(&file, format.extension()).and_then(|mut out| {
+			export::write_frame(&mut out, &frame, format)?;
+			Ok(out.flush()?)
+		});
+		if let Err(why) = result {
+			return Err(why.to_string());
+		}
+
+		if colliders {
+			for (index, mesh) in state.get_collider_meshes() {
+				let shape = format!("{}_shape{}", file, index);
+				let result = export::create_file(&shape, "ply").and_then(|mut out| {
+					export::write_mesh(&mut out, &mesh)?;
+					Ok(out.flush()?)
+				});
+				if let Err(why) = result {
+					return Err(why.to_string());
+				}
+			}
+		}
+
+		let file = format!("{}.{}", file, format.extension());
+		lua_pushlstring(l, file.as_ptr() as _, file.len());
+		Ok(1)
+	})
+}
+
+// flex.start_recording(keyframe_interval?)
+// Records every input from now on, see flex.stop_recording.
+#[lua_function]
+fn start_recording(l: LuaState) -> i32 {
+	protect(l, || {
+		let interval = luaL_optinteger(l, 1, replay::KEYFRAME_INTERVAL as LuaInteger).max(1);
+
+		if let Some(state) = flex_state() {
+			match replay::Recorder::new(state, interval as usize) {
+				Ok(recorder) => state.recorder = Some(recorder),
+				Err(why) => return Err(why.to_string()),
+			}
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.stop_recording(name?) -> data?
+// Without a name, returns the replay as a binary string.
+// With one, writes it to data/gfluid/<name>.dat instead.
+#[lua_function]
+fn stop_recording(l: LuaState) -> i32 {
+	protect(l, || {
+		let name = (!lua_isnoneornil(l, 1)).then(|| check_string(l, 1));
+		let bytes = match flex_state().and_then(|state| state.recorder.take()) {
+			Some(recorder) => recorder.finish(),
+			None => return Ok(0),
+		};
+
+		let name = match name {
+			Some(name) => name,
+			None => {
+				lua_pushlstring(l, bytes.as_ptr() as _, bytes.len());
+				return Ok(1);
+			}
+		};
+
+		if let Err(why) = snapshot::write_file(&name, &bytes) {
+			return Err(why.to_string());
+		}
+
+		Ok(0)
+	})
+}
+
+/// Replays a recording on a fresh solver and pushes the drift report.
+fn push_replay(l: LuaState, bytes: &[u8], tolerance: f32) -> Result<i32, String> {
+	let replay = match replay::Replay::parse(bytes) {
+		Ok(replay) => replay,
+		Err(why) => return Err(why.to_string()),
+	};
+
+	let mut fresh = FlexState::new();
+	if let Err(why) = fresh.init_with(state::Backend::default(), 0, replay.capacity) {
+		return Err(why.to_string());
+	}
+
+	let report = match replay.run(&mut fresh, tolerance) {
+		Ok(report) => report,
+		Err(why) => return Err(why.to_string()),
+	};
+
+	lua_createtable(l, 0, 8);
+
+	lua_pushinteger(l, report.frames as LuaInteger);
+	lua_setfield(l, -2, cstr!("frames"));
+
+	lua_pushinteger(l, report.mismatches as LuaInteger);
+	lua_setfield(l, -2, cstr!("mismatches"));
+
+	lua_pushnumber(l, report.max_centroid_drift as f64);
+	lua_setfield(l, -2, cstr!("max_centroid_drift"));
+
+	lua_pushnumber(l, report.max_particle_drift as f64);
+	lua_setfield(l, -2, cstr!("max_particle_drift"));
+
+	lua_pushnumber(l, report.tolerance as f64);
+	lua_setfield(l, -2, cstr!("tolerance"));
+
+	lua_pushinteger(l, report.over_tolerance as LuaInteger);
+	lua_setfield(l, -2, cstr!("over_tolerance"));
+
+	// Frames are one based in Lua, absent when everything matched
+	if let Some(frame) = report.first_mismatch {
+		lua_pushinteger(l, frame as LuaInteger + 1);
+		lua_setfield(l, -2, cstr!("first_mismatch"));
+	}
+
+	if let Some(frame) = report.first_over_tolerance {
+		lua_pushinteger(l, frame as LuaInteger + 1);
+		lua_setfield(l, -2, cstr!("first_over_tolerance"));
+	}
+
+	Ok(1)
+}
+
+// flex.replay(data, tolerance?) -> report
+// Runs a recording on a separate solver, reporting how far it drifts from what was recorded.
+#[lua_function]
+fn run_replay(l: LuaState) -> i32 {
+	protect(l, || {
+		let mut len = 0;
+		let ptr = luaL_checklstring(l, 1, &mut len);
+		let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, len) };
+		let tolerance = luaL_optnumber(l, 2, 0.01) as f32;
+
+		push_replay(l, bytes, tolerance)
+	})
+}
+
+// flex.replay_file(name, tolerance?) -> report
+#[lua_function]
+fn replay_file(l: LuaState) -> i32 {
+	protect(l, || {
+		luaL_checkstring(l, 1);
+		let tolerance = luaL_optnumber(l, 2, 0.01) as f32;
+
+		let name = check_string(l, 1);
+		match snapshot::read_file(&name) {
+			Ok(bytes) => push_replay(l, &bytes, tolerance),
+			Err(why) => Err(why.to_string()),
+		}
+	})
+}
+
+// flex.set_replication_region(min_x, min_y, min_z, max_x, max_y, max_z)
+// Box replicated positions are quantized within, the whole map by default.
+// Every client gets a whole frame next, as their baselines are no longer valid.
+#[lua_function]
+fn set_replication_region(l: LuaState) -> i32 {
+	let region = replication::Region {
+		min: check_vector(l, 1),
+		max: check_vector(l, 4),
+	};
+
+	if let Some(state) = flex_state() {
+		state.replication.set_region(region);
+	}
+
+	0
+}
+
+// flex.encode_replication(client, x, y, z, budget?) -> data
+// Server side. Encodes the particles for the client with a player at x, y, z, to send with net.WriteData.
+// client is any number identifying them, like their UserID.
+#[lua_function]
+fn encode_replication(l: LuaState) -> i32 {
+	let client = luaL_checkinteger(l, 1) as i32;
+	let viewer = check_vector(l, 2);
+	let budget = luaL_optinteger(l, 5, replication::DEFAULT_BUDGET as LuaInteger).max(0);
+
+	let bytes = match flex_state() {
+		Some(state) => state.encode_replication(client, viewer, budget as usize),
+		None => return 0,
+	};
+
+	lua_pushlstring(l, bytes.as_ptr() as _, bytes.len());
+	1
+}
+
+// flex.ack_replication(client, seq) -> bool
+// Server side. Call when a client acks a frame, so later frames only carry what changed since.
+#[lua_function]
+fn ack_replication(l: LuaState) -> i32 {
+	let client = luaL_checkinteger(l, 1) as i32;
+	let seq = luaL_checkinteger(l, 2).max(0) as u32;
+
+	let acked = match flex_state() {
+		Some(state) => state.replication.encoder(client).ack(seq),
+		None => return 0,
+	};
+
+	lua_pushboolean(l, acked as i32);
+	1
+}
+
+// flex.remove_replication_client(client)
+// Server side. Forgets a client, like when they disconnect.
+#[lua_function]
+fn remove_replication_client(l: LuaState) -> i32 {
+	let client = luaL_checkinteger(l, 1) as i32;
+	if let Some(state) = flex_state() {
+		state.replication.remove(client);
+	}
+
+	0
+}
+
+// flex.decode_replication(data) -> seq
+// Client side. Decodes a frame from flex.encode_replication, returning the seq to ack back.
+#[lua_function]
+fn decode_replication(l: LuaState) -> i32 {
+	protect(l, || {
+		let mut len = 0;
+		let ptr = luaL_checklstring(l, 1, &mut len);
+		let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, len) };
+
+		let seq = match flex_state().map(|state| state.replication.receive(bytes)) {
+			Some(Ok(seq)) => seq,
+			Some(Err(why)) => return Err(why.to_string()),
+			None => return Ok(0),
+		};
+
+		lua_pushinteger(l, seq as LuaInteger);
+		Ok(1)
+	})
+}
+
+// flex.get_replicated_particles() -> { id, x, y, z, ... }
+// Client side. Flat quadruplets of every replicated particle, interpolated to the render time.
+// flex.get_view_particles and flex.get_surface use these too once frames arrive.
+#[lua_function]
+fn get_replicated_particles(l: LuaState) -> i32 {
+	let particles = match flex_state() {
+		Some(state) => state.replication.interpolator.sample(),
+		None => return 0,
+	};
+
+	let start = Instant::now();
+	lua_createtable(l, particles.len() as i32 * 4, 0);
+	for (i, (id, p)) in particles.into_iter().enumerate() {
+		let values = [id as f64, p.0 as f64, p.1 as f64, p.2 as f64];
+		for (k, v) in values.iter().enumerate() {
+			lua_pushnumber(l, *v);
+			lua_rawseti(l, -2, (i * 4 + k) as i32 + 1);
+		}
+	}
+
+	marshalled(start);
+	1
+}
+
+// flex.set_interpolation(delay?, max_extrapolation?)
+// Client side. Seconds rendering lags behind the server to absorb jitter, and seconds particles
+// keep moving once frames stop arriving.
+#[lua_function]
+fn set_interpolation(l: LuaState) -> i32 {
+	use interpolation::{DEFAULT_DELAY, DEFAULT_MAX_EXTRAPOLATION};
+
+	let delay = luaL_optnumber(l, 1, DEFAULT_DELAY as f64).max(0.0);
+	let max_extrapolation = luaL_optnumber(l, 2, DEFAULT_MAX_EXTRAPOLATION as f64).max(0.0);
+
+	if let Some(state) = flex_state() {
+		let interpolator = &mut state.replication.interpolator;
+		interpolator.delay = delay as f32;
+		interpolator.max_extrapolation = max_extrapolation as f32;
+	}
+
+	0
+}
+
+// flex.set_health_check(enabled, response?, rollback?)
+// Off by default, as checking reads every particle back each tick.
+// Response is "quarantine", "remove" or "ignore".
+// Rollback restores the last good snapshot when particles go NaN or energy spikes.
+#[lua_function]
+fn set_health_check(l: LuaState) -> i32 {
+	protect(l, || {
+		let enabled = lua_toboolean(l, 1) != 0;
+		let response = if lua_isnoneornil(l, 2) {
+			None
+		} else {
+			let name = check_string(l, 2);
+			match health::Response::parse(&name) {
+				Some(response) => Some(response),
+				None => return Err(format!("Unknown health response '{}'", name)),
+			}
+		};
+
+		if let Some(state) = flex_state() {
+			state.health.enabled = enabled;
+			if let Some(response) = response {
+				state.health.response = response;
+			}
+			if !lua_isnoneornil(l, 3) {
+				state.health.rollback = lua_toboolean(l, 3) != 0;
+			}
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.set_world_bounds(minx, miny, minz, maxx, maxy, maxz)
+// Particles leaving the bounds fail the health check, calling it without arguments removes them.
+#[lua_function]
+fn set_world_bounds(l: LuaState) -> i32 {
+	let bounds = (!lua_isnoneornil(l, 1)).then(|| (check_vector(l, 1), check_vector(l, 4)));
+
+	if let Some(state) = flex_state() {
+		state.health.bounds = bounds;
+	}
+
+	0
+}
+
+// flex.get_health() -> { nan, fast, escaped, spikes, rollbacks, quarantined }
+// Counts of everything the health check caught so far.
+#[lua_function]
+fn get_health(l: LuaState) -> i32 {
+	let health = match flex_state() {
+		Some(state) => &state.health,
+		None => return 0,
+	};
+
+	let total = health.total;
+	lua_createtable(l, 0, 6);
+
+	lua_pushinteger(l, total.nan as LuaInteger);
+	lua_setfield(l, -2, cstr!("nan"));
+
+	lua_pushinteger(l, total.fast as LuaInteger);
+	lua_setfield(l, -2, cstr!("fast"));
+
+	lua_pushinteger(l, total.escaped as LuaInteger);
+	lua_setfield(l, -2, cstr!("escaped"));
+
+	lua_pushinteger(l, total.spikes as LuaInteger);
+	lua_setfield(l, -2, cstr!("spikes"));
+
+	lua_pushinteger(l, total.rollbacks as LuaInteger);
+	lua_setfield(l, -2, cstr!("rollbacks"));
+
+	lua_pushinteger(l, health.quarantined.len() as LuaInteger);
+	lua_setfield(l, -2, cstr!("quarantined"));
+
+	1
+}
+
+// flex.get_quarantined() -> { id, ... }
+#[lua_function]
+fn get_quarantined(l: LuaState) -> i32 {
+	match flex_state() {
+		Some(state) => push_ids(l, &state.health.quarantined),
+		None => 0,
+	}
+}
+
+// flex.set_timers(enabled)
+// Has FleX time each stage of the solver for flex.get_stats, which costs a little every step.
+#[lua_function]
+fn set_timers(l: LuaState) -> i32 {
+	let enabled = lua_toboolean(l, 1) != 0;
+	if let Some(state) = flex_state() {
+		state.stats.timers_enabled = enabled;
+	}
+	0
+}
+
+// flex.get_stats() -> { particles, shapes, step, readback, marshal, timers?, detail_timers? }
+// step, readback and marshal are { last, average, max } in milliseconds over the last 60 steps.
+// With timers enabled, timers = { [stage] = ms, ... } and detail_timers = { { name, ms }, ... }.
+#[lua_function]
+fn get_stats(l: LuaState) -> i32 {
+	let state = match flex_state() {
+		Some(state) => state,
+		None => return 0,
+	};
+
+	let stats = &state.stats;
+	lua_createtable(l, 0, 7);
+
+	lua_pushinteger(l, state.particles.get_active().len() as LuaInteger);
+	lua_setfield(l, -2, cstr!("particles"));
+
+	lua_pushinteger(l, state.geometry.get_count() as LuaInteger);
+	lua_setfield(l, -2, cstr!("shapes"));
+
+	let timings = [
+		(cstr!("step"), &stats.step),
+		(cstr!("readback"), &stats.readback),
+		(cstr!("marshal"), &stats.marshal),
+	];
+
+	for (name, timing) in timings {
+		lua_createtable(l, 0, 3);
+
+		lua_pushnumber(l, timing.last() as f64);
+		lua_setfield(l, -2, cstr!("last"));
+
+		lua_pushnumber(l, timing.average() as f64);
+		lua_setfield(l, -2, cstr!("average"));
+
+		lua_pushnumber(l, timing.max() as f64);
+		lua_setfield(l, -2, cstr!("max"));
+
+		lua_setfield(l, -2, name);
+	}
+
+	if let Some(timers) = stats.timers.as_ref().filter(|_| stats.timers_enabled) {
+		let stages = stats::stages(timers);
+		lua_createtable(l, 0, stages.len() as i32);
+		for (stage, ms) in stages {
+			let stage = std::ffi::CString::new(stage).unwrap_or_default();
+			lua_pushnumber(l, ms as f64);
+			lua_setfield(l, -2, stage.as_ptr());
+		}
+		lua_setfield(l, -2, cstr!("timers"));
+
+		lua_createtable(l, stats.detail_timers.len() as i32, 0);
+		for (i, (name, ms)) in stats.detail_timers.iter().enumerate() {
+			let name = std::ffi::CString::new(name.as_str()).unwrap_or_default();
+			lua_createtable(l, 2, 0);
+
+			lua_pushstring(l, name.as_ptr());
+			lua_rawseti(l, -2, 1);
+
+			lua_pushnumber(l, *ms as f64);
+			lua_rawseti(l, -2, 2);
+
+			lua_rawseti(l, -2, i as i32 + 1);
+		}
+		lua_setfield(l, -2, cstr!("detail_timers"));
+	}
+
+	1
+}
+
+// flex.set_param(name, ...)
+// e.g. flex.set_param("wind", 0, 5, 0) or flex.set_param("drag", 0.1)
+#[lua_function]
+fn set_param(l: LuaState) -> i32 {
+	protect(l, || {
+		luaL_checkstring(l, 1);
+		let last = lua_gettop(l);
+		for arg in 2 ..= last {
+			luaL_checknumber(l, arg);
+		}
+
+		let name = check_string(l, 1);
+		let values: Vec<f32> = (2 ..= last).map(|arg| lua_tonumber(l, arg) as f32).collect();
+
+		if let Some(state) = flex_state() {
+			if let Err(why) = state.set_param(&name, &values) {
+				return Err(why.to_string());
+			}
+		}
+
+		Ok(0)
+	})
+}
+
+// flex.get_param(name) -> ...
+#[lua_function]
+fn get_param(l: LuaState) -> i32 {
+	let name = check_string(l, 1);
+
+	let values = match flex_state().and_then(|state| params::get(&state.params, &name)) {
+		Some(values) => values,
+		None => return 0,
+	};
+
+	for v in &values {
+		lua_pushnumber(l, *v as f64);
+	}
+
+	values.len() as i32
+}
+
+fn open(l: LuaState) -> Result<(), OpenError> {
+	let mut flex_state = Box::new(FlexState::new());
+	flex_state.init()?;
+
+	let flex_ptr = Box::into_raw(flex_state);
+	STATE.store(flex_ptr, Ordering::Relaxed);
+
+	let r = reg! [
+		"get_state" => get_state,
+		"get_particles" => get_particles,
+		"get" => get,
+
+		"add_box" => add_box,
+		"add_sphere" => add_sphere,
+		"add_capsule" => add_capsule,
+		"set_shape_transform" => set_shape_transform,
+		"get_forces" => get_forces,
+
+		"set_contact_reporting" => set_contact_reporting,
+		"get_contacts" => get_contacts,
+
+		"add_rigid_box" => add_rigid_box,
+		"add_rigid_sphere" => add_rigid_sphere,
+		"add_rigid_mesh" => add_rigid_mesh,
+		"get_rigid" => get_rigid,
+		"get_rigids" => get_rigids,
+
+		"add_cloth" => add_cloth,
+		"pin_cloth" => pin_cloth,
+		"pin_cloth_to_shape" => pin_cloth_to_shape,
+		"get_cloth" => get_cloth,
+
+		"add_soft_body" => add_soft_body,
+		"get_soft_body" => get_soft_body,
+		"add_inflatable" => add_inflatable,
+		"set_inflatable_pressure" => set_inflatable_pressure,
+
+		"get_diffuse" => get_diffuse,
+		"set_diffuse" => set_diffuse,
+		"get_diffuse_settings" => get_diffuse_settings,
+		"get_surface" => get_surface,
+		"get_view_particles" => get_view_particles,
+
+		"add_force_field" => add_force_field,
+		"set_force_field_position" => set_force_field_position,
+		"set_force_field_enabled" => set_force_field_enabled,
+		"set_force_field_groups" => set_force_field_groups,
+		"remove_force_field" => remove_force_field,
+
+		"add_emitter" => add_emitter,
+		"set_emitter_enabled" => set_emitter_enabled,
+		"remove_emitter" => remove_emitter,
+		"add_kill_volume" => add_kill_volume,
+		"clear_kill_volumes" => clear_kill_volumes,
+		"apply_scene" => apply_scene,
+		"load_scene" => load_scene,
+		"dump_scene" => dump_scene,
+
+		"apply_impulse" => apply_impulse,
+		"explode" => explode,
+
+		"fill_box" => fill_box,
+		"fill_sphere" => fill_sphere,
+		"fill_cylinder" => fill_cylinder,
+		"fill_mesh" => fill_mesh,
+		"emit_stream" => emit_stream,
+		"emit_sheet" => emit_sheet,
+
+		"define_phase_group" => define_phase_group,
+		"get_phase_group" => get_phase_group,
+		"set_shape_channels" => set_shape_channels,
+
+		"set_neighbor_tracking" => set_neighbor_tracking,
+		"get_particle_density" => get_particle_density,
+		"get_neighbors" => get_neighbors,
+		"get_surface_particles" => get_surface_particles,
+		"get_density_at" => get_density_at,
+
+		"set_particle_color" => set_particle_color,
+		"set_particle_temperature" => set_particle_temperature,
+		"set_particle_owner" => set_particle_owner,
+		"set_particle_channel" => set_particle_channel,
+		"get_particle_channel" => get_particle_channel,
+		"paint" => paint,
+		"set_color_diffusion" => set_color_diffusion,
+		"remove_particles" => remove_particles,
+		"compact" => compact,
+
+		"save_snapshot" => save_snapshot,
+		"load_snapshot" => load_snapshot,
+		"load_snapshot_file" => load_snapshot_file,
+		"export" => export_particles,
+
+		"start_recording" => start_recording,
+		"stop_recording" => stop_recording,
+		"replay" => run_replay,
+		"replay_file" => replay_file,
+
+		"set_replication_region" => set_replication_region,
+		"encode_replication" => encode_replication,
+		"ack_replication" => ack_replication,
+		"remove_replication_client" => remove_replication_client,
+		"decode_replication" => decode_replication,
+		"get_replicated_particles" => get_replicated_particles,
+		"set_interpolation" => set_interpolation,
+
+		"set_health_check" => set_health_check,
+		"set_world_bounds" => set_world_bounds,
+		"get_health" => get_health,
+		"get_quarantined" => get_quarantined,
+		"set_timers" => set_timers,
+		"get_stats" => get_stats,
+		"set_param" => set_param,
+		"get_param" => get_param
+	];
+
+	lua_getglobal(l, cstr!("hook"));
+	lua_getfield(l, -1, cstr!("Add"));
+
+	lua_remove(l, -2); // Remove 'hook' table from stack
+
+	// Push arguments to hook.Add
+	lua_pushstring(l, cstr!("Tick"));
+	lua_pushstring(l, cstr!("GFluid_Tick"));
+	lua_pushcfunction(l, tick);
+
+	// Call hook.Add
+	lua_call(l, 3, 0);
+
+	luaL_register(l, cstr!("flex"), r.as_ptr());
+
+	#[cfg(feature = "anisotropy")]
+	{
+		lua_pushcfunction(l, get_ellipsoids);
+		lua_setfield(l, -2, cstr!("get_ellipsoids"));
+	}
 
 	Ok(())
 }
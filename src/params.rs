@@ -0,0 +1,191 @@
+// Access to solver parameters by their FleX names, for Lua and file formats.
+use nvflex_sys::NvFlexParams;
+
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParamError {
+	#[error("Unknown parameter '{0}'")]
+	Unknown(String),
+
+	#[error("Parameter '{0}' expects {1} value(s)")]
+	Arity(String, usize),
+}
+
+macro_rules! params {
+	(
+		floats { $($float:ident),* $(,)? }
+		ints { $($int:ident),* $(,)? }
+		vectors { $($vector:ident),* $(,)? }
+	) => {
+		/// Every parameter name accepted by [get] and [set].
+		pub const NAMES: &[&str] = &[
+			$(stringify!($float),)*
+			$(stringify!($int),)*
+			$(stringify!($vector),)*
+		];
+
+		/// Gets a parameter by name, as a list of one value (or three for vectors).
+		pub fn get(params: &NvFlexParams, name: &str) -> Option<Vec<f32>> {
+			match name {
+				$(stringify!($float) => Some(vec![params.$float]),)*
+				$(stringify!($int) => Some(vec![params.$int as f32]),)*
+				$(stringify!($vector) => Some(params.$vector.to_vec()),)*
+				_ => None,
+			}
+		}
+
+		/// Sets a parameter by name, e.g. "viscosity" or "wind".
+		pub fn set(params: &mut NvFlexParams, name: &str, values: &[f32]) -> Result<(), ParamError> {
+			match name {
+				$(stringify!($float) => {
+					let [v] = expect::<1>(name, values)?;
+					params.$float = v;
+				})*
+				$(stringify!($int) => {
+					let [v] = expect::<1>(name, values)?;
+					params.$int = v as _;
+				})*
+				$(stringify!($vector) => {
+					params.$vector = expect::<3>(name, values)?;
+				})*
+				_ => return Err(ParamError::Unknown(name.to_owned())),
+			}
+
+			Ok(())
+		}
+
+		/// Writes every parameter into a snapshot, one field at a time so it's little endian
+		/// like the rest of the format, whatever the host is.
+		pub(crate) fn write(params: &NvFlexParams, w: &mut Writer) {
+			$(w.f32(params.$float);)*
+			$(w.i32(params.$int as i32);)*
+			$(params.$vector.into_iter().for_each(|v| w.f32(v));)*
+
+			w.i32(params.numPlanes);
+			params.planes.iter().flatten().for_each(|v| w.f32(*v));
+		}
+
+		/// Reads what [write] wrote.
+		pub(crate) fn read(r: &mut Reader) -> Result<NvFlexParams, SnapshotError> {
+			let mut params = crate::config::PARAMS;
+			$(params.$float = r.f32()?;)*
+			$(params.$int = r.i32()? as _;)*
+			$(params.$vector = [r.f32()?, r.f32()?, r.f32()?];)*
+
+			params.numPlanes = r.i32()?;
+			for v in params.planes.iter_mut().flatten() {
+				*v = r.f32()?;
+			}
+
+			if !(0 ..= params.planes.len() as i32).contains(&params.numPlanes) {
+				return Err(SnapshotError::Range("plane count"));
+			}
+
+			Ok(params)
+		}
+	};
+}
+
+// Every field of NvFlexParams but the planes is listed, as snapshots and replays store them through here
+params! {
+	floats {
+		radius, solidRestDistance, fluidRestDistance,
+		dynamicFriction, staticFriction, particleFriction, restitution, adhesion,
+		sleepThreshold, maxSpeed, maxAcceleration, shockPropagation, dissipation, damping,
+		drag, lift,
+		cohesion, surfaceTension, viscosity, vorticityConfinement,
+		anisotropyScale, anisotropyMin, anisotropyMax, smoothing,
+		solidPressure, freeSurfaceDrag, buoyancy,
+		diffuseThreshold, diffuseBuoyancy, diffuseDrag, diffuseLifetime,
+		collisionDistance, particleCollisionMargin, shapeCollisionMargin,
+		relaxationFactor,
+	}
+	ints {
+		numIterations, diffuseBallistic, relaxationMode,
+	}
+	vectors {
+		gravity, wind,
+	}
+}
+
+fn expect<const N: usize>(name: &str, values: &[f32]) -> Result<[f32; N], ParamError> {
+	values
+		.try_into()
+		.map_err(|_| ParamError::Arity(name.to_owned(), N))
+}
+
+/// Names of the built in presets accepted by [preset].
+pub const PRESETS: &[&str] = &["default", "water", "honey", "goo"];
+
+/// Params tuned for a kind of fluid, starting from the defaults in [crate::config::PARAMS].
+pub fn preset(name: &str) -> Option<NvFlexParams> {
+	let mut params = crate::config::PARAMS;
+
+	match name {
+		"default" => (),
+		"water" => {
+			params.viscosity = 0.01;
+			params.cohesion = 0.02;
+			params.surfaceTension = 0.0;
+			params.vorticityConfinement = 40.0;
+		}
+		"honey" => {
+			params.viscosity = 20.0;
+			params.cohesion = 0.05;
+			params.adhesion = 0.1;
+			params.vorticityConfinement = 0.0;
+			params.dynamicFriction = 0.2;
+		}
+		"goo" => {
+			params.viscosity = 5.0;
+			params.cohesion = 0.3;
+			params.surfaceTension = 1.0;
+			params.adhesion = 0.5;
+			params.vorticityConfinement = 0.0;
+		}
+		_ => return None,
+	}
+
+	Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn snapshot_round_trip() {
+		let mut params = preset("honey").unwrap();
+		params.wind = [1.0, -2.0, 3.0];
+		params.numPlanes = 2;
+		params.planes[1] = [0.0, 0.0, 1.0, -5.0];
+
+		let mut w = Writer::default();
+		write(&params, &mut w);
+
+		// Little endian whatever the host, starting with the first float
+		assert_eq!(w.bytes[.. 4], params.radius.to_le_bytes());
+
+		let mut r = Reader::new(&w.bytes);
+		let read = read(&mut r).unwrap();
+		assert!(matches!(r.u8(), Err(SnapshotError::Truncated)));
+
+		for name in NAMES {
+			assert_eq!(get(&read, name), get(&params, name), "{}", name);
+		}
+		assert_eq!(read.numPlanes, 2);
+		assert_eq!(read.planes, params.planes);
+	}
+
+	#[test]
+	fn snapshot_rejects_bad_plane_counts() {
+		let mut params = crate::config::PARAMS;
+		params.numPlanes = 9;
+
+		let mut w = Writer::default();
+		write(&params, &mut w);
+
+		assert!(matches!(read(&mut Reader::new(&w.bytes)), Err(SnapshotError::Range(_))));
+	}
+}
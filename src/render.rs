@@ -0,0 +1,99 @@
+// Client side helpers to prepare particles for the screen space fluid renderer.
+use crate::types::Vector3;
+
+/// A view cone to cull and sort particles against.
+#[derive(Clone, Copy, Debug)]
+pub struct Camera {
+	pub origin: Vector3,
+	/// Normalized view direction
+	pub forward: Vector3,
+	/// Half of the field of view, in radians
+	pub half_fov: f32,
+	pub near: f32,
+	pub far: f32,
+
+	right: Vector3,
+	up: Vector3,
+}
+
+/// A particle in view space: x to the right, y up and z forward along the view direction.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ViewParticle {
+	pub position: Vector3,
+	pub radius: f32,
+}
+
+impl Camera {
+	/// Creates a camera looking along `direction`, with a full field of view in degrees.
+	pub fn new(origin: Vector3, direction: Vector3, fov: f32, near: f32, far: f32) -> Self {
+		let length = direction.length();
+		let forward = if length > 0.0 {
+			direction * (1.0 / length)
+		} else {
+			Vector3(1.0, 0.0, 0.0)
+		};
+
+		// gmod's +z is the world up, unless looking straight up or down
+		let world_up = if forward.2.abs() > 0.999 {
+			Vector3(1.0, 0.0, 0.0)
+		} else {
+			Vector3(0.0, 0.0, 1.0)
+		};
+
+		let right = forward.cross(world_up);
+		let right = right * (1.0 / right.length());
+		let up = right.cross(forward);
+
+		Self {
+			origin,
+			forward,
+			half_fov: (fov.clamp(1.0, 179.0) * 0.5).to_radians(),
+			near,
+			far,
+
+			right,
+			up,
+		}
+	}
+
+	/// Transforms a world position into view space.
+	pub fn to_view(&self, point: Vector3) -> Vector3 {
+		let offset = point - self.origin;
+		Vector3(offset.dot(self.right), offset.dot(self.up), offset.dot(self.forward))
+	}
+
+	/// Whether a sphere in view space overlaps the view cone and depth range.
+	pub fn sees(&self, view: Vector3, radius: f32) -> bool {
+		if view.2 + radius < self.near || view.2 - radius > self.far {
+			return false;
+		}
+
+		let lateral = (view.0 * view.0 + view.1 * view.1).sqrt();
+		let (sin, cos) = self.half_fov.sin_cos();
+
+		// Distance from the cone's surface, positive outside of it
+		lateral * cos - view.2 * sin <= radius
+	}
+}
+
+/// Culls particles outside of the view and sorts the rest back to front.
+pub fn view_particles(camera: &Camera, positions: &[Vector3], radius: f32) -> Vec<ViewParticle> {
+	let mut visible: Vec<ViewParticle> = positions
+		.iter()
+		.map(|p| camera.to_view(*p))
+		.filter(|view| camera.sees(*view, radius))
+		.map(|position| ViewParticle { position, radius })
+		.collect();
+
+	visible.sort_by(|a, b| b.position.2.total_cmp(&a.position.2));
+	visible
+}
+
+/// Flattens view particles into x, y, z, radius quadruplets for uploading to a shader.
+pub fn pack(particles: &[ViewParticle]) -> Vec<f32> {
+	particles
+		.iter()
+		.flat_map(|p| [p.position.0, p.position.1, p.position.2, p.radius])
+		.collect()
+}
@@ -0,0 +1,539 @@
+// Deterministic recording and replay of every input to a FlexState.
+//
+// A recording starts with the solver capacity and a snapshot, followed by the inputs that changed
+// before each tick, whatever the tick itself changed and a check of the particle positions after
+// it. Replaying loads the snapshot into a fresh solver, feeds it the same inputs and reports where
+// its positions drifted from the recorded ones.
+use nvflex_sys::*;
+
+use crate::{
+	config::Capacity,
+	forces::Impulse,
+	params,
+	snapshot::{self, Reader, SnapshotError, Writer},
+	state::FlexState,
+	types::{Quat, Vector3, Vector4},
+};
+
+pub const MAGIC: &[u8; 4] = b"GFRP";
+pub const VERSION: u32 = 3;
+
+/// Full positions are stored every this many ticks by default, to measure drift precisely.
+pub const KEYFRAME_INTERVAL: usize = 60;
+
+/// What the simulation looked like after a tick.
+#[derive(Clone, Debug, Default)]
+pub struct FrameCheck {
+	/// CRC-32 of the raw position bytes, equal only if the replay is bit for bit identical.
+	pub checksum: u32,
+	/// Mean of the active particle positions, for a cheap drift estimate on every frame.
+	pub centroid: Vector3,
+	/// Every particle position, only stored on keyframes.
+	pub positions: Option<Vec<Vector4>>,
+}
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub enum Event {
+	Params(#[derivative(Debug = "ignore")] Box<NvFlexParams>),
+	Spawn {
+		positions: Vec<Vector4>,
+		velocities: Vec<Vector3>,
+		phases: Vec<i32>,
+	},
+	Active(Vec<i32>),
+	Compact,
+	Transform {
+		shape: usize,
+		position: Vector4,
+		rotation: Quat,
+	},
+	Impulse(Impulse),
+	/// The health monitor restored this snapshot during the last tick.
+	Rollback(Vec<u8>),
+	Tick(f32),
+	Check(FrameCheck),
+}
+
+const TAG_PARAMS: u8 = 0;
+const TAG_SPAWN: u8 = 1;
+const TAG_ACTIVE: u8 = 2;
+const TAG_COMPACT: u8 = 3;
+const TAG_TRANSFORM: u8 = 4;
+const TAG_IMPULSE: u8 = 5;
+const TAG_ROLLBACK: u8 = 6;
+const TAG_TICK: u8 = 7;
+const TAG_CHECK: u8 = 8;
+
+/// Records inputs by comparing the state against what it last saw before and after every tick.
+/// Fluid emitted and particles drained or removed by the health monitor are recorded as plain
+/// spawns and activity changes, so replays don't run emitters, kill volumes or health checks.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct Recorder {
+	capacity: Capacity,
+	start: Vec<u8>,
+	pub events: Vec<Event>,
+	pub keyframe_interval: usize,
+	frames: usize,
+
+	#[derivative(Debug = "ignore")]
+	params: NvFlexParams,
+	count: i32,
+	active: Vec<i32>,
+	transforms: Vec<(Vector4, Quat)>,
+}
+
+fn same_params(a: &NvFlexParams, b: &NvFlexParams) -> bool {
+	let bytes = |p: &NvFlexParams| {
+		let mut w = Writer::default();
+		params::write(p, &mut w);
+		w.bytes
+	};
+
+	bytes(a) == bytes(b)
+}
+
+fn same_transform((p, q): (Vector4, Quat), (p2, q2): (Vector4, Quat)) -> bool {
+	[p.0, p.1, p.2, p.3, q.0, q.1, q.2, q.3] == [p2.0, p2.1, p2.2, p2.3, q2.0, q2.1, q2.2, q2.3]
+}
+
+/// Checksum and centroid of the particles, plus every position on keyframes.
+pub fn check(positions: &[Vector4], active: &[i32], keyframe: bool) -> FrameCheck {
+	let mut w = Writer::default();
+	positions.iter().for_each(|p| w.vector4(*p));
+
+	let sum = active
+		.iter()
+		.filter_map(|&i| positions.get(i as usize))
+		.fold(Vector3::default(), |acc, p| acc + p.xyz());
+
+	FrameCheck {
+		checksum: snapshot::crc32(&w.bytes),
+		centroid: sum * (1.0 / active.len().max(1) as f32),
+		positions: keyframe.then(|| positions.to_vec()),
+	}
+}
+
+impl Recorder {
+	/// Starts recording from the current state, failing if it can't be snapshotted.
+	pub fn new(state: &FlexState, keyframe_interval: usize) -> Result<Self, SnapshotError> {
+		let mut recorder = Self {
+			capacity: state.capacity(),
+			start: state.save_snapshot()?,
+			events: vec![],
+			keyframe_interval: keyframe_interval.max(1),
+			frames: 0,
+
+			params: state.params,
+			count: 0,
+			active: vec![],
+			transforms: vec![],
+		};
+		recorder.reset(state);
+
+		Ok(recorder)
+	}
+
+	/// Takes the state as the baseline later changes are recorded against.
+	fn reset(&mut self, state: &FlexState) {
+		self.params = state.params;
+		self.count = state.particles.get_count();
+		self.active = state.particles.get_active().to_vec();
+		self.transforms = (0 .. state.geometry.get_count() as usize)
+			.filter_map(|i| state.geometry.get_info(i))
+			.map(|info| (info.position, info.rotation))
+			.collect();
+	}
+
+	/// Records a tick along with whatever changed since the last one, fluid just emitted included.
+	/// Call right before the solver updates.
+	pub fn before_step(&mut self, state: &FlexState, dt: f32) {
+		self.capture(state);
+		self.events.push(Event::Tick(dt));
+	}
+
+	/// Records whatever changed since the last capture.
+	/// Inputs recorded explicitly, like compaction, need a capture first to keep events in order.
+	pub fn capture(&mut self, state: &FlexState) {
+		if !same_params(&self.params, &state.params) {
+			self.params = state.params;
+			self.events.push(Event::Params(Box::new(state.params)));
+		}
+
+		let count = state.particles.get_count();
+		if count > self.count {
+			let (positions, velocities) = unsafe { state.particles.read(state.solver) };
+			let phases = unsafe { state.particles.read_phases(state.solver) };
+
+			let new = self.count as usize .. count as usize;
+			self.events.push(Event::Spawn {
+				positions: positions[new.clone()].to_vec(),
+				velocities: velocities[new.clone()].to_vec(),
+				phases: phases[new].to_vec(),
+			});
+		}
+		self.count = count;
+
+		let active = state.particles.get_active();
+		if active != self.active.as_slice() {
+			self.active = active.to_vec();
+			self.events.push(Event::Active(self.active.clone()));
+		}
+
+		for i in 0 .. state.geometry.get_count() as usize {
+			let info = match state.geometry.get_info(i) {
+				Some(info) => info,
+				None => continue,
+			};
+
+			let transform = (info.position, info.rotation);
+			let changed = self
+				.transforms
+				.get(i)
+				.map_or(true, |last| !same_transform(*last, transform));
+
+			if changed {
+				if i < self.transforms.len() {
+					self.transforms[i] = transform;
+				} else {
+					self.transforms.push(transform);
+				}
+
+				self.events.push(Event::Transform {
+					shape: i,
+					position: info.position,
+					rotation: info.rotation,
+				});
+			}
+		}
+	}
+
+	/// Records what the tick changed itself, like drained particles, followed by the check of
+	/// the tick recorded by [Self::before_step]. Call right after stepping.
+	pub fn after_step(&mut self, state: &FlexState) {
+		self.capture(state);
+
+		let (positions, _) = unsafe { state.particles.read(state.solver) };
+		let keyframe = self.frames % self.keyframe_interval == 0;
+		let frame = check(&positions, state.particles.get_active(), keyframe);
+		self.events.push(Event::Check(frame));
+
+		self.frames += 1;
+	}
+
+	/// Records a snapshot the state was just rolled back to, which becomes the new baseline.
+	pub fn rolled_back(&mut self, state: &FlexState, snapshot: Vec<u8>) {
+		self.events.push(Event::Rollback(snapshot));
+		self.reset(state);
+	}
+
+	pub fn compacted(&mut self, count: i32) {
+		self.events.push(Event::Compact);
+		self.count = count;
+		self.active = (0 .. count).collect();
+	}
+
+	pub fn impulse(&mut self, impulse: &Impulse) {
+		self.events.push(Event::Impulse(*impulse));
+	}
+
+	/// Serializes the recording into a replay file.
+	pub fn finish(&self) -> Vec<u8> {
+		let mut w = Writer::default();
+		let c = &self.capacity;
+		w.i32(c.particles);
+		w.i32(c.shapes);
+		w.i32(c.diffuse_particles);
+		w.i32(c.contacts_per_particle);
+		w.i32(c.neighbors_per_particle);
+
+		w.count(self.start.len());
+		w.bytes.extend_from_slice(&self.start);
+
+		w.count(self.events.len());
+		for event in &self.events {
+			write_event(&mut w, event);
+		}
+
+		snapshot::seal_with(MAGIC, VERSION, w.bytes)
+	}
+}
+
+fn write_event(w: &mut Writer, event: &Event) {
+	match event {
+		Event::Params(changed) => {
+			w.u8(TAG_PARAMS);
+			params::write(changed, w);
+		}
+		Event::Spawn {
+			positions,
+			velocities,
+			phases,
+		} => {
+			w.u8(TAG_SPAWN);
+			w.count(positions.len());
+			positions.iter().for_each(|p| w.vector4(*p));
+			velocities.iter().for_each(|v| w.vector3(*v));
+			phases.iter().for_each(|p| w.i32(*p));
+		}
+		Event::Active(active) => {
+			w.u8(TAG_ACTIVE);
+			w.count(active.len());
+			active.iter().for_each(|i| w.i32(*i));
+		}
+		Event::Compact => w.u8(TAG_COMPACT),
+		Event::Transform {
+			shape,
+			position,
+			rotation,
+		} => {
+			w.u8(TAG_TRANSFORM);
+			w.count(*shape);
+			w.vector4(*position);
+			w.quat(*rotation);
+		}
+		Event::Impulse(impulse) => {
+			w.u8(TAG_IMPULSE);
+			w.vector3(impulse.center);
+			w.f32(impulse.radius);
+			w.f32(impulse.strength);
+			w.f32(impulse.falloff);
+			w.f32(impulse.lift);
+		}
+		Event::Rollback(snapshot) => {
+			w.u8(TAG_ROLLBACK);
+			w.count(snapshot.len());
+			w.bytes.extend_from_slice(snapshot);
+		}
+		Event::Tick(dt) => {
+			w.u8(TAG_TICK);
+			w.f32(*dt);
+		}
+		Event::Check(check) => {
+			w.u8(TAG_CHECK);
+			w.u32(check.checksum);
+			w.vector3(check.centroid);
+
+			match &check.positions {
+				Some(positions) => {
+					w.u8(1);
+					w.count(positions.len());
+					positions.iter().for_each(|p| w.vector4(*p));
+				}
+				None => w.u8(0),
+			}
+		}
+	}
+}
+
+fn read_event(r: &mut Reader) -> Result<Event, SnapshotError> {
+	Ok(match r.u8()? {
+		TAG_PARAMS => Event::Params(Box::new(params::read(r)?)),
+		TAG_SPAWN => {
+			let n = r.count(32)?;
+			Event::Spawn {
+				positions: (0 .. n).map(|_| r.vector4()).collect::<Result<_, _>>()?,
+				velocities: (0 .. n).map(|_| r.vector3()).collect::<Result<_, _>>()?,
+				phases: (0 .. n).map(|_| r.i32()).collect::<Result<_, _>>()?,
+			}
+		}
+		TAG_ACTIVE => {
+			let n = r.count(4)?;
+			Event::Active((0 .. n).map(|_| r.i32()).collect::<Result<_, _>>()?)
+		}
+		TAG_COMPACT => Event::Compact,
+		TAG_TRANSFORM => Event::Transform {
+			shape: r.u32()? as usize,
+			position: r.vector4()?,
+			rotation: r.quat()?,
+		},
+		TAG_IMPULSE => Event::Impulse(Impulse {
+			center: r.vector3()?,
+			radius: r.f32()?,
+			strength: r.f32()?,
+			falloff: r.f32()?,
+			lift: r.f32()?,
+		}),
+		TAG_ROLLBACK => {
+			let len = r.count(1)?;
+			Event::Rollback(r.take(len)?.to_vec())
+		}
+		TAG_TICK => Event::Tick(r.f32()?),
+		TAG_CHECK => {
+			let (checksum, centroid) = (r.u32()?, r.vector3()?);
+			let positions = match r.u8()? {
+				0 => None,
+				_ => {
+					let n = r.count(16)?;
+					Some((0 .. n).map(|_| r.vector4()).collect::<Result<_, _>>()?)
+				}
+			};
+
+			Event::Check(FrameCheck {
+				checksum,
+				centroid,
+				positions,
+			})
+		}
+		tag => return Err(SnapshotError::Event(tag)),
+	})
+}
+
+/// How far a replay drifted from its recording.
+#[derive(Clone, Debug, Default)]
+pub struct DriftReport {
+	pub frames: usize,
+	/// Frames whose positions weren't bit for bit identical.
+	pub mismatches: usize,
+	pub first_mismatch: Option<usize>,
+	/// Largest distance between the recorded and replayed centroids.
+	pub max_centroid_drift: f32,
+	/// Largest distance of any particle from its recorded position, over every keyframe.
+	pub max_particle_drift: f32,
+	/// Distance under which drift is acceptable.
+	pub tolerance: f32,
+	/// Frames where either drift exceeded the tolerance.
+	pub over_tolerance: usize,
+	pub first_over_tolerance: Option<usize>,
+}
+
+/// A parsed replay file.
+#[derive(Debug)]
+pub struct Replay {
+	/// Capacity of the recorded solver, which the replaying one needs to load the snapshot.
+	pub capacity: Capacity,
+	start: Vec<u8>,
+	pub events: Vec<Event>,
+}
+
+impl Replay {
+	pub fn parse(bytes: &[u8]) -> Result<Self, SnapshotError> {
+		let mut r = Reader::new(snapshot::open_with(MAGIC, VERSION, bytes)?);
+
+		let capacity = Capacity {
+			particles: r.i32()?,
+			shapes: r.i32()?,
+			diffuse_particles: r.i32()?,
+			contacts_per_particle: r.i32()?,
+			neighbors_per_particle: r.i32()?,
+		};
+
+		let c = &capacity;
+		let counts = [c.diffuse_particles, c.contacts_per_particle, c.neighbors_per_particle];
+		if c.particles <= 0 || c.shapes <= 0 || counts.iter().any(|&n| n < 0) {
+			return Err(SnapshotError::Range("capacity"));
+		}
+
+		let len = r.count(1)?;
+		let start = r.take(len)?.to_vec();
+
+		let n = r.count(1)?;
+		let events = (0 .. n).map(|_| read_event(&mut r)).collect::<Result<_, _>>()?;
+
+		Ok(Self {
+			capacity,
+			start,
+			events,
+		})
+	}
+
+	/// Drives `state` through the recording, comparing every frame against it.
+	/// `state` should be freshly initialized with [Self::capacity], it's overwritten by the
+	/// starting snapshot.
+	pub fn run(
+		&self,
+		state: &mut FlexState,
+		tolerance: f32,
+	) -> Result<DriftReport, SnapshotError> {
+		state.load_snapshot(&self.start)?;
+		detach(state);
+
+		let mut report = DriftReport {
+			tolerance,
+			..Default::default()
+		};
+
+		for event in &self.events {
+			match event {
+				Event::Params(params) => {
+					state.params = **params;
+					state.params_changed = true;
+				}
+				Event::Spawn {
+					positions,
+					velocities,
+					phases,
+				} => {
+					state.generate(|factory| {
+						let particles = positions.iter().zip(velocities).zip(phases);
+						particles
+							.filter_map(|((p, v), phase)| factory.create(*p, *v, *phase, true))
+							.collect()
+					});
+				}
+				Event::Active(active) => state.particles.set_active(active.clone()),
+				Event::Compact => {
+					state.compact();
+				}
+				Event::Transform {
+					shape,
+					position,
+					rotation,
+				} => {
+					state.geometry.set_transform(*shape, *position, *rotation);
+				}
+				Event::Impulse(impulse) => {
+					state.apply_impulse(impulse);
+				}
+				Event::Rollback(snapshot) => {
+					state.load_snapshot(snapshot)?;
+					detach(state);
+				}
+				Event::Tick(dt) => state.step(*dt),
+				Event::Check(recorded) => {
+					let (positions, _) = unsafe { state.particles.read(state.solver) };
+					let keyframe = recorded.positions.is_some();
+					let replayed = check(&positions, state.particles.get_active(), keyframe);
+					compare(&mut report, recorded, &replayed);
+				}
+			}
+		}
+
+		Ok(report)
+	}
+}
+
+/// Stops whatever the recording already holds the effects of, as events, from happening twice.
+fn detach(state: &mut FlexState) {
+	state.emitters = Default::default();
+	state.kill_volumes.clear();
+	state.health.enabled = false;
+}
+
+fn compare(report: &mut DriftReport, recorded: &FrameCheck, replayed: &FrameCheck) {
+	let frame = report.frames;
+	report.frames += 1;
+
+	if recorded.checksum != replayed.checksum {
+		report.mismatches += 1;
+		report.first_mismatch.get_or_insert(frame);
+	}
+
+	let centroid = (recorded.centroid - replayed.centroid).length();
+	report.max_centroid_drift = report.max_centroid_drift.max(centroid);
+
+	let mut particle = 0.0f32;
+	if let (Some(a), Some(b)) = (&recorded.positions, &replayed.positions) {
+		for (a, b) in a.iter().zip(b) {
+			particle = particle.max((a.xyz() - b.xyz()).length());
+		}
+	}
+	report.max_particle_drift = report.max_particle_drift.max(particle);
+
+	// NaN drift counts as over tolerance too
+	if !(centroid <= report.tolerance && particle <= report.tolerance) {
+		report.over_tolerance += 1;
+		report.first_over_tolerance.get_or_insert(frame);
+	}
+}
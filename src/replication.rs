@@ -0,0 +1,601 @@
+// Server to client replication of particle positions.
+//
+// Positions are quantized to 16 bits per axis within a region, and each frame only carries the
+// particles that changed relative to the last frame the client acknowledged, as varint packed
+// deltas. Frames have a byte budget, spent first on the particles that drifted the most,
+// weighted towards the player.
+//
+// Layout:
+//   version u8 | seq varint | baseline seq varint, 0 for none | server time f32
+//   | region min, max 6 f32
+//   | removed count varint | id gaps varint...
+//   | updated count varint | (id gap, dx, dy, dz) varint...
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::{
+	interpolation::Interpolator,
+	types::{Vector3, Vector4},
+};
+
+pub const VERSION: u8 = 2;
+/// Default bytes per frame, well under what a net message can carry.
+pub const DEFAULT_BUDGET: usize = 4096;
+
+/// Frames kept around waiting for an ack, and decoded frames kept around as baselines.
+const HISTORY: usize = 64;
+/// Particles this far from the player have their priority halved.
+const NEAR_DISTANCE: f32 = 512.0;
+/// Bytes every frame needs regardless of its contents, so the budget can't starve it.
+const HEADER_SIZE: usize = 1 + 5 + 5 + 4 + 24 + 5 + 5;
+
+/// Quantized position of a particle.
+pub type Quantized = [i16; 3];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplicationError {
+	#[error("Unsupported replication version {0}")]
+	Version(u8),
+	#[error("Replication frame ended unexpectedly")]
+	Truncated,
+	#[error("Replication frame is relative to frame {0}, which was never received")]
+	Baseline(u32),
+}
+
+/// Box positions are quantized within, anything outside of it gets clamped to its faces.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+	pub min: Vector3,
+	pub max: Vector3,
+}
+
+impl Default for Region {
+	/// The largest map gmod supports, giving half unit precision.
+	fn default() -> Self {
+		Self {
+			min: Vector3(-16384.0, -16384.0, -16384.0),
+			max: Vector3(16384.0, 16384.0, 16384.0),
+		}
+	}
+}
+
+impl Region {
+	pub fn quantize(&self, p: Vector3) -> Quantized {
+		let axis = |v: f32, min: f32, max: f32| {
+			let t = ((v - min) / (max - min).max(f32::EPSILON)).clamp(0.0, 1.0);
+			((t * 65535.0).round() - 32768.0) as i16
+		};
+
+		[
+			axis(p.0, self.min.0, self.max.0),
+			axis(p.1, self.min.1, self.max.1),
+			axis(p.2, self.min.2, self.max.2),
+		]
+	}
+
+	pub fn dequantize(&self, q: Quantized) -> Vector3 {
+		let axis = |v: i16, min: f32, max: f32| min + (v as f32 + 32768.0) / 65535.0 * (max - min);
+
+		Vector3(
+			axis(q[0], self.min.0, self.max.0),
+			axis(q[1], self.min.1, self.max.1),
+			axis(q[2], self.min.2, self.max.2),
+		)
+	}
+}
+
+fn zigzag(v: i32) -> u32 {
+	((v << 1) ^ (v >> 31)) as u32
+}
+
+fn unzigzag(v: u32) -> i32 {
+	((v >> 1) as i32) ^ -((v & 1) as i32)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u32) {
+	while v >= 0x80 {
+		out.push(v as u8 | 0x80);
+		v >>= 7;
+	}
+	out.push(v as u8);
+}
+
+fn varint_size(v: u32) -> usize {
+	match v {
+		0 ..= 0x7f => 1,
+		0x80 ..= 0x3fff => 2,
+		0x4000 ..= 0x1f_ffff => 3,
+		0x20_0000 ..= 0xfff_ffff => 4,
+		_ => 5,
+	}
+}
+
+/// Reads the varints and floats of a frame.
+struct Cursor<'a> {
+	bytes: &'a [u8],
+}
+
+impl Cursor<'_> {
+	fn u8(&mut self) -> Result<u8, ReplicationError> {
+		let (&first, rest) = self.bytes.split_first().ok_or(ReplicationError::Truncated)?;
+		self.bytes = rest;
+		Ok(first)
+	}
+
+	fn varint(&mut self) -> Result<u32, ReplicationError> {
+		let mut v = 0u32;
+		for shift in (0 .. 35).step_by(7) {
+			let byte = self.u8()?;
+			v |= ((byte & 0x7f) as u32) << shift;
+			if byte & 0x80 == 0 {
+				return Ok(v);
+			}
+		}
+
+		Err(ReplicationError::Truncated)
+	}
+
+	fn f32(&mut self) -> Result<f32, ReplicationError> {
+		let mut bytes = [0; 4];
+		for b in &mut bytes {
+			*b = self.u8()?;
+		}
+		Ok(f32::from_le_bytes(bytes))
+	}
+
+	fn vector3(&mut self) -> Result<Vector3, ReplicationError> {
+		Ok(Vector3(self.f32()?, self.f32()?, self.f32()?))
+	}
+
+	/// Reads a count of elements taking at least `min_size` bytes each, so it can't be absurdly large.
+	fn count(&mut self, min_size: usize) -> Result<usize, ReplicationError> {
+		let len = self.varint()? as usize;
+		if len.saturating_mul(min_size) > self.bytes.len() {
+			return Err(ReplicationError::Truncated);
+		}
+		Ok(len)
+	}
+}
+
+/// Writes ascending ids as gaps from the previous one.
+fn write_ids(out: &mut Vec<u8>, ids: impl ExactSizeIterator<Item = i32>) {
+	write_varint(out, ids.len() as u32);
+
+	let mut next = 0;
+	for id in ids {
+		write_varint(out, (id - next) as u32);
+		next = id + 1;
+	}
+}
+
+/// Replication state of a single client, on the server.
+#[derive(Debug, Default)]
+pub struct Encoder {
+	seq: u32,
+	/// Last frame the client acknowledged and what it holds, which new frames are relative to.
+	baseline: Option<(u32, BTreeMap<i32, Quantized>)>,
+	/// What the client will hold once it receives each frame sent since.
+	pending: VecDeque<(u32, BTreeMap<i32, Quantized>)>,
+}
+
+impl Encoder {
+	/// Marks a frame as received, making it the baseline of every frame after it.
+	/// Returns false if the frame is unknown, like when it was too old or already acked.
+	pub fn ack(&mut self, seq: u32) -> bool {
+		while let Some((pending, _)) = self.pending.front() {
+			if *pending >= seq {
+				break;
+			}
+			self.pending.pop_front();
+		}
+
+		match self.pending.pop_front() {
+			Some((pending, frame)) if pending == seq => {
+				self.baseline = Some((pending, frame));
+				true
+			}
+			Some(other) => {
+				self.pending.push_front(other);
+				false
+			}
+			None => false,
+		}
+	}
+
+	/// Forgets the baseline, so the next frame is sent whole.
+	pub fn reset(&mut self) {
+		self.baseline = None;
+		self.pending.clear();
+	}
+
+	/// Encodes the next frame of `particles`, given as (id, position) pairs, for a player at `viewer`.
+	/// Particles are sent by how far they've drifted from what the client has, weighted towards the
+	/// viewer, until `budget` bytes are used. Particles left out keep their old position on the client.
+	/// `time` is the simulation time of the particles, which clients interpolate by.
+	pub fn encode(
+		&mut self,
+		time: f32,
+		region: &Region,
+		particles: impl IntoIterator<Item = (i32, Vector4)>,
+		viewer: Vector3,
+		budget: usize,
+	) -> Vec<u8> {
+		let empty = BTreeMap::new();
+		let (base_seq, base) = match &self.baseline {
+			Some((seq, frame)) => (*seq, frame),
+			None => (0, &empty),
+		};
+
+		let mut current = BTreeMap::new();
+		let mut changed = vec![];
+		for (id, p) in particles {
+			let q = region.quantize(p.xyz());
+			current.insert(id, q);
+
+			let old = base.get(&id).copied().unwrap_or_default();
+			let delta = [0, 1, 2].map(|k| q[k] as i32 - old[k] as i32);
+			if delta == [0; 3] && base.contains_key(&id) {
+				continue;
+			}
+
+			let error = delta.iter().map(|d| d.unsigned_abs() as f32).sum::<f32>();
+			let distance = (p.xyz() - viewer).length();
+			changed.push((error / (1.0 + distance / NEAR_DISTANCE), id, delta));
+		}
+
+		// Removals are cheap and always sent, so clients never keep ghosts around
+		let removed: Vec<i32> = base
+			.keys()
+			.copied()
+			.filter(|id| !current.contains_key(id))
+			.collect();
+
+		changed.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+		let mut used = HEADER_SIZE + removed.len() * 5;
+		let mut sent: Vec<(i32, [i32; 3])> = vec![];
+		for (_, id, delta) in changed {
+			let size = 5 + delta.iter().map(|d| varint_size(zigzag(*d))).sum::<usize>();
+			if used + size > budget && !sent.is_empty() {
+				continue;
+			}
+
+			used += size;
+			sent.push((id, delta));
+		}
+		sent.sort_by_key(|(id, _)| *id);
+
+		// What the client ends up with: the baseline, plus what was sent, minus what was removed
+		let mut frame = base.clone();
+		for id in &removed {
+			frame.remove(id);
+		}
+		for (id, _) in &sent {
+			frame.insert(*id, current[id]);
+		}
+
+		self.seq += 1;
+		let mut out = vec![VERSION];
+		write_varint(&mut out, self.seq);
+		write_varint(&mut out, base_seq);
+		out.extend_from_slice(&time.to_le_bytes());
+		for c in [region.min, region.max].iter().flat_map(|v| [v.0, v.1, v.2]) {
+			out.extend_from_slice(&c.to_le_bytes());
+		}
+
+		write_ids(&mut out, removed.into_iter());
+
+		write_varint(&mut out, sent.len() as u32);
+		let mut next = 0;
+		for (id, delta) in sent {
+			write_varint(&mut out, (id - next) as u32);
+			next = id + 1;
+			for d in delta {
+				write_varint(&mut out, zigzag(d));
+			}
+		}
+
+		self.pending.push_back((self.seq, frame));
+		if self.pending.len() > HISTORY {
+			self.pending.pop_front();
+		}
+
+		out
+	}
+}
+
+/// A decoded frame, with every particle the client knows of.
+#[derive(Clone, Debug, Default)]
+pub struct Frame {
+	pub seq: u32,
+	/// Server simulation time the frame was taken at
+	pub time: f32,
+	pub region: Region,
+	pub particles: BTreeMap<i32, Quantized>,
+}
+
+impl Frame {
+	/// (id, position) of every particle in the frame, in id order.
+	pub fn positions(&self) -> impl Iterator<Item = (i32, Vector3)> + '_ {
+		self.particles
+			.iter()
+			.map(|(id, q)| (*id, self.region.dequantize(*q)))
+	}
+}
+
+/// Reconstructs frames on the client, keeping recent ones as baselines for the next.
+#[derive(Debug, Default)]
+pub struct Decoder {
+	history: VecDeque<Frame>,
+}
+
+impl Decoder {
+	/// Decodes a frame, whose sequence number should be acked back to the server.
+	/// Frames arriving after a newer one are still decoded, but [Self::latest] won't change.
+	pub fn decode(&mut self, bytes: &[u8]) -> Result<Frame, ReplicationError> {
+		let mut r = Cursor { bytes };
+
+		let version = r.u8()?;
+		if version != VERSION {
+			return Err(ReplicationError::Version(version));
+		}
+
+		let seq = r.varint()?;
+		let base_seq = r.varint()?;
+		let time = r.f32()?;
+		let region = Region {
+			min: r.vector3()?,
+			max: r.vector3()?,
+		};
+
+		let mut particles = if base_seq == 0 {
+			// A whole frame older than what we have means the server started over
+			if self.latest().is_some_and(|latest| latest.seq >= seq) {
+				self.history.clear();
+			}
+
+			BTreeMap::new()
+		} else {
+			self.history
+				.iter()
+				.find(|frame| frame.seq == base_seq)
+				.ok_or(ReplicationError::Baseline(base_seq))?
+				.particles
+				.clone()
+		};
+
+		let mut next = 0i32;
+		for _ in 0 .. r.count(1)? {
+			let id = next.wrapping_add(r.varint()? as i32);
+			particles.remove(&id);
+			next = id.wrapping_add(1);
+		}
+
+		let mut next = 0i32;
+		for _ in 0 .. r.count(4)? {
+			let id = next.wrapping_add(r.varint()? as i32);
+			next = id.wrapping_add(1);
+
+			let old = particles.get(&id).copied().unwrap_or_default();
+			let mut q = [0; 3];
+			for (c, old) in q.iter_mut().zip(old) {
+				*c = (old as i32).wrapping_add(unzigzag(r.varint()?)) as i16;
+			}
+			particles.insert(id, q);
+		}
+
+		// Keep the history sorted so the newest frame is always last
+		let frame = Frame {
+			seq,
+			time,
+			region,
+			particles,
+		};
+		let at = self.history.partition_point(|f| f.seq < seq);
+		if self.history.get(at).map(|f| f.seq) == Some(seq) {
+			self.history[at] = frame.clone();
+		} else {
+			self.history.insert(at, frame.clone());
+		}
+		if self.history.len() > HISTORY {
+			self.history.pop_front();
+		}
+
+		Ok(frame)
+	}
+
+	/// The newest frame received.
+	pub fn latest(&self) -> Option<&Frame> {
+		self.history.back()
+	}
+
+	pub fn get(&self, seq: u32) -> Option<&Frame> {
+		self.history.iter().find(|frame| frame.seq == seq)
+	}
+
+	pub fn reset(&mut self) {
+		self.history.clear();
+	}
+}
+
+/// Every client's encoder on the server, and the decoder on clients.
+#[derive(Debug, Default)]
+pub struct Replication {
+	region: Region,
+	encoders: HashMap<i32, Encoder>,
+	pub decoder: Decoder,
+	pub interpolator: Interpolator,
+}
+
+impl Replication {
+	pub fn region(&self) -> Region {
+		self.region
+	}
+
+	/// Changes the quantization region, making every client start over from a whole frame.
+	pub fn set_region(&mut self, region: Region) {
+		self.region = region;
+		self.reset_encoders();
+	}
+
+	/// Makes every client start over from a whole frame, like when particle ids got reused.
+	pub fn reset_encoders(&mut self) {
+		self.encoders.values_mut().for_each(Encoder::reset);
+	}
+
+	/// The encoder of a client, created on first use.
+	pub fn encoder(&mut self, client: i32) -> &mut Encoder {
+		self.encoders.entry(client).or_default()
+	}
+
+	/// Decodes a frame on the client and buffers it for interpolation, returning the seq to ack.
+	pub fn receive(&mut self, bytes: &[u8]) -> Result<u32, ReplicationError> {
+		let frame = self.decoder.decode(bytes)?;
+		self.interpolator.push(frame.time, frame.positions());
+		Ok(frame.seq)
+	}
+
+	/// Forgets a client, like when they disconnect.
+	pub fn remove(&mut self, client: i32) {
+		self.encoders.remove(&client);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn particles(ids: &[i32], offset: f32) -> Vec<(i32, Vector4)> {
+		ids.iter()
+			.map(|&id| (id, Vector4(id as f32 * 10.0 + offset, -5.0, 100.0, 1.0)))
+			.collect()
+	}
+
+	fn encode(encoder: &mut Encoder, particles: Vec<(i32, Vector4)>, budget: usize) -> Vec<u8> {
+		encoder.encode(1.5, &Region::default(), particles, Vector3::default(), budget)
+	}
+
+	#[test]
+	fn varint_round_trip() {
+		for v in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX] {
+			let mut out = vec![];
+			write_varint(&mut out, v);
+			assert_eq!(out.len(), varint_size(v));
+			assert_eq!(Cursor { bytes: &out }.varint().unwrap(), v);
+		}
+
+		for v in [0, 1, -1, i32::MAX, i32::MIN] {
+			assert_eq!(unzigzag(zigzag(v)), v);
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		let (mut encoder, mut decoder) = (Encoder::default(), Decoder::default());
+		let sent = particles(&[0, 3, 4, 70], 0.0);
+
+		let frame = decoder.decode(&encode(&mut encoder, sent.clone(), DEFAULT_BUDGET)).unwrap();
+		assert_eq!(frame.seq, 1);
+		assert_eq!(frame.time, 1.5);
+
+		let received: Vec<_> = frame.positions().collect();
+		assert_eq!(received.len(), sent.len());
+		for ((id, p), (sent_id, sent_p)) in received.into_iter().zip(sent) {
+			assert_eq!(id, sent_id);
+			// Half unit precision in the default region
+			assert!((p - sent_p.xyz()).length() < 0.5);
+		}
+	}
+
+	#[test]
+	fn frames_are_relative_to_the_acked_baseline() {
+		let (mut encoder, mut decoder) = (Encoder::default(), Decoder::default());
+
+		let first = encode(&mut encoder, particles(&[0, 1, 2], 0.0), DEFAULT_BUDGET);
+		let seq = decoder.decode(&first).unwrap().seq;
+		assert!(encoder.ack(seq));
+		assert!(!encoder.ack(seq));
+
+		// Only particle 1 moved, so only it is sent
+		let mut moved = particles(&[0, 1, 2], 0.0);
+		moved[1].1 .0 += 50.0;
+		let second = encode(&mut encoder, moved.clone(), DEFAULT_BUDGET);
+		assert!(second.len() < first.len());
+
+		let frame = decoder.decode(&second).unwrap();
+		assert_eq!(frame.particles.len(), 3);
+		let region = Region::default();
+		for (id, p) in moved {
+			assert_eq!(frame.particles[&id], region.quantize(p.xyz()));
+		}
+
+		// A client that never got the baseline can't decode it
+		assert!(matches!(
+			Decoder::default().decode(&second),
+			Err(ReplicationError::Baseline(1))
+		));
+	}
+
+	#[test]
+	fn reset_sends_whole_frames() {
+		let (mut encoder, mut decoder) = (Encoder::default(), Decoder::default());
+
+		let first = encode(&mut encoder, particles(&[0, 1], 0.0), DEFAULT_BUDGET);
+		assert!(encoder.ack(decoder.decode(&first).unwrap().seq));
+		encoder.reset();
+
+		let frame = encode(&mut encoder, particles(&[5], 0.0), DEFAULT_BUDGET);
+		let frame = Decoder::default().decode(&frame).unwrap();
+		assert_eq!(frame.particles.keys().copied().collect::<Vec<_>>(), vec![5]);
+	}
+
+	#[test]
+	fn budget_limits_frame_size() {
+		let (mut encoder, mut decoder) = (Encoder::default(), Decoder::default());
+		let ids: Vec<i32> = (0 .. 1000).collect();
+		let budget = 512;
+
+		let bytes = encode(&mut encoder, particles(&ids, 0.0), budget);
+		assert!(bytes.len() <= budget);
+
+		let frame = decoder.decode(&bytes).unwrap();
+		assert!(!frame.particles.is_empty() && frame.particles.len() < ids.len());
+		assert!(encoder.ack(frame.seq));
+
+		// What was left out is sent in later frames
+		let bytes = encode(&mut encoder, particles(&ids, 0.0), budget);
+		let next = decoder.decode(&bytes).unwrap();
+		assert!(next.particles.len() > frame.particles.len());
+
+		// Even a tiny budget sends something, so the client can't stall
+		let bytes = encode(&mut Encoder::default(), particles(&ids, 0.0), 0);
+		assert_eq!(Decoder::default().decode(&bytes).unwrap().particles.len(), 1);
+	}
+
+	#[test]
+	fn removals_are_replicated() {
+		let (mut encoder, mut decoder) = (Encoder::default(), Decoder::default());
+
+		let first = encode(&mut encoder, particles(&[0, 1, 2, 3], 0.0), DEFAULT_BUDGET);
+		assert!(encoder.ack(decoder.decode(&first).unwrap().seq));
+
+		let second = encode(&mut encoder, particles(&[0, 2], 0.0), 0);
+		let frame = decoder.decode(&second).unwrap();
+		assert_eq!(frame.particles.keys().copied().collect::<Vec<_>>(), vec![0, 2]);
+	}
+
+	#[test]
+	fn decode_rejects_bad_frames() {
+		let bytes = encode(&mut Encoder::default(), particles(&[0, 1], 0.0), DEFAULT_BUDGET);
+
+		let mut other = bytes.clone();
+		other[0] = VERSION + 1;
+		assert!(matches!(
+			Decoder::default().decode(&other),
+			Err(ReplicationError::Version(_))
+		));
+
+		for len in 0 .. bytes.len() {
+			assert!(Decoder::default().decode(&bytes[.. len]).is_err());
+		}
+	}
+}
@@ -0,0 +1,1174 @@
+// Scene files describing a whole simulation setup in TOML, loaded by the command line runner
+// and from Lua, and written back out from a live state.
+//
+// preset = "water"
+// planes = [[0, 0, 1, 0]]
+//
+// [params]
+// gravity = [0, 0, -9.8]
+//
+// [capacity]
+// particles = 65536
+//
+// [[shapes]]
+// type = "mesh"
+// file = "bowl.obj"
+// position = [0, 0, 10]
+//
+// [[fills]]
+// type = "box"
+// center = [0, 0, 20]
+// half_extents = [2, 2, 2]
+//
+// [[emitters]]
+// origin = [0, 0, 50]
+// direction = [0, 0, -1]
+// radius = 2
+// speed = 10
+use nvflex_sys::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::{
+	config::Capacity,
+	emitter::{Emitter, KillVolume},
+	forces::{FieldKind, ForceField},
+	helper::*,
+	params::{self, ParamError},
+	state::{
+		phase::{self, PhaseGroup},
+		Backend, FlexState, InitError, Lattice,
+	},
+	types::{Quat, Vector3, Vector4},
+};
+
+/// Folder mesh files are relative to for scenes applied from Lua.
+pub const DATA_FOLDER: &str = "garrysmod/data/gfluid";
+
+/// Most collision planes FleX supports.
+pub const MAX_PLANES: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SceneError {
+	#[error("{0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid scene: {0}")]
+	Parse(#[from] toml::de::Error),
+	#[error("Couldn't write scene: {0}")]
+	Write(#[from] toml::ser::Error),
+	#[error("{0}")]
+	Param(#[from] ParamError),
+	#[error("Unknown preset '{0}'")]
+	Preset(String),
+	#[error("Unknown phase group '{0}'")]
+	Group(String),
+	#[error("Scene has more than 8 planes")]
+	Planes,
+	#[error("Scene has more shapes than the maximum")]
+	Shapes,
+	#[error("Invalid mesh file '{0}'")]
+	Mesh(String),
+	#[error("Mesh file '{0}' isn't inside the scene's folder")]
+	MeshPath(String),
+	#[error("{0}")]
+	Init(#[from] InitError),
+}
+
+fn is_default<T: Default + PartialEq>(v: &T) -> bool {
+	*v == T::default()
+}
+
+fn identity() -> [f32; 4] {
+	[0.0, 0.0, 0.0, 1.0]
+}
+
+fn is_identity(q: &[f32; 4]) -> bool {
+	*q == identity()
+}
+
+fn one() -> [f32; 3] {
+	[1.0; 3]
+}
+
+fn is_one(v: &[f32; 3]) -> bool {
+	*v == one()
+}
+
+fn vector(v: [f32; 3]) -> Vector3 {
+	Vector3(v[0], v[1], v[2])
+}
+
+fn array(v: Vector3) -> [f32; 3] {
+	[v.0, v.1, v.2]
+}
+
+/// Mesh files loaded by a scene, by file name.
+type Meshes<'a> = BTreeMap<&'a str, (Vec<Vector3>, Vec<u32>)>;
+
+/// A parameter given either as a single number or as a vector.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ParamValue {
+	Scalar(f32),
+	Vector(Vec<f32>),
+}
+
+impl ParamValue {
+	pub fn values(&self) -> Vec<f32> {
+		match self {
+			ParamValue::Scalar(v) => vec![*v],
+			ParamValue::Vector(v) => v.clone(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ShapeKind {
+	Box {
+		half_extents: [f32; 3],
+	},
+	Sphere {
+		radius: f32,
+	},
+	/// Along the x axis, like FleX's capsules
+	Capsule {
+		radius: f32,
+		half_height: f32,
+	},
+	/// Wavefront OBJ file, relative to the scene file
+	Mesh {
+		file: String,
+		#[serde(default = "one", skip_serializing_if = "is_one")]
+		scale: [f32; 3],
+	},
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Shape {
+	#[serde(flatten)]
+	pub kind: ShapeKind,
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub position: [f32; 3],
+	/// Quaternion as [x, y, z, w]
+	#[serde(default = "identity", skip_serializing_if = "is_identity")]
+	pub rotation: [f32; 4],
+	/// Shape channels colliding with it, from 0 to 7, every channel when empty
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub channels: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FillKind {
+	Box {
+		center: [f32; 3],
+		half_extents: [f32; 3],
+	},
+	Sphere {
+		center: [f32; 3],
+		radius: f32,
+	},
+	/// Along the z axis
+	Cylinder {
+		center: [f32; 3],
+		radius: f32,
+		half_height: f32,
+	},
+	/// Fills the inside of a closed Wavefront OBJ file, relative to the scene file
+	Mesh {
+		file: String,
+		#[serde(default)]
+		position: [f32; 3],
+	},
+}
+
+/// Particles initially filling a volume, on a lattice at the fluid rest distance.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Fill {
+	#[serde(flatten)]
+	pub kind: FillKind,
+	/// Named phase group, the default fluid when unset
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub group: Option<String>,
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub velocity: [f32; 3],
+	/// Random offset of each particle, as a fraction of the spacing
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub jitter: f32,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub spacing: Option<f32>,
+}
+
+/// A single particle, as written when dumping a live state.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParticleDesc {
+	/// Position and inverse mass
+	pub position: [f32; 4],
+	#[serde(default, skip_serializing_if = "is_default")]
+	pub velocity: [f32; 3],
+	/// Named phase group, the default fluid when unset
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub group: Option<String>,
+	/// eNvFlexPhase* flags replacing the group's, when the particle's differ from them
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub flags: Option<i32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupDesc {
+	pub name: String,
+	#[serde(default)]
+	pub fluid: bool,
+	#[serde(default)]
+	pub self_collide: bool,
+	/// Shape channels the group collides with, from 0 to 7, every channel when empty
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub channels: Vec<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmitterDesc {
+	pub origin: [f32; 3],
+	pub direction: [f32; 3],
+	pub radius: f32,
+	pub speed: f32,
+	/// Named phase group to emit into, the default fluid when unset
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub group: Option<String>,
+	#[serde(default = "enabled", skip_serializing_if = "is_true")]
+	pub enabled: bool,
+}
+
+fn enabled() -> bool {
+	true
+}
+
+fn is_true(v: &bool) -> bool {
+	*v
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FieldDesc {
+	Radial {
+		strength: f32,
+		radius: f32,
+		#[serde(default = "field_falloff")]
+		falloff: f32,
+	},
+	Wind {
+		acceleration: [f32; 3],
+		half_extents: [f32; 3],
+	},
+	Vortex {
+		axis: [f32; 3],
+		strength: f32,
+		radius: f32,
+	},
+	Noise {
+		strength: f32,
+		frequency: f32,
+		radius: f32,
+	},
+}
+
+fn field_falloff() -> f32 {
+	1.0
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ForceFieldDesc {
+	#[serde(flatten)]
+	pub kind: FieldDesc,
+	pub position: [f32; 3],
+	/// Phase groups affected, every group when empty
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub groups: Vec<i32>,
+	#[serde(default = "enabled", skip_serializing_if = "is_true")]
+	pub enabled: bool,
+}
+
+/// Axis aligned box, for kill volumes and the bounds particles shouldn't escape.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bounds {
+	pub min: [f32; 3],
+	pub max: [f32; 3],
+}
+
+impl Bounds {
+	pub fn contains(&self, p: Vector3) -> bool {
+		self.volume().contains(p)
+	}
+
+	fn volume(&self) -> KillVolume {
+		KillVolume {
+			min: vector(self.min),
+			max: vector(self.max),
+		}
+	}
+}
+
+fn default_duration() -> f32 {
+	10.0
+}
+
+fn default_dt() -> f32 {
+	1.0 / 60.0
+}
+
+/// Plain values have to come before tables and arrays of tables for TOML, so keep them first.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+	/// Params preset to start from, see [params::PRESETS]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub preset: Option<String>,
+	/// Seconds to simulate
+	#[serde(default = "default_duration")]
+	pub duration: f32,
+	/// Seconds per step
+	#[serde(default = "default_dt")]
+	pub dt: f32,
+	/// Collision planes as [nx, ny, nz, d], keeping particles where nx*x + ny*y + nz*z + d >= 0
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub planes: Vec<[f32; 4]>,
+
+	/// Solver parameters by their FleX names, overriding the preset, see [crate::params]
+	#[serde(default)]
+	pub params: BTreeMap<String, ParamValue>,
+	/// Only used when building a new state, like from the command line or flex.load_scene,
+	/// as buffers can't be resized
+	#[serde(default)]
+	pub capacity: Capacity,
+	/// World bounds, particles leaving them are caught by the health checks once enabled,
+	/// see [crate::health]
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub bounds: Option<Bounds>,
+
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub groups: Vec<GroupDesc>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub shapes: Vec<Shape>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub fills: Vec<Fill>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub particles: Vec<ParticleDesc>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub emitters: Vec<EmitterDesc>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub force_fields: Vec<ForceFieldDesc>,
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub kill_volumes: Vec<Bounds>,
+
+	/// Folder mesh files are relative to, the scene file's own
+	#[serde(skip)]
+	pub root: PathBuf,
+}
+
+/// Reads the vertices and zero based triangle indices of a Wavefront OBJ file.
+/// Polygons are triangulated as fans, and anything besides positions and faces is ignored.
+pub fn load_obj(path: &Path) -> Result<(Vec<Vector3>, Vec<u32>), SceneError> {
+	let invalid = || SceneError::Mesh(path.display().to_string());
+	let source = std::fs::read_to_string(path)?;
+
+	let mut vertices = vec![];
+	let mut indices = vec![];
+	for line in source.lines() {
+		let mut words = line.split_whitespace();
+		match words.next() {
+			Some("v") => {
+				let mut c = words.map(|w| w.parse::<f32>().map_err(|_| invalid()));
+				let (x, y, z) = match (c.next(), c.next(), c.next()) {
+					(Some(x), Some(y), Some(z)) => (x?, y?, z?),
+					_ => return Err(invalid()),
+				};
+				vertices.push(Vector3(x, y, z));
+			}
+			Some("f") => {
+				// Faces are "v", "v/vt" or "v/vt/vn", one based or negative from the end
+				let face = words
+					.map(|w| {
+						let index: i64 = w.split('/').next()?.parse().ok()?;
+						let index = if index < 0 {
+							vertices.len() as i64 + index
+						} else {
+							index - 1
+						};
+						(0 .. vertices.len() as i64).contains(&index).then(|| index as u32)
+					})
+					.collect::<Option<Vec<u32>>>()
+					.ok_or_else(invalid)?;
+
+				for i in 1 .. face.len().saturating_sub(1) {
+					indices.extend_from_slice(&[face[0], face[i], face[i + 1]]);
+				}
+			}
+			_ => (),
+		}
+	}
+
+	if indices.is_empty() {
+		return Err(invalid());
+	}
+
+	Ok((vertices, indices))
+}
+
+impl Scene {
+	pub fn parse(source: &str) -> Result<Self, SceneError> {
+		Ok(toml::from_str(source)?)
+	}
+
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, SceneError> {
+		let path = path.as_ref();
+		let mut scene = Self::parse(&std::fs::read_to_string(path)?)?;
+		scene.root = path.parent().map(Path::to_path_buf).unwrap_or_default();
+		Ok(scene)
+	}
+
+	pub fn to_toml(&self) -> Result<String, SceneError> {
+		Ok(toml::to_string(self)?)
+	}
+
+	/// Steps needed to simulate the whole duration.
+	pub fn steps(&self) -> usize {
+		(self.duration / self.dt.max(f32::EPSILON)).ceil() as usize
+	}
+
+	/// Creates a state with the scene's capacity, then sets it up like [Self::apply].
+	pub fn build(&self, backend: Backend, device: i32) -> Result<FlexState, SceneError> {
+		let mut state = FlexState::new();
+		state.init_with(backend, device, self.capacity)?;
+		self.apply(&mut state)?;
+		Ok(state)
+	}
+
+	fn phase(&self, state: &FlexState, group: &Option<String>) -> Result<i32, SceneError> {
+		match group {
+			Some(name) => match state.phases.get(name) {
+				Some(group) => Ok(group.phase()),
+				None => Err(SceneError::Group(name.clone())),
+			},
+			None => Ok(state.fluid_phase()),
+		}
+	}
+
+	/// Path of a mesh file, which has to be relative and can't leave the scene's folder.
+	fn mesh_path(&self, file: &str) -> Result<PathBuf, SceneError> {
+		let inside = Path::new(file)
+			.components()
+			.all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+
+		if !inside {
+			return Err(SceneError::MeshPath(file.to_owned()));
+		}
+
+		Ok(self.root.join(file))
+	}
+
+	/// Loads every mesh file used by the scene's shapes and fills.
+	fn load_meshes(&self) -> Result<Meshes<'_>, SceneError> {
+		let shapes = self.shapes.iter().filter_map(|shape| match &shape.kind {
+			ShapeKind::Mesh { file, .. } => Some(file),
+			_ => None,
+		});
+		let fills = self.fills.iter().filter_map(|fill| match &fill.kind {
+			FillKind::Mesh { file, .. } => Some(file),
+			_ => None,
+		});
+
+		let mut meshes = Meshes::new();
+		for file in shapes.chain(fills) {
+			if !meshes.contains_key(file.as_str()) {
+				meshes.insert(file.as_str(), load_obj(&self.mesh_path(file)?)?);
+			}
+		}
+
+		Ok(meshes)
+	}
+
+	/// Checks every group used is either defined by the scene or already in the state.
+	fn check_groups(&self, state: &FlexState) -> Result<(), SceneError> {
+		let fills = self.fills.iter().map(|fill| &fill.group);
+		let particles = self.particles.iter().map(|p| &p.group);
+		let emitters = self.emitters.iter().map(|desc| &desc.group);
+
+		for name in fills.chain(particles).chain(emitters).flatten() {
+			let defined = self.groups.iter().any(|group| group.name == *name);
+			if !defined && state.phases.get(name).is_none() {
+				return Err(SceneError::Group(name.clone()));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Adds everything in the scene to an initialized state, replacing its params and planes.
+	/// The scene is checked and its meshes loaded first, so errors leave the state as it was.
+	pub fn apply(&self, state: &mut FlexState) -> Result<(), SceneError> {
+		let mut params = state.params;
+		if let Some(preset) = &self.preset {
+			params = params::preset(preset).ok_or_else(|| SceneError::Preset(preset.clone()))?;
+		}
+
+		for (name, value) in &self.params {
+			params::set(&mut params, name, &value.values())?;
+		}
+
+		if self.planes.len() > MAX_PLANES {
+			return Err(SceneError::Planes);
+		}
+		if !self.planes.is_empty() {
+			params.planes[.. self.planes.len()].copy_from_slice(&self.planes);
+			params.numPlanes = self.planes.len() as i32;
+		}
+
+		let shapes = state.geometry.get_count().max(0) as usize + self.shapes.len();
+		if shapes > state.geometry.capacity().max(0) as usize {
+			return Err(SceneError::Shapes);
+		}
+
+		self.check_groups(state)?;
+		let meshes = self.load_meshes()?;
+
+		state.params = params;
+		state.params_changed = true;
+
+		for group in &self.groups {
+			let mut flags = 0;
+			if group.fluid {
+				flags |= eNvFlexPhaseFluid;
+			}
+			if group.self_collide {
+				flags |= eNvFlexPhaseSelfCollide;
+			}
+			state.define_phase_group(&group.name, flags, phase::channel_mask(&group.channels));
+		}
+
+		for shape in &self.shapes {
+			self.add_shape(state, shape, &meshes)?;
+		}
+
+		for fill in &self.fills {
+			self.add_fill(state, fill, &meshes)?;
+		}
+
+		if !self.particles.is_empty() {
+			let phases = self
+				.particles
+				.iter()
+				.map(|p| {
+					let phase = self.phase(state, &p.group)?;
+					Ok(match p.flags {
+						Some(flags) => {
+							(phase & !eNvFlexPhaseFlagsMask) | (flags & eNvFlexPhaseFlagsMask)
+						}
+						None => phase,
+					})
+				})
+				.collect::<Result<Vec<i32>, SceneError>>()?;
+
+			state.generate(|factory| {
+				self.particles
+					.iter()
+					.zip(phases)
+					.filter_map(|(p, phase)| {
+						let [x, y, z, w] = p.position;
+						let v = vector(p.velocity);
+						factory.create(Vector4(x, y, z, w), v, phase, true)
+					})
+					.collect()
+			});
+		}
+
+		for desc in &self.emitters {
+			let phase = self.phase(state, &desc.group)?;
+			let (origin, direction) = (vector(desc.origin), vector(desc.direction));
+			let mut emitter = Emitter::new(origin, direction, desc.radius, desc.speed, phase);
+			emitter.enabled = desc.enabled;
+			state.emitters.add(emitter);
+		}
+
+		for desc in &self.force_fields {
+			let kind = match desc.kind {
+				FieldDesc::Radial {
+					strength,
+					radius,
+					falloff,
+				} => FieldKind::Radial {
+					strength,
+					radius,
+					falloff,
+				},
+				FieldDesc::Wind {
+					acceleration,
+					half_extents,
+				} => FieldKind::Directional {
+					acceleration: vector(acceleration),
+					half_extents: vector(half_extents),
+				},
+				FieldDesc::Vortex {
+					axis,
+					strength,
+					radius,
+				} => {
+					let axis = vector(axis);
+					FieldKind::Vortex {
+						axis: axis * (1.0 / axis.length().max(f32::EPSILON)),
+						strength,
+						radius,
+					}
+				}
+				FieldDesc::Noise {
+					strength,
+					frequency,
+					radius,
+				} => FieldKind::Noise {
+					strength,
+					frequency,
+					radius,
+				},
+			};
+
+			let mut field = ForceField::new(kind, vector(desc.position));
+			field.groups = desc.groups.clone();
+			field.enabled = desc.enabled;
+			state.forces.add(field);
+		}
+
+		state
+			.kill_volumes
+			.extend(self.kill_volumes.iter().map(Bounds::volume));
+
+		if let Some(bounds) = self.bounds {
+			state.health.bounds = Some((vector(bounds.min), vector(bounds.max)));
+		}
+
+		Ok(())
+	}
+
+	fn add_shape(
+		&self,
+		state: &mut FlexState,
+		shape: &Shape,
+		meshes: &Meshes,
+	) -> Result<(), SceneError> {
+		let [x, y, z] = shape.position;
+		let [qx, qy, qz, qw] = shape.rotation;
+		let (position, rotation) = (Vector4(x, y, z, 0.0), Quat(qx, qy, qz, qw));
+
+		let (geometry, ty) = match &shape.kind {
+			ShapeKind::Box { half_extents } => (
+				NvFlexCollisionGeometry {
+					box_: NvFlexBoxGeometry {
+						halfExtents: *half_extents,
+					},
+				},
+				eNvFlexShapeBox,
+			),
+			ShapeKind::Sphere { radius } => (
+				NvFlexCollisionGeometry {
+					sphere: NvFlexSphereGeometry { radius: *radius },
+				},
+				eNvFlexShapeSphere,
+			),
+			ShapeKind::Capsule {
+				radius,
+				half_height,
+			} => (
+				NvFlexCollisionGeometry {
+					capsule: NvFlexCapsuleGeometry {
+						radius: *radius,
+						halfHeight: *half_height,
+					},
+				},
+				eNvFlexShapeCapsule,
+			),
+			ShapeKind::Mesh { file, scale } => {
+				let (vertices, indices) = &meshes[file.as_str()];
+				let transform = (position, rotation, vector(*scale));
+				let index = state
+					.add_mesh_shape(vertices, indices, transform, Some(file.clone()))
+					.ok_or(SceneError::Shapes)?;
+
+				if !shape.channels.is_empty() {
+					state.geometry.set_channels(index, phase::channel_mask(&shape.channels));
+				}
+				return Ok(());
+			}
+		};
+
+		let channels = phase::channel_mask(&shape.channels);
+		let flags = NvFlexMakeShapeFlagsWithChannels(ty, false, channels);
+		state
+			.geometry
+			.add_shape(geometry, position, rotation, flags)
+			.ok_or(SceneError::Shapes)?;
+
+		Ok(())
+	}
+
+	fn add_fill(
+		&self,
+		state: &mut FlexState,
+		fill: &Fill,
+		meshes: &Meshes,
+	) -> Result<(), SceneError> {
+		let base = state.lattice(self.phase(state, &fill.group)?);
+		let lattice = Lattice {
+			spacing: fill.spacing.unwrap_or(base.spacing),
+			velocity: vector(fill.velocity),
+			jitter: fill.jitter,
+			..base
+		};
+
+		match &fill.kind {
+			FillKind::Box {
+				center,
+				half_extents,
+			} => {
+				let (center, half) = (vector(*center), vector(*half_extents));
+				state.generate(|factory| factory.fill_box(center, half, &lattice));
+			}
+			FillKind::Sphere { center, radius } => {
+				let center = vector(*center);
+				state.generate(|factory| factory.fill_sphere(center, *radius, &lattice));
+			}
+			FillKind::Cylinder {
+				center,
+				radius,
+				half_height,
+			} => {
+				let center = vector(*center);
+				state.generate(|factory| {
+					factory.fill_cylinder(center, *radius, *half_height, &lattice)
+				});
+			}
+			FillKind::Mesh { file, position } => {
+				let (vertices, indices) = &meshes[file.as_str()];
+				let offset = vector(*position);
+				let vertices: Vec<Vector3> = vertices.iter().map(|v| *v + offset).collect();
+				state.generate(|factory| factory.fill_mesh(&vertices, indices, &lattice));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Describes a live state, with its particles written one by one instead of as fills.
+	/// Rigid bodies, soft bodies, cloth and mesh shapes not loaded from a file are left out.
+	/// Particles in groups without a name are written into the default fluid's group.
+	pub fn capture(state: &FlexState) -> Self {
+		let p = &state.params;
+
+		let params = params::NAMES
+			.iter()
+			.filter_map(|name| {
+				let values = params::get(p, name)?;
+				let value = match values.as_slice() {
+					[v] => ParamValue::Scalar(*v),
+					_ => ParamValue::Vector(values),
+				};
+				Some((name.to_string(), value))
+			})
+			.collect();
+
+		let planes = p.planes[.. (p.numPlanes.max(0) as usize).min(MAX_PLANES)].to_vec();
+
+		// Phase groups by number, to name the group of emitters and particles
+		let names: BTreeMap<i32, (&String, &PhaseGroup)> = state
+			.phases
+			.named()
+			.map(|(name, group)| (group.group, (name, group)))
+			.collect();
+		let group_name = |phase: i32| {
+			let group = phase & eNvFlexPhaseGroupMask;
+			names
+				.get(&group)
+				.filter(|(name, _)| name.as_str() != phase::FLUID)
+				.map(|(name, _)| name.to_string())
+		};
+		// Flags of the group a particle gets back when applied, the default fluid's if unnamed
+		let group_flags = |phase: i32| {
+			let group = names.get(&(phase & eNvFlexPhaseGroupMask));
+			match group.filter(|(name, _)| name.as_str() != phase::FLUID) {
+				Some((_, group)) => group.flags,
+				None => state.fluid_phase() & eNvFlexPhaseFlagsMask,
+			}
+		};
+
+		let mut groups: Vec<GroupDesc> = state
+			.phases
+			.named()
+			.filter(|(name, _)| name.as_str() != phase::FLUID)
+			.map(|(name, group)| GroupDesc {
+				name: name.clone(),
+				fluid: group.flags & eNvFlexPhaseFluid != 0,
+				self_collide: group.flags & eNvFlexPhaseSelfCollide != 0,
+				channels: channel_list(group.channels),
+			})
+			.collect();
+		groups.sort_by(|a, b| a.name.cmp(&b.name));
+
+		let shapes = (0 .. state.geometry.get_count() as usize)
+			.filter_map(|i| {
+				let geometry = state.geometry.get_geometry(i)?;
+				let info = state.geometry.get_info(i)?;
+				if info.entity.is_some() {
+					return None;
+				}
+
+				let kind = unsafe {
+					match info.shape_type() {
+						x if x == eNvFlexShapeBox => ShapeKind::Box {
+							half_extents: geometry.box_.halfExtents,
+						},
+						x if x == eNvFlexShapeSphere => ShapeKind::Sphere {
+							radius: geometry.sphere.radius,
+						},
+						x if x == eNvFlexShapeCapsule => ShapeKind::Capsule {
+							radius: geometry.capsule.radius,
+							half_height: geometry.capsule.halfHeight,
+						},
+						x if x == eNvFlexShapeTriangleMesh => {
+							let mesh = geometry.triMesh;
+							ShapeKind::Mesh {
+								file: state.meshes.get(mesh.mesh)?.file.clone()?,
+								scale: mesh.scale,
+							}
+						}
+						_ => return None,
+					}
+				};
+
+				let Vector4(x, y, z, _) = info.position;
+				let Quat(qx, qy, qz, qw) = info.rotation;
+				Some(Shape {
+					kind,
+					position: [x, y, z],
+					rotation: [qx, qy, qz, qw],
+					channels: channel_list(info.flags & eNvFlexPhaseShapeChannelMask),
+				})
+			})
+			.collect();
+
+		let (positions, velocities) = unsafe { state.particles.read(state.solver) };
+		let phases = unsafe { state.particles.read_phases(state.solver) };
+		let particles = state
+			.particles
+			.get_active()
+			.iter()
+			.map(|&i| i as usize)
+			.filter(|&i| !state.rigids.contains(i as i32) && !state.cloth.contains(i as i32))
+			.map(|i| {
+				let Vector4(x, y, z, w) = positions[i];
+				let flags = phases[i] & eNvFlexPhaseFlagsMask;
+				ParticleDesc {
+					position: [x, y, z, w],
+					velocity: array(velocities[i]),
+					group: group_name(phases[i]),
+					flags: (flags != group_flags(phases[i])).then_some(flags),
+				}
+			})
+			.collect();
+
+		let emitters = state
+			.emitters
+			.iter()
+			.map(|e| EmitterDesc {
+				origin: array(e.origin),
+				direction: array(e.direction),
+				radius: e.radius,
+				speed: e.speed,
+				group: group_name(e.phase),
+				enabled: e.enabled,
+			})
+			.collect();
+
+		let force_fields = state
+			.forces
+			.iter()
+			.map(|field| {
+				let kind = match field.kind {
+					FieldKind::Radial {
+						strength,
+						radius,
+						falloff,
+					} => FieldDesc::Radial {
+						strength,
+						radius,
+						falloff,
+					},
+					FieldKind::Directional {
+						acceleration,
+						half_extents,
+					} => FieldDesc::Wind {
+						acceleration: array(acceleration),
+						half_extents: array(half_extents),
+					},
+					FieldKind::Vortex {
+						axis,
+						strength,
+						radius,
+					} => FieldDesc::Vortex {
+						axis: array(axis),
+						strength,
+						radius,
+					},
+					FieldKind::Noise {
+						strength,
+						frequency,
+						radius,
+					} => FieldDesc::Noise {
+						strength,
+						frequency,
+						radius,
+					},
+				};
+
+				ForceFieldDesc {
+					kind,
+					position: array(field.position),
+					groups: field.groups.clone(),
+					enabled: field.enabled,
+				}
+			})
+			.collect();
+
+		let kill_volumes = state
+			.kill_volumes
+			.iter()
+			.map(|v| Bounds {
+				min: array(v.min),
+				max: array(v.max),
+			})
+			.collect();
+
+		Self {
+			preset: None,
+			duration: default_duration(),
+			dt: default_dt(),
+			planes,
+			params,
+			capacity: state.capacity(),
+			bounds: state.health.bounds.map(|(min, max)| Bounds {
+				min: array(min),
+				max: array(max),
+			}),
+			groups,
+			shapes,
+			fills: vec![],
+			particles,
+			emitters,
+			force_fields,
+			kill_volumes,
+			root: PathBuf::new(),
+		}
+	}
+}
+
+/// Turns a channel mask back into channel numbers, empty when it has every channel.
+fn channel_list(mask: i32) -> Vec<u32> {
+	if mask & eNvFlexPhaseShapeChannelMask == eNvFlexPhaseShapeChannelMask {
+		return vec![];
+	}
+
+	(0 .. phase::CHANNELS)
+		.filter(|c| mask & (eNvFlexPhaseShapeChannel0 << c) != 0)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SCENE: &str = r#"
+preset = "water"
+planes = [[0.0, 0.0, 1.0, 0.0]]
+
+[params]
+radius = 0.2
+gravity = [0.0, 0.0, -5.0]
+
+[capacity]
+particles = 1024
+
+[[groups]]
+name = "oil"
+fluid = true
+channels = [1]
+
+[[shapes]]
+type = "capsule"
+radius = 1.0
+half_height = 2.0
+position = [0.0, 0.0, 3.0]
+
+[[fills]]
+type = "sphere"
+center = [0.0, 0.0, 10.0]
+radius = 2.0
+group = "oil"
+
+[[particles]]
+position = [1.0, 2.0, 3.0, 1.0]
+group = "oil"
+flags = 0
+
+[[kill_volumes]]
+min = [-1.0, -1.0, -1.0]
+max = [1.0, 1.0, 1.0]
+"#;
+
+	/// Writes an OBJ file to a temporary path unique to the test.
+	fn obj(name: &str, source: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("gfluid_{}_{}.obj", name, std::process::id()));
+		std::fs::write(&path, source).unwrap();
+		path
+	}
+
+	#[test]
+	fn parse() {
+		let scene = Scene::parse(SCENE).unwrap();
+
+		assert_eq!(scene.preset.as_deref(), Some("water"));
+		assert_eq!(scene.planes, vec![[0.0, 0.0, 1.0, 0.0]]);
+		assert_eq!(scene.params["radius"].values(), vec![0.2]);
+		assert_eq!(scene.params["gravity"].values(), vec![0.0, 0.0, -5.0]);
+
+		// Unset capacities keep their defaults
+		assert_eq!(scene.capacity.particles, 1024);
+		assert_eq!(scene.capacity.shapes, Capacity::default().shapes);
+
+		assert!(matches!(
+			scene.shapes[0].kind,
+			ShapeKind::Capsule { radius, half_height } if radius == 1.0 && half_height == 2.0
+		));
+		assert_eq!(scene.shapes[0].rotation, identity());
+		assert_eq!(scene.fills[0].group.as_deref(), Some("oil"));
+		assert_eq!(scene.particles[0].flags, Some(0));
+		assert_eq!(scene.groups[0].channels, vec![1]);
+		assert_eq!(scene.kill_volumes.len(), 1);
+		assert_eq!(scene.duration, default_duration());
+	}
+
+	#[test]
+	fn parse_rejects_unknown_fields() {
+		assert!(Scene::parse("unknown = 1").is_err());
+		assert!(Scene::parse("[capacity]\nparticle = 1").is_err());
+	}
+
+	#[test]
+	fn to_toml_round_trip() {
+		let scene = Scene::parse(SCENE).unwrap();
+		let again = Scene::parse(&scene.to_toml().unwrap()).unwrap();
+
+		assert_eq!(again.preset, scene.preset);
+		assert_eq!(again.planes, scene.planes);
+		assert_eq!(again.params.len(), scene.params.len());
+		assert_eq!(again.capacity, scene.capacity);
+		assert_eq!(again.groups.len(), 1);
+		assert_eq!(again.shapes[0].position, scene.shapes[0].position);
+		assert_eq!(again.fills.len(), 1);
+		assert_eq!(again.particles[0].position, scene.particles[0].position);
+		assert_eq!(again.particles[0].group, scene.particles[0].group);
+		assert_eq!(again.kill_volumes[0].max, scene.kill_volumes[0].max);
+	}
+
+	#[test]
+	fn load_obj_triangulates_fans() {
+		let path = obj(
+			"fan",
+			"# a quad and a triangle\nv 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\n\
+			 f 1/1/1 2/2/1 3/3/1 4/4/1\nf 1 2 3\n",
+		);
+		let (vertices, indices) = load_obj(&path).unwrap();
+		std::fs::remove_file(path).unwrap();
+
+		assert_eq!(vertices.len(), 4);
+		assert_eq!(indices, vec![0, 1, 2, 0, 2, 3, 0, 1, 2]);
+	}
+
+	#[test]
+	fn load_obj_negative_indices() {
+		let path = obj("negative", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\n");
+		let (_, indices) = load_obj(&path).unwrap();
+		std::fs::remove_file(path).unwrap();
+
+		assert_eq!(indices, vec![0, 1, 2]);
+	}
+
+	#[test]
+	fn load_obj_rejects_out_of_range_indices() {
+		for face in ["f 1 2 4", "f 0 1 2", "f -4 1 2"] {
+			let path = obj("range", &format!("v 0 0 0\nv 1 0 0\nv 0 1 0\n{}\n", face));
+			let result = load_obj(&path);
+			std::fs::remove_file(path).unwrap();
+
+			assert!(matches!(result, Err(SceneError::Mesh(_))), "{}", face);
+		}
+	}
+
+	#[test]
+	fn mesh_paths_stay_inside_the_root() {
+		let scene = Scene {
+			root: PathBuf::from("scenes"),
+			..Scene::parse("").unwrap()
+		};
+
+		for file in ["bowl.obj", "meshes/bowl.obj", "./meshes/bowl.obj"] {
+			assert!(scene.mesh_path(file).unwrap().starts_with("scenes"), "{}", file);
+		}
+		for file in ["../bowl.obj", "meshes/../../bowl.obj", "/etc/bowl.obj"] {
+			let result = scene.mesh_path(file);
+			assert!(matches!(result, Err(SceneError::MeshPath(_))), "{}", file);
+		}
+	}
+
+	#[test]
+	fn load_meshes_once_per_file() {
+		let path = obj("shared", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n");
+		let file = path.file_name().unwrap().to_str().unwrap();
+		let source = format!(
+			"[[shapes]]\ntype = \"mesh\"\nfile = \"{0}\"\n\n\
+			 [[fills]]\ntype = \"mesh\"\nfile = \"{0}\"\n",
+			file
+		);
+		let scene = Scene {
+			root: std::env::temp_dir(),
+			..Scene::parse(&source).unwrap()
+		};
+
+		// Nothing outside the folder is read, even when it exists
+		let escape = Scene {
+			root: std::env::temp_dir().join("scenes"),
+			..Scene::parse(&source.replace(file, &format!("../{}", file))).unwrap()
+		};
+
+		let (meshes, escaped) = (scene.load_meshes(), escape.load_meshes());
+		std::fs::remove_file(&path).unwrap();
+
+		let meshes = meshes.unwrap();
+		assert_eq!(meshes.len(), 1);
+		assert_eq!(meshes[file].1, vec![0, 1, 2]);
+		assert!(matches!(escaped, Err(SceneError::MeshPath(_))));
+	}
+
+	#[test]
+	fn builtin_scenes_parse() {
+		let default = Scene::parse(include_str!("../scenes/default.toml")).unwrap();
+		assert_eq!(default.capacity.particles, 65536);
+		assert_eq!(default.shapes.len(), 3);
+
+		let basin = Scene::parse(include_str!("../scenes/basin.toml")).unwrap();
+		assert!(basin.preset.is_some() && basin.bounds.is_some());
+		assert_eq!(basin.emitters.len(), 1);
+		assert!(basin.steps() > 0);
+	}
+}
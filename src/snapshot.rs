@@ -0,0 +1,351 @@
+// Versioned binary snapshots of the simulation, to resume it exactly later on.
+//
+// Layout, all little endian:
+//   magic "GFSN" | version u32 | payload length u64 | crc32 of the payload u32 | payload
+use std::path::PathBuf;
+
+use crate::types::{Quat, Vector3, Vector4};
+
+pub const MAGIC: &[u8; 4] = b"GFSN";
+pub const VERSION: u32 = 3;
+const HEADER: usize = 4 + 4 + 8 + 4;
+
+/// Folder snapshot files are kept in, relative to the game's working directory.
+pub const DATA_FOLDER: &str = "garrysmod/data/gfluid";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+	#[error("Not a snapshot")]
+	BadMagic,
+	#[error("Unsupported version {0}, expected {1}")]
+	Version(u32, u32),
+	#[error("Snapshot is corrupted, checksum mismatch")]
+	Checksum,
+	#[error("Snapshot ended unexpectedly")]
+	Truncated,
+	#[error("Snapshot holds {0} particles, more than the maximum of {1}")]
+	Capacity(usize, usize),
+	#[error("Shapes of type {0} can't be snapshotted")]
+	Shape(i32),
+	#[error("Snapshot is corrupted, {0} out of range")]
+	Range(&'static str),
+	#[error("Unknown replay event {0}")]
+	Event(u8),
+	#[error("Invalid snapshot name '{0}'")]
+	Name(String),
+	#[error("{0}")]
+	Io(#[from] std::io::Error),
+}
+
+/// Appends plain values to a byte buffer.
+#[derive(Debug, Default)]
+pub struct Writer {
+	pub bytes: Vec<u8>,
+}
+
+impl Writer {
+	pub fn u8(&mut self, v: u8) {
+		self.bytes.push(v);
+	}
+
+	pub fn u32(&mut self, v: u32) {
+		self.bytes.extend_from_slice(&v.to_le_bytes());
+	}
+
+	pub fn i32(&mut self, v: i32) {
+		self.bytes.extend_from_slice(&v.to_le_bytes());
+	}
+
+	pub fn f32(&mut self, v: f32) {
+		self.bytes.extend_from_slice(&v.to_le_bytes());
+	}
+
+	pub fn count(&mut self, len: usize) {
+		self.u32(len as u32);
+	}
+
+	pub fn string(&mut self, s: &str) {
+		self.count(s.len());
+		self.bytes.extend_from_slice(s.as_bytes());
+	}
+
+	pub fn vector3(&mut self, v: Vector3) {
+		[v.0, v.1, v.2].into_iter().for_each(|c| self.f32(c));
+	}
+
+	pub fn vector4(&mut self, v: Vector4) {
+		[v.0, v.1, v.2, v.3].into_iter().for_each(|c| self.f32(c));
+	}
+
+	pub fn quat(&mut self, q: Quat) {
+		[q.0, q.1, q.2, q.3].into_iter().for_each(|c| self.f32(c));
+	}
+
+	/// Writes a length followed by every value, e.g. `w.slice(&indices, Writer::i32)`.
+	pub fn slice<T: Copy>(&mut self, values: &[T], write: fn(&mut Self, T)) {
+		self.count(values.len());
+		values.iter().for_each(|v| write(self, *v));
+	}
+}
+
+/// Reads back what a [Writer] wrote.
+#[derive(Debug)]
+pub struct Reader<'a> {
+	bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	pub fn new(bytes: &'a [u8]) -> Self {
+		Self { bytes }
+	}
+
+	pub fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+		if self.bytes.len() < n {
+			return Err(SnapshotError::Truncated);
+		}
+
+		let (head, rest) = self.bytes.split_at(n);
+		self.bytes = rest;
+		Ok(head)
+	}
+
+	fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+		let mut out = [0; N];
+		out.copy_from_slice(self.take(N)?);
+		Ok(out)
+	}
+
+	pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+		Ok(self.take(1)?[0])
+	}
+
+	pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+		Ok(u32::from_le_bytes(self.array()?))
+	}
+
+	pub fn i32(&mut self) -> Result<i32, SnapshotError> {
+		Ok(i32::from_le_bytes(self.array()?))
+	}
+
+	pub fn f32(&mut self) -> Result<f32, SnapshotError> {
+		Ok(f32::from_le_bytes(self.array()?))
+	}
+
+	/// Reads a length, checking there are at least `min_size` bytes per element left so
+	/// a corrupted length can't cause a huge allocation.
+	pub fn count(&mut self, min_size: usize) -> Result<usize, SnapshotError> {
+		let len = self.u32()? as usize;
+		if len.saturating_mul(min_size) > self.bytes.len() {
+			return Err(SnapshotError::Truncated);
+		}
+
+		Ok(len)
+	}
+
+	pub fn string(&mut self) -> Result<String, SnapshotError> {
+		let len = self.count(1)?;
+		Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+	}
+
+	pub fn vector3(&mut self) -> Result<Vector3, SnapshotError> {
+		Ok(Vector3(self.f32()?, self.f32()?, self.f32()?))
+	}
+
+	pub fn vector4(&mut self) -> Result<Vector4, SnapshotError> {
+		Ok(Vector4(self.f32()?, self.f32()?, self.f32()?, self.f32()?))
+	}
+
+	pub fn quat(&mut self) -> Result<Quat, SnapshotError> {
+		Ok(Quat(self.f32()?, self.f32()?, self.f32()?, self.f32()?))
+	}
+
+	/// Reads what [Writer::slice] wrote, `min_size` being the bytes each value takes at least.
+	pub fn vec<T>(
+		&mut self,
+		min_size: usize,
+		read: fn(&mut Self) -> Result<T, SnapshotError>,
+	) -> Result<Vec<T>, SnapshotError> {
+		let len = self.count(min_size)?;
+		(0 .. len).map(|_| read(self)).collect()
+	}
+}
+
+/// CRC-32 (IEEE), bit by bit since snapshots are rare enough not to need a table.
+pub fn crc32(bytes: &[u8]) -> u32 {
+	let mut crc = !0u32;
+	for &byte in bytes {
+		crc ^= byte as u32;
+		for _ in 0 .. 8 {
+			let mask = (crc & 1).wrapping_neg();
+			crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+		}
+	}
+
+	!crc
+}
+
+/// Wraps a payload with the header.
+pub fn seal(payload: Vec<u8>) -> Vec<u8> {
+	seal_with(MAGIC, VERSION, payload)
+}
+
+/// Wraps a payload with a header of another format sharing the snapshot layout.
+pub fn seal_with(magic: &[u8; 4], version: u32, payload: Vec<u8>) -> Vec<u8> {
+	let mut out = Vec::with_capacity(HEADER + payload.len());
+	out.extend_from_slice(magic);
+	out.extend_from_slice(&version.to_le_bytes());
+	out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+	out.extend_from_slice(&crc32(&payload).to_le_bytes());
+	out.extend_from_slice(&payload);
+	out
+}
+
+/// Checks the header and checksum, returning the payload.
+pub fn open(bytes: &[u8]) -> Result<&[u8], SnapshotError> {
+	open_with(MAGIC, VERSION, bytes)
+}
+
+/// Checks the header of another format sharing the snapshot layout.
+pub fn open_with<'a>(
+	magic: &[u8; 4],
+	expected: u32,
+	bytes: &'a [u8],
+) -> Result<&'a [u8], SnapshotError> {
+	if bytes.len() < HEADER {
+		return Err(SnapshotError::Truncated);
+	}
+
+	if &bytes[0 .. 4] != magic {
+		return Err(SnapshotError::BadMagic);
+	}
+
+	let mut header = Reader::new(&bytes[4 .. HEADER]);
+	let version = header.u32()?;
+	if version != expected {
+		return Err(SnapshotError::Version(version, expected));
+	}
+
+	let (lo, hi) = (header.u32()? as u64, header.u32()? as u64);
+	let len = (lo | (hi << 32)) as usize;
+	let checksum = header.u32()?;
+
+	let payload = bytes.get(HEADER .. HEADER.saturating_add(len));
+	match payload {
+		Some(payload) if crc32(payload) == checksum => Ok(payload),
+		Some(_) => Err(SnapshotError::Checksum),
+		None => Err(SnapshotError::Truncated),
+	}
+}
+
+/// Names are restricted to letters, digits, dashes and underscores so they can't escape the data folder.
+pub fn check_name(name: &str) -> Result<(), SnapshotError> {
+	let valid = !name.is_empty()
+		&& name
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+	if !valid {
+		return Err(SnapshotError::Name(name.to_owned()));
+	}
+
+	Ok(())
+}
+
+/// Path of a named snapshot file in the data folder.
+pub fn data_path(name: &str) -> Result<PathBuf, SnapshotError> {
+	check_name(name)?;
+
+	// gmod only lets Lua read .txt and .dat files from the data folder
+	Ok(PathBuf::from(DATA_FOLDER).join(format!("{}.dat", name)))
+}
+
+pub fn write_file(name: &str, bytes: &[u8]) -> Result<(), SnapshotError> {
+	let path = data_path(name)?;
+	std::fs::create_dir_all(DATA_FOLDER)?;
+	std::fs::write(path, bytes)?;
+	Ok(())
+}
+
+pub fn read_file(name: &str) -> Result<Vec<u8>, SnapshotError> {
+	Ok(std::fs::read(data_path(name)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn crc32_check_value() {
+		assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+		assert_eq!(crc32(b""), 0);
+	}
+
+	#[test]
+	fn seal_round_trip() {
+		let sealed = seal(b"payload".to_vec());
+		assert_eq!(open(&sealed).unwrap(), b"payload");
+	}
+
+	#[test]
+	fn open_rejects_truncation() {
+		let sealed = seal(b"payload".to_vec());
+
+		assert!(matches!(open(&sealed[.. HEADER - 1]), Err(SnapshotError::Truncated)));
+		assert!(matches!(open(&sealed[.. sealed.len() - 1]), Err(SnapshotError::Truncated)));
+	}
+
+	#[test]
+	fn open_rejects_bad_magic() {
+		let mut sealed = seal(b"payload".to_vec());
+		sealed[0] = b'X';
+
+		assert!(matches!(open(&sealed), Err(SnapshotError::BadMagic)));
+		assert!(open_with(b"XFSN", VERSION, &sealed).is_ok());
+	}
+
+	#[test]
+	fn open_rejects_other_versions() {
+		let sealed = seal(b"payload".to_vec());
+
+		assert!(matches!(
+			open_with(MAGIC, VERSION + 1, &sealed),
+			Err(SnapshotError::Version(VERSION, _))
+		));
+	}
+
+	#[test]
+	fn open_rejects_bad_checksum() {
+		let mut sealed = seal(b"payload".to_vec());
+		*sealed.last_mut().unwrap() ^= 1;
+
+		assert!(matches!(open(&sealed), Err(SnapshotError::Checksum)));
+	}
+
+	#[test]
+	fn count_guards_length() {
+		let mut w = Writer::default();
+		w.count(3);
+		w.f32(1.0);
+		w.f32(2.0);
+		w.f32(3.0);
+
+		assert_eq!(Reader::new(&w.bytes).count(4).unwrap(), 3);
+		assert!(matches!(Reader::new(&w.bytes).count(5), Err(SnapshotError::Truncated)));
+
+		let mut w = Writer::default();
+		w.count(u32::MAX as usize);
+		assert!(matches!(Reader::new(&w.bytes).count(1), Err(SnapshotError::Truncated)));
+	}
+
+	#[test]
+	fn slice_round_trip() {
+		let mut w = Writer::default();
+		w.slice(&[1, -2, 3], Writer::i32);
+		w.string("name");
+
+		let mut r = Reader::new(&w.bytes);
+		assert_eq!(r.vec(4, Reader::i32).unwrap(), vec![1, -2, 3]);
+		assert_eq!(r.string().unwrap(), "name");
+		assert!(matches!(r.u8(), Err(SnapshotError::Truncated)));
+	}
+}
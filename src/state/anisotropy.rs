@@ -0,0 +1,92 @@
+use nvflex_sys::*;
+use std::mem::size_of;
+
+use crate::types::{Vector3, Vector4};
+
+/// A fluid particle drawn as an ellipsoid, centered on its smoothed position.
+#[derive(Clone, Copy, Debug)]
+pub struct Ellipsoid {
+	pub center: Vector3,
+	/// Principal axes, scaled by their radius along that direction.
+	pub axes: [Vector3; 3],
+}
+
+/// Smoothed positions and anisotropy computed by FleX from `smoothing` and `anisotropy*` params.
+#[derive(Debug)]
+pub struct AnisotropyState {
+	pub smooth: *mut NvFlexBuffer, // Vec<Vector4>
+	pub q1: *mut NvFlexBuffer,     // Vec<Vector4>, xyz axis and w scale
+	pub q2: *mut NvFlexBuffer,     // Vec<Vector4>
+	pub q3: *mut NvFlexBuffer,     // Vec<Vector4>
+}
+
+impl Default for AnisotropyState {
+	fn default() -> Self {
+		Self {
+			smooth: std::ptr::null_mut(),
+			q1: std::ptr::null_mut(),
+			q2: std::ptr::null_mut(),
+			q3: std::ptr::null_mut(),
+		}
+	}
+}
+
+impl AnisotropyState {
+	/// # Safety
+	/// Do not call this function more than once
+	pub unsafe fn alloc(&mut self, flex: *mut NvFlexLibrary, capacity: i32) {
+		for buffer in [&mut self.smooth, &mut self.q1, &mut self.q2, &mut self.q3] {
+			*buffer = NvFlexAllocBuffer(
+				flex,
+				capacity,
+				size_of::<Vector4>() as i32,
+				eNvFlexBufferHost,
+			);
+		}
+	}
+
+	/// Reads back an ellipsoid for each of the first `count` particles.
+	/// # Safety
+	/// The solver must be valid.
+	pub unsafe fn read(&self, solver: *mut NvFlexSolver, count: usize) -> Vec<Ellipsoid> {
+		NvFlexGetSmoothParticles(solver, self.smooth, std::ptr::null());
+		NvFlexGetAnisotropy(solver, self.q1, self.q2, self.q3, std::ptr::null());
+
+		let smooth = NvFlexMap(self.smooth, eNvFlexMapWait) as *const Vector4;
+		let q1 = NvFlexMap(self.q1, eNvFlexMapWait) as *const Vector4;
+		let q2 = NvFlexMap(self.q2, eNvFlexMapWait) as *const Vector4;
+		let q3 = NvFlexMap(self.q3, eNvFlexMapWait) as *const Vector4;
+
+		let axis = |q: Vector4| q.xyz() * q.3;
+
+		let out = (0 .. count)
+			.map(|i| Ellipsoid {
+				center: (*smooth.add(i)).xyz(),
+				axes: [axis(*q1.add(i)), axis(*q2.add(i)), axis(*q3.add(i))],
+			})
+			.collect();
+
+		self.unmap();
+		out
+	}
+
+	pub fn unmap(&self) {
+		unsafe {
+			NvFlexUnmap(self.smooth);
+			NvFlexUnmap(self.q1);
+			NvFlexUnmap(self.q2);
+			NvFlexUnmap(self.q3);
+		}
+	}
+}
+
+impl Drop for AnisotropyState {
+	fn drop(&mut self) {
+		unsafe {
+			NvFlexFreeBuffer(self.smooth);
+			NvFlexFreeBuffer(self.q1);
+			NvFlexFreeBuffer(self.q2);
+			NvFlexFreeBuffer(self.q3);
+		}
+	}
+}
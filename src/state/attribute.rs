@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use crate::{grid::HashGrid, types::Vector3};
+
+/// Attributes a new particle starts with.
+#[derive(Clone, Copy, Debug)]
+pub struct Attributes {
+	/// RGBA from 0 to 1
+	pub color: [f32; 4],
+	pub temperature: f32,
+	/// gmod entity index of whoever spawned the particle, -1 for nobody
+	pub owner: i32,
+}
+
+impl Default for Attributes {
+	fn default() -> Self {
+		Self {
+			color: [1.0, 1.0, 1.0, 1.0],
+			temperature: 20.0,
+			owner: -1,
+		}
+	}
+}
+
+/// Moves per particle values to their new slots after compaction, dropping removed particles.
+/// `remap` holds the new slot of each old one, or -1 if it was removed.
+pub fn remap_slots<T>(values: &mut Vec<T>, remap: &[i32]) {
+	let mut moved: Vec<(usize, T)> = std::mem::take(values)
+		.into_iter()
+		.zip(remap)
+		.filter(|(_, &new)| new >= 0)
+		.map(|(value, &new)| (new as usize, value))
+		.collect();
+
+	moved.sort_unstable_by_key(|(new, _)| *new);
+	*values = moved.into_iter().map(|(_, value)| value).collect();
+}
+
+/// Gameplay data FleX doesn't know about, stored in parallel to the particle slots.
+#[derive(Debug, Default)]
+pub struct AttributeState {
+	pub defaults: Attributes,
+	/// How fast colors blend between neighbors, per second. Zero disables diffusion.
+	pub color_diffusion: f32,
+
+	pub colors: Vec<[f32; 4]>,
+	pub temperatures: Vec<f32>,
+	pub owners: Vec<i32>,
+	/// Named user channels, all zero for new particles.
+	pub channels: HashMap<String, Vec<f32>>,
+}
+
+impl AttributeState {
+	pub fn len(&self) -> usize {
+		self.colors.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.colors.is_empty()
+	}
+
+	/// Grows or shrinks the attributes to match the particle count, new slots getting [Self::defaults].
+	pub fn resize(&mut self, count: usize) {
+		let defaults = self.defaults;
+
+		self.colors.resize(count, defaults.color);
+		self.temperatures.resize(count, defaults.temperature);
+		self.owners.resize(count, defaults.owner);
+
+		for channel in self.channels.values_mut() {
+			channel.resize(count, 0.0);
+		}
+	}
+
+	/// Moves attributes along with their particles after compaction.
+	/// `remap` holds the new slot of each old one, or -1 if it was removed.
+	pub fn remap(&mut self, remap: &[i32]) {
+		remap_slots(&mut self.colors, remap);
+		remap_slots(&mut self.temperatures, remap);
+		remap_slots(&mut self.owners, remap);
+
+		for channel in self.channels.values_mut() {
+			remap_slots(channel, remap);
+		}
+	}
+
+	pub fn set_channel(&mut self, name: &str, particle: usize, value: f32) -> bool {
+		let len = self.len();
+		if particle >= len {
+			return false;
+		}
+
+		let channel = self
+			.channels
+			.entry(name.to_owned())
+			.or_insert_with(|| vec![0.0; len]);
+
+		channel[particle] = value;
+		true
+	}
+
+	pub fn get_channel(&self, name: &str, particle: usize) -> Option<f32> {
+		self.channels.get(name)?.get(particle).copied()
+	}
+
+	/// Blends the color of each `active` particle towards the average of its active neighbors
+	/// within `radius`. Removed and quarantined particles neither change nor bleed into others.
+	pub fn diffuse_colors(&mut self, positions: &[Vector3], active: &[i32], radius: f32, dt: f32) {
+		let rate = (self.color_diffusion * dt).clamp(0.0, 1.0);
+		if rate <= 0.0 {
+			return;
+		}
+
+		let count = positions.len().min(self.colors.len());
+		let active: Vec<usize> = active
+			.iter()
+			.map(|&i| i as usize)
+			.filter(|&i| i < count)
+			.collect();
+
+		let points: Vec<Vector3> = active.iter().map(|&i| positions[i]).collect();
+		let grid = HashGrid::new(points.iter().copied(), radius);
+		let radius2 = radius * radius;
+
+		let blended: Vec<[f32; 4]> = (0 .. active.len())
+			.map(|a| {
+				let mut sum = [0.0; 4];
+				let mut neighbors = 0;
+
+				grid.query(points[a], radius, |b| {
+					if b != a && (points[b] - points[a]).length_squared() <= radius2 {
+						for (s, c) in sum.iter_mut().zip(self.colors[active[b]]) {
+							*s += c;
+						}
+						neighbors += 1;
+					}
+				});
+
+				let mut color = self.colors[active[a]];
+				if neighbors > 0 {
+					for (c, s) in color.iter_mut().zip(sum) {
+						*c += (s / neighbors as f32 - *c) * rate;
+					}
+				}
+
+				color
+			})
+			.collect();
+
+		for (&i, color) in active.iter().zip(blended) {
+			self.colors[i] = color;
+		}
+	}
+}
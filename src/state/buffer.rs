@@ -0,0 +1,69 @@
+use nvflex_sys::*;
+use std::marker::PhantomData;
+use std::mem::size_of;
+
+/// A FleX host buffer that mirrors a Rust slice, growing whenever the uploaded data doesn't fit.
+/// Used for constraint data (rigids, springs, triangles) whose size isn't known up front.
+#[derive(Debug)]
+pub struct HostBuffer<T> {
+	pub buffer: *mut NvFlexBuffer,
+	capacity: usize,
+	_marker: PhantomData<T>,
+}
+
+impl<T> Default for HostBuffer<T> {
+	fn default() -> Self {
+		Self {
+			buffer: std::ptr::null_mut(),
+			capacity: 0,
+			_marker: PhantomData,
+		}
+	}
+}
+
+impl<T: Copy> HostBuffer<T> {
+	/// Copies `data` into the buffer, reallocating it if it is too small.
+	/// # Safety
+	/// `flex` must be the library the buffer was (or will be) allocated from.
+	pub unsafe fn upload(&mut self, flex: *mut NvFlexLibrary, data: &[T]) {
+		if self.buffer.is_null() || data.len() > self.capacity {
+			if !self.buffer.is_null() {
+				NvFlexFreeBuffer(self.buffer);
+			}
+
+			self.capacity = data.len().max(1);
+			self.buffer = NvFlexAllocBuffer(
+				flex,
+				self.capacity as i32,
+				size_of::<T>() as i32,
+				eNvFlexBufferHost,
+			);
+		}
+
+		let mapped = NvFlexMap(self.buffer, eNvFlexMapWait) as *mut T;
+		std::ptr::copy_nonoverlapping(data.as_ptr(), mapped, data.len());
+		NvFlexUnmap(self.buffer);
+	}
+
+	/// Copies the start of the buffer back into `data`.
+	/// # Safety
+	/// The buffer must have been uploaded with at least `data.len()` elements.
+	pub unsafe fn download(&self, data: &mut [T]) {
+		if self.buffer.is_null() {
+			return;
+		}
+
+		let len = data.len().min(self.capacity);
+		let mapped = NvFlexMap(self.buffer, eNvFlexMapWait) as *const T;
+		std::ptr::copy_nonoverlapping(mapped, data.as_mut_ptr(), len);
+		NvFlexUnmap(self.buffer);
+	}
+}
+
+impl<T> Drop for HostBuffer<T> {
+	fn drop(&mut self) {
+		if !self.buffer.is_null() {
+			unsafe { NvFlexFreeBuffer(self.buffer) };
+		}
+	}
+}
@@ -0,0 +1,504 @@
+use nvflex_sys::*;
+
+use crate::{
+	snapshot::{Reader, SnapshotError, Writer},
+	types::{Quat, Vector3, Vector4},
+};
+
+use super::buffer::HostBuffer;
+
+/// A sheet or closed mesh of particles held together by springs, rendered through its triangles.
+#[derive(Clone, Debug)]
+pub struct Cloth {
+	/// Particle indices, in row major order for grids
+	pub particles: Vec<i32>,
+
+	/// Triangle indices into [Self::particles], for rendering.
+	pub indices: Vec<u32>,
+
+	/// Range of this cloth's triangles among all dynamic triangles, counted in triangles.
+	pub first_triangle: usize,
+	pub ntriangles: usize,
+}
+
+/// A closed cloth mesh that keeps its volume through pressure, like a balloon.
+#[derive(Clone, Copy, Debug)]
+pub struct Inflatable {
+	pub cloth: usize,
+	pub rest_volume: f32,
+	/// Multiplier of the rest volume the inflatable tries to reach, 1 keeps it at rest.
+	pub pressure: f32,
+	pub constraint_scale: f32,
+}
+
+/// A cloth particle that follows a shape, usually one bound to an entity.
+#[derive(Clone, Copy, Debug)]
+pub struct Pin {
+	pub particle: i32,
+	pub shape: usize,
+	/// Position of the particle in the shape's local space
+	pub offset: Vector3,
+}
+
+/// Stiffness of each kind of spring generated for a cloth, from 0 to 1.
+#[derive(Clone, Copy, Debug)]
+pub struct ClothStiffness {
+	pub stretch: f32,
+	pub shear: f32,
+	pub bend: f32,
+}
+
+impl Default for ClothStiffness {
+	fn default() -> Self {
+		Self {
+			stretch: 0.8,
+			shear: 0.5,
+			bend: 0.2,
+		}
+	}
+}
+
+#[derive(derivative::Derivative, Default)]
+#[derivative(Debug)]
+pub struct ClothState {
+	has_changes: bool,
+
+	pub cloths: Vec<Cloth>,
+	pub pins: Vec<Pin>,
+	inflatables: Vec<Inflatable>,
+
+	/// Pairs of particle indices
+	spring_indices: Vec<i32>,
+	spring_lengths: Vec<f32>,
+	spring_stiffness: Vec<f32>,
+
+	/// Triplets of particle indices
+	triangles: Vec<i32>,
+	triangle_normals: Vec<Vector3>,
+
+	#[derivative(Debug = "ignore")]
+	buffers: ClothBuffers,
+}
+
+#[derive(Debug, Default)]
+struct ClothBuffers {
+	spring_indices: HostBuffer<i32>,
+	spring_lengths: HostBuffer<f32>,
+	spring_stiffness: HostBuffer<f32>,
+
+	triangles: HostBuffer<i32>,
+	triangle_normals: HostBuffer<Vector3>,
+
+	inflatable_starts: HostBuffer<i32>,
+	inflatable_counts: HostBuffer<i32>,
+	inflatable_volumes: HostBuffer<f32>,
+	inflatable_pressures: HostBuffer<f32>,
+	inflatable_scales: HostBuffer<f32>,
+}
+
+impl ClothState {
+	pub fn add_spring(&mut self, a: i32, b: i32, length: f32, stiffness: f32) {
+		self.spring_indices.push(a);
+		self.spring_indices.push(b);
+		self.spring_lengths.push(length);
+		self.spring_stiffness.push(stiffness);
+
+		self.has_changes = true;
+	}
+
+	pub fn add_triangle(&mut self, a: i32, b: i32, c: i32, normal: Vector3) {
+		self.triangles.extend_from_slice(&[a, b, c]);
+		self.triangle_normals.push(normal);
+
+		self.has_changes = true;
+	}
+
+	pub fn ntriangles(&self) -> usize {
+		self.triangle_normals.len()
+	}
+
+	/// Connects a grid of particles with stretch, shear and bend springs and triangulates it.
+	/// `positions` are the rest positions of `particles`, in the same row major order.
+	pub fn add_cloth(
+		&mut self,
+		particles: Vec<i32>,
+		positions: &[Vector3],
+		columns: usize,
+		rows: usize,
+		stiffness: ClothStiffness,
+	) -> usize {
+		let at = |x: usize, y: usize| y * columns + x;
+
+		let spring = |this: &mut Self, a: usize, b: usize, k: f32| {
+			let length = (positions[a] - positions[b]).length();
+			this.add_spring(particles[a], particles[b], length, k);
+		};
+
+		for y in 0 .. rows {
+			for x in 0 .. columns {
+				// Stretch, along the grid
+				if x + 1 < columns {
+					spring(self, at(x, y), at(x + 1, y), stiffness.stretch);
+				}
+				if y + 1 < rows {
+					spring(self, at(x, y), at(x, y + 1), stiffness.stretch);
+				}
+
+				// Shear, across each quad
+				if x + 1 < columns && y + 1 < rows {
+					spring(self, at(x, y), at(x + 1, y + 1), stiffness.shear);
+					spring(self, at(x + 1, y), at(x, y + 1), stiffness.shear);
+				}
+
+				// Bend, skipping a particle
+				if x + 2 < columns {
+					spring(self, at(x, y), at(x + 2, y), stiffness.bend);
+				}
+				if y + 2 < rows {
+					spring(self, at(x, y), at(x, y + 2), stiffness.bend);
+				}
+			}
+		}
+
+		let first_triangle = self.ntriangles();
+		let mut indices = vec![];
+		for y in 0 .. rows.saturating_sub(1) {
+			for x in 0 .. columns.saturating_sub(1) {
+				let (a, b, c, d) = (at(x, y), at(x + 1, y), at(x, y + 1), at(x + 1, y + 1));
+				indices.extend([a, b, c, b, d, c].iter().map(|&i| i as u32));
+
+				self.add_triangle(
+					particles[a],
+					particles[b],
+					particles[c],
+					triangle_normal(positions[a], positions[b], positions[c]),
+				);
+				self.add_triangle(
+					particles[b],
+					particles[d],
+					particles[c],
+					triangle_normal(positions[b], positions[d], positions[c]),
+				);
+			}
+		}
+
+		self.cloths.push(Cloth {
+			particles,
+			indices,
+			first_triangle,
+			ntriangles: self.ntriangles() - first_triangle,
+		});
+
+		self.cloths.len() - 1
+	}
+
+	/// Connects an arbitrary triangle mesh of particles with a spring along every edge.
+	/// `indices` are zero based into `particles` and `positions`.
+	pub fn add_mesh(
+		&mut self,
+		particles: Vec<i32>,
+		positions: &[Vector3],
+		indices: Vec<u32>,
+		stiffness: f32,
+	) -> usize {
+		let mut edges = std::collections::HashSet::new();
+
+		let first_triangle = self.ntriangles();
+		for tri in indices.chunks_exact(3) {
+			let (a, b, c) = (tri[0] as usize, tri[1] as usize, tri[2] as usize);
+
+			for (from, to) in [(a, b), (b, c), (c, a)] {
+				if edges.insert((from.min(to), from.max(to))) {
+					let length = (positions[from] - positions[to]).length();
+					self.add_spring(particles[from], particles[to], length, stiffness);
+				}
+			}
+
+			self.add_triangle(
+				particles[a],
+				particles[b],
+				particles[c],
+				triangle_normal(positions[a], positions[b], positions[c]),
+			);
+		}
+
+		self.cloths.push(Cloth {
+			particles,
+			indices,
+			first_triangle,
+			ntriangles: self.ntriangles() - first_triangle,
+		});
+
+		self.cloths.len() - 1
+	}
+
+	/// Turns a closed mesh of particles into an inflatable, returning its cloth handle.
+	pub fn add_inflatable(
+		&mut self,
+		particles: Vec<i32>,
+		positions: &[Vector3],
+		indices: Vec<u32>,
+		stiffness: f32,
+		pressure: f32,
+	) -> usize {
+		let rest_volume = crate::voxel::volume(positions, &indices);
+		let cloth = self.add_mesh(particles, positions, indices, stiffness);
+
+		self.inflatables.push(Inflatable {
+			cloth,
+			rest_volume,
+			pressure,
+			constraint_scale: 1.0,
+		});
+
+		cloth
+	}
+
+	/// Changes the pressure of the inflatable made from the given cloth.
+	pub fn set_pressure(&mut self, cloth: usize, pressure: f32) -> bool {
+		match self.inflatables.iter_mut().find(|i| i.cloth == cloth) {
+			Some(inflatable) => {
+				inflatable.pressure = pressure;
+				self.has_changes = true;
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Follows particles moved by compaction. Cloth particles are never removed, so every one has a new slot.
+	pub fn remap(&mut self, remap: &[i32]) {
+		let moved = |index: &mut i32| *index = remap[*index as usize];
+
+		self.spring_indices.iter_mut().for_each(moved);
+		self.triangles.iter_mut().for_each(moved);
+		self.pins.iter_mut().for_each(|pin| moved(&mut pin.particle));
+
+		for cloth in &mut self.cloths {
+			cloth.particles.iter_mut().for_each(moved);
+		}
+
+		self.has_changes = true;
+	}
+
+	/// Writes every cloth, pin and inflatable into a snapshot.
+	pub fn write_snapshot(&self, w: &mut Writer) {
+		w.count(self.cloths.len());
+		for cloth in &self.cloths {
+			w.slice(&cloth.particles, Writer::i32);
+			w.slice(&cloth.indices, Writer::u32);
+			w.count(cloth.first_triangle);
+			w.count(cloth.ntriangles);
+		}
+
+		w.count(self.pins.len());
+		for pin in &self.pins {
+			w.i32(pin.particle);
+			w.count(pin.shape);
+			w.vector3(pin.offset);
+		}
+
+		w.count(self.inflatables.len());
+		for inflatable in &self.inflatables {
+			w.count(inflatable.cloth);
+			w.f32(inflatable.rest_volume);
+			w.f32(inflatable.pressure);
+			w.f32(inflatable.constraint_scale);
+		}
+
+		w.slice(&self.spring_indices, Writer::i32);
+		w.slice(&self.spring_lengths, Writer::f32);
+		w.slice(&self.spring_stiffness, Writer::f32);
+		w.slice(&self.triangles, Writer::i32);
+		w.slice(&self.triangle_normals, Writer::vector3);
+	}
+
+	/// Reads cloth written by [Self::write_snapshot], which may only use the first `particles`
+	/// and pin to the first `shapes`.
+	pub fn read_snapshot(
+		r: &mut Reader,
+		particles: usize,
+		shapes: usize,
+	) -> Result<Self, SnapshotError> {
+		let mut cloths = vec![];
+		for _ in 0 .. r.count(16)? {
+			cloths.push(Cloth {
+				particles: r.vec(4, Reader::i32)?,
+				indices: r.vec(4, Reader::u32)?,
+				first_triangle: r.u32()? as usize,
+				ntriangles: r.u32()? as usize,
+			});
+		}
+
+		let mut pins = vec![];
+		for _ in 0 .. r.count(20)? {
+			pins.push(Pin {
+				particle: r.i32()?,
+				shape: r.u32()? as usize,
+				offset: r.vector3()?,
+			});
+		}
+
+		let mut inflatables = vec![];
+		for _ in 0 .. r.count(16)? {
+			inflatables.push(Inflatable {
+				cloth: r.u32()? as usize,
+				rest_volume: r.f32()?,
+				pressure: r.f32()?,
+				constraint_scale: r.f32()?,
+			});
+		}
+
+		let cloth = Self {
+			has_changes: true,
+
+			cloths,
+			pins,
+			inflatables,
+
+			spring_indices: r.vec(4, Reader::i32)?,
+			spring_lengths: r.vec(4, Reader::f32)?,
+			spring_stiffness: r.vec(4, Reader::f32)?,
+
+			triangles: r.vec(4, Reader::i32)?,
+			triangle_normals: r.vec(12, Reader::vector3)?,
+
+			buffers: ClothBuffers::default(),
+		};
+
+		let particle = |i: &i32| 0 <= *i && (*i as usize) < particles;
+		let ntriangles = cloth.ntriangles();
+		let valid = cloth.spring_indices.len() == cloth.spring_lengths.len() * 2
+			&& cloth.spring_stiffness.len() == cloth.spring_lengths.len()
+			&& cloth.triangles.len() == ntriangles * 3
+			&& cloth.spring_indices.iter().all(particle)
+			&& cloth.triangles.iter().all(particle)
+			&& cloth.cloths.iter().all(|c| {
+				c.particles.iter().all(particle)
+					&& c.indices.iter().all(|&i| (i as usize) < c.particles.len())
+					&& c.first_triangle.saturating_add(c.ntriangles) <= ntriangles
+			})
+			&& cloth.pins.iter().all(|pin| particle(&pin.particle) && pin.shape < shapes)
+			&& cloth.inflatables.iter().all(|i| i.cloth < cloth.cloths.len());
+
+		if !valid {
+			return Err(SnapshotError::Range("cloth"));
+		}
+
+		Ok(cloth)
+	}
+
+	/// Whether a particle belongs to any cloth, and so can't be removed.
+	pub fn contains(&self, particle: i32) -> bool {
+		self.cloths.iter().any(|cloth| cloth.particles.contains(&particle))
+	}
+
+	/// Pushes spring and triangle changes to the FleX state
+	/// # Safety
+	/// `flex` and `solver` must be valid.
+	pub unsafe fn flush(&mut self, flex: *mut NvFlexLibrary, solver: *mut NvFlexSolver) {
+		if !self.has_changes {
+			return;
+		}
+
+		let b = &mut self.buffers;
+		b.spring_indices.upload(flex, &self.spring_indices);
+		b.spring_lengths.upload(flex, &self.spring_lengths);
+		b.spring_stiffness.upload(flex, &self.spring_stiffness);
+
+		NvFlexSetSprings(
+			solver,
+			b.spring_indices.buffer,
+			b.spring_lengths.buffer,
+			b.spring_stiffness.buffer,
+			self.spring_lengths.len() as i32,
+		);
+
+		b.triangles.upload(flex, &self.triangles);
+		b.triangle_normals.upload(flex, &self.triangle_normals);
+
+		NvFlexSetDynamicTriangles(
+			solver,
+			b.triangles.buffer,
+			b.triangle_normals.buffer,
+			self.triangle_normals.len() as i32,
+		);
+
+		// Also pushed when there are none, so inflatables replaced by a snapshot don't linger
+		{
+			let (mut starts, mut counts) = (vec![], vec![]);
+			let (mut volumes, mut pressures, mut scales) = (vec![], vec![], vec![]);
+
+			for inflatable in &self.inflatables {
+				let cloth = &self.cloths[inflatable.cloth];
+				starts.push(cloth.first_triangle as i32);
+				counts.push(cloth.ntriangles as i32);
+
+				volumes.push(inflatable.rest_volume);
+				pressures.push(inflatable.pressure);
+				scales.push(inflatable.constraint_scale);
+			}
+
+			b.inflatable_starts.upload(flex, &starts);
+			b.inflatable_counts.upload(flex, &counts);
+			b.inflatable_volumes.upload(flex, &volumes);
+			b.inflatable_pressures.upload(flex, &pressures);
+			b.inflatable_scales.upload(flex, &scales);
+
+			NvFlexSetInflatables(
+				solver,
+				b.inflatable_starts.buffer,
+				b.inflatable_counts.buffer,
+				b.inflatable_volumes.buffer,
+				b.inflatable_pressures.buffer,
+				b.inflatable_scales.buffer,
+				self.inflatables.len() as i32,
+			);
+		}
+
+		self.has_changes = false;
+	}
+}
+
+pub fn triangle_normal(a: Vector3, b: Vector3, c: Vector3) -> Vector3 {
+	let n = (b - a).cross(c - a);
+	let length = n.length();
+
+	if length > 0.0 {
+		n * (1.0 / length)
+	} else {
+		Vector3(0.0, 0.0, 1.0)
+	}
+}
+
+/// Smooth per vertex normals for an indexed triangle list, by summing area weighted face normals.
+pub fn vertex_normals(vertices: &[Vector3], indices: &[u32]) -> Vec<Vector3> {
+	let mut normals = vec![Vector3::default(); vertices.len()];
+
+	for tri in indices.chunks_exact(3) {
+		let (a, b, c) = (tri[0] as usize, tri[1] as usize, tri[2] as usize);
+		if a >= vertices.len() || b >= vertices.len() || c >= vertices.len() {
+			continue;
+		}
+
+		let n = (vertices[b] - vertices[a]).cross(vertices[c] - vertices[a]);
+		normals[a] += n;
+		normals[b] += n;
+		normals[c] += n;
+	}
+
+	for n in normals.iter_mut() {
+		let length = n.length();
+		if length > 0.0 {
+			*n = *n * (1.0 / length);
+		}
+	}
+
+	normals
+}
+
+/// Position of a pin's particle given the current transform of the shape it follows.
+pub fn pin_position(pin: &Pin, position: Vector4, rotation: Quat) -> Vector4 {
+	let p = position.xyz() + rotation.rotate(pin.offset);
+	Vector4(p.0, p.1, p.2, 0.0)
+}
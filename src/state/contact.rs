@@ -0,0 +1,238 @@
+use nvflex_sys::*;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use crate::{
+	config,
+	types::{Vector3, Vector4},
+};
+
+/// Summary of the particles that touched a shape during the last tick.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShapeContact {
+	pub shape: usize,
+	pub count: u32,
+	/// Average speed particles hit the shape at, along the contact normal.
+	pub average_speed: f32,
+}
+
+#[derive(Debug)]
+pub struct ContactState {
+	/// Contacts are only read back when enabled, as it's a full copy of the contact buffers every tick.
+	pub enabled: bool,
+	/// Whether to also keep a list of which particle touched which shape.
+	pub per_particle: bool,
+	/// Minimum time between two reports of the same shape to Lua.
+	pub interval: Duration,
+
+	reports: Vec<ShapeContact>,
+	particles: Vec<(i32, usize)>,
+	last_reported: HashMap<usize, Instant>,
+	/// Stride of the contact buffers
+	max_per_particle: usize,
+
+	pub planes: *mut NvFlexBuffer,     // Vec<Vector4>
+	pub velocities: *mut NvFlexBuffer, // Vec<Vector4>, w is the shape index
+	pub indices: *mut NvFlexBuffer,    // Vec<i32>
+	pub counts: *mut NvFlexBuffer,     // Vec<u32>
+}
+
+impl Default for ContactState {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+			per_particle: false,
+			interval: Duration::from_millis(100),
+
+			reports: vec![],
+			particles: vec![],
+			last_reported: HashMap::new(),
+			max_per_particle: config::MAX_CONTACTS_PER_PARTICLE as usize,
+
+			planes: std::ptr::null_mut(),
+			velocities: std::ptr::null_mut(),
+			indices: std::ptr::null_mut(),
+			counts: std::ptr::null_mut(),
+		}
+	}
+}
+
+impl ContactState {
+	/// # Safety
+	/// Do not call this function more than once
+	pub unsafe fn alloc(&mut self, flex: *mut NvFlexLibrary, capacity: &config::Capacity) {
+		self.max_per_particle = capacity.contacts_per_particle as usize;
+		let max_contacts = capacity.particles * capacity.contacts_per_particle;
+
+		self.planes = NvFlexAllocBuffer(
+			flex,
+			max_contacts,
+			size_of::<Vector4>() as i32,
+			eNvFlexBufferHost,
+		);
+
+		self.velocities = NvFlexAllocBuffer(
+			flex,
+			max_contacts,
+			size_of::<Vector4>() as i32,
+			eNvFlexBufferHost,
+		);
+
+		self.indices = NvFlexAllocBuffer(
+			flex,
+			capacity.particles,
+			size_of::<i32>() as i32,
+			eNvFlexBufferHost,
+		);
+
+		self.counts = NvFlexAllocBuffer(
+			flex,
+			capacity.particles,
+			size_of::<u32>() as i32,
+			eNvFlexBufferHost,
+		);
+	}
+
+	pub fn reports(&self) -> &[ShapeContact] {
+		&self.reports
+	}
+
+	/// (particle, shape) pairs from the last tick, empty unless [Self::per_particle] is set.
+	pub fn particles(&self) -> &[(i32, usize)] {
+		&self.particles
+	}
+
+	/// Forgets the last tick's contacts, like when the particles and shapes are replaced.
+	pub fn clear(&mut self) {
+		self.reports.clear();
+		self.particles.clear();
+		self.last_reported.clear();
+	}
+
+	/// Renumbers the per particle contacts after compaction, dropping removed particles.
+	/// `remap` holds the new id of each old one, or -1 if it was removed.
+	pub fn remap(&mut self, remap: &[i32]) {
+		self.particles.retain_mut(|(particle, _)| {
+			*particle = remap.get(*particle as usize).copied().unwrap_or(-1);
+			*particle >= 0
+		});
+	}
+
+	/// Reads back contacts from FleX and rebuilds the per shape reports for the `active` particles.
+	/// # Safety
+	/// The solver must be valid and `velocities` must hold the velocities of every particle, by id.
+	pub unsafe fn update(
+		&mut self,
+		solver: *mut NvFlexSolver,
+		velocities: &[Vector3],
+		active: &[i32],
+		nshapes: usize,
+	) {
+		self.reports.clear();
+		self.particles.clear();
+
+		NvFlexGetContacts(
+			solver,
+			self.planes,
+			self.velocities,
+			self.indices,
+			self.counts,
+		);
+
+		let planes = NvFlexMap(self.planes, eNvFlexMapWait) as *const Vector4;
+		let contact_velocities = NvFlexMap(self.velocities, eNvFlexMapWait) as *const Vector4;
+		let indices = NvFlexMap(self.indices, eNvFlexMapWait) as *const i32;
+		let counts = NvFlexMap(self.counts, eNvFlexMapWait) as *const u32;
+
+		let max = self.max_per_particle;
+		let mut totals: Vec<(u32, f32)> = vec![(0, 0.0); nshapes];
+
+		// Inactive particles keep the counts of their last contacts, so only look at active ones
+		for &particle in active {
+			let velocity = match velocities.get(particle as usize) {
+				Some(velocity) => velocity,
+				None => continue,
+			};
+
+			let contact = *indices.add(particle as usize) as usize;
+			let ncontacts = (*counts.add(contact) as usize).min(max);
+
+			for c in 0 .. ncontacts {
+				let plane = *planes.add(contact * max + c);
+				let shape_velocity = *contact_velocities.add(contact * max + c);
+
+				let shape = shape_velocity.3 as usize;
+				if shape >= nshapes {
+					continue;
+				}
+
+				let relative = *velocity - shape_velocity.xyz();
+				let speed = (-relative.dot(plane.xyz())).max(0.0);
+
+				let total = &mut totals[shape];
+				total.0 += 1;
+				total.1 += speed;
+
+				if self.per_particle {
+					self.particles.push((particle, shape));
+				}
+			}
+		}
+
+		self.unmap();
+
+		for (shape, (count, speed)) in totals.into_iter().enumerate() {
+			if count > 0 {
+				self.reports.push(ShapeContact {
+					shape,
+					count,
+					average_speed: speed / count as f32,
+				});
+			}
+		}
+	}
+
+	/// Reports that haven't been sent to Lua within [Self::interval], marking them as sent.
+	pub fn throttled(&mut self) -> Vec<ShapeContact> {
+		let now = Instant::now();
+		let interval = self.interval;
+		let last_reported = &mut self.last_reported;
+
+		self.reports
+			.iter()
+			.filter(|report| {
+				let due = last_reported
+					.get(&report.shape)
+					.map_or(true, |last| now.duration_since(*last) >= interval);
+
+				if due {
+					last_reported.insert(report.shape, now);
+				}
+
+				due
+			})
+			.copied()
+			.collect()
+	}
+
+	pub fn unmap(&self) {
+		unsafe {
+			NvFlexUnmap(self.planes);
+			NvFlexUnmap(self.velocities);
+			NvFlexUnmap(self.indices);
+			NvFlexUnmap(self.counts);
+		}
+	}
+}
+
+impl Drop for ContactState {
+	fn drop(&mut self) {
+		unsafe {
+			NvFlexFreeBuffer(self.planes);
+			NvFlexFreeBuffer(self.velocities);
+			NvFlexFreeBuffer(self.indices);
+			NvFlexFreeBuffer(self.counts);
+		}
+	}
+}
@@ -0,0 +1,157 @@
+use nvflex_sys::*;
+
+use crate::{
+	config,
+	types::{Vector3, Vector4},
+};
+
+use super::geometry::{GeometryState, ShapeInfo};
+
+/// Forces the fluid exerted on an entity-bound shape during the last tick.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShapeForce {
+	pub shape: usize,
+	pub entity: i32,
+
+	pub buoyancy: Vector3,
+	pub drag: Vector3,
+	/// Torque around the shape's origin, from both buoyancy and drag.
+	pub torque: Vector3,
+
+	/// Fraction of the shape's volume estimated to be under fluid, from 0 to 1.
+	pub submerged: f32,
+}
+
+/// Two way coupling between the fluid and entity-bound shapes.
+/// Uses a particle-in-volume estimate, every particle inside a shape displaces `rest_distance^3` of fluid.
+#[derive(Debug)]
+pub struct CouplingState {
+	/// Mass per unit of volume of the fluid.
+	pub density: f32,
+	/// How strongly the relative velocity between particles and a shape drags it along.
+	pub drag: f32,
+
+	forces: Vec<ShapeForce>,
+}
+
+impl Default for CouplingState {
+	fn default() -> Self {
+		Self {
+			density: 1.0,
+			drag: 0.5,
+
+			forces: vec![],
+		}
+	}
+}
+
+impl CouplingState {
+	pub fn forces(&self) -> &[ShapeForce] {
+		&self.forces
+	}
+
+	/// Forgets the last forces, like when the shapes they were on are replaced.
+	pub fn clear(&mut self) {
+		self.forces.clear();
+	}
+
+	/// Recomputes the forces on every entity-bound shape from the given particle readback.
+	pub fn update(
+		&mut self,
+		params: &NvFlexParams,
+		geometry: &GeometryState,
+		positions: &[Vector4],
+		velocities: &[Vector3],
+		dt: f32,
+	) {
+		self.forces.clear();
+
+		let spacing = config::rest_distance(params);
+		let particle_volume = spacing * spacing * spacing;
+		let gravity = Vector3(params.gravity[0], params.gravity[1], params.gravity[2]);
+
+		for (index, info, shape) in geometry.bound() {
+			let center = info.position.xyz();
+			let inverse = info.rotation.conjugate();
+
+			let shape_velocity = if dt > 0.0 {
+				(center - info.previous_position.xyz()) * (1.0 / dt)
+			} else {
+				Vector3::default()
+			};
+
+			let mut force = ShapeForce {
+				shape: index,
+				entity: info.entity.unwrap_or(-1),
+				..Default::default()
+			};
+
+			let mut inside = 0;
+			for (pos, vel) in positions.iter().zip(velocities) {
+				let offset = pos.xyz() - center;
+				if !contains(info, shape, inverse.rotate(offset)) {
+					continue;
+				}
+
+				inside += 1;
+
+				let buoyancy = -gravity * (particle_volume * self.density);
+				let drag = (*vel - shape_velocity) * self.drag;
+
+				force.buoyancy += buoyancy;
+				force.drag += drag;
+				force.torque += offset.cross(buoyancy + drag);
+			}
+
+			if let Some(volume) = volume(info, shape) {
+				force.submerged = (inside as f32 * particle_volume / volume).min(1.0);
+			}
+
+			self.forces.push(force);
+		}
+	}
+}
+
+/// Whether a point in the shape's local space is inside of it.
+/// Meshes and SDFs aren't supported and never contain anything.
+fn contains(info: &ShapeInfo, shape: &NvFlexCollisionGeometry, local: Vector3) -> bool {
+	unsafe {
+		match info.shape_type() {
+			x if x == eNvFlexShapeSphere => local.length() <= shape.sphere.radius,
+			x if x == eNvFlexShapeCapsule => {
+				let capsule = shape.capsule;
+				// Capsules are aligned along the x axis in FleX
+				let x = local.0.clamp(-capsule.halfHeight, capsule.halfHeight);
+				(local - Vector3(x, 0.0, 0.0)).length() <= capsule.radius
+			}
+			x if x == eNvFlexShapeBox => {
+				let half = shape.box_.halfExtents;
+				local.0.abs() <= half[0] && local.1.abs() <= half[1] && local.2.abs() <= half[2]
+			}
+			_ => false,
+		}
+	}
+}
+
+fn volume(info: &ShapeInfo, shape: &NvFlexCollisionGeometry) -> Option<f32> {
+	use std::f32::consts::PI;
+
+	unsafe {
+		match info.shape_type() {
+			x if x == eNvFlexShapeSphere => {
+				let r = shape.sphere.radius;
+				Some(4.0 / 3.0 * PI * r * r * r)
+			}
+			x if x == eNvFlexShapeCapsule => {
+				let capsule = shape.capsule;
+				let r = capsule.radius;
+				Some(PI * r * r * (4.0 / 3.0 * r + 2.0 * capsule.halfHeight))
+			}
+			x if x == eNvFlexShapeBox => {
+				let half = shape.box_.halfExtents;
+				Some(8.0 * half[0] * half[1] * half[2])
+			}
+			_ => None,
+		}
+	}
+}
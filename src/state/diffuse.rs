@@ -0,0 +1,205 @@
+use nvflex_sys::*;
+use std::mem::size_of;
+
+use crate::{
+	config,
+	grid::HashGrid,
+	types::{Vector3, Vector4},
+};
+
+/// Fluid neighbors under which a diffuse particle is considered spray.
+const SPRAY_NEIGHBORS: usize = 6;
+/// Fluid neighbors above which a diffuse particle is considered a bubble.
+const BUBBLE_NEIGHBORS: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffuseKind {
+	/// Airborne, away from the fluid
+	Spray,
+	/// Floating on the surface
+	Foam,
+	/// Submerged inside the fluid
+	Bubble,
+}
+
+impl DiffuseKind {
+	pub fn name(&self) -> &'static str {
+		match self {
+			DiffuseKind::Spray => "spray",
+			DiffuseKind::Foam => "foam",
+			DiffuseKind::Bubble => "bubble",
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DiffuseParticle {
+	pub position: Vector3,
+	pub velocity: Vector3,
+	/// Seconds left before the particle disappears
+	pub lifetime: f32,
+	pub kind: DiffuseKind,
+}
+
+/// How foam, spray and bubbles spawn and behave, see [super::FlexState::set_diffuse].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiffuseSettings {
+	/// Kinetic energy above which fluid particles spawn diffuse ones. [f32::MAX] disables them.
+	pub threshold: f32,
+	/// Scales gravity for diffuse particles, negative values make bubbles rise.
+	pub buoyancy: f32,
+	/// How much diffuse particles follow the fluid around them.
+	pub drag: f32,
+	/// Seconds a diffuse particle lives.
+	pub lifetime: f32,
+	/// Most diffuse particles alive at once, at most the capacity the solver was created with.
+	pub max_particles: i32,
+}
+
+/// Foam, spray and bubble particles spawned by FleX where the fluid is turbulent.
+/// They're disabled until `diffuseThreshold` is lowered from its default of [f32::MAX].
+#[derive(Debug)]
+pub struct DiffuseState {
+	capacity: i32,
+	/// Diffuse particles past this are dropped after each step, when under the capacity.
+	limit: i32,
+
+	pub positions: *mut NvFlexBuffer,  // Vec<Vector4>, w is the lifetime
+	pub velocities: *mut NvFlexBuffer, // Vec<Vector4>
+	pub count: *mut NvFlexBuffer,      // i32
+}
+
+impl Default for DiffuseState {
+	fn default() -> Self {
+		Self {
+			capacity: config::MAX_DIFFUSE_PARTICLES,
+			limit: config::MAX_DIFFUSE_PARTICLES,
+
+			positions: std::ptr::null_mut(),
+			velocities: std::ptr::null_mut(),
+			count: std::ptr::null_mut(),
+		}
+	}
+}
+
+impl DiffuseState {
+	/// # Safety
+	/// Do not call this function more than once
+	pub unsafe fn alloc(&mut self, flex: *mut NvFlexLibrary, capacity: i32) {
+		self.capacity = capacity;
+		self.limit = capacity;
+
+		self.positions = NvFlexAllocBuffer(
+			flex,
+			capacity,
+			size_of::<Vector4>() as i32,
+			eNvFlexBufferHost,
+		);
+
+		self.velocities = NvFlexAllocBuffer(
+			flex,
+			capacity,
+			size_of::<Vector4>() as i32,
+			eNvFlexBufferHost,
+		);
+
+		self.count = NvFlexAllocBuffer(flex, 1, size_of::<i32>() as i32, eNvFlexBufferHost);
+	}
+
+	pub fn capacity(&self) -> i32 {
+		self.capacity
+	}
+
+	pub fn limit(&self) -> i32 {
+		self.limit
+	}
+
+	/// Caps how many diffuse particles stay alive. Going past the capacity needs a new solver,
+	/// so the limit is clamped to it.
+	pub fn set_limit(&mut self, limit: i32) {
+		self.limit = limit.clamp(0, self.capacity);
+	}
+
+	/// Drops diffuse particles past the limit. Only reads anything back when the limit is lower
+	/// than the capacity, as FleX already stops spawning them at that.
+	/// # Safety
+	/// The solver must be valid.
+	pub unsafe fn trim(&self, solver: *mut NvFlexSolver) {
+		if self.limit >= self.capacity {
+			return;
+		}
+
+		NvFlexGetDiffuseParticles(solver, self.positions, self.velocities, self.count);
+		let count = *(NvFlexMap(self.count, eNvFlexMapWait) as *const i32);
+		NvFlexUnmap(self.count);
+
+		if count > self.limit {
+			NvFlexSetDiffuseParticles(solver, self.positions, self.velocities, self.limit);
+		}
+	}
+
+	/// Reads back every live diffuse particle, classifying them against the fluid `particles`.
+	/// # Safety
+	/// The solver must be valid.
+	pub unsafe fn read(
+		&self,
+		solver: *mut NvFlexSolver,
+		particles: &[Vector4],
+		radius: f32,
+	) -> Vec<DiffuseParticle> {
+		NvFlexGetDiffuseParticles(solver, self.positions, self.velocities, self.count);
+
+		let count = *(NvFlexMap(self.count, eNvFlexMapWait) as *const i32);
+		let count = count.clamp(0, self.limit) as usize;
+
+		let positions = NvFlexMap(self.positions, eNvFlexMapWait) as *const Vector4;
+		let velocities = NvFlexMap(self.velocities, eNvFlexMapWait) as *const Vector4;
+
+		let fluid: Vec<Vector3> = particles.iter().map(|p| p.xyz()).collect();
+		let grid = HashGrid::new(fluid.iter().copied(), radius * 2.0);
+
+		let out = (0 .. count)
+			.map(|i| {
+				let p = *positions.add(i);
+				let v = *velocities.add(i);
+
+				let neighbors = grid.count_within(&fluid, p.xyz(), radius * 2.0);
+				let kind = if neighbors < SPRAY_NEIGHBORS {
+					DiffuseKind::Spray
+				} else if neighbors > BUBBLE_NEIGHBORS {
+					DiffuseKind::Bubble
+				} else {
+					DiffuseKind::Foam
+				};
+
+				DiffuseParticle {
+					position: p.xyz(),
+					velocity: v.xyz(),
+					lifetime: p.3,
+					kind,
+				}
+			})
+			.collect();
+
+		self.unmap();
+		out
+	}
+
+	pub fn unmap(&self) {
+		unsafe {
+			NvFlexUnmap(self.positions);
+			NvFlexUnmap(self.velocities);
+			NvFlexUnmap(self.count);
+		}
+	}
+}
+
+impl Drop for DiffuseState {
+	fn drop(&mut self) {
+		unsafe {
+			NvFlexFreeBuffer(self.positions);
+			NvFlexFreeBuffer(self.velocities);
+			NvFlexFreeBuffer(self.count);
+		}
+	}
+}
@@ -3,18 +3,176 @@ use std::mem::size_of;
 
 use crate::{
 	config,
+	snapshot::{Reader, SnapshotError, Writer},
 	types::{Quat, Vector3, Vector4},
 };
 
-use super::FlexState;
+use super::{mesh::MeshState, FlexState};
+
+/// Rust side copy of a shape's flags and transform, so it can be read without mapping buffers.
+#[derive(Clone, Copy, Debug)]
+pub struct ShapeInfo {
+	pub flags: i32,
+
+	pub position: Vector4,
+	pub rotation: Quat,
+
+	/// Transform at the start of the last step, which FleX and coupling derive velocity from.
+	pub previous_position: Vector4,
+	pub previous_rotation: Quat,
+
+	/// Index of the gmod entity this shape follows, if any.
+	pub entity: Option<i32>,
+
+	/// Whether the shape was moved since the last flush.
+	moved: bool,
+}
+
+impl ShapeInfo {
+	pub fn shape_type(&self) -> NvFlexCollisionShapeType {
+		self.flags & eNvFlexShapeFlagTypeMask
+	}
+
+	pub fn is_dynamic(&self) -> bool {
+		self.flags & eNvFlexShapeFlagDynamic != 0
+	}
+}
+
+/// A shape's geometry as written to snapshots. Meshes are referred to by their index in
+/// [MeshState], as their ids don't survive a reload.
+#[derive(Clone, Copy, Debug)]
+pub enum SavedGeometry {
+	Box(Vector3),
+	Sphere(f32),
+	Capsule { radius: f32, half_height: f32 },
+	Mesh { scale: Vector3, mesh: usize },
+}
+
+impl SavedGeometry {
+	pub fn new(
+		info: &ShapeInfo,
+		geometry: &NvFlexCollisionGeometry,
+		meshes: &MeshState,
+	) -> Result<Self, SnapshotError> {
+		let ty = info.shape_type();
+		unsafe {
+			match ty {
+				x if x == eNvFlexShapeBox => {
+					let [x, y, z] = geometry.box_.halfExtents;
+					Ok(Self::Box(Vector3(x, y, z)))
+				}
+				x if x == eNvFlexShapeSphere => Ok(Self::Sphere(geometry.sphere.radius)),
+				x if x == eNvFlexShapeCapsule => Ok(Self::Capsule {
+					radius: geometry.capsule.radius,
+					half_height: geometry.capsule.halfHeight,
+				}),
+				x if x == eNvFlexShapeTriangleMesh => {
+					let [x, y, z] = geometry.triMesh.scale;
+					let mesh = meshes
+						.position(geometry.triMesh.mesh)
+						.ok_or(SnapshotError::Shape(ty))?;
+					Ok(Self::Mesh {
+						scale: Vector3(x, y, z),
+						mesh,
+					})
+				}
+				_ => Err(SnapshotError::Shape(ty)),
+			}
+		}
+	}
+
+	pub fn shape_type(&self) -> NvFlexCollisionShapeType {
+		match self {
+			Self::Box(_) => eNvFlexShapeBox,
+			Self::Sphere(_) => eNvFlexShapeSphere,
+			Self::Capsule { .. } => eNvFlexShapeCapsule,
+			Self::Mesh { .. } => eNvFlexShapeTriangleMesh,
+		}
+	}
+
+	pub fn write(&self, w: &mut Writer) {
+		w.i32(self.shape_type());
+		match *self {
+			Self::Box(half_extents) => w.vector3(half_extents),
+			Self::Sphere(radius) => w.f32(radius),
+			Self::Capsule {
+				radius,
+				half_height,
+			} => {
+				w.f32(radius);
+				w.f32(half_height);
+			}
+			Self::Mesh { scale, mesh } => {
+				w.vector3(scale);
+				w.count(mesh);
+			}
+		}
+	}
+
+	pub fn read(r: &mut Reader) -> Result<Self, SnapshotError> {
+		match r.i32()? {
+			x if x == eNvFlexShapeBox => Ok(Self::Box(r.vector3()?)),
+			x if x == eNvFlexShapeSphere => Ok(Self::Sphere(r.f32()?)),
+			x if x == eNvFlexShapeCapsule => Ok(Self::Capsule {
+				radius: r.f32()?,
+				half_height: r.f32()?,
+			}),
+			x if x == eNvFlexShapeTriangleMesh => Ok(Self::Mesh {
+				scale: r.vector3()?,
+				mesh: r.u32()? as usize,
+			}),
+			x => Err(SnapshotError::Shape(x)),
+		}
+	}
+
+	/// FleX geometry for this shape, `meshes` holding the ids of the recreated meshes.
+	/// Returns None if the mesh doesn't exist.
+	pub fn geometry(&self, meshes: &[NvFlexTriangleMeshId]) -> Option<NvFlexCollisionGeometry> {
+		let geometry = match *self {
+			Self::Box(Vector3(x, y, z)) => NvFlexCollisionGeometry {
+				box_: NvFlexBoxGeometry {
+					halfExtents: [x, y, z],
+				},
+			},
+			Self::Sphere(radius) => NvFlexCollisionGeometry {
+				sphere: NvFlexSphereGeometry { radius },
+			},
+			Self::Capsule {
+				radius,
+				half_height,
+			} => NvFlexCollisionGeometry {
+				capsule: NvFlexCapsuleGeometry {
+					radius,
+					halfHeight: half_height,
+				},
+			},
+			Self::Mesh {
+				scale: Vector3(x, y, z),
+				mesh,
+			} => NvFlexCollisionGeometry {
+				triMesh: NvFlexTriangleMeshGeometry {
+					scale: [x, y, z],
+					mesh: *meshes.get(mesh)?,
+				},
+			},
+		};
+
+		Some(geometry)
+	}
+}
 
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
 pub struct GeometryState {
 	#[derivative(Debug = "ignore")]
 	shapes: Vec<NvFlexCollisionGeometry>,
+	info: Vec<ShapeInfo>,
 	count: i32,
+	capacity: i32,
 	has_changes: bool,
+	/// Whether shapes were pushed with a previous transform differing from their current one,
+	/// which has to be pushed again once they stop or FleX keeps moving them.
+	moving: bool,
 
 	pub buffer: *mut NvFlexBuffer,
 
@@ -31,8 +189,11 @@ impl Default for GeometryState {
 	fn default() -> Self {
 		Self {
 			shapes: vec![],
+			info: vec![],
 			count: 0,
+			capacity: config::MAX_SHAPES,
 			has_changes: true,
+			moving: false,
 
 			buffer: std::ptr::null_mut(),
 
@@ -50,45 +211,47 @@ impl GeometryState {
 	/// Allocates buffers used by the geometry state
 	/// # Safety
 	/// Do not call this function more than once
-	pub unsafe fn alloc(&mut self, flex: *mut NvFlexLibrary) {
+	pub unsafe fn alloc(&mut self, flex: *mut NvFlexLibrary, capacity: i32) {
+		self.capacity = capacity;
+
 		self.buffer = NvFlexAllocBuffer(
 			flex,
-			config::MAX_SHAPES,
+			capacity,
 			size_of::<NvFlexCollisionGeometry>() as i32,
 			eNvFlexBufferHost,
 		);
 
 		self.positions = NvFlexAllocBuffer(
 			flex,
-			config::MAX_SHAPES,
+			capacity,
 			size_of::<Vector4>() as i32,
 			eNvFlexBufferHost,
 		);
 
 		self.rotations = NvFlexAllocBuffer(
 			flex,
-			config::MAX_SHAPES,
+			capacity,
 			size_of::<Quat>() as i32,
 			eNvFlexBufferHost,
 		);
 
 		self.previous_positions = NvFlexAllocBuffer(
 			flex,
-			config::MAX_SHAPES,
+			capacity,
 			size_of::<Vector4>() as i32,
 			eNvFlexBufferHost,
 		);
 
 		self.previous_rotations = NvFlexAllocBuffer(
 			flex,
-			config::MAX_SHAPES,
+			capacity,
 			size_of::<Quat>() as i32,
 			eNvFlexBufferHost,
 		);
 
 		self.flags = NvFlexAllocBuffer(
 			flex,
-			config::MAX_SHAPES,
+			capacity,
 			size_of::<i32>() as i32,
 			eNvFlexBufferHost,
 		);
@@ -98,14 +261,34 @@ impl GeometryState {
 		self.count
 	}
 
+	/// Most shapes there can be at once.
+	pub fn capacity(&self) -> i32 {
+		self.capacity
+	}
+
+	/// Adds a shape to the geometry buffers, returning its index.
+	/// Returns None if the capacity has been reached.
 	pub fn add_shape(
 		&mut self,
 		shape: NvFlexCollisionGeometry,
 		pos: Vector4,
 		rot: Quat,
 		flag: i32,
-	) {
+	) -> Option<usize> {
+		if self.count >= self.capacity {
+			return None;
+		}
+
 		self.shapes.push(shape);
+		self.info.push(ShapeInfo {
+			flags: flag,
+			position: pos,
+			rotation: rot,
+			previous_position: pos,
+			previous_rotation: rot,
+			entity: None,
+			moved: false,
+		});
 
 		let count = self.count as isize;
 
@@ -136,6 +319,100 @@ impl GeometryState {
 
 		self.count += 1;
 		self.has_changes = true;
+
+		Some(count as usize)
+	}
+
+	/// Removes every shape.
+	pub fn clear(&mut self) {
+		self.shapes.clear();
+		self.info.clear();
+		self.count = 0;
+		self.has_changes = true;
+		self.moving = false;
+	}
+
+	/// Moves a shape. The transform it had at the last flush stays the previous one,
+	/// so FleX can derive its velocity over the next step even if it's moved several times.
+	/// Returns false if the shape doesn't exist.
+	pub fn set_transform(&mut self, index: usize, pos: Vector4, rot: Quat) -> bool {
+		let info = match self.info.get_mut(index) {
+			Some(info) => info,
+			None => return false,
+		};
+
+		if !info.moved {
+			info.previous_position = info.position;
+			info.previous_rotation = info.rotation;
+			info.moved = true;
+		}
+		info.position = pos;
+		info.rotation = rot;
+
+		let ind = index as isize;
+		unsafe {
+			let positions = NvFlexMap(self.positions, eNvFlexMapWait) as *mut Vector4;
+			let rotations = NvFlexMap(self.rotations, eNvFlexMapWait) as *mut Quat;
+
+			positions.offset(ind).write(info.position);
+			rotations.offset(ind).write(info.rotation);
+
+			NvFlexUnmap(self.positions);
+			NvFlexUnmap(self.rotations);
+		}
+
+		self.has_changes = true;
+		true
+	}
+
+	/// Changes which phase channels a shape collides with, as a mask of eNvFlexPhaseShapeChannel* bits.
+	/// Returns false if the shape doesn't exist.
+	pub fn set_channels(&mut self, index: usize, channels: i32) -> bool {
+		let info = match self.info.get_mut(index) {
+			Some(info) => info,
+			None => return false,
+		};
+
+		info.flags = (info.flags & !eNvFlexPhaseShapeChannelMask)
+			| (channels & eNvFlexPhaseShapeChannelMask);
+
+		unsafe {
+			let flags = NvFlexMap(self.flags, eNvFlexMapWait) as *mut i32;
+			flags.add(index).write(info.flags);
+			NvFlexUnmap(self.flags);
+		}
+
+		self.has_changes = true;
+		true
+	}
+
+	/// Binds a shape to a gmod entity index, so coupling forces get reported for it.
+	pub fn bind_entity(&mut self, index: usize, entity: Option<i32>) -> bool {
+		match self.info.get_mut(index) {
+			Some(info) => {
+				info.entity = entity;
+				true
+			}
+			None => false,
+		}
+	}
+
+	pub fn get_info(&self, index: usize) -> Option<&ShapeInfo> {
+		self.info.get(index)
+	}
+
+	pub fn get_geometry(&self, index: usize) -> Option<&NvFlexCollisionGeometry> {
+		self.shapes.get(index)
+	}
+
+	/// Iterates over every shape that is bound to an entity.
+	pub fn bound(&self) -> impl Iterator<Item = (usize, &ShapeInfo, &NvFlexCollisionGeometry)> {
+		self.info
+			.iter()
+			.zip(self.shapes.iter())
+			.enumerate()
+			.filter(|(_, (info, _))| info.entity.is_some())
+			.map(|(i, (info, shape))| (i, info, shape))
 	}
 
 	pub fn unmap(&self) {
@@ -153,10 +430,32 @@ impl GeometryState {
 	/// # Safety
 	/// Make sure that all of the buffers have been unmapped before calling this.
 	pub unsafe fn flush(&mut self, solver: *mut NvFlexSolver) {
-		if !self.has_changes {
+		if !self.has_changes && !self.moving {
 			return;
 		}
 
+		// Shapes that weren't moved since the last flush are at rest, so their previous transform
+		// catches up with the current one, or FleX and coupling would see them moving forever.
+		let previous_positions = NvFlexMap(self.previous_positions, eNvFlexMapWait) as *mut Vector4;
+		let previous_rotations = NvFlexMap(self.previous_rotations, eNvFlexMapWait) as *mut Quat;
+
+		self.moving = false;
+		for (i, info) in self.info.iter_mut().enumerate() {
+			if info.moved {
+				self.moving = true;
+			} else {
+				info.previous_position = info.position;
+				info.previous_rotation = info.rotation;
+			}
+			info.moved = false;
+
+			previous_positions.add(i).write(info.previous_position);
+			previous_rotations.add(i).write(info.previous_rotation);
+		}
+
+		NvFlexUnmap(self.previous_positions);
+		NvFlexUnmap(self.previous_rotations);
+
 		NvFlexSetShapes(
 			solver,
 			self.buffer,
@@ -0,0 +1,96 @@
+use nvflex_sys::*;
+
+use crate::types::{Vector3, Vector4};
+
+use super::buffer::HostBuffer;
+
+/// A triangle mesh uploaded to FleX, for mesh collision shapes.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct TriangleMesh {
+	pub id: NvFlexTriangleMeshId,
+	/// File the mesh was loaded from, if any, so scenes can refer back to it
+	pub file: Option<String>,
+
+	/// Copies of what was uploaded, for snapshots and exports
+	#[derivative(Debug = "ignore")]
+	pub vertices: Vec<Vector3>,
+	#[derivative(Debug = "ignore")]
+	pub indices: Vec<u32>,
+
+	#[derivative(Debug = "ignore")]
+	vertex_buffer: HostBuffer<Vector4>,
+	#[derivative(Debug = "ignore")]
+	index_buffer: HostBuffer<i32>,
+}
+
+#[derive(Debug, Default)]
+pub struct MeshState {
+	meshes: Vec<TriangleMesh>,
+}
+
+impl MeshState {
+	/// Uploads a mesh with zero based `indices`, returning its id for [NvFlexTriangleMeshGeometry].
+	/// # Safety
+	/// `flex` must be the library the solver was created with.
+	pub unsafe fn create(
+		&mut self,
+		flex: *mut NvFlexLibrary,
+		vertices: &[Vector3],
+		indices: &[u32],
+		file: Option<String>,
+	) -> NvFlexTriangleMeshId {
+		let id = NvFlexCreateTriangleMesh(flex);
+
+		let (lower, upper) = crate::voxel::bounds(vertices).unwrap_or_default();
+		let points: Vec<Vector4> = vertices.iter().map(|v| Vector4(v.0, v.1, v.2, 0.0)).collect();
+		let triangles: Vec<i32> = indices.iter().map(|i| *i as i32).collect();
+
+		let mut mesh = TriangleMesh {
+			id,
+			file,
+			vertices: vertices.to_vec(),
+			indices: indices.to_vec(),
+			vertex_buffer: HostBuffer::default(),
+			index_buffer: HostBuffer::default(),
+		};
+		mesh.vertex_buffer.upload(flex, &points);
+		mesh.index_buffer.upload(flex, &triangles);
+
+		NvFlexUpdateTriangleMesh(
+			flex,
+			id,
+			mesh.vertex_buffer.buffer,
+			mesh.index_buffer.buffer,
+			points.len() as i32,
+			(triangles.len() / 3) as i32,
+			[lower.0, lower.1, lower.2].as_ptr(),
+			[upper.0, upper.1, upper.2].as_ptr(),
+		);
+
+		self.meshes.push(mesh);
+		id
+	}
+
+	pub fn get(&self, id: NvFlexTriangleMeshId) -> Option<&TriangleMesh> {
+		self.meshes.iter().find(|mesh| mesh.id == id)
+	}
+
+	/// Index of a mesh in creation order, which is also the order of [Self::iter].
+	pub fn position(&self, id: NvFlexTriangleMeshId) -> Option<usize> {
+		self.meshes.iter().position(|mesh| mesh.id == id)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &TriangleMesh> {
+		self.meshes.iter()
+	}
+
+	/// Destroys every mesh, which has to happen before the library shuts down.
+	/// # Safety
+	/// `flex` must be the library the meshes were created with, and no shape can still use them.
+	pub unsafe fn destroy(&mut self, flex: *mut NvFlexLibrary) {
+		for mesh in self.meshes.drain(..) {
+			NvFlexDestroyTriangleMesh(flex, mesh.id);
+		}
+	}
+}
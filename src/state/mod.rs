@@ -4,24 +4,87 @@ use std::time::Instant;
 // State holding all of the data for FleX.
 use crate::{
 	config,
+	emitter::{Emitters, KillVolume},
+	export::{self, Frame, Mesh},
+	forces::{ForceFields, Impulse},
+	health::{HealthMonitor, Response},
 	helper::*,
+	params::{self, ParamError},
+	render::{self, Camera, ViewParticle},
+	replay::Recorder,
+	replication::Replication,
+	scene::Scene,
+	snapshot::{self, Reader, SnapshotError, Writer},
+	stats::Stats,
+	surface::{self, SurfaceMesh, SurfaceParams},
 	types::{Particle, Quat, Vector3, Vector4},
+	voxel,
 };
 use nvflex_sys::*;
 
+mod buffer;
+
+pub mod attribute;
+use attribute::AttributeState;
+
+#[cfg(feature = "anisotropy")]
+pub mod anisotropy;
+#[cfg(feature = "anisotropy")]
+use anisotropy::{AnisotropyState, Ellipsoid};
+
+pub mod cloth;
+use cloth::{ClothStiffness, ClothState, Pin};
+
+pub mod contact;
+use contact::ContactState;
+
+pub mod coupling;
+use coupling::CouplingState;
+
+pub mod diffuse;
+use diffuse::{DiffuseParticle, DiffuseSettings, DiffuseState};
+
 mod geometry;
-use geometry::GeometryState;
+use geometry::{GeometryState, SavedGeometry};
+
+pub mod mesh;
+use mesh::MeshState;
+
+pub mod neighbor;
+use neighbor::NeighborState;
 
 mod particle;
+pub use particle::factory::{Lattice, ParticleFactory};
 use particle::ParticleState;
 
-#[derive(Debug)]
+pub mod phase;
+use phase::{PhaseGroup, PhaseState};
+
+mod rigid;
+use rigid::RigidState;
+
+pub mod soft;
+use soft::{SoftParams, SoftState};
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
 pub struct FlexState {
 	/* Shared */
 	initialized: bool,
 	instant: Instant,
 	lib: *mut NvFlexLibrary,
 
+	/// Solver parameters, pushed to FleX on the next tick when [Self::params_changed] is set.
+	#[derivative(Debug = "ignore")]
+	pub params: NvFlexParams,
+	pub params_changed: bool,
+
+	pub phases: PhaseState,
+	/// Room allocated for particles and shapes, set when initializing.
+	capacity: config::Capacity,
+	/// Seconds simulated so far
+	time: f32,
+
 	/// Note this will most likely be null.
 	desc: *mut NvFlexInitDesc,
 
@@ -29,7 +92,27 @@ pub struct FlexState {
 	pub solver: *mut NvFlexSolver,
 
 	pub particles: ParticleState,
+	pub attributes: AttributeState,
 	pub geometry: GeometryState,
+	pub meshes: MeshState,
+	pub coupling: CouplingState,
+	pub contacts: ContactState,
+	pub neighbors: NeighborState,
+	pub rigids: RigidState,
+	pub cloth: ClothState,
+	pub soft: SoftState,
+	pub forces: ForceFields,
+	pub emitters: Emitters,
+	pub kill_volumes: Vec<KillVolume>,
+	pub health: HealthMonitor,
+	pub stats: Stats,
+	pub diffuse: DiffuseState,
+	#[cfg(feature = "anisotropy")]
+	pub anisotropy: AnisotropyState,
+
+	/// Records every input while set, see [crate::replay].
+	pub recorder: Option<Recorder>,
+	pub replication: Replication,
 }
 
 impl Default for FlexState {
@@ -39,6 +122,13 @@ impl Default for FlexState {
 			instant: Instant::now(),
 			lib: std::ptr::null_mut(),
 
+			params: config::PARAMS,
+			params_changed: false,
+
+			phases: PhaseState::default(),
+			capacity: config::Capacity::default(),
+			time: 0.0,
+
 			desc: std::ptr::null_mut(),
 
 			solver_desc: MaybeUninit::uninit(),
@@ -47,7 +137,54 @@ impl Default for FlexState {
 			/* Separate States */
 
 			particles: ParticleState::default(),
+			attributes: AttributeState::default(),
 			geometry: GeometryState::default(),
+			meshes: MeshState::default(),
+			coupling: CouplingState::default(),
+			contacts: ContactState::default(),
+			neighbors: NeighborState::default(),
+			rigids: RigidState::default(),
+			cloth: ClothState::default(),
+			soft: SoftState::default(),
+			forces: ForceFields::default(),
+			emitters: Emitters::default(),
+			kill_volumes: vec![],
+			health: HealthMonitor::default(),
+			stats: Stats::default(),
+			diffuse: DiffuseState::default(),
+			#[cfg(feature = "anisotropy")]
+			anisotropy: AnisotropyState::default(),
+
+			recorder: None,
+			replication: Replication::default(),
+		}
+	}
+}
+
+/// Which API FleX runs its solver on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+	#[default]
+	Cuda,
+	D3D11,
+	D3D12,
+}
+
+impl Backend {
+	pub fn parse(name: &str) -> Option<Self> {
+		Some(match name {
+			"cuda" => Backend::Cuda,
+			"d3d11" | "dx11" => Backend::D3D11,
+			"d3d12" | "dx12" => Backend::D3D12,
+			_ => return None,
+		})
+	}
+
+	fn compute_type(&self) -> NvFlexComputeType {
+		match self {
+			Backend::Cuda => eNvFlexCUDA,
+			Backend::D3D11 => eNvFlexD3D11,
+			Backend::D3D12 => eNvFlexD3D12,
 		}
 	}
 }
@@ -56,85 +193,100 @@ impl Default for FlexState {
 pub enum InitError {
 	#[error("Failed to create Flex Library")]
 	NvFlexInit,
+	#[error("Invalid default scene: {0}")]
+	Scene(String),
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum ShutdownError {}
 
+/// Scene [FlexState::init] starts with.
+const DEFAULT_SCENE: &str = include_str!("../../scenes/default.toml");
+
 impl FlexState {
 	pub fn new() -> Self {
 		Self::default()
 	}
 
+	/// Starts FleX on the default backend with the built in scene, see scenes/default.toml.
 	pub fn init(&mut self) -> Result<&mut Self, InitError> {
+		let scene = Scene::parse(DEFAULT_SCENE).map_err(|e| InitError::Scene(e.to_string()))?;
+		self.init_with(Backend::default(), 0, scene.capacity)?;
+		scene
+			.apply(self)
+			.map_err(|e| InitError::Scene(e.to_string()))?;
+
+		// This will call all of the NvFlexSet* functions
 		unsafe {
-			use std::mem::size_of;
+			self.particles.flush(self.solver);
+			self.geometry.flush(self.solver);
+		}
 
-			let flex = NvFlexInit(NV_FLEX_VERSION as i32, None, std::ptr::null_mut());
+		Ok(self)
+	}
+
+	/// Starts FleX on a backend and GPU, without any shapes or particles.
+	pub fn init_with(
+		&mut self,
+		backend: Backend,
+		device: i32,
+		capacity: config::Capacity,
+	) -> Result<&mut Self, InitError> {
+		self.capacity = capacity;
+
+		unsafe {
+			let mut init_desc = NvFlexInitDesc {
+				deviceIndex: device,
+				enableExtensions: true,
+				renderDevice: std::ptr::null_mut(),
+				renderContext: std::ptr::null_mut(),
+				computeContext: std::ptr::null_mut(),
+				runOnRenderContext: false,
+				computeType: backend.compute_type(),
+			};
+
+			let flex = NvFlexInit(NV_FLEX_VERSION as i32, None, &mut init_desc);
 
 			if flex.is_null() {
 				return Err(InitError::NvFlexInit);
 			}
 
 			NvFlexSetSolverDescDefaults(self.solver_desc.as_mut_ptr());
+			let desc = &mut *self.solver_desc.as_mut_ptr();
+			desc.maxParticles = capacity.particles;
+			desc.maxContactsPerParticle = capacity.contacts_per_particle;
+			desc.maxDiffuseParticles = capacity.diffuse_particles;
+			desc.maxNeighborsPerParticle = capacity.neighbors_per_particle;
 
 			self.solver = NvFlexCreateSolver(flex, self.solver_desc.as_ptr());
 
 			self.particles = ParticleState::default();
-			self.particles.alloc(flex);
+			self.particles.alloc(flex, capacity.particles);
 
 			self.geometry = GeometryState::default();
-			self.geometry.alloc(flex);
+			self.geometry.alloc(flex, capacity.shapes);
 
-			let baux = NvFlexCollisionGeometry {
-				box_: NvFlexBoxGeometry {
-					halfExtents: [50000.0, 50000.0, 5.0],
-				},
-			};
+			self.contacts = ContactState::default();
+			self.contacts.alloc(flex, &capacity);
 
-			let flag = NvFlexMakeShapeFlags(eNvFlexShapeBox, false);
-			self.geometry.add_shape(
-				baux,
-				Vector4(0.0, 0.0, 0.0, 0.0),
-				Quat(0.0, 0.0, 0.0, 0.0),
-				flag,
-			);
-
-			self.geometry.add_shape(
-				baux,
-				Vector4(0.0, 0.0, 0.0, 0.0),
-				Quat(0.0, 1.0, 0.0, 0.0),
-				flag,
-			);
-
-			self.geometry.add_shape(
-				baux,
-				Vector4(0.0, 0.0, 0.0, 0.0),
-				Quat(1.0, 0.0, 0.0, 0.0),
-				flag,
-			);
-
-			let fluid = NvFlexMakePhase(0, eNvFlexPhaseSelfCollide | eNvFlexPhaseFluid);
-
-			self.particles.factory(|mut x| {
-				for i in 0 .. config::MAX_PARTICLES {
-					x.create(
-						Vector4(50.0 * i as f32, 0.0, 5000.0, 2.0),
-						Vector3(0.0, 0.0, -5.0),
-						fluid,
-						true
-					);
-				}
-			});
+			self.neighbors = NeighborState::default();
+			self.neighbors.alloc(flex, &capacity);
 
-			// Unmap here
+			self.diffuse = DiffuseState::default();
+			self.diffuse.alloc(flex, capacity.diffuse_particles);
 
-			// Transfer data
-			NvFlexSetParams(self.solver, &config::PARAMS);
+			#[cfg(feature = "anisotropy")]
+			{
+				self.anisotropy = AnisotropyState::default();
+				self.anisotropy.alloc(flex, capacity.particles);
+			}
 
-			// This will call all of the NvFlexSet* functions
-			self.particles.flush(self.solver);
-			self.geometry.flush(self.solver);
+			self.phases = PhaseState::default();
+			let flags = eNvFlexPhaseSelfCollide | eNvFlexPhaseFluid;
+			self.phases.define(phase::FLUID, flags, eNvFlexPhaseShapeChannelMask);
+
+			// Transfer data
+			NvFlexSetParams(self.solver, &self.params);
 
 			self.lib = flex;
 		}
@@ -144,15 +296,1011 @@ impl FlexState {
 		Ok(self)
 	}
 
+	/// Room allocated for particles and shapes when initializing.
+	pub fn capacity(&self) -> config::Capacity {
+		self.capacity
+	}
+
+	/// Phase of the default fluid group.
+	pub fn fluid_phase(&self) -> i32 {
+		self.phases
+			.get(phase::FLUID)
+			.map(|group| group.phase())
+			.unwrap_or_else(|| NvFlexMakePhase(0, eNvFlexPhaseSelfCollide | eNvFlexPhaseFluid))
+	}
+
 	pub fn tick(&mut self) {
+		let dt = self.instant.elapsed().as_secs_f32();
+		self.instant = Instant::now();
+		self.step(dt);
+	}
+
+	/// Hands the recorder, if any, the state it's recording.
+	fn record(&mut self, f: impl FnOnce(&mut Recorder, &Self)) {
+		if let Some(mut recorder) = self.recorder.take() {
+			f(&mut recorder, self);
+			self.recorder = Some(recorder);
+		}
+	}
+
+	/// Brings the recorder up to date before an input it records explicitly.
+	fn sync_recorder(&mut self) {
+		self.record(|recorder, state| recorder.capture(state));
+	}
+
+	/// Advances the simulation by `dt` seconds.
+	pub fn step(&mut self, dt: f32) {
+		let start = Instant::now();
+		self.simulate(dt);
+		self.stats.end_step(start.elapsed());
+
+		if self.stats.timers_enabled {
+			unsafe { self.stats.read_timers(self.solver) };
+		}
+
+		self.record(|recorder, state| recorder.after_step(state));
+	}
+
+	fn simulate(&mut self, dt: f32) {
+		self.time += dt;
+
+		if self.emitters.any_enabled() {
+			self.emit(dt);
+		}
+
+		// After emitting, so emitted fluid is recorded as spawned before this tick
+		self.record(|recorder, state| recorder.before_step(state, dt));
+
+		unsafe {
+			// Push anything that changed since the last tick, like moved shapes.
+			if self.params_changed {
+				NvFlexSetParams(self.solver, &self.params);
+				self.params_changed = false;
+			}
+
+			self.particles.flush(self.solver);
+			self.geometry.flush(self.solver);
+			self.rigids.flush(self.lib, self.solver);
+			self.cloth.flush(self.lib, self.solver);
+
+			if !self.cloth.pins.is_empty() {
+				let (pins, geometry) = (&self.cloth.pins, &self.geometry);
+				self.particles.modify(self.solver, |positions, velocities| {
+					for pin in pins {
+						let index = pin.particle as usize;
+						let info = geometry.get_info(pin.shape);
+
+						if let (Some(info), Some(pos)) = (info, positions.get_mut(index)) {
+							*pos = cloth::pin_position(pin, info.position, info.rotation);
+							velocities[index] = Vector3::default();
+						}
+					}
+				});
+			}
+
+			if self.forces.any_enabled() {
+				let groups: Vec<i32> = self
+					.particles
+					.read_phases(self.solver)
+					.iter()
+					.map(|phase| phase & eNvFlexPhaseGroupMask)
+					.collect();
+
+				let (forces, time) = (&self.forces, self.time);
+				self.particles.modify(self.solver, |positions, velocities| {
+					let positions = positions.iter().map(|p| p.xyz());
+					forces.apply(positions, velocities, &groups, dt, time);
+				});
+			}
+
+			NvFlexUpdateSolver(self.solver, dt, 1, self.stats.timers_enabled);
+
+			if self.params.diffuseThreshold < f32::MAX {
+				self.diffuse.trim(self.solver);
+			}
+
+			self.rigids.read(self.solver);
+		}
+
+		let coupled = self.geometry.bound().next().is_some();
+		let diffusing = self.attributes.color_diffusion > 0.0;
+		let draining = !self.kill_volumes.is_empty();
+		let monitored = self.health.enabled;
+		let readback = coupled || self.contacts.enabled || self.neighbors.enabled || diffusing;
+		if readback || draining || monitored {
+			let start = Instant::now();
+			let (positions, velocities) = unsafe { self.particles.read(self.solver) };
+			self.stats.add_readback(start.elapsed());
+
+			if monitored && !self.check_health(&positions, &velocities) {
+				// Rolled back, so what was just read is gone
+				return;
+			}
+
+			if draining {
+				let drained: Vec<i32> = self
+					.particles
+					.get_active()
+					.iter()
+					.copied()
+					.filter(|&i| {
+						let p = positions[i as usize].xyz();
+						self.kill_volumes.iter().any(|volume| volume.contains(p))
+					})
+					.collect();
+
+				self.remove_particles(&drained);
+			}
+
+			if coupled {
+				let (params, geometry) = (&self.params, &self.geometry);
+				self.coupling.update(params, geometry, &positions, &velocities, dt);
+			}
+
+			if self.contacts.enabled {
+				let nshapes = self.geometry.get_count() as usize;
+				let (solver, active) = (self.solver, self.particles.get_active());
+				unsafe { self.contacts.update(solver, &velocities, active, nshapes) };
+			}
+
+			if self.neighbors.enabled {
+				let active = self.particles.get_active().len();
+				let (solver, params) = (self.solver, &self.params);
+				unsafe { self.neighbors.update(solver, &positions, active, params) };
+			}
+
+			if diffusing {
+				let positions: Vec<Vector3> = positions.iter().map(|p| p.xyz()).collect();
+				let radius = self.params.radius * 2.0;
+				let active = self.particles.get_active();
+				self.attributes.diffuse_colors(&positions, active, radius, dt);
+			}
+		}
+	}
+
+	/// Runs the health checks on particles just read back, dealing with the ones that fail.
+	/// Returns false if the simulation was rolled back instead.
+	fn check_health(&mut self, positions: &[Vector4], velocities: &[Vector3]) -> bool {
+		let active = self.particles.get_active();
+		let mut check = self.health.check(positions, velocities, active, self.params.maxSpeed);
+
+		if check.is_fatal() && self.health.rollback {
+			let snapshot = self.health.last_good().map(<[u8]>::to_vec).unwrap_or_default();
+			if !snapshot.is_empty() && self.load_snapshot(&snapshot).is_ok() {
+				// Replays can't redo the rollback as health checks don't run there
+				self.record(|recorder, state| recorder.rolled_back(state, snapshot));
+				check.report.rollbacks += 1;
+				self.health.record(&check.report);
+				return false;
+			}
+		}
+
+		self.health.record(&check.report);
+
+		match self.health.response {
+			Response::Quarantine if !check.offenders.is_empty() => {
+				self.remove_particles(&check.offenders);
+
+				let active = self.particles.get_active();
+				let stopped = check.offenders.iter().filter(|id| !active.contains(*id));
+				self.health.quarantined.extend(stopped);
+			}
+			Response::Remove => {
+				self.remove_particles(&check.offenders);
+			}
+			_ => (),
+		}
+
+		if check.report.is_healthy() && self.health.wants_snapshot() {
+			// Only fails with shapes of an unknown type, which can't be rolled back then
+			if let Ok(snapshot) = self.save_snapshot() {
+				self.health.keep_snapshot(snapshot);
+			}
+		}
+
+		true
+	}
+
+	/// Spawns whatever fluid the emitters owe after `dt` seconds.
+	fn emit(&mut self, dt: f32) {
+		let spacing = config::rest_distance(&self.params);
+		let streams: Vec<_> = self
+			.emitters
+			.iter_mut()
+			.filter_map(|emitter| Some((emitter.advance(dt, spacing)?, emitter.clone())))
+			.collect();
+
+		for (length, emitter) in streams {
+			let lattice = self.lattice(emitter.phase);
+			self.generate(|factory| {
+				let (origin, direction) = (emitter.origin, emitter.direction);
+				factory.stream(origin, direction, emitter.radius, length, emitter.speed, &lattice)
+			});
+		}
+	}
+
+	/// Allocates a new phase group, for bodies that shouldn't collide with themselves.
+	pub fn next_group(&mut self) -> i32 {
+		self.phases.next_group()
+	}
+
+	/// Creates or redefines a named phase group, updating any particles already in it.
+	pub fn define_phase_group(&mut self, name: &str, flags: i32, channels: i32) -> PhaseGroup {
+		let group = self.phases.define(name, flags, channels);
+
+		unsafe {
+			self.particles.modify_phases(self.solver, |phases| {
+				for phase in phases {
+					if *phase & eNvFlexPhaseGroupMask == group.group {
+						*phase = group.phase();
+					}
+				}
+			});
+		}
+
+		group
+	}
+
+	/// Sets a solver parameter by name, see [params].
+	pub fn set_param(&mut self, name: &str, values: &[f32]) -> Result<(), ParamError> {
+		params::set(&mut self.params, name, values)?;
+		self.params_changed = true;
+		Ok(())
+	}
+
+	/// Adds a dynamic shape at the origin, optionally bound to a gmod entity.
+	pub fn add_dynamic_shape(
+		&mut self,
+		shape: NvFlexCollisionGeometry,
+		ty: NvFlexCollisionShapeType,
+		entity: Option<i32>,
+	) -> Option<usize> {
+		let index = self.geometry.add_shape(
+			shape,
+			Vector4(0.0, 0.0, 0.0, 0.0),
+			Quat(0.0, 0.0, 0.0, 1.0),
+			NvFlexMakeShapeFlags(ty, true),
+		)?;
+
+		self.geometry.bind_entity(index, entity);
+		Some(index)
+	}
+
+	/// Adds a static triangle mesh collider with zero based `indices`, scaled by `scale`.
+	/// `file` is remembered so the scene can be saved back out, see [crate::scene].
+	pub fn add_mesh_shape(
+		&mut self,
+		vertices: &[Vector3],
+		indices: &[u32],
+		transform: (Vector4, Quat, Vector3),
+		file: Option<String>,
+	) -> Option<usize> {
+		if self.geometry.get_count() >= self.geometry.capacity() {
+			return None;
+		}
+
+		let (position, rotation, scale) = transform;
+		let mesh = unsafe { self.meshes.create(self.lib, vertices, indices, file) };
+		let geometry = NvFlexCollisionGeometry {
+			triMesh: NvFlexTriangleMeshGeometry {
+				scale: [scale.0, scale.1, scale.2],
+				mesh,
+			},
+		};
+
+		let flags = NvFlexMakeShapeFlags(eNvFlexShapeTriangleMesh, false);
+		self.geometry.add_shape(geometry, position, rotation, flags)
+	}
+
+	/// Creates a rigid body out of particles at `center` + each of `points`, returning its handle.
+	/// Returns None if there isn't room for all of its particles.
+	pub fn add_rigid(
+		&mut self,
+		points: &[Vector3],
+		center: Vector3,
+		stiffness: f32,
+		threshold: f32,
+		creep: f32,
+	) -> Option<usize> {
+		let phase = NvFlexMakePhase(self.next_group(), 0);
+		let points: Vec<Vector3> = points.iter().map(|point| center + *point).collect();
+
+		let (ids, positions) = self.spawn(&points, phase)?;
+		Some(self.rigids.add(&ids, &positions, stiffness, threshold, creep))
+	}
+
+	/// Creates a hanging sheet of cloth, spanning +x and -z from `origin`, returning its handle.
+	/// Returns None if there isn't room for all of its particles.
+	pub fn add_cloth(
+		&mut self,
+		origin: Vector3,
+		columns: usize,
+		rows: usize,
+		spacing: f32,
+		stiffness: ClothStiffness,
+	) -> Option<usize> {
+		let count = columns.checked_mul(rows).filter(|&count| count <= self.room())?;
+		let flags = eNvFlexPhaseSelfCollide | eNvFlexPhaseSelfCollideFilter;
+		let phase = NvFlexMakePhase(self.next_group(), flags);
+
+		let mut positions = Vec::with_capacity(count);
+		for y in 0 .. rows {
+			for x in 0 .. columns {
+				positions.push(origin + Vector3(x as f32 * spacing, 0.0, -(y as f32) * spacing));
+			}
+		}
+
+		let (ids, positions) = self.spawn(&positions, phase)?;
+		Some(self.cloth.add_cloth(ids, &positions, columns, rows, stiffness))
+	}
+
+	fn cloth_particle(&self, cloth: usize, vertex: usize) -> Option<i32> {
+		self.cloth.cloths.get(cloth)?.particles.get(vertex).copied()
+	}
+
+	/// Pins a cloth vertex in place, optionally moving it to `position` first.
+	pub fn pin_cloth(&mut self, cloth: usize, vertex: usize, position: Option<Vector3>) -> bool {
+		let particle = match self.cloth_particle(cloth, vertex) {
+			Some(particle) => particle as usize,
+			None => return false,
+		};
+
+		unsafe {
+			self.particles.modify(self.solver, |positions, velocities| {
+				if let Some(pos) = positions.get_mut(particle) {
+					let at = position.unwrap_or_else(|| pos.xyz());
+					// Zero inverse mass makes the particle immovable
+					*pos = Vector4(at.0, at.1, at.2, 0.0);
+					velocities[particle] = Vector3::default();
+				}
+			});
+		}
+
+		true
+	}
+
+	/// Pins a cloth vertex to a shape, so it follows it (and the entity it's bound to) around.
+	pub fn pin_cloth_to_shape(&mut self, cloth: usize, vertex: usize, shape: usize) -> bool {
+		let particle = match self.cloth_particle(cloth, vertex) {
+			Some(particle) => particle,
+			None => return false,
+		};
+
+		let info = match self.geometry.get_info(shape) {
+			Some(info) => *info,
+			None => return false,
+		};
+
+		let mut offset = None;
+		unsafe {
+			self.particles.modify(self.solver, |positions, _| {
+				if let Some(pos) = positions.get_mut(particle as usize) {
+					let local = pos.xyz() - info.position.xyz();
+					offset = Some(info.rotation.conjugate().rotate(local));
+					pos.3 = 0.0;
+				}
+			});
+		}
+
+		match offset {
+			Some(offset) => {
+				self.cloth.pins.push(Pin {
+					particle,
+					shape,
+					offset,
+				});
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Current vertex positions and smooth normals of a cloth, for rendering.
+	pub fn get_cloth_mesh(&self, cloth: usize) -> Option<(Vec<Vector3>, Vec<Vector3>)> {
+		let cloth = self.cloth.cloths.get(cloth)?;
+		let (positions, _) = unsafe { self.particles.read(self.solver) };
+
+		let vertices: Vec<Vector3> = cloth
+			.particles
+			.iter()
+			.map(|p| positions.get(*p as usize).map(|v| v.xyz()).unwrap_or_default())
+			.collect();
+
+		let normals = cloth::vertex_normals(&vertices, &cloth.indices);
+		Some((vertices, normals))
+	}
+
+	/// Creates particles at each of `points`, returning their indices and positions.
+	/// Returns None if there isn't room for all of them.
+	fn spawn(&mut self, points: &[Vector3], phase: i32) -> Option<(Vec<i32>, Vec<Vector3>)> {
+		if points.is_empty() || points.len() > self.room() {
+			return None;
+		}
+
+		let mut ids = Vec::with_capacity(points.len());
+		unsafe {
+			self.particles.factory(|factory| {
+				for pos in points {
+					let particle = Vector4(pos.0, pos.1, pos.2, 1.0);
+					ids.extend(factory.create(particle, Vector3::default(), phase, true));
+				}
+			});
+		}
+
+		self.attributes.resize(self.particles.get_count() as usize);
+
+		Some((ids, points.to_vec()))
+	}
+
+	/// How many more particles fit before reaching the capacity.
+	pub fn room(&self) -> usize {
+		(self.particles.capacity() - self.particles.get_count()).max(0) as usize
+	}
+
+	/// Particles spaced at the fluid rest distance in the given phase, without jitter or velocity.
+	pub fn lattice(&self, phase: i32) -> Lattice {
+		Lattice {
+			spacing: config::rest_distance(&self.params),
+			jitter: 0.0,
+			velocity: Vector3::default(),
+			phase,
+			inverse_mass: 1.0,
+		}
+	}
+
+	/// Runs one of the [ParticleFactory] generators, returning the ids of the particles it created.
+	/// Generators stop early when capacity runs out.
+	pub fn generate<F>(&mut self, mut generator: F) -> Vec<i32>
+	where
+		F: FnMut(&mut ParticleFactory) -> Vec<i32>,
+	{
+		let mut ids = vec![];
 		unsafe {
-			let dt = self.instant.elapsed();
-			self.instant = Instant::now();
+			self.particles.factory(|factory| ids = generator(factory));
+		}
+
+		self.attributes.resize(self.particles.get_count() as usize);
+		ids
+	}
+
+	/// Adds a single particle, returning its id. Returns None if the capacity has been reached.
+	pub fn add_particle(&mut self, pos: Vector4, vel: Vector3, phase: i32) -> Option<i32> {
+		let id = self.particles.add_particle(pos, vel, phase, true)?;
+		self.attributes.resize(self.particles.get_count() as usize);
+		Some(id)
+	}
+
+	/// Creates a soft body by filling a closed mesh with clustered particles, returning its handle.
+	/// `vertices` are relative to `center`, `indices` are zero based.
+	pub fn add_soft_body(
+		&mut self,
+		center: Vector3,
+		vertices: &[Vector3],
+		indices: Vec<u32>,
+		params: SoftParams,
+	) -> Option<usize> {
+		let spacing = config::solid_rest_distance(&self.params);
+		// One point past the room is enough for spawn to turn the body down
+		let points: Vec<Vector3> = voxel::sample_mesh(vertices, &indices, spacing)
+			.map(|p| p + center)
+			.take(self.room() + 1)
+			.collect();
+
+		let phase = NvFlexMakePhase(self.next_group(), 0);
+		let (ids, positions) = self.spawn(&points, phase)?;
+
+		let mesh = (vertices.iter().map(|v| *v + center).collect(), indices);
+		let body = self.soft.add(&mut self.rigids, &ids, &positions, mesh, spacing, params);
 
-			NvFlexUpdateSolver(self.solver, dt.as_secs_f32(), 1, false);
+		Some(body)
+	}
+
+	/// Current render mesh of a soft body, as (vertices, zero based indices).
+	pub fn get_soft_mesh(&self, body: usize) -> Option<(Vec<Vector3>, &[u32])> {
+		let vertices = self.soft.deformed(&self.rigids, body)?;
+		Some((vertices, &self.soft.bodies[body].indices))
+	}
+
+	/// Creates an inflatable with a particle at each vertex of a closed mesh, returning its cloth handle.
+	/// `vertices` are relative to `center`, `indices` are zero based.
+	pub fn add_inflatable(
+		&mut self,
+		center: Vector3,
+		vertices: &[Vector3],
+		indices: Vec<u32>,
+		stiffness: f32,
+		pressure: f32,
+	) -> Option<usize> {
+		let flags = eNvFlexPhaseSelfCollide | eNvFlexPhaseSelfCollideFilter;
+		let phase = NvFlexMakePhase(self.next_group(), flags);
+
+		let points: Vec<Vector3> = vertices.iter().map(|v| *v + center).collect();
+		let (ids, positions) = self.spawn(&points, phase)?;
+
+		let cloth = self.cloth.add_inflatable(ids, &positions, indices, stiffness, pressure);
+
+		Some(cloth)
+	}
+
+	pub fn diffuse_settings(&self) -> DiffuseSettings {
+		DiffuseSettings {
+			threshold: self.params.diffuseThreshold,
+			buoyancy: self.params.diffuseBuoyancy,
+			drag: self.params.diffuseDrag,
+			lifetime: self.params.diffuseLifetime,
+			max_particles: self.diffuse.limit(),
 		}
 	}
 
+	/// Configures foam, spray and bubbles. The params are pushed with the next step.
+	pub fn set_diffuse(&mut self, settings: DiffuseSettings) {
+		self.params.diffuseThreshold = settings.threshold;
+		self.params.diffuseBuoyancy = settings.buoyancy;
+		self.params.diffuseDrag = settings.drag;
+		self.params.diffuseLifetime = settings.lifetime;
+		self.params_changed = true;
+
+		self.diffuse.set_limit(settings.max_particles);
+	}
+
+	/// Reads back every live foam, spray and bubble particle.
+	pub fn get_diffuse(&self) -> Vec<DiffuseParticle> {
+		unsafe {
+			let (positions, _) = self.particles.read(self.solver);
+			self.diffuse.read(self.solver, &positions, self.params.radius)
+		}
+	}
+
+	/// Reads back the smoothed center and anisotropic axes of every particle.
+	#[cfg(feature = "anisotropy")]
+	pub fn get_ellipsoids(&self) -> Vec<Ellipsoid> {
+		let count = self.particles.get_count() as usize;
+		unsafe { self.anisotropy.read(self.solver, count) }
+	}
+
+	/// Positions of every active fluid particle, leaving out rigids, cloth and soft bodies.
+	pub fn get_fluid_positions(&self) -> Vec<Vector3> {
+		let (positions, phases) = unsafe {
+			let (positions, _) = self.particles.read(self.solver);
+			(positions, self.particles.read_phases(self.solver))
+		};
+
+		self.particles
+			.get_active()
+			.iter()
+			.map(|&i| i as usize)
+			.filter(|&i| phases[i] & eNvFlexPhaseFluid != 0)
+			.map(|i| positions[i].xyz())
+			.collect()
+	}
+
+	/// Removes particles from the simulation, returning how many were removed.
+	/// Particles belonging to rigids, soft bodies or cloth are skipped, as that would break their constraints.
+	pub fn remove_particles(&mut self, ids: &[i32]) -> usize {
+		let ids: Vec<i32> = ids
+			.iter()
+			.copied()
+			.filter(|&id| !self.rigids.contains(id) && !self.cloth.contains(id))
+			.collect();
+
+		self.particles.remove(&ids)
+	}
+
+	/// Reclaims the slots of removed particles, moving the rest along with their attributes and constraints.
+	/// Particle ids change, returns the new id of each old one or -1 if it was removed.
+	pub fn compact(&mut self) -> Vec<i32> {
+		self.sync_recorder();
+
+		let remap = unsafe { self.particles.compact(self.solver) };
+		if let Some(recorder) = &mut self.recorder {
+			recorder.compacted(self.particles.get_count());
+		}
+
+		self.attributes.remap(&remap);
+		self.rigids.remap(&remap);
+		self.cloth.remap(&remap);
+		self.neighbors.remap(&remap);
+		self.contacts.remap(&remap);
+		// Quarantined particles are inactive, so compacting got rid of them
+		self.health.quarantined.clear();
+		// Ids moved, so deltas against what clients hold would be applied to the wrong particles
+		self.replication.reset_encoders();
+
+		remap
+	}
+
+	/// Sets the color of every active particle within `radius` of `center`,
+	/// returning how many were painted.
+	pub fn paint(&mut self, center: Vector3, radius: f32, color: [f32; 4]) -> usize {
+		let (positions, _) = unsafe { self.particles.read(self.solver) };
+		let radius2 = radius * radius;
+
+		let mut painted = 0;
+		for &id in self.particles.get_active() {
+			let id = id as usize;
+			let (pos, c) = match (positions.get(id), self.attributes.colors.get_mut(id)) {
+				(Some(pos), Some(c)) => (pos, c),
+				_ => continue,
+			};
+
+			if (pos.xyz() - center).length_squared() <= radius2 {
+				*c = color;
+				painted += 1;
+			}
+		}
+
+		painted
+	}
+
+	/// Fluid density at a point relative to the rest density, so 1 is a fluid at rest and 0 is air.
+	pub fn get_density_at(&self, point: Vector3) -> f32 {
+		neighbor::density_at(&self.get_fluid_positions(), point, &self.params)
+	}
+
+	/// Positions to render the fluid at: replicated from the server once any arrived, local otherwise.
+	pub fn get_render_positions(&self) -> Vec<Vector3> {
+		let interpolator = &self.replication.interpolator;
+		if interpolator.is_empty() {
+			return self.get_fluid_positions();
+		}
+
+		interpolator.sample().into_iter().map(|(_, p)| p).collect()
+	}
+
+	/// Reconstructs a triangle mesh of the fluid's surface.
+	pub fn get_surface(&self, cell: Option<f32>, region: Option<(Vector3, Vector3)>) -> SurfaceMesh {
+		let radius = self.params.radius;
+		let params = SurfaceParams {
+			cell: cell.unwrap_or(radius * 0.5),
+			kernel: radius * 2.0,
+			iso: 0.5,
+			region,
+		};
+
+		surface::extract(&self.get_render_positions(), &params)
+	}
+
+	/// Fluid particles visible from a camera in view space, sorted back to front.
+	pub fn get_view_particles(&self, camera: &Camera) -> Vec<ViewParticle> {
+		render::view_particles(camera, &self.get_render_positions(), self.params.radius)
+	}
+
+	/// Encodes the next replication frame of the active particles for a client, see [crate::replication].
+	pub fn encode_replication(&mut self, client: i32, viewer: Vector3, budget: usize) -> Vec<u8> {
+		let (positions, _) = unsafe { self.particles.read(self.solver) };
+		let particles = self
+			.particles
+			.get_active()
+			.iter()
+			.map(|&i| (i, positions[i as usize]));
+
+		let region = self.replication.region();
+		self.replication
+			.encoder(client)
+			.encode(self.time, &region, particles, viewer, budget)
+	}
+
+	/// Every active particle with its attributes, for exporting.
+	pub fn get_export_frame(&self) -> Frame {
+		fn slot<T: Copy>(values: &[T], i: &i32, default: T) -> T {
+			values.get(*i as usize).copied().unwrap_or(default)
+		}
+
+		let (positions, velocities) = unsafe { self.particles.read(self.solver) };
+		let phases = unsafe { self.particles.read_phases(self.solver) };
+
+		let (a, d) = (&self.attributes, self.attributes.defaults);
+		let ids = self.particles.get_active().to_vec();
+		let pick = |i: &i32| *i as usize;
+
+		let mut channels: Vec<_> = a.channels.iter().collect();
+		channels.sort_by(|x, y| x.0.cmp(y.0));
+
+		Frame {
+			positions: ids.iter().map(|i| positions[pick(i)]).collect(),
+			velocities: ids.iter().map(|i| velocities[pick(i)]).collect(),
+			phases: ids.iter().map(|i| phases[pick(i)]).collect(),
+			// Slots without attributes get the defaults, so every column lines up with the ids
+			colors: ids.iter().map(|i| slot(&a.colors, i, d.color)).collect(),
+			temperatures: ids.iter().map(|i| slot(&a.temperatures, i, d.temperature)).collect(),
+			owners: ids.iter().map(|i| slot(&a.owners, i, d.owner)).collect(),
+			channels: channels
+				.into_iter()
+				.map(|(name, values)| {
+					let values = ids.iter().map(|i| slot(values, i, 0.0)).collect();
+					(name.clone(), values)
+				})
+				.collect(),
+			ids,
+		}
+	}
+
+	/// World space meshes of every collider, along with their shape index.
+	pub fn get_collider_meshes(&self) -> Vec<(usize, Mesh)> {
+		(0 .. self.geometry.get_count() as usize)
+			.filter_map(|i| {
+				let geometry = self.geometry.get_geometry(i)?;
+				let info = self.geometry.get_info(i)?;
+				let ty = info.shape_type();
+
+				let triangles = if ty == eNvFlexShapeTriangleMesh {
+					let mesh = self.meshes.get(unsafe { geometry.triMesh.mesh })?;
+					Some((mesh.vertices.as_slice(), mesh.indices.as_slice()))
+				} else {
+					None
+				};
+
+				let (position, rotation) = (info.position, info.rotation);
+				let mesh = export::collider_mesh(geometry, ty, triangles, position, rotation)?;
+				Some((i, mesh))
+			})
+			.collect()
+	}
+
+	/// Applies an impulse to every particle it reaches, including rigid, soft and cloth ones.
+	/// Returns how many particles were affected.
+	pub fn apply_impulse(&mut self, impulse: &Impulse) -> usize {
+		self.sync_recorder();
+		if let Some(recorder) = &mut self.recorder {
+			recorder.impulse(impulse);
+		}
+
+		let mut affected = 0;
+		unsafe {
+			self.particles.modify(self.solver, |positions, velocities| {
+				affected = impulse.apply(positions, velocities);
+			});
+		}
+		self.health.forget_energy();
+
+		affected
+	}
+
+	/// Serializes the whole simulation into a snapshot: params, phase groups, particles, shapes
+	/// along with their meshes, attributes, constraints, emitters, force fields and kill volumes.
+	/// Fails if a shape is of a type snapshots don't know about.
+	pub fn save_snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+		let mut w = Writer::default();
+		params::write(&self.params, &mut w);
+		w.f32(self.time);
+
+		w.i32(self.phases.last_group());
+		let groups: Vec<_> = self.phases.named().collect();
+		w.count(groups.len());
+		for (name, group) in groups {
+			w.string(name);
+			w.i32(group.group);
+			w.i32(group.flags);
+			w.i32(group.channels);
+		}
+
+		let (positions, velocities) = unsafe { self.particles.read(self.solver) };
+		let phases = unsafe { self.particles.read_phases(self.solver) };
+		w.count(positions.len());
+		positions.iter().for_each(|p| w.vector4(*p));
+		velocities.iter().for_each(|v| w.vector3(*v));
+		phases.iter().for_each(|p| w.i32(*p));
+
+		let active = self.particles.get_active();
+		w.count(active.len());
+		active.iter().for_each(|i| w.i32(*i));
+
+		w.count(self.meshes.iter().count());
+		for mesh in self.meshes.iter() {
+			w.u8(mesh.file.is_some() as u8);
+			w.string(mesh.file.as_deref().unwrap_or_default());
+			w.slice(&mesh.vertices, Writer::vector3);
+			w.slice(&mesh.indices, Writer::u32);
+		}
+
+		let nshapes = self.geometry.get_count() as usize;
+		w.count(nshapes);
+		for i in 0 .. nshapes {
+			let geometry = self.geometry.get_geometry(i);
+			let (geometry, info) = match (geometry, self.geometry.get_info(i)) {
+				(Some(geometry), Some(info)) => (geometry, info),
+				_ => continue,
+			};
+
+			SavedGeometry::new(info, geometry, &self.meshes)?.write(&mut w);
+			w.i32(info.flags);
+			w.vector4(info.position);
+			w.quat(info.rotation);
+			w.i32(info.entity.unwrap_or(-1));
+		}
+
+		let a = &self.attributes;
+		a.defaults.color.iter().for_each(|c| w.f32(*c));
+		w.f32(a.defaults.temperature);
+		w.i32(a.defaults.owner);
+		w.f32(a.color_diffusion);
+
+		w.count(a.len());
+		a.colors.iter().flatten().for_each(|c| w.f32(*c));
+		a.temperatures.iter().for_each(|t| w.f32(*t));
+		a.owners.iter().for_each(|o| w.i32(*o));
+
+		// Sorted so the same state always gives the same bytes
+		let mut channels: Vec<_> = a.channels.iter().collect();
+		channels.sort_by(|x, y| x.0.cmp(y.0));
+		w.count(channels.len());
+		for (name, values) in channels {
+			w.string(name);
+			values.iter().for_each(|v| w.f32(*v));
+		}
+
+		self.rigids.write_snapshot(&mut w);
+		self.cloth.write_snapshot(&mut w);
+		self.soft.write_snapshot(&mut w);
+		self.emitters.write_snapshot(&mut w);
+		self.forces.write_snapshot(&mut w);
+
+		w.count(self.kill_volumes.len());
+		for volume in &self.kill_volumes {
+			w.vector3(volume.min);
+			w.vector3(volume.max);
+		}
+
+		Ok(snapshot::seal(w.bytes))
+	}
+
+	/// Replaces the simulation with one saved by [Self::save_snapshot].
+	/// Nothing is changed if the snapshot is invalid.
+	pub fn load_snapshot(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+		let mut r = Reader::new(snapshot::open(bytes)?);
+		let params = params::read(&mut r)?;
+		let time = r.f32()?;
+
+		let last_group = r.i32()?;
+		let mut groups = vec![];
+		for _ in 0 .. r.count(16)? {
+			let name = r.string()?;
+			let (group, flags, channels) = (r.i32()?, r.i32()?, r.i32()?);
+			groups.push((name, PhaseGroup { group, flags, channels }));
+		}
+
+		let count = r.count(32)?;
+		let capacity = self.particles.capacity() as usize;
+		if count > capacity {
+			return Err(SnapshotError::Capacity(count, capacity));
+		}
+
+		let positions = (0 .. count).map(|_| r.vector4()).collect::<Result<Vec<_>, _>>()?;
+		let velocities = (0 .. count).map(|_| r.vector3()).collect::<Result<Vec<_>, _>>()?;
+		let phases = (0 .. count).map(|_| r.i32()).collect::<Result<Vec<_>, _>>()?;
+
+		let nactive = r.count(4)?;
+		let active = (0 .. nactive).map(|_| r.i32()).collect::<Result<Vec<_>, _>>()?;
+
+		let mut meshes = vec![];
+		for _ in 0 .. r.count(13)? {
+			let named = r.u8()? != 0;
+			let file = Some(r.string()?).filter(|_| named);
+			let vertices: Vec<Vector3> = r.vec(12, Reader::vector3)?;
+			let indices: Vec<u32> = r.vec(4, Reader::u32)?;
+
+			if indices.len() % 3 != 0 || indices.iter().any(|&i| i as usize >= vertices.len()) {
+				return Err(SnapshotError::Range("mesh"));
+			}
+			meshes.push((file, vertices, indices));
+		}
+
+		let nshapes = r.count(48)?;
+		if nshapes > self.geometry.capacity() as usize {
+			return Err(SnapshotError::Range("shape count"));
+		}
+
+		let mut shapes = vec![];
+		for _ in 0 .. nshapes {
+			let geometry = SavedGeometry::read(&mut r)?;
+			let flags = r.i32()?;
+			let (position, rotation) = (r.vector4()?, r.quat()?);
+			let entity = Some(r.i32()?).filter(|e| *e >= 0);
+
+			let mesh = match geometry {
+				SavedGeometry::Mesh { mesh, .. } => mesh < meshes.len(),
+				_ => true,
+			};
+			if !mesh || flags & eNvFlexShapeFlagTypeMask != geometry.shape_type() {
+				return Err(SnapshotError::Range("shape"));
+			}
+			shapes.push((geometry, flags, position, rotation, entity));
+		}
+
+		let mut defaults = attribute::Attributes::default();
+		for c in &mut defaults.color {
+			*c = r.f32()?;
+		}
+		defaults.temperature = r.f32()?;
+		defaults.owner = r.i32()?;
+		let color_diffusion = r.f32()?;
+
+		let nattributes = r.count(24)?;
+		let mut colors = vec![[0.0; 4]; nattributes];
+		for c in colors.iter_mut().flatten() {
+			*c = r.f32()?;
+		}
+		let temperatures = (0 .. nattributes).map(|_| r.f32()).collect::<Result<Vec<_>, _>>()?;
+		let owners = (0 .. nattributes).map(|_| r.i32()).collect::<Result<Vec<_>, _>>()?;
+
+		let mut channels = std::collections::HashMap::new();
+		for _ in 0 .. r.count(4)? {
+			let name = r.string()?;
+			let values = (0 .. nattributes).map(|_| r.f32()).collect::<Result<Vec<_>, _>>()?;
+			channels.insert(name, values);
+		}
+
+		let rigids = RigidState::read_snapshot(&mut r, count)?;
+		let cloth = ClothState::read_snapshot(&mut r, count, shapes.len())?;
+		let soft = SoftState::read_snapshot(&mut r, rigids.get_count())?;
+		let emitters = Emitters::read_snapshot(&mut r)?;
+		let forces = ForceFields::read_snapshot(&mut r)?;
+
+		let mut kill_volumes = vec![];
+		for _ in 0 .. r.count(24)? {
+			let (min, max) = (r.vector3()?, r.vector3()?);
+			kill_volumes.push(KillVolume { min, max });
+		}
+
+		// Everything parsed, nothing can fail from here on
+		self.params = params;
+		self.params_changed = true;
+		self.time = time;
+		self.phases.restore(last_group, groups);
+
+		unsafe {
+			self.particles.restore(self.solver, &positions, &velocities, &phases, active);
+		}
+
+		// Every shape using the old meshes goes away first, so they can be destroyed
+		self.geometry.clear();
+		let ids: Vec<_> = unsafe {
+			self.meshes.destroy(self.lib);
+			meshes
+				.into_iter()
+				.map(|(file, vertices, indices)| {
+					self.meshes.create(self.lib, &vertices, &indices, file)
+				})
+				.collect()
+		};
+
+		for (geometry, flags, position, rotation, entity) in shapes {
+			let geometry = match geometry.geometry(&ids) {
+				Some(geometry) => geometry,
+				None => continue,
+			};
+
+			if let Some(index) = self.geometry.add_shape(geometry, position, rotation, flags) {
+				self.geometry.bind_entity(index, entity);
+			}
+		}
+
+		self.rigids = rigids;
+		self.cloth = cloth;
+		self.soft = soft;
+		self.emitters = emitters;
+		self.forces = forces;
+		self.kill_volumes = kill_volumes;
+
+		self.attributes = AttributeState {
+			defaults,
+			color_diffusion,
+			colors,
+			temperatures,
+			owners,
+			channels,
+		};
+		self.attributes.resize(self.particles.get_count() as usize);
+		self.health.quarantined.clear();
+		// Readbacks from before describe particles and shapes that are gone
+		self.contacts.clear();
+		self.neighbors.clear();
+		self.coupling.clear();
+		self.replication.reset_encoders();
+
+		Ok(())
+	}
+
 	pub unsafe fn get(&self) -> Option<Vec<Particle>> {
 		self.particles.get(self.solver)
 	}
@@ -170,6 +1318,7 @@ impl Drop for FlexState {
 	fn drop(&mut self) {
 		unsafe {
 			NvFlexDestroySolver(self.solver);
+			self.meshes.destroy(self.lib);
 			NvFlexShutdown(self.lib);
 		}
 	}
@@ -0,0 +1,282 @@
+use nvflex_sys::*;
+use std::f32::consts::PI;
+use std::mem::size_of;
+
+use crate::{
+	config,
+	types::{Vector3, Vector4},
+};
+
+use super::attribute;
+
+/// Particles whose neighbors' center of mass is further than this fraction of the
+/// smoothing radius away from them are on the surface, as their neighborhood is lopsided.
+const SURFACE_OFFSET: f32 = 0.2;
+/// Stiffness of the equation of state turning density into pressure.
+const PRESSURE_STIFFNESS: f32 = 1.0;
+
+/// CPU side density and pressure estimates, from the neighbor lists FleX builds each step.
+#[derive(Debug)]
+pub struct NeighborState {
+	/// Neighbors are only read back when enabled, as it's the largest readback there is.
+	pub enabled: bool,
+
+	/// Neighbor particle ids of each particle, indexed by particle id.
+	neighbors: Vec<Vec<i32>>,
+	/// Density relative to the rest density, so 1 is a fluid at rest.
+	densities: Vec<f32>,
+	pressures: Vec<f32>,
+	surface: Vec<bool>,
+	/// Particle capacity, the stride of the neighbor lists
+	stride: usize,
+	max_per_particle: usize,
+
+	pub indices: *mut NvFlexBuffer,         // Vec<i32>, column major
+	pub counts: *mut NvFlexBuffer,          // Vec<i32>
+	pub api_to_internal: *mut NvFlexBuffer, // Vec<i32>
+	pub internal_to_api: *mut NvFlexBuffer, // Vec<i32>
+}
+
+impl Default for NeighborState {
+	fn default() -> Self {
+		Self {
+			enabled: false,
+
+			neighbors: vec![],
+			densities: vec![],
+			pressures: vec![],
+			surface: vec![],
+			stride: config::MAX_PARTICLES as usize,
+			max_per_particle: config::MAX_NEIGHBORS_PER_PARTICLE as usize,
+
+			indices: std::ptr::null_mut(),
+			counts: std::ptr::null_mut(),
+			api_to_internal: std::ptr::null_mut(),
+			internal_to_api: std::ptr::null_mut(),
+		}
+	}
+}
+
+/// Poly6 smoothing kernel.
+fn poly6(r2: f32, h: f32) -> f32 {
+	let h2 = h * h;
+	if r2 >= h2 {
+		return 0.0;
+	}
+
+	let d = h2 - r2;
+	315.0 / (64.0 * PI * h.powi(9)) * d * d * d
+}
+
+/// Kernel density of particles on a cubic lattice at `spacing`, what a fluid at rest should measure.
+fn rest_density(spacing: f32, h: f32) -> f32 {
+	let steps = (h / spacing).ceil() as i32;
+
+	let mut density = 0.0;
+	for x in -steps ..= steps {
+		for y in -steps ..= steps {
+			for z in -steps ..= steps {
+				let offset = Vector3(x as f32, y as f32, z as f32) * spacing;
+				density += poly6(offset.length_squared(), h);
+			}
+		}
+	}
+
+	density
+}
+
+impl NeighborState {
+	/// # Safety
+	/// Do not call this function more than once
+	pub unsafe fn alloc(&mut self, flex: *mut NvFlexLibrary, capacity: &config::Capacity) {
+		self.stride = capacity.particles as usize;
+		self.max_per_particle = capacity.neighbors_per_particle as usize;
+
+		self.indices = NvFlexAllocBuffer(
+			flex,
+			capacity.particles * capacity.neighbors_per_particle,
+			size_of::<i32>() as i32,
+			eNvFlexBufferHost,
+		);
+
+		for buffer in [
+			&mut self.counts,
+			&mut self.api_to_internal,
+			&mut self.internal_to_api,
+		] {
+			*buffer = NvFlexAllocBuffer(
+				flex,
+				capacity.particles,
+				size_of::<i32>() as i32,
+				eNvFlexBufferHost,
+			);
+		}
+	}
+
+	/// Reads back the neighbor lists and recomputes density, pressure and surface flags.
+	/// # Safety
+	/// The solver must be valid and `particles` must hold the positions of every particle.
+	pub unsafe fn update(
+		&mut self,
+		solver: *mut NvFlexSolver,
+		particles: &[Vector4],
+		active: usize,
+		params: &NvFlexParams,
+	) {
+		NvFlexGetNeighbors(
+			solver,
+			self.indices,
+			self.counts,
+			self.api_to_internal,
+			self.internal_to_api,
+		);
+
+		let indices = NvFlexMap(self.indices, eNvFlexMapWait) as *const i32;
+		let counts = NvFlexMap(self.counts, eNvFlexMapWait) as *const i32;
+		let internal_to_api = NvFlexMap(self.internal_to_api, eNvFlexMapWait) as *const i32;
+
+		let count = particles.len();
+		self.neighbors = vec![vec![]; count];
+
+		// Lists are indexed by FleX's internal (sorted) order, with the particle capacity as stride
+		let (stride, max) = (self.stride, self.max_per_particle);
+		for internal in 0 .. active.min(stride) {
+			let particle = *internal_to_api.add(internal) as usize;
+			if particle >= count {
+				continue;
+			}
+
+			let n = (*counts.add(internal)).clamp(0, max as i32) as usize;
+			let list = &mut self.neighbors[particle];
+			for c in 0 .. n {
+				let neighbor = *indices.add(c * stride + internal) as usize;
+				if neighbor >= active.min(stride) {
+					continue;
+				}
+
+				let id = *internal_to_api.add(neighbor);
+				if id >= 0 && (id as usize) < count {
+					list.push(id);
+				}
+			}
+		}
+
+		NvFlexUnmap(self.indices);
+		NvFlexUnmap(self.counts);
+		NvFlexUnmap(self.internal_to_api);
+
+		self.estimate(particles, params);
+	}
+
+	fn estimate(&mut self, particles: &[Vector4], params: &NvFlexParams) {
+		let h = params.radius;
+		let rest = rest_density(config::rest_distance(params), h).max(f32::EPSILON);
+
+		let count = particles.len();
+		self.densities = vec![0.0; count];
+		self.pressures = vec![0.0; count];
+		self.surface = vec![false; count];
+
+		for (i, neighbors) in self.neighbors.iter().enumerate().take(count) {
+			let pos = particles[i].xyz();
+			let mut density = poly6(0.0, h);
+			let mut center = Vector3::default();
+			let mut others = 0;
+
+			for &j in neighbors {
+				let other = match particles.get(j as usize) {
+					Some(other) if j as usize != i => other,
+					_ => continue,
+				};
+
+				let offset = other.xyz() - pos;
+				density += poly6(offset.length_squared(), h);
+				center += offset;
+				others += 1;
+			}
+
+			let lopsided = if others > 0 {
+				(center * (1.0 / others as f32)).length() > h * SURFACE_OFFSET
+			} else {
+				true
+			};
+
+			let density = density / rest;
+			self.densities[i] = density;
+			self.pressures[i] = (PRESSURE_STIFFNESS * (density - 1.0)).max(0.0);
+			self.surface[i] = lopsided;
+		}
+	}
+
+	/// Forgets the last update, like when the particles are replaced.
+	pub fn clear(&mut self) {
+		self.neighbors.clear();
+		self.densities.clear();
+		self.pressures.clear();
+		self.surface.clear();
+	}
+
+	/// Moves everything along with the particles after compaction, until the next update.
+	/// `remap` holds the new id of each old one, or -1 if it was removed.
+	pub fn remap(&mut self, remap: &[i32]) {
+		for list in &mut self.neighbors {
+			*list = list
+				.iter()
+				.filter_map(|&j| remap.get(j as usize).copied())
+				.filter(|&j| j >= 0)
+				.collect();
+		}
+
+		attribute::remap_slots(&mut self.neighbors, remap);
+		attribute::remap_slots(&mut self.densities, remap);
+		attribute::remap_slots(&mut self.pressures, remap);
+		attribute::remap_slots(&mut self.surface, remap);
+	}
+
+	/// Neighbor ids of a particle as of the last update.
+	pub fn get_neighbors(&self, particle: usize) -> Option<&[i32]> {
+		self.neighbors.get(particle).map(|n| n.as_slice())
+	}
+
+	/// (density, pressure, on the surface) of a particle as of the last update.
+	pub fn get(&self, particle: usize) -> Option<(f32, f32, bool)> {
+		Some((
+			*self.densities.get(particle)?,
+			*self.pressures.get(particle)?,
+			*self.surface.get(particle)?,
+		))
+	}
+
+	/// Ids of every particle on the fluid's surface as of the last update.
+	pub fn surface(&self) -> impl Iterator<Item = i32> + '_ {
+		self.surface
+			.iter()
+			.enumerate()
+			.filter(|(_, surface)| **surface)
+			.map(|(i, _)| i as i32)
+	}
+}
+
+/// Density at an arbitrary point relative to the rest density, from the fluid particles around it.
+pub fn density_at(fluid: &[Vector3], point: Vector3, params: &NvFlexParams) -> f32 {
+	let h = params.radius;
+	let rest = rest_density(config::rest_distance(params), h).max(f32::EPSILON);
+
+	let density: f32 = fluid
+		.iter()
+		.map(|p| poly6((*p - point).length_squared(), h))
+		.sum();
+
+	density / rest
+}
+
+impl Drop for NeighborState {
+	fn drop(&mut self) {
+		unsafe {
+			NvFlexFreeBuffer(self.indices);
+			NvFlexFreeBuffer(self.counts);
+			NvFlexFreeBuffer(self.api_to_internal);
+			NvFlexFreeBuffer(self.internal_to_api);
+		}
+	}
+}
@@ -1,8 +1,15 @@
-use crate::types::*;
+use crate::{types::*, voxel};
 
 #[derive(Debug)]
 pub struct ParticleFactory {
+	/// Index of the first particle this factory creates
+	offset: isize,
+	/// Amount of particles created so far
 	pub nparticles: isize,
+	/// Index no particle can be created at or past
+	capacity: isize,
+	/// Particles created inactive, which FleX won't simulate until activated
+	pub inactive: Vec<i32>,
 
 	/// Return values from NvFlexMap(...)
 	buffer: *mut Vector4,
@@ -11,10 +18,33 @@ pub struct ParticleFactory {
 	active_indices: *mut i32
 }
 
+/// How a generator lays out and initializes particles.
+#[derive(Clone, Copy, Debug)]
+pub struct Lattice {
+	/// Distance between neighboring particles, usually the fluid rest distance.
+	pub spacing: f32,
+	/// Random offset of each particle, as a fraction of the spacing.
+	/// Breaks up the lattice so it doesn't settle in visible layers.
+	pub jitter: f32,
+	pub velocity: Vector3,
+	pub phase: i32,
+	pub inverse_mass: f32,
+}
+
 impl ParticleFactory {
-	pub fn new(offset: Option<isize>, buffer: *mut Vector4, velocities: *mut Vector3, phases: *mut i32, indices: *mut i32) -> Self {
+	pub fn new(
+		offset: Option<isize>,
+		buffer: *mut Vector4,
+		velocities: *mut Vector3,
+		phases: *mut i32,
+		indices: *mut i32,
+		capacity: i32,
+	) -> Self {
 		Self {
-			nparticles: offset.unwrap_or(0_isize),
+			offset: offset.unwrap_or(0_isize),
+			nparticles: 0,
+			capacity: capacity as isize,
+			inactive: vec![],
 
 			buffer,
 			velocities,
@@ -23,8 +53,13 @@ impl ParticleFactory {
 		}
 	}
 
-	pub fn create(&mut self, pos: Vector4, velocity: Vector3, phase: i32, _active: bool){
-		let index = self.nparticles;
+	/// Creates a particle, returning its index.
+	/// Returns None if the capacity has been reached.
+	pub fn create(&mut self, pos: Vector4, velocity: Vector3, phase: i32, active: bool) -> Option<i32> {
+		let index = self.offset + self.nparticles;
+		if index >= self.capacity {
+			return None;
+		}
 
 		unsafe {
 			self.buffer
@@ -39,11 +74,145 @@ impl ParticleFactory {
 				.offset(index)
 				.write(phase);
 
-			// Assumes particle is active for now.
 			self.active_indices
 				.offset(index)
 				.write(index as i32);
 		}
 		self.nparticles += 1;
+
+		if !active {
+			self.inactive.push(index as i32);
+		}
+
+		Some(index as i32)
+	}
+
+	/// Creates a particle at each point until capacity runs out, returning the created indices.
+	/// Points are pulled lazily, so a lattice bigger than the room left is never built in full.
+	pub fn fill(&mut self, points: impl IntoIterator<Item = Vector3>, lattice: &Lattice) -> Vec<i32> {
+		let mut ids = vec![];
+
+		for point in points {
+			let offset = jitter(self.offset + self.nparticles) * (lattice.jitter * lattice.spacing);
+			let pos = point + offset;
+			let particle = Vector4(pos.0, pos.1, pos.2, lattice.inverse_mass);
+
+			match self.create(particle, lattice.velocity, lattice.phase, true) {
+				Some(id) => ids.push(id),
+				None => break,
+			}
+		}
+
+		ids
+	}
+
+	/// Fills an axis aligned box.
+	pub fn fill_box(&mut self, center: Vector3, half: Vector3, lattice: &Lattice) -> Vec<i32> {
+		let points = voxel::sample_box(half, lattice.spacing);
+		self.fill(points.map(|p| p + center), lattice)
+	}
+
+	pub fn fill_sphere(&mut self, center: Vector3, radius: f32, lattice: &Lattice) -> Vec<i32> {
+		let points = voxel::sample_sphere(radius, lattice.spacing);
+		self.fill(points.map(|p| p + center), lattice)
+	}
+
+	/// Fills an upright cylinder.
+	pub fn fill_cylinder(
+		&mut self,
+		center: Vector3,
+		radius: f32,
+		half_height: f32,
+		lattice: &Lattice,
+	) -> Vec<i32> {
+		let points = voxel::sample_cylinder(radius, half_height, lattice.spacing);
+		self.fill(points.map(|p| p + center), lattice)
 	}
-}
\ No newline at end of file
+
+	/// Fills the inside of a closed mesh, given in world space with zero based indices.
+	pub fn fill_mesh(&mut self, vertices: &[Vector3], indices: &[u32], lattice: &Lattice) -> Vec<i32> {
+		let points = voxel::sample_mesh(vertices, indices, lattice.spacing);
+		self.fill(points, lattice)
+	}
+
+	/// A round jet of particles starting at `origin` and extending `length` along `direction`.
+	/// The lattice's velocity is added to `speed` along the direction.
+	pub fn stream(
+		&mut self,
+		origin: Vector3,
+		direction: Vector3,
+		radius: f32,
+		length: f32,
+		speed: f32,
+		lattice: &Lattice,
+	) -> Vec<i32> {
+		let (forward, right, up) = basis(direction);
+		let half = length * 0.5;
+
+		let points = voxel::sample_cylinder(radius, half, lattice.spacing)
+			.map(|p| origin + right * p.0 + up * p.1 + forward * (p.2 + half));
+
+		let lattice = Lattice {
+			velocity: lattice.velocity + forward * speed,
+			..*lattice
+		};
+		self.fill(points, &lattice)
+	}
+
+	/// A flat sheet of particles one particle thick, `width` across and extending `length` along `direction`.
+	/// The lattice's velocity is added to `speed` along the direction.
+	pub fn sheet(
+		&mut self,
+		origin: Vector3,
+		direction: Vector3,
+		width: f32,
+		length: f32,
+		speed: f32,
+		lattice: &Lattice,
+	) -> Vec<i32> {
+		let (forward, right, _) = basis(direction);
+		let half = Vector3(width * 0.5, length * 0.5, 0.0);
+
+		let points = voxel::sample_box(half, lattice.spacing)
+			.map(|p| origin + right * p.0 + forward * (p.1 + half.1));
+
+		let lattice = Lattice {
+			velocity: lattice.velocity + forward * speed,
+			..*lattice
+		};
+		self.fill(points, &lattice)
+	}
+}
+
+/// Normalized `direction` and two axes perpendicular to it, as (forward, right, up).
+fn basis(direction: Vector3) -> (Vector3, Vector3, Vector3) {
+	let length = direction.length();
+	let forward = if length > 0.0 {
+		direction * (1.0 / length)
+	} else {
+		Vector3(0.0, 0.0, -1.0)
+	};
+
+	let reference = if forward.2.abs() > 0.999 {
+		Vector3(1.0, 0.0, 0.0)
+	} else {
+		Vector3(0.0, 0.0, 1.0)
+	};
+
+	let right = forward.cross(reference);
+	let right = right * (1.0 / right.length());
+	(forward, right, right.cross(forward))
+}
+
+/// Deterministic offset in the unit cube around the origin for a particle index.
+fn jitter(index: isize) -> Vector3 {
+	let mut h = (index as u32).wrapping_mul(0x9e37_79b9) ^ 0x85eb_ca6b;
+	let mut next = || {
+		h ^= h << 13;
+		h ^= h >> 17;
+		h ^= h << 5;
+		(h as f32 / u32::MAX as f32) - 0.5
+	};
+
+	Vector3(next(), next(), next())
+}
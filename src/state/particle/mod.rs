@@ -5,14 +5,18 @@ use nvflex_sys::*;
 use crate::{config, types::*};
 use std::mem::size_of;
 
-mod factory;
+pub mod factory;
 
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
 pub struct ParticleState {
 	has_changes: bool,
+	/// First slot spawned since the last flush. Those only exist in the host buffers,
+	/// so reading back from the solver stops short of them.
+	spawned: Option<i32>,
 
 	count: i32,
+	capacity: i32,
 	active: Vec<i32>,
 
 	pub buffer: *mut NvFlexBuffer,
@@ -25,8 +29,10 @@ impl Default for ParticleState {
 	fn default() -> Self {
 		Self {
 			has_changes: false,
+			spawned: None,
 
 			count: 0,
+			capacity: config::MAX_PARTICLES,
 			active: vec![],
 
 			buffer: std::ptr::null_mut(),
@@ -41,47 +47,55 @@ impl Default for ParticleState {
 impl ParticleState {
 	/// # Safety
 	/// Do not call this function more than once
-	pub unsafe fn alloc(&mut self, flex: *mut NvFlexLibrary) {
+	pub unsafe fn alloc(&mut self, flex: *mut NvFlexLibrary, capacity: i32) {
+		self.capacity = capacity;
+
 		self.buffer = NvFlexAllocBuffer(
 			flex,
-			config::MAX_PARTICLES,
+			capacity,
 			size_of::<Vector4>() as i32,
 			eNvFlexBufferHost,
 		);
 
 		self.velocities = NvFlexAllocBuffer(
 			flex,
-			config::MAX_PARTICLES,
+			capacity,
 			size_of::<Vector3>() as i32,
 			eNvFlexBufferHost,
 		);
 
 		self.phases = NvFlexAllocBuffer(
 			flex,
-			config::MAX_PARTICLES,
+			capacity,
 			size_of::<i32>() as i32,
 			eNvFlexBufferHost,
 		);
 
 		self.active_indices = NvFlexAllocBuffer(
 			flex,
-			config::MAX_PARTICLES,
+			capacity,
 			size_of::<i32>() as i32,
 			eNvFlexBufferHost,
 		);
 	}
 
-	/// Adds a particle to FleX
+	/// Adds a particle to FleX, returning its index.
+	/// Returns None if the capacity has been reached.
 	/// Note the changes won't be applied to flex immediately, you need to call [self.flush]
 	/// Also this is very inefficient since it maps and unmaps every call..
+	/// Prefer [crate::state::FlexState::add_particle], which also makes room for its attributes.
 	pub fn add_particle(
 		&mut self,
 		pos: Vector4,
 		vel: Vector3,
 		phase: i32,
 		active: bool
-	) {
+	) -> Option<i32> {
 		let i = self.count;
+		if i >= self.capacity {
+			return None;
+		}
+
 		let ind = i as isize;
 		unsafe {
 			let particles = NvFlexMap(self.buffer, eNvFlexMapWait) as *mut Vector4;
@@ -89,21 +103,23 @@ impl ParticleState {
 			let phases = NvFlexMap(self.phases, eNvFlexMapWait) as *mut i32;
 			let active_indices = NvFlexMap(self.active_indices, eNvFlexMapWait) as *mut i32;
 
-			particles
-				.offset(ind)
-				.write(Vector4(50.0 * i as f32, 0.0, 5000.0, 2.0));
-
-			velocities.offset(ind).write(Vector3(0.0, 0.0, -5.0));
+			particles.offset(ind).write(pos);
+			velocities.offset(ind).write(vel);
 			phases.offset(ind).write(phase);
-
-			// Assume active for now.
 			active_indices.offset(ind).write(i);
 
 			self.unmap();
 		}
 
+		if active {
+			self.active.push(i);
+		}
+
 		self.count += 1;
 		self.has_changes = true;
+		self.spawned.get_or_insert(i);
+
+		Some(i)
 	}
 
 	pub fn unmap(&self) {
@@ -115,11 +131,32 @@ impl ParticleState {
 		}
 	}
 
+	/// Copies a buffer back from the solver through `get`, skipping particles that were spawned
+	/// since the last flush, as the solver doesn't have them yet.
+	unsafe fn fetch(
+		&self,
+		get: unsafe extern "C" fn(*mut NvFlexSolver, *mut NvFlexBuffer, *const NvFlexCopyDesc),
+		solver: *mut NvFlexSolver,
+		buffer: *mut NvFlexBuffer,
+	) {
+		match self.spawned {
+			Some(spawned) => {
+				let desc = NvFlexCopyDesc {
+					srcOffset: 0,
+					dstOffset: 0,
+					elementCount: spawned,
+				};
+				get(solver, buffer, &desc);
+			}
+			None => get(solver, buffer, std::ptr::null()),
+		}
+	}
+
 	/// Gets a pointer to the particle buffer
 	/// # Safety
 	/// This function must be followed by a proper release, through self.unmap(), as this calls NvFlexMap
 	pub unsafe fn get_particles(&self, solver: *mut NvFlexSolver) -> *mut Vector4 {
-		NvFlexGetParticles(solver, self.buffer, std::ptr::null());
+		self.fetch(NvFlexGetParticles, solver, self.buffer);
 		NvFlexMap(self.buffer, eNvFlexMapWait) as *mut Vector4
 	}
 
@@ -127,7 +164,7 @@ impl ParticleState {
 	/// # Safety
 	/// This function must be followed by a proper release, through self.unmap(), as this calls NvFlexMap
 	pub unsafe fn get_velocities(&self, solver: *mut NvFlexSolver) -> *mut Vector3 {
-		NvFlexGetVelocities(solver, self.velocities, std::ptr::null());
+		self.fetch(NvFlexGetVelocities, solver, self.velocities);
 		NvFlexMap(self.velocities, eNvFlexMapWait) as *mut Vector3
 	}
 
@@ -135,8 +172,8 @@ impl ParticleState {
 	/// # Safety
 	/// This function must be followed by a proper release, through self.unmap(), as this calls NvFlexMap
 	pub unsafe fn get_phases(&self, solver: *mut NvFlexSolver) -> *mut i32 {
-		NvFlexGetPhases(solver, self.velocities, std::ptr::null());
-		NvFlexMap(self.velocities, eNvFlexMapWait) as *mut i32
+		self.fetch(NvFlexGetPhases, solver, self.phases);
+		NvFlexMap(self.phases, eNvFlexMapWait) as *mut i32
 	}
 
 	/// # Safety
@@ -166,44 +203,236 @@ impl ParticleState {
 		Some(pvec)
 	}
 
+	pub fn get_count(&self) -> i32 {
+		self.count
+	}
+
+	/// Most particles there can be at once.
+	pub fn capacity(&self) -> i32 {
+		self.capacity
+	}
+
+	pub fn get_active(&self) -> &[i32] {
+		&self.active
+	}
+
+	/// Replaces the set of active particles.
+	pub fn set_active(&mut self, active: Vec<i32>) {
+		self.active = active.into_iter().filter(|&i| i >= 0 && i < self.count).collect();
+		self.has_changes = true;
+	}
+
+	/// Deactivates particles, so FleX stops simulating them.
+	/// Their slots stay allocated until [Self::compact] is called.
+	pub fn remove(&mut self, ids: &[i32]) -> usize {
+		let before = self.active.len();
+		self.active.retain(|id| !ids.contains(id));
+
+		let removed = before - self.active.len();
+		if removed > 0 {
+			self.has_changes = true;
+		}
+
+		removed
+	}
+
+	/// Packs the active particles to the start of the buffers, reclaiming removed slots.
+	/// Returns where each old slot moved to, or -1 if it was removed.
+	/// # Safety
+	/// The solver must be valid and no particle buffers can currently be mapped.
+	pub unsafe fn compact(&mut self, solver: *mut NvFlexSolver) -> Vec<i32> {
+		self.flush(solver);
+
+		let mut remap = vec![-1; self.count as usize];
+		let mut active = self.active.clone();
+		active.sort_unstable();
+
+		let particles = self.get_particles(solver);
+		let velocities = self.get_velocities(solver);
+		let phases = self.get_phases(solver);
+
+		// Slots only ever move downwards, so this can be done in place
+		for (new, &old) in active.iter().enumerate() {
+			let (new, old) = (new as isize, old as isize);
+			particles.offset(new).write(particles.offset(old).read());
+			velocities.offset(new).write(velocities.offset(old).read());
+			phases.offset(new).write(phases.offset(old).read());
+
+			remap[old as usize] = new as i32;
+		}
+
+		NvFlexUnmap(self.buffer);
+		NvFlexUnmap(self.velocities);
+		NvFlexUnmap(self.phases);
+
+		self.count = active.len() as i32;
+		self.active = (0 .. self.count).collect();
+		self.has_changes = true;
+		self.flush(solver);
+
+		remap
+	}
+
+	/// Replaces every particle, like when loading a snapshot.
+	/// Extra entries past the capacity are dropped.
+	/// # Safety
+	/// The solver must be valid and no particle buffers can currently be mapped.
+	pub unsafe fn restore(
+		&mut self,
+		solver: *mut NvFlexSolver,
+		positions: &[Vector4],
+		velocities: &[Vector3],
+		phases: &[i32],
+		active: Vec<i32>,
+	) {
+		let count = positions
+			.len()
+			.min(velocities.len())
+			.min(phases.len())
+			.min(self.capacity as usize);
+
+		let particles = NvFlexMap(self.buffer, eNvFlexMapWait) as *mut Vector4;
+		let vels = NvFlexMap(self.velocities, eNvFlexMapWait) as *mut Vector3;
+		let phs = NvFlexMap(self.phases, eNvFlexMapWait) as *mut i32;
+
+		std::ptr::copy_nonoverlapping(positions.as_ptr(), particles, count);
+		std::ptr::copy_nonoverlapping(velocities.as_ptr(), vels, count);
+		std::ptr::copy_nonoverlapping(phases.as_ptr(), phs, count);
+
+		NvFlexUnmap(self.buffer);
+		NvFlexUnmap(self.velocities);
+		NvFlexUnmap(self.phases);
+
+		self.count = count as i32;
+		self.active = active.into_iter().filter(|&i| i >= 0 && i < self.count).collect();
+		self.has_changes = true;
+		self.flush(solver);
+	}
+
+	/// Reads back the positions and velocities of every particle into owned buffers.
+	/// Unlike [Self::get], the buffers are unmapped before returning.
+	/// # Safety
+	/// The solver must be valid and no particle buffers can currently be mapped.
+	pub unsafe fn read(&self, solver: *mut NvFlexSolver) -> (Vec<Vector4>, Vec<Vector3>) {
+		let count = self.count as usize;
+
+		let particles = self.get_particles(solver);
+		let velocities = self.get_velocities(solver);
+
+		let out = (
+			std::slice::from_raw_parts(particles, count).to_vec(),
+			std::slice::from_raw_parts(velocities, count).to_vec(),
+		);
+
+		NvFlexUnmap(self.buffer);
+		NvFlexUnmap(self.velocities);
+
+		out
+	}
+
+	/// Reads back the phase of every particle into an owned buffer.
+	/// # Safety
+	/// The solver must be valid and no particle buffers can currently be mapped.
+	pub unsafe fn read_phases(&self, solver: *mut NvFlexSolver) -> Vec<i32> {
+		let phases = self.get_phases(solver);
+		let out = std::slice::from_raw_parts(phases, self.count as usize).to_vec();
+
+		NvFlexUnmap(self.phases);
+		out
+	}
+
+	/// Reads back positions and velocities, lets `f` edit them and writes them back to the solver.
+	/// # Safety
+	/// The solver must be valid and no particle buffers can currently be mapped.
+	pub unsafe fn modify<F: FnOnce(&mut [Vector4], &mut [Vector3])>(
+		&mut self,
+		solver: *mut NvFlexSolver,
+		f: F,
+	) {
+		// Pending particles would be lost when reading back otherwise
+		self.flush(solver);
+
+		let count = self.count as usize;
+
+		let particles = self.get_particles(solver);
+		let velocities = self.get_velocities(solver);
+
+		f(
+			std::slice::from_raw_parts_mut(particles, count),
+			std::slice::from_raw_parts_mut(velocities, count),
+		);
+
+		NvFlexUnmap(self.buffer);
+		NvFlexUnmap(self.velocities);
+
+		NvFlexSetParticles(solver, self.buffer, std::ptr::null_mut());
+		NvFlexSetVelocities(solver, self.velocities, std::ptr::null_mut());
+	}
+
+	/// Reads back phases, lets `f` edit them and writes them back to the solver.
+	/// # Safety
+	/// The solver must be valid and no particle buffers can currently be mapped.
+	pub unsafe fn modify_phases<F: FnOnce(&mut [i32])>(
+		&mut self,
+		solver: *mut NvFlexSolver,
+		f: F,
+	) {
+		self.flush(solver);
+
+		let phases = self.get_phases(solver);
+		f(std::slice::from_raw_parts_mut(phases, self.count as usize));
+		NvFlexUnmap(self.phases);
+
+		NvFlexSetPhases(solver, self.phases, std::ptr::null_mut());
+	}
+
 	pub fn flush(&mut self, solver: *mut NvFlexSolver) -> bool {
 		if !self.has_changes {
 			return false;
 		}
 
 		unsafe {
+			let active_indices = NvFlexMap(self.active_indices, eNvFlexMapWait) as *mut i32;
+			std::ptr::copy_nonoverlapping(self.active.as_ptr(), active_indices, self.active.len());
+			NvFlexUnmap(self.active_indices);
+
 			NvFlexSetParticles(solver, self.buffer, std::ptr::null_mut());
 			NvFlexSetVelocities(solver, self.velocities, std::ptr::null_mut());
 			NvFlexSetPhases(solver, self.phases, std::ptr::null_mut());
 			NvFlexSetActive(solver, self.active_indices, std::ptr::null_mut());
-			NvFlexSetActiveCount(solver, self.count as i32); // All are active for now.
+			NvFlexSetActiveCount(solver, self.active.len() as i32);
 		}
 
 		self.has_changes = false;
+		self.spawned = None;
 
 		true
 	}
 
 	/// Creates an environment to safely and efficiently create new particles.
 	/// They will be properly mapped and unmapped, however, you still need to [flush] these changes.
-	pub unsafe fn factory<F: Fn(&mut factory::ParticleFactory)>(&mut self, generator: F) {
+	pub unsafe fn factory<F: FnMut(&mut factory::ParticleFactory)>(&mut self, mut generator: F) {
 		let particles = NvFlexMap(self.buffer, eNvFlexMapWait) as *mut Vector4;
 		let velocities = NvFlexMap(self.velocities, eNvFlexMapWait) as *mut Vector3;
 		let phases = NvFlexMap(self.phases, eNvFlexMapWait) as *mut i32;
 		let active_indices = NvFlexMap(self.active_indices, eNvFlexMapWait) as *mut i32;
 
 		let mut factory = factory::ParticleFactory::new(
-			None,
+			Some(self.count as isize),
 			particles,
 			velocities,
 			phases,
-			active_indices
+			active_indices,
+			self.capacity,
 		);
 
 		generator(&mut factory);
 
 		if factory.nparticles > 0 {
 			self.has_changes = true;
+			self.spawned.get_or_insert(self.count);
+			let created = self.count .. self.count + factory.nparticles as i32;
+			self.active.extend(created.filter(|i| !factory.inactive.contains(i)));
 			self.count += factory.nparticles as i32;
 		}
 
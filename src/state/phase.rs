@@ -0,0 +1,89 @@
+use nvflex_sys::*;
+use std::collections::HashMap;
+
+use crate::helper::*;
+
+/// Amount of shape collision channels FleX supports.
+pub const CHANNELS: u32 = 8;
+/// Name of the group the default fluid spawns in.
+pub const FLUID: &str = "fluid";
+
+/// A phase group and how its particles behave.
+#[derive(Clone, Copy, Debug)]
+pub struct PhaseGroup {
+	pub group: i32,
+	/// Any of eNvFlexPhaseSelfCollide, eNvFlexPhaseSelfCollideFilter and eNvFlexPhaseFluid.
+	pub flags: i32,
+	/// Shape channels the group collides with, as a mask of eNvFlexPhaseShapeChannel* bits.
+	pub channels: i32,
+}
+
+impl PhaseGroup {
+	pub fn phase(&self) -> i32 {
+		NvFlexMakePhaseWithChannels(self.group, self.flags, self.channels)
+	}
+}
+
+/// Turns channel numbers from 0 to 7 into a channel mask, where no channels means all of them.
+pub fn channel_mask(channels: &[u32]) -> i32 {
+	if channels.is_empty() {
+		return eNvFlexPhaseShapeChannelMask;
+	}
+
+	channels
+		.iter()
+		.filter(|&&c| c < CHANNELS)
+		.fold(0, |mask, c| mask | (eNvFlexPhaseShapeChannel0 << c))
+}
+
+/// Hands out phase groups, keeping track of the ones given a name from Lua.
+#[derive(Debug, Default)]
+pub struct PhaseState {
+	/// Last group handed out, group 0 belongs to the default fluid.
+	last: i32,
+	named: HashMap<String, PhaseGroup>,
+}
+
+impl PhaseState {
+	/// Allocates a new anonymous group, for bodies that shouldn't collide with themselves.
+	pub fn next_group(&mut self) -> i32 {
+		self.last += 1;
+		self.last
+	}
+
+	/// Creates or redefines a named group, keeping its group number if it already existed.
+	pub fn define(&mut self, name: &str, flags: i32, channels: i32) -> PhaseGroup {
+		let group = match self.named.get(name) {
+			Some(existing) => existing.group,
+			None if name == FLUID => 0,
+			None => self.next_group(),
+		};
+
+		let phase = PhaseGroup {
+			group,
+			flags: flags & eNvFlexPhaseFlagsMask,
+			channels: channels & eNvFlexPhaseShapeChannelMask,
+		};
+
+		self.named.insert(name.to_owned(), phase);
+		phase
+	}
+
+	pub fn get(&self, name: &str) -> Option<&PhaseGroup> {
+		self.named.get(name)
+	}
+
+	pub fn named(&self) -> impl Iterator<Item = (&String, &PhaseGroup)> {
+		self.named.iter()
+	}
+
+	pub fn last_group(&self) -> i32 {
+		self.last
+	}
+
+	/// Replaces every group, like when loading a snapshot.
+	pub fn restore(&mut self, last: i32, named: impl IntoIterator<Item = (String, PhaseGroup)>) {
+		self.last = last;
+		self.named = named.into_iter().collect();
+	}
+}
@@ -0,0 +1,241 @@
+use nvflex_sys::*;
+
+use crate::{
+	snapshot::{Reader, SnapshotError, Writer},
+	types::{Quat, Vector3, Vector4},
+};
+
+use super::buffer::HostBuffer;
+
+/// Shape matching constraints, clustering particles into rigid bodies.
+#[derive(derivative::Derivative, Default)]
+#[derivative(Debug)]
+pub struct RigidState {
+	has_changes: bool,
+
+	/// Start of each rigid in [Self::indices], with one extra entry for the end of the last.
+	offsets: Vec<i32>,
+	indices: Vec<i32>,
+	rest_positions: Vec<Vector3>,
+	rest_normals: Vec<Vector4>,
+	stiffness: Vec<f32>,
+	thresholds: Vec<f32>,
+	creeps: Vec<f32>,
+	rotations: Vec<Quat>,
+	translations: Vec<Vector3>,
+
+	#[derivative(Debug = "ignore")]
+	buffers: RigidBuffers,
+}
+
+#[derive(Debug, Default)]
+struct RigidBuffers {
+	offsets: HostBuffer<i32>,
+	indices: HostBuffer<i32>,
+	rest_positions: HostBuffer<Vector3>,
+	rest_normals: HostBuffer<Vector4>,
+	stiffness: HostBuffer<f32>,
+	thresholds: HostBuffer<f32>,
+	creeps: HostBuffer<f32>,
+	rotations: HostBuffer<Quat>,
+	translations: HostBuffer<Vector3>,
+}
+
+impl RigidState {
+	pub fn get_count(&self) -> usize {
+		self.stiffness.len()
+	}
+
+	/// Registers a cluster of particles as a shape matching constraint, returning its handle.
+	/// `positions` are the current world positions of `particles`, which become the rest pose.
+	/// A `threshold` above zero makes the cluster deform plastically, at a rate given by `creep`.
+	pub fn add(
+		&mut self,
+		particles: &[i32],
+		positions: &[Vector3],
+		stiffness: f32,
+		threshold: f32,
+		creep: f32,
+	) -> usize {
+		let count = particles.len().max(1) as f32;
+		let center = positions
+			.iter()
+			.fold(Vector3::default(), |acc, p| acc + *p)
+			* (1.0 / count);
+
+		if self.offsets.is_empty() {
+			self.offsets.push(0);
+		}
+
+		for (particle, pos) in particles.iter().zip(positions) {
+			let rest = *pos - center;
+			let length = rest.length();
+			let normal = if length > 0.0 {
+				rest * (1.0 / length)
+			} else {
+				Vector3(0.0, 0.0, 1.0)
+			};
+
+			self.indices.push(*particle);
+			self.rest_positions.push(rest);
+			self.rest_normals.push(Vector4(normal.0, normal.1, normal.2, 0.0));
+		}
+
+		self.offsets.push(self.indices.len() as i32);
+		self.stiffness.push(stiffness);
+		self.thresholds.push(threshold);
+		self.creeps.push(creep);
+		self.rotations.push(Quat(0.0, 0.0, 0.0, 1.0));
+		self.translations.push(center);
+
+		self.has_changes = true;
+		self.stiffness.len() - 1
+	}
+
+	/// Latest (translation, rotation) of a rigid, as of the last [Self::read].
+	pub fn get_transform(&self, rigid: usize) -> Option<(Vector3, Quat)> {
+		Some((*self.translations.get(rigid)?, *self.rotations.get(rigid)?))
+	}
+
+	/// Particle indices belonging to a rigid.
+	pub fn get_particles(&self, rigid: usize) -> Option<&[i32]> {
+		let start = *self.offsets.get(rigid)? as usize;
+		let end = *self.offsets.get(rigid + 1)? as usize;
+		self.indices.get(start .. end)
+	}
+
+	/// Whether a particle belongs to any rigid, and so can't be removed.
+	pub fn contains(&self, particle: i32) -> bool {
+		self.indices.contains(&particle)
+	}
+
+	/// Follows particles moved by compaction. Rigid particles are never removed, so every one has a new slot.
+	pub fn remap(&mut self, remap: &[i32]) {
+		for index in &mut self.indices {
+			*index = remap[*index as usize];
+		}
+
+		self.has_changes = true;
+	}
+
+	/// Pushes rigid changes to the FleX state
+	/// # Safety
+	/// `flex` and `solver` must be valid.
+	pub unsafe fn flush(&mut self, flex: *mut NvFlexLibrary, solver: *mut NvFlexSolver) {
+		if !self.has_changes {
+			return;
+		}
+
+		let b = &mut self.buffers;
+		b.offsets.upload(flex, &self.offsets);
+		b.indices.upload(flex, &self.indices);
+		b.rest_positions.upload(flex, &self.rest_positions);
+		b.rest_normals.upload(flex, &self.rest_normals);
+		b.stiffness.upload(flex, &self.stiffness);
+		b.thresholds.upload(flex, &self.thresholds);
+		b.creeps.upload(flex, &self.creeps);
+		b.rotations.upload(flex, &self.rotations);
+		b.translations.upload(flex, &self.translations);
+
+		NvFlexSetRigids(
+			solver,
+			b.offsets.buffer,
+			b.indices.buffer,
+			b.rest_positions.buffer,
+			b.rest_normals.buffer,
+			b.stiffness.buffer,
+			b.thresholds.buffer,
+			b.creeps.buffer,
+			b.rotations.buffer,
+			b.translations.buffer,
+			self.stiffness.len() as i32,
+			self.indices.len() as i32,
+		);
+
+		self.has_changes = false;
+	}
+
+	/// Writes every rigid into a snapshot, with transforms as of the last [Self::read].
+	pub fn write_snapshot(&self, w: &mut Writer) {
+		w.slice(&self.offsets, Writer::i32);
+		w.slice(&self.indices, Writer::i32);
+		w.slice(&self.rest_positions, Writer::vector3);
+		w.slice(&self.rest_normals, Writer::vector4);
+		w.slice(&self.stiffness, Writer::f32);
+		w.slice(&self.thresholds, Writer::f32);
+		w.slice(&self.creeps, Writer::f32);
+		w.slice(&self.rotations, Writer::quat);
+		w.slice(&self.translations, Writer::vector3);
+	}
+
+	/// Reads rigids written by [Self::write_snapshot], which may only use the first `particles`.
+	pub fn read_snapshot(r: &mut Reader, particles: usize) -> Result<Self, SnapshotError> {
+		let rigids = Self {
+			has_changes: true,
+
+			offsets: r.vec(4, Reader::i32)?,
+			indices: r.vec(4, Reader::i32)?,
+			rest_positions: r.vec(12, Reader::vector3)?,
+			rest_normals: r.vec(16, Reader::vector4)?,
+			stiffness: r.vec(4, Reader::f32)?,
+			thresholds: r.vec(4, Reader::f32)?,
+			creeps: r.vec(4, Reader::f32)?,
+			rotations: r.vec(16, Reader::quat)?,
+			translations: r.vec(12, Reader::vector3)?,
+
+			buffers: RigidBuffers::default(),
+		};
+
+		let count = rigids.stiffness.len();
+		let per_rigid = [
+			rigids.thresholds.len(),
+			rigids.creeps.len(),
+			rigids.rotations.len(),
+			rigids.translations.len(),
+		];
+		let nindices = rigids.indices.len();
+		let offsets = match rigids.offsets.len() {
+			0 => count == 0,
+			n => n == count + 1 && rigids.offsets.last() == Some(&(nindices as i32)),
+		};
+
+		let valid = offsets
+			&& per_rigid.iter().all(|&n| n == count)
+			&& rigids.offsets.windows(2).all(|w| 0 <= w[0] && w[0] <= w[1])
+			&& rigids.rest_positions.len() == nindices
+			&& rigids.rest_normals.len() == nindices
+			&& rigids.indices.iter().all(|&i| 0 <= i && (i as usize) < particles);
+
+		if !valid {
+			return Err(SnapshotError::Range("rigid"));
+		}
+
+		Ok(rigids)
+	}
+
+	/// Reads back the transforms of every rigid from the solver.
+	/// # Safety
+	/// The solver must be valid and the rigids must have been flushed.
+	pub unsafe fn read(&mut self, solver: *mut NvFlexSolver) {
+		if self.stiffness.is_empty() {
+			return;
+		}
+
+		let b = &self.buffers;
+		NvFlexGetRigids(
+			solver,
+			std::ptr::null_mut(),
+			std::ptr::null_mut(),
+			std::ptr::null_mut(),
+			std::ptr::null_mut(),
+			std::ptr::null_mut(),
+			std::ptr::null_mut(),
+			std::ptr::null_mut(),
+			b.rotations.buffer,
+			b.translations.buffer,
+		);
+
+		b.rotations.download(&mut self.rotations);
+		b.translations.download(&mut self.translations);
+	}
+}
@@ -0,0 +1,277 @@
+use crate::{
+	snapshot::{Reader, SnapshotError, Writer},
+	types::{Quat, Vector3},
+	voxel,
+};
+
+use super::rigid::RigidState;
+
+/// Max amount of clusters a mesh vertex is skinned to.
+const MAX_INFLUENCES: usize = 4;
+
+/// Parameters used when clustering a soft body.
+#[derive(Clone, Copy, Debug)]
+pub struct SoftParams {
+	/// Stiffness of each shape matching cluster, lower is squishier.
+	pub stiffness: f32,
+	/// Distance between cluster centers, as a multiple of the particle spacing.
+	pub cluster_spacing: f32,
+	/// Radius of each cluster, as a multiple of the particle spacing.
+	/// Should be larger than [Self::cluster_spacing] so clusters overlap.
+	pub cluster_radius: f32,
+
+	/// Deformation above which the body deforms permanently, zero to disable plasticity.
+	pub threshold: f32,
+	/// How fast plastic deformation is absorbed into the rest pose.
+	pub creep: f32,
+}
+
+impl Default for SoftParams {
+	fn default() -> Self {
+		Self {
+			stiffness: 0.5,
+			cluster_spacing: 4.0,
+			cluster_radius: 6.0,
+
+			threshold: 0.0,
+			creep: 0.0,
+		}
+	}
+}
+
+/// A closed mesh simulated by overlapping shape matching clusters.
+/// The render mesh is skinned to the cluster transforms.
+#[derive(Clone, Debug)]
+pub struct SoftBody {
+	/// Rigid handles of each cluster
+	pub clusters: Vec<usize>,
+	/// Center of each cluster at rest, in world space
+	pub rest_centers: Vec<Vector3>,
+
+	/// Mesh vertices at rest, in world space
+	pub vertices: Vec<Vector3>,
+	pub indices: Vec<u32>,
+	/// (cluster, weight) influences for each vertex
+	skin: Vec<Vec<(usize, f32)>>,
+}
+
+#[derive(Debug, Default)]
+pub struct SoftState {
+	pub bodies: Vec<SoftBody>,
+}
+
+impl SoftState {
+	/// Registers a soft body whose particles are already created, returning its handle.
+	/// `positions` are the world positions of `particles`, `mesh` the world space render mesh.
+	pub fn add(
+		&mut self,
+		rigids: &mut RigidState,
+		particles: &[i32],
+		positions: &[Vector3],
+		mesh: (Vec<Vector3>, Vec<u32>),
+		spacing: f32,
+		params: SoftParams,
+	) -> usize {
+		let (vertices, indices) = mesh;
+
+		let groups = cluster(
+			positions,
+			params.cluster_spacing * spacing,
+			params.cluster_radius * spacing,
+		);
+
+		let mut clusters = Vec::with_capacity(groups.len());
+		let mut rest_centers = Vec::with_capacity(groups.len());
+
+		for group in groups {
+			let ids: Vec<i32> = group.iter().map(|&i| particles[i]).collect();
+			let points: Vec<Vector3> = group.iter().map(|&i| positions[i]).collect();
+
+			let center = points
+				.iter()
+				.fold(Vector3::default(), |acc, p| acc + *p)
+				* (1.0 / points.len() as f32);
+
+			clusters.push(rigids.add(
+				&ids,
+				&points,
+				params.stiffness,
+				params.threshold,
+				params.creep,
+			));
+			rest_centers.push(center);
+		}
+
+		let skin = vertices
+			.iter()
+			.map(|v| skin_weights(*v, &rest_centers))
+			.collect();
+
+		self.bodies.push(SoftBody {
+			clusters,
+			rest_centers,
+			vertices,
+			indices,
+			skin,
+		});
+
+		self.bodies.len() - 1
+	}
+
+	/// Deformed render mesh vertices of a soft body, from the latest cluster transforms.
+	pub fn deformed(&self, rigids: &RigidState, body: usize) -> Option<Vec<Vector3>> {
+		let body = self.bodies.get(body)?;
+
+		let transforms: Vec<(Vector3, Quat)> = body
+			.clusters
+			.iter()
+			.map(|&rigid| {
+				rigids
+					.get_transform(rigid)
+					.unwrap_or((Vector3::default(), Quat(0.0, 0.0, 0.0, 1.0)))
+			})
+			.collect();
+
+		let vertices = body
+			.vertices
+			.iter()
+			.zip(&body.skin)
+			.map(|(vertex, influences)| {
+				influences
+					.iter()
+					.fold(Vector3::default(), |acc, &(cluster, weight)| {
+						let (translation, rotation) = transforms[cluster];
+						let local = *vertex - body.rest_centers[cluster];
+						acc + (translation + rotation.rotate(local)) * weight
+					})
+			})
+			.collect();
+
+		Some(vertices)
+	}
+
+	/// Writes every soft body into a snapshot. Skins aren't written as they're rebuilt on read.
+	pub fn write_snapshot(&self, w: &mut Writer) {
+		w.count(self.bodies.len());
+		for body in &self.bodies {
+			w.slice(&body.clusters, |w, rigid| w.count(rigid));
+			w.slice(&body.rest_centers, Writer::vector3);
+			w.slice(&body.vertices, Writer::vector3);
+			w.slice(&body.indices, Writer::u32);
+		}
+	}
+
+	/// Reads soft bodies written by [Self::write_snapshot], whose clusters must be among `rigids`.
+	pub fn read_snapshot(r: &mut Reader, rigids: usize) -> Result<Self, SnapshotError> {
+		let mut bodies = vec![];
+		for _ in 0 .. r.count(16)? {
+			let clusters: Vec<usize> = r.vec(4, |r| Ok(r.u32()? as usize))?;
+			let rest_centers = r.vec(12, Reader::vector3)?;
+			let vertices: Vec<Vector3> = r.vec(12, Reader::vector3)?;
+			let indices: Vec<u32> = r.vec(4, Reader::u32)?;
+
+			let valid = clusters.len() == rest_centers.len()
+				&& clusters.iter().all(|&rigid| rigid < rigids)
+				&& indices.iter().all(|&i| (i as usize) < vertices.len());
+
+			if !valid {
+				return Err(SnapshotError::Range("soft body"));
+			}
+
+			let skin = vertices
+				.iter()
+				.map(|v| skin_weights(*v, &rest_centers))
+				.collect();
+
+			bodies.push(SoftBody {
+				clusters,
+				rest_centers,
+				vertices,
+				indices,
+				skin,
+			});
+		}
+
+		Ok(Self { bodies })
+	}
+}
+
+/// Splits points into overlapping clusters, centered on a lattice with `spacing` between centers.
+/// Every point ends up in at least one cluster.
+pub fn cluster(points: &[Vector3], spacing: f32, radius: f32) -> Vec<Vec<usize>> {
+	let (lower, upper) = match voxel::bounds(points) {
+		Some(bounds) => bounds,
+		None => return vec![],
+	};
+
+	let mid = (lower + upper) * 0.5;
+	let half = (upper - lower) * 0.5;
+
+	let mut clusters: Vec<(Vector3, Vec<usize>)> = voxel::sample_box(half, spacing)
+		.map(|c| {
+			let center = c + mid;
+			let members: Vec<usize> = points
+				.iter()
+				.enumerate()
+				.filter(|(_, p)| (**p - center).length() <= radius)
+				.map(|(i, _)| i)
+				.collect();
+
+			(center, members)
+		})
+		.filter(|(_, members)| members.len() > 1)
+		.collect();
+
+	if clusters.is_empty() {
+		return vec![(0 .. points.len()).collect()];
+	}
+
+	// Give stray points to the nearest cluster so nothing is left unconstrained
+	let mut covered = vec![false; points.len()];
+	for (_, members) in &clusters {
+		for &i in members {
+			covered[i] = true;
+		}
+	}
+
+	for (i, point) in points.iter().enumerate() {
+		if covered[i] {
+			continue;
+		}
+
+		let nearest = clusters
+			.iter_mut()
+			.min_by(|(a, _), (b, _)| {
+				let da = (*a - *point).length_squared();
+				let db = (*b - *point).length_squared();
+				da.total_cmp(&db)
+			})
+			.map(|(_, members)| members);
+
+		if let Some(members) = nearest {
+			members.push(i);
+		}
+	}
+
+	clusters.into_iter().map(|(_, members)| members).collect()
+}
+
+/// Inverse distance weights of the nearest cluster centers to a vertex.
+fn skin_weights(vertex: Vector3, centers: &[Vector3]) -> Vec<(usize, f32)> {
+	let mut nearest: Vec<(usize, f32)> = centers
+		.iter()
+		.enumerate()
+		.map(|(i, c)| (i, (*c - vertex).length()))
+		.collect();
+
+	nearest.sort_by(|a, b| a.1.total_cmp(&b.1));
+	nearest.truncate(MAX_INFLUENCES);
+
+	let weights: Vec<(usize, f32)> = nearest
+		.into_iter()
+		.map(|(i, d)| (i, 1.0 / d.max(1e-4)))
+		.collect();
+
+	let total: f32 = weights.iter().map(|(_, w)| w).sum();
+	weights.into_iter().map(|(i, w)| (i, w / total)).collect()
+}
@@ -0,0 +1,127 @@
+// Per step timings, to see what leaving fluid running costs a server.
+use nvflex_sys::*;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Steps the rolling averages span.
+const WINDOW: usize = 60;
+
+/// Milliseconds something took over the last few steps.
+#[derive(Debug, Default)]
+pub struct Timing {
+	samples: VecDeque<f32>,
+}
+
+impl Timing {
+	fn push(&mut self, duration: Duration) {
+		if self.samples.len() >= WINDOW {
+			self.samples.pop_front();
+		}
+		self.samples.push_back(duration.as_secs_f32() * 1000.0);
+	}
+
+	pub fn last(&self) -> f32 {
+		self.samples.back().copied().unwrap_or_default()
+	}
+
+	pub fn average(&self) -> f32 {
+		if self.samples.is_empty() {
+			return 0.0;
+		}
+		self.samples.iter().sum::<f32>() / self.samples.len() as f32
+	}
+
+	pub fn max(&self) -> f32 {
+		self.samples.iter().copied().fold(0.0, f32::max)
+	}
+}
+
+#[derive(derivative::Derivative, Default)]
+#[derivative(Debug)]
+pub struct Stats {
+	/// Has FleX time each stage of the solver, which costs a little and syncs with the GPU.
+	pub timers_enabled: bool,
+
+	/// Whole step, including pushing changes and any readback
+	pub step: Timing,
+	/// Copying particles back from FleX
+	pub readback: Timing,
+	/// Building tables for Lua since the step before
+	pub marshal: Timing,
+
+	/// Milliseconds each stage took last step, when timers are enabled
+	#[derivative(Debug = "ignore")]
+	pub timers: Option<NvFlexTimers>,
+	/// Milliseconds of every kernel FleX ran last step, when timers are enabled
+	pub detail_timers: Vec<(String, f32)>,
+
+	readback_pending: Duration,
+	marshal_pending: Duration,
+}
+
+impl Stats {
+	pub fn add_readback(&mut self, duration: Duration) {
+		self.readback_pending += duration;
+	}
+
+	pub fn add_marshal(&mut self, duration: Duration) {
+		self.marshal_pending += duration;
+	}
+
+	/// Records a finished step, along with the readback and marshalling done since the last one.
+	pub fn end_step(&mut self, duration: Duration) {
+		self.step.push(duration);
+		self.readback.push(std::mem::take(&mut self.readback_pending));
+		self.marshal.push(std::mem::take(&mut self.marshal_pending));
+	}
+
+	/// Reads the timers of the last step, which only has anything when it ran with timers enabled.
+	/// # Safety
+	/// The solver must be valid.
+	pub unsafe fn read_timers(&mut self, solver: *mut NvFlexSolver) {
+		let mut timers: NvFlexTimers = std::mem::zeroed();
+		NvFlexGetTimers(solver, &mut timers);
+		self.timers = Some(timers);
+
+		let mut detail: *mut NvFlexDetailTimer = std::ptr::null_mut();
+		let count = NvFlexGetDetailTimers(solver, &mut detail);
+
+		self.detail_timers.clear();
+		if !detail.is_null() {
+			for timer in std::slice::from_raw_parts(detail, count.max(0) as usize) {
+				let name = std::ffi::CStr::from_ptr(timer.name).to_string_lossy();
+				self.detail_timers.push((name.into_owned(), timer.time));
+			}
+		}
+	}
+}
+
+/// Every stage of [NvFlexTimers] by name, in the order FleX runs them.
+pub fn stages(t: &NvFlexTimers) -> [(&'static str, f32); 24] {
+	[
+		("predict", t.predict),
+		("createCellIndices", t.createCellIndices),
+		("sortCellIndices", t.sortCellIndices),
+		("createGrid", t.createGrid),
+		("reorder", t.reorder),
+		("collideParticles", t.collideParticles),
+		("collideShapes", t.collideShapes),
+		("collideTriangles", t.collideTriangles),
+		("collideFields", t.collideFields),
+		("calculateDensity", t.calculateDensity),
+		("solveDensities", t.solveDensities),
+		("solveVelocities", t.solveVelocities),
+		("solveShapes", t.solveShapes),
+		("solveSprings", t.solveSprings),
+		("solveContacts", t.solveContacts),
+		("solveInflatables", t.solveInflatables),
+		("applyDeltas", t.applyDeltas),
+		("calculateAnisotropy", t.calculateAnisotropy),
+		("updateDiffuse", t.updateDiffuse),
+		("updateTriangles", t.updateTriangles),
+		("updateNormals", t.updateNormals),
+		("finalize", t.finalize),
+		("updateBounds", t.updateBounds),
+		("total", t.total),
+	]
+}
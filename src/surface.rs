@@ -0,0 +1,317 @@
+// Surface reconstruction of the fluid.
+// Particles are splatted into a density grid, which is then polygonized with marching cubes.
+use std::collections::HashMap;
+
+use crate::types::Vector3;
+
+/// Grids larger than this per axis are coarsened to keep a single extraction bounded.
+const MAX_CELLS: usize = 192;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SurfaceParams {
+	/// Size of a grid cell, smaller is smoother and slower.
+	pub cell: f32,
+	/// Radius of the kernel each particle is splatted with.
+	pub kernel: f32,
+	/// Density at which the surface is extracted.
+	pub iso: f32,
+	/// Region of interest as (lower, upper), defaults to the bounds of the particles.
+	pub region: Option<(Vector3, Vector3)>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SurfaceMesh {
+	pub vertices: Vec<Vector3>,
+	pub normals: Vec<Vector3>,
+	/// Zero based triangle indices, wound clockwise when seen from outside like Source expects.
+	pub indices: Vec<u32>,
+}
+
+struct Grid {
+	origin: Vector3,
+	cell: f32,
+	dims: [usize; 3],
+	density: Vec<f32>,
+}
+
+impl Grid {
+	fn index(&self, x: usize, y: usize, z: usize) -> usize {
+		(z * self.dims[1] + y) * self.dims[0] + x
+	}
+
+	fn at(&self, x: usize, y: usize, z: usize) -> f32 {
+		self.density[self.index(x, y, z)]
+	}
+
+	fn position(&self, x: usize, y: usize, z: usize) -> Vector3 {
+		self.origin + Vector3(x as f32, y as f32, z as f32) * self.cell
+	}
+
+	/// Density gradient at a node from central differences, clamped at the borders.
+	fn gradient(&self, x: usize, y: usize, z: usize) -> Vector3 {
+		let [nx, ny, nz] = self.dims;
+		let diff = |a: f32, b: f32, span: usize| (a - b) / (span as f32 * self.cell);
+
+		let (x0, x1) = (x.saturating_sub(1), (x + 1).min(nx - 1));
+		let (y0, y1) = (y.saturating_sub(1), (y + 1).min(ny - 1));
+		let (z0, z1) = (z.saturating_sub(1), (z + 1).min(nz - 1));
+
+		Vector3(
+			diff(self.at(x1, y, z), self.at(x0, y, z), (x1 - x0).max(1)),
+			diff(self.at(x, y1, z), self.at(x, y0, z), (y1 - y0).max(1)),
+			diff(self.at(x, y, z1), self.at(x, y, z0), (z1 - z0).max(1)),
+		)
+	}
+}
+
+/// Builds a triangle mesh of the fluid surface from particle positions.
+pub fn extract(points: &[Vector3], params: &SurfaceParams) -> SurfaceMesh {
+	let grid = match splat(points, params) {
+		Some(grid) => grid,
+		None => return SurfaceMesh::default(),
+	};
+
+	let table = CaseTable::new();
+	let mut mesh = SurfaceMesh::default();
+	// Vertices are shared between cells through the grid edge they lie on, so normals stay smooth
+	let mut shared: HashMap<(usize, u8), u32> = HashMap::new();
+
+	let [nx, ny, nz] = grid.dims;
+	for z in 0 .. nz - 1 {
+		for y in 0 .. ny - 1 {
+			for x in 0 .. nx - 1 {
+				let corner = |c: usize| (x + (c & 1), y + ((c >> 1) & 1), z + ((c >> 2) & 1));
+
+				let mut case = 0;
+				for c in 0 .. 8 {
+					let (cx, cy, cz) = corner(c);
+					if grid.at(cx, cy, cz) >= params.iso {
+						case |= 1 << c;
+					}
+				}
+
+				for tri in &table.cases[case] {
+					let mut ids = [0; 3];
+
+					for (id, &edge) in ids.iter_mut().zip(tri) {
+						let (a, b) = EDGES[edge];
+						let (a, b) = (corner(a), corner(b));
+						let axis = EDGE_AXIS[edge];
+
+						*id = *shared
+							.entry((grid.index(a.0, a.1, a.2), axis))
+							.or_insert_with(|| {
+								let (da, db) = (grid.at(a.0, a.1, a.2), grid.at(b.0, b.1, b.2));
+								let t = ((params.iso - da) / (db - da)).clamp(0.0, 1.0);
+
+								let pa = grid.position(a.0, a.1, a.2);
+								let pb = grid.position(b.0, b.1, b.2);
+								let ga = grid.gradient(a.0, a.1, a.2);
+								let gb = grid.gradient(b.0, b.1, b.2);
+
+								// Density increases inwards, so the outward normal is against the gradient
+								let normal = -(ga + (gb - ga) * t);
+								let length = normal.length();
+
+								mesh.vertices.push(pa + (pb - pa) * t);
+								mesh.normals.push(if length > 0.0 {
+									normal * (1.0 / length)
+								} else {
+									Vector3(0.0, 0.0, 1.0)
+								});
+
+								mesh.vertices.len() as u32 - 1
+							});
+					}
+
+					push_triangle(&mut mesh, ids);
+				}
+			}
+		}
+	}
+
+	mesh
+}
+
+/// Pushes a triangle, flipping it if needed so it's clockwise around its vertex normals.
+fn push_triangle(mesh: &mut SurfaceMesh, [a, b, c]: [u32; 3]) {
+	if a == b || b == c || a == c {
+		return;
+	}
+
+	let v = |i: u32| mesh.vertices[i as usize];
+	let n = |i: u32| mesh.normals[i as usize];
+
+	let face = (v(b) - v(a)).cross(v(c) - v(a));
+	if face.dot(n(a) + n(b) + n(c)) > 0.0 {
+		mesh.indices.extend_from_slice(&[a, c, b]);
+	} else {
+		mesh.indices.extend_from_slice(&[a, b, c]);
+	}
+}
+
+/// Splats particles into a density grid covering the region of interest.
+fn splat(points: &[Vector3], params: &SurfaceParams) -> Option<Grid> {
+	let kernel = params.kernel.max(f32::EPSILON);
+	let pad = Vector3(kernel, kernel, kernel);
+
+	let (lower, upper) = match params.region {
+		Some(region) => region,
+		None => {
+			let (lo, hi) = crate::voxel::bounds(points)?;
+			(lo - pad, hi + pad)
+		}
+	};
+
+	let extent = upper - lower;
+	let longest = extent.0.max(extent.1).max(extent.2);
+	if longest <= 0.0 {
+		return None;
+	}
+
+	let cell = params.cell.max(longest / MAX_CELLS as f32);
+	let steps = |e: f32| (e / cell).ceil() as usize + 1;
+	let dims = [steps(extent.0), steps(extent.1), steps(extent.2)];
+
+	let mut grid = Grid {
+		origin: lower,
+		cell,
+		dims,
+		density: vec![0.0; dims[0] * dims[1] * dims[2]],
+	};
+
+	let kernel2 = kernel * kernel;
+	let node = |v: f32, lo: f32, n: usize| {
+		((v - lo) / cell).floor().clamp(0.0, (n - 1) as f32) as usize
+	};
+
+	for p in points {
+		let (lo, hi) = (*p - pad, *p + pad);
+		if hi.0 < lower.0 || hi.1 < lower.1 || hi.2 < lower.2 {
+			continue;
+		}
+		if lo.0 > upper.0 || lo.1 > upper.1 || lo.2 > upper.2 {
+			continue;
+		}
+
+		for z in node(lo.2, lower.2, dims[2]) ..= node(hi.2, lower.2, dims[2]) + 1 {
+			for y in node(lo.1, lower.1, dims[1]) ..= node(hi.1, lower.1, dims[1]) + 1 {
+				for x in node(lo.0, lower.0, dims[0]) ..= node(hi.0, lower.0, dims[0]) + 1 {
+					if x >= dims[0] || y >= dims[1] || z >= dims[2] {
+						continue;
+					}
+
+					let r2 = (grid.position(x, y, z) - *p).length_squared();
+					if r2 < kernel2 {
+						// Poly6 style falloff, 1 at the particle and 0 at the kernel's edge
+						let w = 1.0 - r2 / kernel2;
+						let index = grid.index(x, y, z);
+						grid.density[index] += w * w * w;
+					}
+				}
+			}
+		}
+	}
+
+	Some(grid)
+}
+
+/// Corners of each cube edge, with corner `c` at (c & 1, c >> 1 & 1, c >> 2 & 1).
+const EDGES: [(usize, usize); 12] = [
+	(0, 1), (2, 3), (4, 5), (6, 7), // x
+	(0, 2), (1, 3), (4, 6), (5, 7), // y
+	(0, 4), (1, 5), (2, 6), (3, 7), // z
+];
+const EDGE_AXIS: [u8; 12] = [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2];
+
+/// Corners of each cube face, in order around it.
+const FACES: [[usize; 4]; 6] = [
+	[0, 2, 6, 4], [1, 3, 7, 5], // -x, +x
+	[0, 1, 5, 4], [2, 3, 7, 6], // -y, +y
+	[0, 1, 3, 2], [4, 5, 7, 6], // -z, +z
+];
+
+/// Triangulation of all 256 marching cubes cases, as triplets of cube edges.
+/// Rather than hardcoding the usual lookup table, it's derived from the cube's faces.
+/// Each face contributes the segments its corners imply, which chain into loops that get fan triangulated.
+/// Ambiguous faces always separate the inside corners, so neighboring cubes agree on them.
+struct CaseTable {
+	cases: Vec<Vec<[usize; 3]>>,
+}
+
+impl CaseTable {
+	fn new() -> Self {
+		Self {
+			cases: (0 .. 256).map(triangulate).collect(),
+		}
+	}
+}
+
+fn edge_between(a: usize, b: usize) -> usize {
+	EDGES
+		.iter()
+		.position(|&(x, y)| (x, y) == (a, b) || (x, y) == (b, a))
+		.expect("corners should share an edge")
+}
+
+fn triangulate(case: usize) -> Vec<[usize; 3]> {
+	let inside = |c: usize| case & (1 << c) != 0;
+
+	// Two neighbors per crossed edge
+	let mut links: Vec<Vec<usize>> = vec![vec![]; 12];
+	let mut link = |a: usize, b: usize| {
+		links[a].push(b);
+		links[b].push(a);
+	};
+
+	for face in FACES {
+		let crossing = |i: usize| {
+			let (a, b) = (face[i], face[(i + 1) % 4]);
+			(inside(a) != inside(b)).then(|| edge_between(a, b))
+		};
+
+		let crossings: Vec<usize> = (0 .. 4).filter_map(crossing).collect();
+		match crossings.len() {
+			2 => link(crossings[0], crossings[1]),
+			4 => {
+				// Cut off each inside corner by joining its two edges
+				for i in 0 .. 4 {
+					if inside(face[i]) {
+						let prev = edge_between(face[(i + 3) % 4], face[i]);
+						let next = edge_between(face[i], face[(i + 1) % 4]);
+						link(prev, next);
+					}
+				}
+			}
+			_ => (),
+		}
+	}
+
+	let mut triangles = vec![];
+	let mut visited = [false; 12];
+
+	for start in 0 .. 12 {
+		if visited[start] || links[start].is_empty() {
+			continue;
+		}
+
+		let mut polygon = vec![start];
+		visited[start] = true;
+
+		let (mut prev, mut current) = (start, links[start][0]);
+		while current != start && !visited[current] {
+			visited[current] = true;
+			polygon.push(current);
+
+			let next = links[current].iter().copied().find(|&n| n != prev).unwrap_or(start);
+			prev = current;
+			current = next;
+		}
+
+		for i in 1 .. polygon.len().saturating_sub(1) {
+			triangles.push([polygon[0], polygon[i], polygon[i + 1]]);
+		}
+	}
+
+	triangles
+}
@@ -1,3 +1,5 @@
+use std::ops::{Add, AddAssign, Mul, Neg, Sub};
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Vector3(pub f32, pub f32, pub f32);
@@ -18,3 +20,83 @@ pub struct Particle<'a> {
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Quat(pub f32, pub f32, pub f32, pub f32);
+
+impl Vector3 {
+	pub fn dot(&self, other: Vector3) -> f32 {
+		self.0 * other.0 + self.1 * other.1 + self.2 * other.2
+	}
+
+	pub fn cross(&self, other: Vector3) -> Vector3 {
+		Vector3(
+			self.1 * other.2 - self.2 * other.1,
+			self.2 * other.0 - self.0 * other.2,
+			self.0 * other.1 - self.1 * other.0,
+		)
+	}
+
+	pub fn length_squared(&self) -> f32 {
+		self.dot(*self)
+	}
+
+	pub fn length(&self) -> f32 {
+		self.length_squared().sqrt()
+	}
+}
+
+impl Vector4 {
+	/// Position part of the vector, dropping the inverse mass.
+	pub fn xyz(&self) -> Vector3 {
+		Vector3(self.0, self.1, self.2)
+	}
+}
+
+impl Quat {
+	pub fn conjugate(&self) -> Quat {
+		Quat(-self.0, -self.1, -self.2, self.3)
+	}
+
+	/// Rotates a vector by this quaternion, assuming it is normalized.
+	pub fn rotate(&self, v: Vector3) -> Vector3 {
+		let q = Vector3(self.0, self.1, self.2);
+		let t = q.cross(v) * 2.0;
+		v + t * self.3 + q.cross(t)
+	}
+}
+
+impl Add for Vector3 {
+	type Output = Vector3;
+
+	fn add(self, rhs: Vector3) -> Vector3 {
+		Vector3(self.0 + rhs.0, self.1 + rhs.1, self.2 + rhs.2)
+	}
+}
+
+impl AddAssign for Vector3 {
+	fn add_assign(&mut self, rhs: Vector3) {
+		*self = *self + rhs;
+	}
+}
+
+impl Sub for Vector3 {
+	type Output = Vector3;
+
+	fn sub(self, rhs: Vector3) -> Vector3 {
+		Vector3(self.0 - rhs.0, self.1 - rhs.1, self.2 - rhs.2)
+	}
+}
+
+impl Mul<f32> for Vector3 {
+	type Output = Vector3;
+
+	fn mul(self, rhs: f32) -> Vector3 {
+		Vector3(self.0 * rhs, self.1 * rhs, self.2 * rhs)
+	}
+}
+
+impl Neg for Vector3 {
+	type Output = Vector3;
+
+	fn neg(self) -> Vector3 {
+		Vector3(-self.0, -self.1, -self.2)
+	}
+}
@@ -0,0 +1,185 @@
+// Sampling of primitive and mesh volumes into lattices of points, used to build particle bodies.
+use crate::types::Vector3;
+
+/// Most points a lattice can have, so a huge volume or a tiny spacing from Lua is turned down
+/// instead of hanging the server or running it out of memory.
+pub const MAX_LATTICE_POINTS: usize = 1 << 24;
+
+/// Points along each axis of a lattice filling a box, or None if the spacing isn't positive
+/// or there would be more than [MAX_LATTICE_POINTS] of them.
+pub fn lattice_steps(half: Vector3, spacing: f32) -> Option<[usize; 3]> {
+	if spacing.is_nan() || spacing <= 0.0 {
+		return None;
+	}
+
+	let steps = |extent: f32| {
+		let steps = (2.0 * extent / spacing).floor();
+		if steps.is_nan() || steps <= 0.0 {
+			Some(1)
+		} else if steps < MAX_LATTICE_POINTS as f32 {
+			Some(steps as usize + 1)
+		} else {
+			None
+		}
+	};
+
+	let steps = [steps(half.0)?, steps(half.1)?, steps(half.2)?];
+	let count = steps[0].checked_mul(steps[1])?.checked_mul(steps[2])?;
+	(count <= MAX_LATTICE_POINTS).then_some(steps)
+}
+
+/// Points on a lattice with `spacing` between them, filling a box centered at the origin.
+/// Generated lazily and empty if [lattice_steps] turns the box down.
+pub fn sample_box(half: Vector3, spacing: f32) -> impl Iterator<Item = Vector3> {
+	let [nx, ny, nz] = lattice_steps(half, spacing).unwrap_or_default();
+
+	// Center the lattice so leftover space is split evenly on both sides
+	let start = Vector3(
+		-((nx.max(1) - 1) as f32) * spacing * 0.5,
+		-((ny.max(1) - 1) as f32) * spacing * 0.5,
+		-((nz.max(1) - 1) as f32) * spacing * 0.5,
+	);
+
+	(0 .. nx).flat_map(move |x| {
+		(0 .. ny).flat_map(move |y| {
+			(0 .. nz).map(move |z| start + Vector3(x as f32, y as f32, z as f32) * spacing)
+		})
+	})
+}
+
+/// Points on a lattice filling a sphere centered at the origin.
+pub fn sample_sphere(radius: f32, spacing: f32) -> impl Iterator<Item = Vector3> {
+	sample_box(Vector3(radius, radius, radius), spacing).filter(move |p| p.length() <= radius)
+}
+
+/// Points on a lattice filling a cylinder centered at the origin, along the z axis.
+pub fn sample_cylinder(
+	radius: f32,
+	half_height: f32,
+	spacing: f32,
+) -> impl Iterator<Item = Vector3> {
+	let radius2 = radius * radius;
+
+	sample_box(Vector3(radius, radius, half_height), spacing)
+		.filter(move |p| p.0 * p.0 + p.1 * p.1 <= radius2)
+}
+
+/// Points on a lattice inside of a closed triangle mesh.
+/// `indices` are triplets of zero based indices into `vertices`.
+pub fn sample_mesh<'a>(
+	vertices: &'a [Vector3],
+	indices: &'a [u32],
+	spacing: f32,
+) -> impl Iterator<Item = Vector3> + 'a {
+	let (lower, upper) = bounds(vertices).unwrap_or_default();
+	let center = (lower + upper) * 0.5;
+	let half = (upper - lower) * 0.5;
+
+	// Without vertices there are no triangles, so nothing is inside
+	sample_box(half, spacing)
+		.map(move |p| p + center)
+		.filter(move |p| contains(vertices, indices, *p))
+}
+
+/// Axis aligned bounds of a set of points, as (lower, upper).
+pub fn bounds(points: &[Vector3]) -> Option<(Vector3, Vector3)> {
+	let first = *points.first()?;
+
+	Some(points.iter().fold((first, first), |(lo, hi), p| {
+		(
+			Vector3(lo.0.min(p.0), lo.1.min(p.1), lo.2.min(p.2)),
+			Vector3(hi.0.max(p.0), hi.1.max(p.1), hi.2.max(p.2)),
+		)
+	}))
+}
+
+/// Volume enclosed by a closed, consistently wound mesh, from the sum of signed tetrahedra.
+pub fn volume(vertices: &[Vector3], indices: &[u32]) -> f32 {
+	let volume: f32 = indices
+		.chunks_exact(3)
+		.filter_map(|tri| {
+			let a = vertices.get(tri[0] as usize)?;
+			let b = vertices.get(tri[1] as usize)?;
+			let c = vertices.get(tri[2] as usize)?;
+			Some(a.dot(b.cross(*c)) / 6.0)
+		})
+		.sum();
+
+	volume.abs()
+}
+
+/// Whether a point is inside a closed mesh, by counting crossings of a ray cast along +x.
+pub fn contains(vertices: &[Vector3], indices: &[u32], point: Vector3) -> bool {
+	let dir = Vector3(1.0, 0.0, 0.0);
+	let mut crossings = 0;
+
+	for tri in indices.chunks_exact(3) {
+		let get = |i: u32| vertices.get(i as usize).copied();
+		let (a, b, c) = match (get(tri[0]), get(tri[1]), get(tri[2])) {
+			(Some(a), Some(b), Some(c)) => (a, b, c),
+			_ => continue,
+		};
+
+		if ray_triangle(point, dir, a, b, c) {
+			crossings += 1;
+		}
+	}
+
+	crossings % 2 == 1
+}
+
+/// Möller–Trumbore intersection, only counting hits in front of the origin.
+fn ray_triangle(origin: Vector3, dir: Vector3, a: Vector3, b: Vector3, c: Vector3) -> bool {
+	const EPSILON: f32 = 1e-7;
+
+	let e1 = b - a;
+	let e2 = c - a;
+	let p = dir.cross(e2);
+	let det = e1.dot(p);
+
+	if det.abs() < EPSILON {
+		return false;
+	}
+
+	let inv = 1.0 / det;
+	let t = origin - a;
+	let u = t.dot(p) * inv;
+	if !(0.0 ..= 1.0).contains(&u) {
+		return false;
+	}
+
+	let q = t.cross(e1);
+	let v = dir.dot(q) * inv;
+	if v < 0.0 || u + v > 1.0 {
+		return false;
+	}
+
+	e2.dot(q) * inv > EPSILON
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn box_lattice_is_centered() {
+		let points: Vec<Vector3> = sample_box(Vector3(1.0, 0.5, 0.0), 1.0).collect();
+		assert_eq!(points.len(), 3 * 2);
+		assert_eq!(lattice_steps(Vector3(1.0, 0.5, 0.0), 1.0), Some([3, 2, 1]));
+
+		let (lower, upper) = bounds(&points).unwrap();
+		assert!((lower + upper).length() < 1e-6);
+	}
+
+	#[test]
+	fn huge_lattices_are_turned_down() {
+		assert_eq!(lattice_steps(Vector3(1e4, 1e4, 1e4), 0.01), None);
+		assert_eq!(lattice_steps(Vector3(f32::INFINITY, 1.0, 1.0), 1.0), None);
+		assert_eq!(lattice_steps(Vector3(1.0, 1.0, 1.0), 0.0), None);
+		assert_eq!(lattice_steps(Vector3(1.0, 1.0, 1.0), f32::NAN), None);
+		assert!(sample_sphere(1e4, 0.01).next().is_none());
+
+		// Taking only a few points never builds the rest of the lattice
+		assert_eq!(sample_box(Vector3(100.0, 100.0, 100.0), 1.0).take(5).count(), 5);
+	}
+}